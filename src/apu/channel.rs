@@ -0,0 +1,116 @@
+use crate::state::{self, Reader, Writer};
+
+/// Length counter, which silences a channel after a set time.
+#[derive(Debug)]
+pub struct Length {
+  max: u16,
+  counter: u16,
+  pub enabled: bool,
+}
+
+impl Length {
+  pub fn new(max: u16) -> Length {
+    Length {
+      max,
+      counter: 0,
+      enabled: false,
+    }
+  }
+
+  /// Load the counter from the length bits of NRx1.
+  pub fn load(&mut self, value: u8) {
+    self.counter = self.max - u16::from(value);
+  }
+
+  pub fn trigger(&mut self) {
+    if self.counter == 0 {
+      self.counter = self.max;
+    }
+  }
+
+  /// Clock the counter at 256 Hz.
+  /// Returns true if it expired, disabling the channel.
+  pub fn clock(&mut self) -> bool {
+    if self.enabled && self.counter > 0 {
+      self.counter -= 1;
+      self.counter == 0
+    } else {
+      false
+    }
+  }
+
+  pub fn save_state(&self, w: &mut Writer) {
+    w.u16(self.counter);
+    w.bool(self.enabled);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    self.counter = r.u16()?.min(self.max);
+    self.enabled = r.bool()?;
+    Ok(())
+  }
+}
+
+/// Volume envelope (NRx2).
+#[derive(Debug, Default)]
+pub struct Envelope {
+  initial: u8,
+  increase: bool,
+  period: u8,
+  timer: u8,
+  pub volume: u8,
+}
+
+impl Envelope {
+  pub fn rb(&self) -> u8 {
+    self.initial << 4 | (self.increase as u8) << 3 | self.period
+  }
+
+  pub fn wb(&mut self, value: u8) {
+    self.initial = value >> 4;
+    self.increase = value & 0x08 != 0;
+    self.period = value & 0x07;
+  }
+
+  /// Whether the channel's DAC is on, which is controlled by the upper five
+  /// bits of NRx2.
+  pub fn dac_enabled(&self) -> bool {
+    self.initial != 0 || self.increase
+  }
+
+  pub fn trigger(&mut self) {
+    self.volume = self.initial;
+    self.timer = self.period;
+  }
+
+  /// Clock the envelope at 64 Hz.
+  pub fn clock(&mut self) {
+    if self.period == 0 {
+      return;
+    }
+    if self.timer > 1 {
+      self.timer -= 1;
+      return;
+    }
+    self.timer = self.period;
+    if self.increase && self.volume < 15 {
+      self.volume += 1;
+    } else if !self.increase && self.volume > 0 {
+      self.volume -= 1;
+    }
+  }
+
+  pub fn save_state(&self, w: &mut Writer) {
+    w.u8(self.rb());
+    w.u8(self.timer);
+    w.u8(self.volume);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    let value = r.u8()?;
+    self.wb(value);
+    self.timer = r.u8()? & 0x07;
+    self.volume = r.u8()? & 0x0f;
+    Ok(())
+  }
+}
@@ -0,0 +1,503 @@
+mod channel;
+mod noise;
+mod square;
+mod wave;
+
+use self::noise::Noise;
+use self::square::Square;
+use self::wave::Wave;
+use crate::state::{self, Reader, Writer};
+
+/// The APU clock rate, in Hz. It isn't affected by CGB double speed.
+pub const CLOCK_RATE: u32 = 4_194_304;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+/// Per-clock charge factor of the capacitor filtering DC out of the DAC
+/// output.
+const HIGH_PASS_CHARGE: f64 = 0.999_958;
+
+/// Filters DC out of a stereo signal, like the capacitors on the real
+/// outputs.
+#[derive(Debug, Default, Copy, Clone)]
+struct HighPass {
+  capacitor: (f32, f32),
+}
+
+impl HighPass {
+  fn filter(&mut self, (left, right): (f32, f32), charge: f32) -> (f32, f32) {
+    let out = (left - self.capacitor.0, right - self.capacitor.1);
+    self.capacitor = (left - out.0 * charge, right - out.1 * charge);
+    out
+  }
+}
+
+/// Audio processing unit.
+pub struct Apu {
+  /// NR52 bit 7.
+  power: bool,
+
+  square1: Square,
+  square2: Square,
+  wave: Wave,
+  noise: Noise,
+
+  /// Master volume and VIN panning.
+  nr50: u8,
+  /// Channel panning.
+  nr51: u8,
+
+  /// Next step of the 512 Hz frame sequencer.
+  frame_step: u8,
+  /// Last seen value of the DIV bit that clocks the frame sequencer.
+  div_bit: bool,
+
+  sample_rate: u32,
+  /// Progress towards the next sample, in units of 1/`sample_rate` cycles.
+  phase: u32,
+  /// Sum of each channel's panned output since the last sample, weighted by
+  /// the number of cycles it was held.
+  acc: [(f32, f32); 4],
+  acc_cycles: u32,
+  /// High-pass filters for the mix and for each channel.
+  filters: [HighPass; 5],
+  high_pass: f32,
+
+  /// Interleaved stereo samples that haven't been taken yet.
+  samples: Vec<f32>,
+  /// Whether to also produce samples for each channel on its own.
+  channel_samples_enabled: bool,
+  /// Stereo samples for each channel, eight values per sample period.
+  channel_samples: Vec<f32>,
+}
+
+impl Apu {
+  pub fn new(sample_rate: u32) -> Apu {
+    let mut apu = Apu {
+      power: true,
+      square1: Square::new(true),
+      square2: Square::new(false),
+      wave: Wave::new(),
+      noise: Noise::new(),
+      nr50: 0,
+      nr51: 0,
+      frame_step: 0,
+      div_bit: false,
+      sample_rate,
+      phase: 0,
+      acc: [(0.0, 0.0); 4],
+      acc_cycles: 0,
+      filters: [HighPass::default(); 5],
+      high_pass: 0.0,
+      samples: vec![],
+      channel_samples_enabled: false,
+      channel_samples: vec![],
+    };
+    apu.set_sample_rate(sample_rate);
+    apu
+  }
+
+  /// Return to the state at power-on, keeping the sample settings.
+  pub fn reset(&mut self) {
+    let mut apu = Apu::new(self.sample_rate);
+    apu.channel_samples_enabled = self.channel_samples_enabled;
+    *self = apu;
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  pub fn set_sample_rate(&mut self, sample_rate: u32) {
+    assert!(sample_rate > 0 && sample_rate <= CLOCK_RATE);
+    self.sample_rate = sample_rate;
+    self.phase = 0;
+    self.high_pass = HIGH_PASS_CHARGE
+      .powf(f64::from(CLOCK_RATE) / f64::from(sample_rate))
+      as f32;
+  }
+
+  /// Take the samples generated so far, as interleaved left/right pairs in
+  /// the range -1.0 to 1.0.
+  pub fn take_samples(&mut self) -> Vec<f32> {
+    std::mem::take(&mut self.samples)
+  }
+
+  /// Produce samples for each channel on its own, as well as the mix.
+  pub fn set_channel_samples(&mut self, enabled: bool) {
+    self.channel_samples_enabled = enabled;
+    self.channel_samples.clear();
+  }
+
+  /// Take the per-channel samples generated so far. Each sample period has
+  /// a left/right pair for channels 1 to 4 in turn. Each channel has had
+  /// panning and master volume applied, so together they add up to the
+  /// mix.
+  pub fn take_channel_samples(&mut self) -> Vec<f32> {
+    std::mem::take(&mut self.channel_samples)
+  }
+
+  pub fn rb(&self, addr: u16) -> u8 {
+    match addr {
+      0xff10..=0xff14 => self.square1.rb(addr - 0xff10),
+      0xff15..=0xff19 => self.square2.rb(addr - 0xff15),
+      0xff1a..=0xff1e => self.wave.rb(addr - 0xff1a),
+      0xff1f..=0xff23 => self.noise.rb(addr - 0xff1f),
+      0xff24 => self.nr50,
+      0xff25 => self.nr51,
+      0xff26 => {
+        (self.power as u8) << 7
+          | 0x70
+          | (self.noise.enabled as u8) << 3
+          | (self.wave.enabled as u8) << 2
+          | (self.square2.enabled as u8) << 1
+          | self.square1.enabled as u8
+      }
+      0xff30..=0xff3f => self.wave.ram[(addr - 0xff30) as usize],
+      _ => 0xff,
+    }
+  }
+
+  pub fn wb(&mut self, addr: u16, value: u8) {
+    match addr {
+      0xff26 => self.set_power(value & 0x80 != 0),
+      0xff30..=0xff3f => self.wave.ram[(addr - 0xff30) as usize] = value,
+      // Other registers are read-only while the APU is off.
+      _ if !self.power => (),
+      0xff10..=0xff14 => self.square1.wb(addr - 0xff10, value),
+      0xff15..=0xff19 => self.square2.wb(addr - 0xff15, value),
+      0xff1a..=0xff1e => self.wave.wb(addr - 0xff1a, value),
+      0xff1f..=0xff23 => self.noise.wb(addr - 0xff1f, value),
+      0xff24 => self.nr50 = value,
+      0xff25 => self.nr51 = value,
+      _ => (),
+    }
+  }
+
+  fn set_power(&mut self, power: bool) {
+    if power && !self.power {
+      self.frame_step = 0;
+    } else if !power && self.power {
+      // Powering off clears every register, but not wave RAM.
+      let ram = self.wave.ram;
+      self.square1 = Square::new(true);
+      self.square2 = Square::new(false);
+      self.wave = Wave::new();
+      self.wave.ram = ram;
+      self.noise = Noise::new();
+      self.nr50 = 0;
+      self.nr51 = 0;
+    }
+    self.power = power;
+  }
+
+  /// Run for `cycles` T-cycles. `div_bit` is the DIV bit that clocks the
+  /// frame sequencer on its falling edge (bit 4, or bit 5 in double speed).
+  pub fn step(&mut self, mut cycles: u32, div_bit: bool) {
+    if self.div_bit && !div_bit && self.power {
+      self.clock_frame_sequencer();
+    }
+    self.div_bit = div_bit;
+
+    while cycles > 0 {
+      // Cycles until the next sample is due, rounded up.
+      let until = (CLOCK_RATE - self.phase).div_ceil(self.sample_rate);
+      let n = cycles.min(until);
+      self.run(n);
+      cycles -= n;
+      self.phase += n * self.sample_rate;
+      if self.phase >= CLOCK_RATE {
+        self.phase -= CLOCK_RATE;
+        self.push_sample();
+      }
+    }
+  }
+
+  fn clock_frame_sequencer(&mut self) {
+    match self.frame_step {
+      0 | 4 => self.clock_length(),
+      2 | 6 => {
+        self.clock_length();
+        self.square1.clock_sweep();
+      }
+      7 => {
+        self.square1.clock_envelope();
+        self.square2.clock_envelope();
+        self.noise.clock_envelope();
+      }
+      _ => (),
+    }
+    self.frame_step = (self.frame_step + 1) % 8;
+  }
+
+  fn clock_length(&mut self) {
+    self.square1.clock_length();
+    self.square2.clock_length();
+    self.wave.clock_length();
+    self.noise.clock_length();
+  }
+
+  /// Advance the channels by `cycles` and accumulate their output.
+  fn run(&mut self, cycles: u32) {
+    if self.power {
+      self.square1.step(cycles);
+      self.square2.step(cycles);
+      self.wave.step(cycles);
+      self.noise.step(cycles);
+    }
+    let mix = self.mix();
+    for (acc, (left, right)) in self.acc.iter_mut().zip(&mix) {
+      acc.0 += left * cycles as f32;
+      acc.1 += right * cycles as f32;
+    }
+    self.acc_cycles += cycles;
+  }
+
+  /// The analog output of each channel's DAC, from -1.0 to 1.0.
+  fn channel_outputs(&self) -> [f32; 4] {
+    let dac = |enabled: bool, output: u8| {
+      if enabled {
+        f32::from(output) / 7.5 - 1.0
+      } else {
+        0.0
+      }
+    };
+    [
+      dac(self.square1.dac_enabled(), self.square1.output()),
+      dac(self.square2.dac_enabled(), self.square2.output()),
+      dac(self.wave.dac_enabled(), self.wave.output()),
+      dac(self.noise.dac_enabled(), self.noise.output()),
+    ]
+  }
+
+  /// Pan each channel according to NR51 and apply the NR50 volume. The
+  /// mix is the sum of the results.
+  fn mix(&self) -> [(f32, f32); 4] {
+    let mut result = [(0.0, 0.0); 4];
+    if !self.power {
+      return result;
+    }
+    let volume = |bits: u8| f32::from((bits & 0x07) + 1) / 8.0 / 4.0;
+    let (left_volume, right_volume) =
+      (volume(self.nr50 >> 4), volume(self.nr50));
+    for (i, output) in self.channel_outputs().iter().enumerate() {
+      if self.nr51 & (0x10 << i) != 0 {
+        result[i].0 = output * left_volume;
+      }
+      if self.nr51 & (0x01 << i) != 0 {
+        result[i].1 = output * right_volume;
+      }
+    }
+    result
+  }
+
+  fn push_sample(&mut self) {
+    let n = self.acc_cycles.max(1) as f32;
+    let channels: Vec<(f32, f32)> =
+      self.acc.iter().map(|&(l, r)| (l / n, r / n)).collect();
+    self.acc = [(0.0, 0.0); 4];
+    self.acc_cycles = 0;
+
+    let mix = channels
+      .iter()
+      .fold((0.0, 0.0), |(l, r), &(cl, cr)| (l + cl, r + cr));
+    let (left, right) = self.filters[0].filter(mix, self.high_pass);
+    push_bounded(&mut self.samples, &[left, right], self.sample_rate);
+
+    if self.channel_samples_enabled {
+      let mut frame = [0.0; 8];
+      for (i, &channel) in channels.iter().enumerate() {
+        let (left, right) = self.filters[i + 1].filter(channel, self.high_pass);
+        frame[i * 2] = left;
+        frame[i * 2 + 1] = right;
+      }
+      push_bounded(&mut self.channel_samples, &frame, self.sample_rate);
+    }
+  }
+
+  /// Save the registers, channels and the state of the resampler. Samples
+  /// not taken yet aren't saved.
+  pub fn save_state(&self, w: &mut Writer) {
+    w.bool(self.power);
+    self.square1.save_state(w);
+    self.square2.save_state(w);
+    self.wave.save_state(w);
+    self.noise.save_state(w);
+    w.u8(self.nr50);
+    w.u8(self.nr51);
+    w.u8(self.frame_step);
+    w.bool(self.div_bit);
+
+    w.u32(self.phase);
+    for &(left, right) in &self.acc {
+      w.f32(left);
+      w.f32(right);
+    }
+    w.u32(self.acc_cycles);
+    for filter in &self.filters {
+      w.f32(filter.capacitor.0);
+      w.f32(filter.capacitor.1);
+    }
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    self.power = r.bool()?;
+    self.square1.load_state(r)?;
+    self.square2.load_state(r)?;
+    self.wave.load_state(r)?;
+    self.noise.load_state(r)?;
+    self.nr50 = r.u8()?;
+    self.nr51 = r.u8()?;
+    self.frame_step = r.u8()? % 8;
+    self.div_bit = r.bool()?;
+
+    self.phase = r.u32()? % CLOCK_RATE;
+    for acc in self.acc.iter_mut() {
+      *acc = (r.f32()?, r.f32()?);
+    }
+    self.acc_cycles = r.u32()?;
+    for filter in self.filters.iter_mut() {
+      filter.capacitor = (r.f32()?, r.f32()?);
+    }
+    Ok(())
+  }
+}
+
+/// Append one sample period's values to `samples`, dropping the oldest
+/// second if nothing has taken samples for two seconds.
+fn push_bounded(samples: &mut Vec<f32>, values: &[f32], sample_rate: u32) {
+  let limit = sample_rate as usize * values.len();
+  if samples.len() >= limit * 2 {
+    samples.drain(..limit);
+  }
+  samples.extend_from_slice(values);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Clock the frame sequencer `n` times.
+  fn clock_frames(apu: &mut Apu, n: u32) {
+    for _ in 0..n {
+      apu.step(0, true);
+      apu.step(0, false);
+    }
+  }
+
+  #[test]
+  fn register_reads() {
+    let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+    apu.wb(0xff11, 0x80);
+    assert_eq!(apu.rb(0xff11), 0xbf);
+    assert_eq!(apu.rb(0xff13), 0xff);
+    assert_eq!(apu.rb(0xff15), 0xff);
+    assert_eq!(apu.rb(0xff2a), 0xff);
+    apu.wb(0xff30, 0x12);
+    assert_eq!(apu.rb(0xff30), 0x12);
+  }
+
+  #[test]
+  fn power_off() {
+    let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+    apu.wb(0xff12, 0xf0);
+    apu.wb(0xff14, 0x80);
+    apu.wb(0xff25, 0xff);
+    apu.wb(0xff3f, 0x34);
+    assert_eq!(apu.rb(0xff26), 0xf1);
+
+    apu.wb(0xff26, 0x00);
+    assert_eq!(apu.rb(0xff26), 0x70);
+    assert_eq!(apu.rb(0xff12), 0x00);
+    assert_eq!(apu.rb(0xff25), 0x00);
+    assert_eq!(apu.rb(0xff3f), 0x34);
+    // Writes are ignored until the APU is powered back on.
+    apu.wb(0xff25, 0xff);
+    assert_eq!(apu.rb(0xff25), 0x00);
+    apu.wb(0xff26, 0x80);
+    apu.wb(0xff25, 0xff);
+    assert_eq!(apu.rb(0xff25), 0xff);
+  }
+
+  #[test]
+  fn length_counter() {
+    let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+    apu.wb(0xff21, 0xf0);
+    apu.wb(0xff20, 0x3e);
+    apu.wb(0xff23, 0xc0);
+    assert_eq!(apu.rb(0xff26) & 0x08, 0x08);
+    // Length is clocked on every other step.
+    clock_frames(&mut apu, 1);
+    assert_eq!(apu.rb(0xff26) & 0x08, 0x08);
+    clock_frames(&mut apu, 2);
+    assert_eq!(apu.rb(0xff26) & 0x08, 0x00);
+  }
+
+  #[test]
+  fn sweep_overflow() {
+    let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+    apu.wb(0xff10, 0x11);
+    apu.wb(0xff12, 0xf0);
+    apu.wb(0xff13, 0x00);
+    apu.wb(0xff14, 0x85);
+    assert_eq!(apu.rb(0xff26) & 0x01, 0x01);
+    // The first sweep clock sets the frequency to 0x780, and the check that
+    // immediately follows finds 0x780 + 0x3c0 overflows.
+    clock_frames(&mut apu, 3);
+    assert_eq!(apu.rb(0xff26) & 0x01, 0x00);
+  }
+
+  #[test]
+  fn sample_rate() {
+    let mut apu = Apu::new(44_100);
+    for _ in 0..CLOCK_RATE / 16 {
+      apu.step(16, false);
+    }
+    assert_eq!(apu.take_samples().len(), 44_100 * 2);
+    assert!(apu.take_samples().is_empty());
+  }
+
+  #[test]
+  fn channel_samples_add_up() {
+    let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+    apu.set_channel_samples(true);
+    apu.wb(0xff24, 0x77);
+    apu.wb(0xff25, 0x31);
+    apu.wb(0xff12, 0xf0);
+    apu.wb(0xff14, 0x87);
+    apu.wb(0xff17, 0xa0);
+    apu.wb(0xff19, 0x86);
+    for _ in 0..1000 {
+      apu.step(16, false);
+    }
+    let mix = apu.take_samples();
+    let channels = apu.take_channel_samples();
+    assert_eq!(channels.len(), mix.len() * 4);
+    for (frame, channels) in mix.chunks(2).zip(channels.chunks(8)) {
+      assert!((frame[0] - (channels[0] + channels[2])).abs() < 1e-5);
+      assert!((frame[1] - (channels[1] + channels[3])).abs() < 1e-5);
+      // Channel 2 is only panned left.
+      assert_eq!(channels[3], 0.0);
+    }
+  }
+
+  #[test]
+  fn square_output() {
+    let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+    apu.wb(0xff25, 0x11);
+    apu.wb(0xff24, 0x77);
+    apu.wb(0xff11, 0x80);
+    apu.wb(0xff12, 0xf0);
+    apu.wb(0xff13, 0xff);
+    apu.wb(0xff14, 0x87);
+    // A 50% duty wave is high for half of each 32-cycle period.
+    let mut high = 0;
+    for _ in 0..64 {
+      apu.step(1, false);
+      if apu.square1.output() == 15 {
+        high += 1;
+      }
+    }
+    assert_eq!(high, 32);
+  }
+}
@@ -0,0 +1,140 @@
+use crate::apu::channel::{Envelope, Length};
+use crate::state::{self, Reader, Writer};
+
+/// Base divisors selected by NR43 bits 0-2, in T-cycles.
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// The noise channel (channel 4), driven by a linear feedback shift register.
+#[derive(Debug)]
+pub struct Noise {
+  pub enabled: bool,
+  shift: u8,
+  /// Use a 7-bit LFSR instead of a 15-bit one.
+  short: bool,
+  divisor: u8,
+  timer: u32,
+  lfsr: u16,
+  length: Length,
+  envelope: Envelope,
+}
+
+impl Noise {
+  pub fn new() -> Noise {
+    Noise {
+      enabled: false,
+      shift: 0,
+      short: false,
+      divisor: 0,
+      timer: 0,
+      lfsr: 0x7fff,
+      length: Length::new(64),
+      envelope: Envelope::default(),
+    }
+  }
+
+  /// Read register NR41-NR44. Register 0 is unused.
+  pub fn rb(&self, reg: u16) -> u8 {
+    match reg {
+      2 => self.envelope.rb(),
+      3 => self.shift << 4 | (self.short as u8) << 3 | self.divisor,
+      4 => 0xbf | (self.length.enabled as u8) << 6,
+      _ => 0xff,
+    }
+  }
+
+  /// Write register NR41-NR44. Register 0 is unused.
+  pub fn wb(&mut self, reg: u16, value: u8) {
+    match reg {
+      1 => self.length.load(value & 0x3f),
+      2 => {
+        self.envelope.wb(value);
+        if !self.dac_enabled() {
+          self.enabled = false;
+        }
+      }
+      3 => {
+        self.shift = value >> 4;
+        self.short = value & 0x08 != 0;
+        self.divisor = value & 0x07;
+      }
+      4 => {
+        self.length.enabled = value & 0x40 != 0;
+        if value & 0x80 != 0 {
+          self.trigger();
+        }
+      }
+      _ => (),
+    }
+  }
+
+  fn period(&self) -> u32 {
+    DIVISORS[self.divisor as usize] << self.shift
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.dac_enabled();
+    self.length.trigger();
+    self.timer = self.period();
+    self.envelope.trigger();
+    self.lfsr = 0x7fff;
+  }
+
+  pub fn dac_enabled(&self) -> bool {
+    self.envelope.dac_enabled()
+  }
+
+  /// Advance the LFSR timer by `cycles` T-cycles.
+  pub fn step(&mut self, mut cycles: u32) {
+    if !self.enabled {
+      return;
+    }
+    while cycles >= self.timer {
+      cycles -= self.timer;
+      self.timer = self.period();
+      let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+      self.lfsr = (self.lfsr >> 1) | (bit << 14);
+      if self.short {
+        self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+      }
+    }
+    self.timer -= cycles;
+  }
+
+  /// The current digital output, from 0 to 15.
+  pub fn output(&self) -> u8 {
+    if self.enabled && self.lfsr & 1 == 0 {
+      self.envelope.volume
+    } else {
+      0
+    }
+  }
+
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+
+  pub fn clock_envelope(&mut self) {
+    self.envelope.clock();
+  }
+
+  pub fn save_state(&self, w: &mut Writer) {
+    w.bool(self.enabled);
+    w.u8(self.rb(3));
+    w.u32(self.timer);
+    w.u16(self.lfsr);
+    self.length.save_state(w);
+    self.envelope.save_state(w);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    self.enabled = r.bool()?;
+    let nr43 = r.u8()?;
+    self.wb(3, nr43);
+    self.timer = r.u32()?;
+    self.lfsr = r.u16()? & 0x7fff;
+    self.length.load_state(r)?;
+    self.envelope.load_state(r)
+  }
+}
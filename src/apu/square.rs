@@ -0,0 +1,225 @@
+use crate::apu::channel::{Envelope, Length};
+use crate::state::{self, Reader, Writer};
+
+/// Duty cycle waveforms, played from the most significant bit.
+const DUTY: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+/// Frequency sweep unit (NR10), only present on channel 1.
+#[derive(Debug, Default)]
+struct Sweep {
+  period: u8,
+  negate: bool,
+  shift: u8,
+  timer: u8,
+  shadow: u16,
+  enabled: bool,
+}
+
+impl Sweep {
+  /// Calculate the next frequency, which may overflow past 2047.
+  fn next_frequency(&self) -> u16 {
+    let delta = self.shadow >> self.shift;
+    if self.negate {
+      self.shadow - delta
+    } else {
+      self.shadow + delta
+    }
+  }
+}
+
+/// A square wave channel (channels 1 and 2).
+#[derive(Debug)]
+pub struct Square {
+  pub enabled: bool,
+  sweep: Option<Sweep>,
+  duty: u8,
+  position: u8,
+  frequency: u16,
+  timer: u32,
+  length: Length,
+  envelope: Envelope,
+}
+
+impl Square {
+  pub fn new(sweep: bool) -> Square {
+    Square {
+      enabled: false,
+      sweep: if sweep { Some(Sweep::default()) } else { None },
+      duty: 0,
+      position: 0,
+      frequency: 0,
+      timer: 0,
+      length: Length::new(64),
+      envelope: Envelope::default(),
+    }
+  }
+
+  /// Read register NRx0-NRx4.
+  pub fn rb(&self, reg: u16) -> u8 {
+    match reg {
+      0 => match self.sweep {
+        Some(ref s) => 0x80 | s.period << 4 | (s.negate as u8) << 3 | s.shift,
+        None => 0xff,
+      },
+      1 => self.duty << 6 | 0x3f,
+      2 => self.envelope.rb(),
+      4 => 0xbf | (self.length.enabled as u8) << 6,
+      _ => 0xff,
+    }
+  }
+
+  /// Write register NRx0-NRx4.
+  pub fn wb(&mut self, reg: u16, value: u8) {
+    match reg {
+      0 => {
+        if let Some(ref mut s) = self.sweep {
+          s.period = (value >> 4) & 0x07;
+          s.negate = value & 0x08 != 0;
+          s.shift = value & 0x07;
+        }
+      }
+      1 => {
+        self.duty = value >> 6;
+        self.length.load(value & 0x3f);
+      }
+      2 => {
+        self.envelope.wb(value);
+        if !self.dac_enabled() {
+          self.enabled = false;
+        }
+      }
+      3 => self.frequency = (self.frequency & 0x700) | u16::from(value),
+      4 => {
+        self.frequency =
+          (self.frequency & 0xff) | (u16::from(value & 0x07) << 8);
+        self.length.enabled = value & 0x40 != 0;
+        if value & 0x80 != 0 {
+          self.trigger();
+        }
+      }
+      _ => (),
+    }
+  }
+
+  fn period(&self) -> u32 {
+    (2048 - u32::from(self.frequency)) * 4
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.dac_enabled();
+    self.length.trigger();
+    self.timer = self.period();
+    self.envelope.trigger();
+
+    let frequency = self.frequency;
+    if let Some(ref mut s) = self.sweep {
+      s.shadow = frequency;
+      s.timer = if s.period == 0 { 8 } else { s.period };
+      s.enabled = s.period != 0 || s.shift != 0;
+      if s.shift != 0 && s.next_frequency() > 2047 {
+        self.enabled = false;
+      }
+    }
+  }
+
+  pub fn dac_enabled(&self) -> bool {
+    self.envelope.dac_enabled()
+  }
+
+  /// Advance the frequency timer by `cycles` T-cycles.
+  pub fn step(&mut self, mut cycles: u32) {
+    if !self.enabled {
+      return;
+    }
+    while cycles >= self.timer {
+      cycles -= self.timer;
+      self.timer = self.period();
+      self.position = (self.position + 1) & 7;
+    }
+    self.timer -= cycles;
+  }
+
+  /// The current digital output, from 0 to 15.
+  pub fn output(&self) -> u8 {
+    if self.enabled && DUTY[self.duty as usize] >> (7 - self.position) & 1 != 0
+    {
+      self.envelope.volume
+    } else {
+      0
+    }
+  }
+
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+
+  pub fn clock_envelope(&mut self) {
+    self.envelope.clock();
+  }
+
+  pub fn save_state(&self, w: &mut Writer) {
+    w.bool(self.enabled);
+    if let Some(ref s) = self.sweep {
+      w.u8(s.period);
+      w.bool(s.negate);
+      w.u8(s.shift);
+      w.u8(s.timer);
+      w.u16(s.shadow);
+      w.bool(s.enabled);
+    }
+    w.u8(self.duty);
+    w.u8(self.position);
+    w.u16(self.frequency);
+    w.u32(self.timer);
+    self.length.save_state(w);
+    self.envelope.save_state(w);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    self.enabled = r.bool()?;
+    if let Some(ref mut s) = self.sweep {
+      s.period = r.u8()? & 0x07;
+      s.negate = r.bool()?;
+      s.shift = r.u8()? & 0x07;
+      s.timer = r.u8()? & 0x0f;
+      s.shadow = r.u16()? & 0x7ff;
+      s.enabled = r.bool()?;
+    }
+    self.duty = r.u8()? & 0x03;
+    self.position = r.u8()? & 0x07;
+    self.frequency = r.u16()? & 0x7ff;
+    self.timer = r.u32()?;
+    self.length.load_state(r)?;
+    self.envelope.load_state(r)
+  }
+
+  /// Clock the frequency sweep at 128 Hz.
+  pub fn clock_sweep(&mut self) {
+    let s = match self.sweep {
+      Some(ref mut s) => s,
+      None => return,
+    };
+    if s.timer > 1 {
+      s.timer -= 1;
+      return;
+    }
+    s.timer = if s.period == 0 { 8 } else { s.period };
+    if !s.enabled || s.period == 0 {
+      return;
+    }
+
+    let frequency = s.next_frequency();
+    if frequency > 2047 {
+      self.enabled = false;
+    } else if s.shift != 0 {
+      s.shadow = frequency;
+      self.frequency = frequency;
+      // The new frequency is checked for overflow again straight away.
+      if s.next_frequency() > 2047 {
+        self.enabled = false;
+      }
+    }
+  }
+}
@@ -0,0 +1,142 @@
+use crate::apu::channel::Length;
+use crate::state::{self, Reader, Writer};
+
+/// The wave channel (channel 3), which plays 4-bit samples from wave RAM.
+#[derive(Debug)]
+pub struct Wave {
+  pub enabled: bool,
+  dac: bool,
+  /// Output level (NR32 bits 5-6).
+  level: u8,
+  frequency: u16,
+  timer: u32,
+  position: u8,
+  sample: u8,
+  length: Length,
+  pub ram: [u8; 16],
+}
+
+impl Wave {
+  pub fn new() -> Wave {
+    Wave {
+      enabled: false,
+      dac: false,
+      level: 0,
+      frequency: 0,
+      timer: 0,
+      position: 0,
+      sample: 0,
+      length: Length::new(256),
+      ram: [0; 16],
+    }
+  }
+
+  /// Read register NR30-NR34.
+  pub fn rb(&self, reg: u16) -> u8 {
+    match reg {
+      0 => 0x7f | (self.dac as u8) << 7,
+      2 => 0x9f | self.level << 5,
+      4 => 0xbf | (self.length.enabled as u8) << 6,
+      _ => 0xff,
+    }
+  }
+
+  /// Write register NR30-NR34.
+  pub fn wb(&mut self, reg: u16, value: u8) {
+    match reg {
+      0 => {
+        self.dac = value & 0x80 != 0;
+        if !self.dac {
+          self.enabled = false;
+        }
+      }
+      1 => self.length.load(value),
+      2 => self.level = (value >> 5) & 0x03,
+      3 => self.frequency = (self.frequency & 0x700) | u16::from(value),
+      4 => {
+        self.frequency =
+          (self.frequency & 0xff) | (u16::from(value & 0x07) << 8);
+        self.length.enabled = value & 0x40 != 0;
+        if value & 0x80 != 0 {
+          self.trigger();
+        }
+      }
+      _ => (),
+    }
+  }
+
+  fn period(&self) -> u32 {
+    (2048 - u32::from(self.frequency)) * 2
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.dac;
+    self.length.trigger();
+    self.timer = self.period();
+    self.position = 0;
+  }
+
+  pub fn dac_enabled(&self) -> bool {
+    self.dac
+  }
+
+  /// Advance the frequency timer by `cycles` T-cycles.
+  pub fn step(&mut self, mut cycles: u32) {
+    if !self.enabled {
+      return;
+    }
+    while cycles >= self.timer {
+      cycles -= self.timer;
+      self.timer = self.period();
+      self.position = (self.position + 1) & 31;
+      let byte = self.ram[(self.position / 2) as usize];
+      self.sample = if self.position & 1 == 0 {
+        byte >> 4
+      } else {
+        byte & 0x0f
+      };
+    }
+    self.timer -= cycles;
+  }
+
+  /// The current digital output, from 0 to 15.
+  pub fn output(&self) -> u8 {
+    if !self.enabled {
+      return 0;
+    }
+    match self.level {
+      0 => 0,
+      level => self.sample >> (level - 1),
+    }
+  }
+
+  pub fn save_state(&self, w: &mut Writer) {
+    w.bool(self.enabled);
+    w.bool(self.dac);
+    w.u8(self.level);
+    w.u16(self.frequency);
+    w.u32(self.timer);
+    w.u8(self.position);
+    w.u8(self.sample);
+    self.length.save_state(w);
+    w.bytes(&self.ram);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    self.enabled = r.bool()?;
+    self.dac = r.bool()?;
+    self.level = r.u8()? & 0x03;
+    self.frequency = r.u16()? & 0x7ff;
+    self.timer = r.u32()?;
+    self.position = r.u8()? & 31;
+    self.sample = r.u8()? & 0x0f;
+    self.length.load_state(r)?;
+    r.bytes_into(&mut self.ram)
+  }
+
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+}
@@ -0,0 +1,72 @@
+use crate::audio::AudioSink;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Most frames to queue, in seconds. Anything beyond this, e.g. while
+/// fast-forwarding, is dropped.
+const MAX_QUEUED_SECONDS: usize = 1;
+
+/// Plays audio on the default output device.
+pub struct DeviceSink {
+  sample_rate: u32,
+  queue: Arc<Mutex<VecDeque<f32>>>,
+  _stream: cpal::Stream,
+}
+
+impl DeviceSink {
+  pub fn open(sample_rate: u32) -> Result<DeviceSink, Box<dyn Error>> {
+    let device = cpal::default_host()
+      .default_output_device()
+      .ok_or("No audio output device")?;
+    let config = cpal::StreamConfig {
+      channels: 2,
+      sample_rate: cpal::SampleRate(sample_rate),
+      buffer_size: cpal::BufferSize::Default,
+    };
+
+    let queue = Arc::new(Mutex::new(VecDeque::new()));
+    let source = Arc::clone(&queue);
+    let stream = device.build_output_stream(
+      &config,
+      move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+        let mut queue = source.lock().unwrap();
+        for sample in data.iter_mut() {
+          // Play silence on underrun.
+          *sample = queue.pop_front().unwrap_or(0.0);
+        }
+      },
+      |e| eprintln!("Audio stream error: {}", e),
+      None,
+    )?;
+    stream.play()?;
+
+    Ok(DeviceSink {
+      sample_rate,
+      queue,
+      _stream: stream,
+    })
+  }
+}
+
+impl AudioSink for DeviceSink {
+  fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+    let mut queue = self.queue.lock().unwrap();
+    let max = self.sample_rate as usize * 2 * MAX_QUEUED_SECONDS;
+    let room = max.saturating_sub(queue.len());
+    queue.extend(&samples[..samples.len().min(room) & !1]);
+    Ok(())
+  }
+
+  fn queued(&self) -> Option<usize> {
+    Some(self.queue.lock().unwrap().len() / 2)
+  }
+}
@@ -0,0 +1,152 @@
+mod record;
+mod resample;
+mod wav;
+
+#[cfg(feature = "audio-device")]
+mod device;
+
+pub use self::record::Recorder;
+pub use self::resample::Resampler;
+pub use self::wav::{WavSink, WavWriter};
+
+#[cfg(feature = "audio-device")]
+pub use self::device::DeviceSink;
+
+use crate::apu;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Rate the APU generates samples at before they're resampled for a sink.
+pub const APU_RATE: u32 = apu::CLOCK_RATE / 32;
+
+/// Somewhere to send audio.
+pub trait AudioSink {
+  /// Rate the sink expects samples at, in Hz.
+  fn sample_rate(&self) -> u32;
+
+  /// Queue interleaved stereo samples in the range -1.0 to 1.0.
+  fn write(&mut self, samples: &[f32]) -> io::Result<()>;
+
+  /// Number of stereo frames waiting to be played, for sinks that play in
+  /// real time.
+  fn queued(&self) -> Option<usize> {
+    None
+  }
+}
+
+/// Discards all audio.
+pub struct NullSink {
+  sample_rate: u32,
+}
+
+impl NullSink {
+  pub fn new(sample_rate: u32) -> NullSink {
+    NullSink { sample_rate }
+  }
+}
+
+impl AudioSink for NullSink {
+  fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  fn write(&mut self, _samples: &[f32]) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+#[derive(Debug)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Invalid audio output: {}", self.0)
+  }
+}
+
+impl Error for ParseError {}
+
+/// An audio output selected on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+  Device,
+  Wav(PathBuf),
+  Null,
+}
+
+impl Backend {
+  /// Parse `device`, `none`, or the path of a `.wav` file.
+  pub fn parse(s: &str) -> Result<Backend, ParseError> {
+    match s {
+      "device" if cfg!(feature = "audio-device") => Ok(Backend::Device),
+      "device" => Err(ParseError(
+        "built without the audio-device feature".to_string(),
+      )),
+      "none" => Ok(Backend::Null),
+      _ if s.ends_with(".wav") => Ok(Backend::Wav(PathBuf::from(s))),
+      _ => Err(ParseError(s.to_string())),
+    }
+  }
+
+  pub fn open(
+    &self,
+    sample_rate: u32,
+  ) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
+    Ok(match *self {
+      #[cfg(feature = "audio-device")]
+      Backend::Device => Box::new(DeviceSink::open(sample_rate)?),
+      #[cfg(not(feature = "audio-device"))]
+      Backend::Device => {
+        return Err("Built without audio device support".into())
+      }
+      Backend::Wav(ref path) => Box::new(WavSink::create(path, sample_rate)?),
+      Backend::Null => Box::new(NullSink::new(sample_rate)),
+    })
+  }
+}
+
+impl Default for Backend {
+  fn default() -> Backend {
+    if cfg!(feature = "audio-device") {
+      Backend::Device
+    } else {
+      Backend::Null
+    }
+  }
+}
+
+/// A sink along with the resampler feeding it APU samples.
+pub struct AudioOutput {
+  sink: Box<dyn AudioSink>,
+  resampler: Resampler,
+  buffer: Vec<f32>,
+}
+
+impl AudioOutput {
+  pub fn new(sink: Box<dyn AudioSink>) -> AudioOutput {
+    AudioOutput {
+      resampler: Resampler::new(APU_RATE, sink.sample_rate()),
+      sink,
+      buffer: vec![],
+    }
+  }
+
+  /// Resample APU samples and send them to the sink.
+  pub fn push(&mut self, samples: &[f32]) -> io::Result<()> {
+    self.buffer.clear();
+    self.resampler.process(samples, &mut self.buffer);
+    self.sink.write(&self.buffer)
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sink.sample_rate()
+  }
+
+  /// Frames waiting to be played, if the sink plays in real time.
+  pub fn queued(&self) -> Option<usize> {
+    self.sink.queued()
+  }
+}
@@ -0,0 +1,103 @@
+use crate::audio::{Resampler, WavWriter, APU_RATE};
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// One WAV file along with the resampler feeding it.
+struct Track {
+  writer: WavWriter<BufWriter<File>>,
+  resampler: Resampler,
+  buffer: Vec<f32>,
+}
+
+impl Track {
+  fn create(path: &Path, sample_rate: u32) -> io::Result<Track> {
+    Ok(Track {
+      writer: WavWriter::create(path, sample_rate, 2)?,
+      resampler: Resampler::new(APU_RATE, sample_rate),
+      buffer: vec![],
+    })
+  }
+
+  fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+    self.buffer.clear();
+    self.resampler.process(samples, &mut self.buffer);
+    self.writer.write_samples(&self.buffer)
+  }
+}
+
+/// Records the APU's output to WAV files, optionally with a separate file
+/// for each channel.
+/// Samples are written as the APU produces them, so a recording only
+/// depends on emulated time and is the same on every run.
+pub struct Recorder {
+  mix: Track,
+  channels: Vec<Track>,
+}
+
+/// Path of the file recording channel `n` (1-4) alongside `path`, e.g.
+/// `music.ch1.wav` for `music.wav`.
+pub fn channel_path(path: &Path, n: usize) -> PathBuf {
+  let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+  path.with_file_name(format!("{}.ch{}.wav", stem, n))
+}
+
+impl Recorder {
+  pub fn create(
+    path: &Path,
+    sample_rate: u32,
+    channels: bool,
+  ) -> io::Result<Recorder> {
+    let mix = Track::create(path, sample_rate)?;
+    let channels = if channels {
+      (1..=4)
+        .map(|n| Track::create(&channel_path(path, n), sample_rate))
+        .collect::<io::Result<_>>()?
+    } else {
+      vec![]
+    };
+    Ok(Recorder { mix, channels })
+  }
+
+  /// Whether each channel is recorded to its own file.
+  pub fn records_channels(&self) -> bool {
+    !self.channels.is_empty()
+  }
+
+  /// Write APU samples: the stereo mix, and the per-channel samples if
+  /// recording channels.
+  pub fn write(&mut self, mix: &[f32], channels: &[f32]) -> io::Result<()> {
+    self.mix.write(mix)?;
+    for (i, track) in self.channels.iter_mut().enumerate() {
+      let samples: Vec<f32> = channels
+        .chunks_exact(8)
+        .flat_map(|frame| frame[i * 2..i * 2 + 2].iter().copied())
+        .collect();
+      track.write(&samples)?;
+    }
+    Ok(())
+  }
+
+  /// Complete the files, reporting any error.
+  pub fn finish(mut self) -> io::Result<()> {
+    self.mix.writer.finish()?;
+    for track in &mut self.channels {
+      track.writer.finish()?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn channel_paths() {
+    assert_eq!(
+      channel_path(Path::new("out/music.wav"), 2),
+      PathBuf::from("out/music.ch2.wav")
+    );
+  }
+}
@@ -0,0 +1,118 @@
+use std::f64::consts::PI;
+
+/// Input frames each output frame is computed from.
+const TAPS: usize = 64;
+/// Number of precomputed fractional positions between input frames.
+const PHASES: usize = 256;
+
+/// Band-limited stereo resampler, using a windowed-sinc filter.
+pub struct Resampler {
+  /// Input frames per output frame.
+  step: f64,
+  /// Position of the next output frame in `history`.
+  pos: f64,
+  history: Vec<[f32; 2]>,
+  /// Filter kernels for each phase, `TAPS` coefficients each.
+  kernels: Vec<f32>,
+}
+
+fn sinc(x: f64) -> f64 {
+  if x == 0.0 {
+    1.0
+  } else {
+    (PI * x).sin() / (PI * x)
+  }
+}
+
+fn blackman(x: f64) -> f64 {
+  let t = x / TAPS as f64;
+  0.42 + 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos()
+}
+
+impl Resampler {
+  pub fn new(input_rate: u32, output_rate: u32) -> Resampler {
+    // Cut off a little below the lower of the two Nyquist frequencies.
+    let cutoff =
+      f64::min(1.0, f64::from(output_rate) / f64::from(input_rate)) * 0.9;
+    let mut kernels = Vec::with_capacity((PHASES + 1) * TAPS);
+    for phase in 0..=PHASES {
+      let frac = phase as f64 / PHASES as f64;
+      let kernel: Vec<f64> = (0..TAPS)
+        .map(|k| {
+          let x = (k as f64 + 1.0 - (TAPS / 2) as f64) - frac;
+          sinc(cutoff * x) * blackman(x)
+        })
+        .collect();
+      // Normalise for unity gain at DC.
+      let sum: f64 = kernel.iter().sum();
+      kernels.extend(kernel.iter().map(|&w| (w / sum) as f32));
+    }
+    Resampler {
+      step: f64::from(input_rate) / f64::from(output_rate),
+      pos: (TAPS / 2 - 1) as f64,
+      history: vec![[0.0; 2]; TAPS],
+      kernels,
+    }
+  }
+
+  /// Resample interleaved stereo `input`, appending to `output`.
+  pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+    self
+      .history
+      .extend(input.chunks_exact(2).map(|s| [s[0], s[1]]));
+
+    while self.pos as usize + TAPS / 2 < self.history.len() {
+      let base = self.pos as usize;
+      let phase = ((self.pos - base as f64) * PHASES as f64).round() as usize;
+      let kernel = &self.kernels[phase * TAPS..(phase + 1) * TAPS];
+      let frames = &self.history[base + 1 - TAPS / 2..=base + TAPS / 2];
+      let (mut left, mut right) = (0.0, 0.0);
+      for (w, frame) in kernel.iter().zip(frames) {
+        left += w * frame[0];
+        right += w * frame[1];
+      }
+      output.push(left);
+      output.push(right);
+      self.pos += self.step;
+    }
+
+    // Forget the frames no future output needs.
+    let consumed = (self.pos as usize + 1).saturating_sub(TAPS / 2);
+    self.history.drain(..consumed);
+    self.pos -= consumed as f64;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rate_and_gain() {
+    let mut resampler = Resampler::new(131_072, 48_000);
+    let mut output = vec![];
+    for _ in 0..16 {
+      resampler.process(&[0.5; 131_072 / 8], &mut output);
+    }
+    // One second of input gives about one second of output.
+    let frames = output.len() / 2;
+    assert!((frames as isize - 48_000).abs() < TAPS as isize);
+    // The level of a constant signal is unchanged.
+    assert!(output[1000..].iter().all(|s| (s - 0.5).abs() < 1e-3));
+  }
+
+  #[test]
+  fn removes_high_frequencies() {
+    let mut resampler = Resampler::new(131_072, 48_000);
+    // A tone near the input's Nyquist frequency is above the output's.
+    let input: Vec<f32> = (0..131_072)
+      .flat_map(|i| {
+        let s = if (i / 2) % 2 == 0 { 1.0 } else { -1.0 };
+        vec![s, s]
+      })
+      .collect();
+    let mut output = vec![];
+    resampler.process(&input, &mut output);
+    assert!(output[1000..].iter().all(|s| s.abs() < 0.01));
+  }
+}
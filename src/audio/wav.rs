@@ -0,0 +1,169 @@
+use crate::audio::AudioSink;
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Size of the RIFF header before the sample data.
+const HEADER_SIZE: u32 = 44;
+
+/// Most sample data the 32-bit RIFF sizes can describe.
+const MAX_DATA_LEN: u32 = u32::MAX - (HEADER_SIZE - 8);
+
+/// Writes 16-bit PCM WAV data. The header's sizes are filled in when the
+/// writer is finished or dropped.
+pub struct WavWriter<W: Write + Seek> {
+  writer: W,
+  channels: u16,
+  data_len: u32,
+  finished: bool,
+}
+
+impl WavWriter<BufWriter<File>> {
+  pub fn create(
+    path: &Path,
+    sample_rate: u32,
+    channels: u16,
+  ) -> io::Result<WavWriter<BufWriter<File>>> {
+    WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+  }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+  pub fn new(
+    mut writer: W,
+    sample_rate: u32,
+    channels: u16,
+  ) -> io::Result<WavWriter<W>> {
+    let block_align = channels * 2;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // PCM
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&channels.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * u32::from(block_align)).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&16u16.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    Ok(WavWriter {
+      writer,
+      channels,
+      data_len: 0,
+      finished: false,
+    })
+  }
+
+  pub fn channels(&self) -> u16 {
+    self.channels
+  }
+
+  /// Write interleaved samples in the range -1.0 to 1.0. Fails without
+  /// writing anything once the file would pass the 4 GiB RIFF limit.
+  pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+    let data_len = u32::try_from(samples.len() * 2)
+      .ok()
+      .and_then(|len| self.data_len.checked_add(len))
+      .filter(|&len| len <= MAX_DATA_LEN)
+      .ok_or_else(|| io::Error::other("WAV file is at its 4 GiB limit"))?;
+    for &sample in samples {
+      let value = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16;
+      self.writer.write_all(&value.to_le_bytes())?;
+    }
+    self.data_len = data_len;
+    Ok(())
+  }
+
+  /// Fill in the header and flush everything written.
+  pub fn finish(&mut self) -> io::Result<()> {
+    self.finished = true;
+    self.writer.seek(SeekFrom::Start(4))?;
+    self
+      .writer
+      .write_all(&(HEADER_SIZE - 8 + self.data_len).to_le_bytes())?;
+    self
+      .writer
+      .seek(SeekFrom::Start(u64::from(HEADER_SIZE) - 4))?;
+    self.writer.write_all(&self.data_len.to_le_bytes())?;
+    self.writer.seek(SeekFrom::End(0))?;
+    self.writer.flush()
+  }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+  fn drop(&mut self) {
+    if !self.finished {
+      if let Err(e) = self.finish() {
+        eprintln!("Unable to finish WAV file: {}", e);
+      }
+    }
+  }
+}
+
+/// Writes audio to a WAV file as fast as it's produced.
+pub struct WavSink {
+  sample_rate: u32,
+  writer: WavWriter<BufWriter<File>>,
+}
+
+impl WavSink {
+  pub fn create(path: &Path, sample_rate: u32) -> io::Result<WavSink> {
+    Ok(WavSink {
+      sample_rate,
+      writer: WavWriter::create(path, sample_rate, 2)?,
+    })
+  }
+}
+
+impl AudioSink for WavSink {
+  fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+    self.writer.write_samples(samples)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  #[test]
+  fn header() {
+    let mut data = vec![];
+    {
+      let mut writer =
+        WavWriter::new(Cursor::new(&mut data), 48_000, 2).unwrap();
+      writer.write_samples(&[0.0, 1.0, -1.0, 0.5]).unwrap();
+    }
+    assert_eq!(data.len(), 44 + 8);
+    assert_eq!(&data[0..4], b"RIFF");
+    assert_eq!(data[4..8], 44u32.to_le_bytes());
+    assert_eq!(data[24..28], 48_000u32.to_le_bytes());
+    assert_eq!(data[40..44], 8u32.to_le_bytes());
+    assert_eq!(data[46..48], i16::MAX.to_le_bytes());
+    assert_eq!(data[48..50], (-i16::MAX).to_le_bytes());
+  }
+
+  #[test]
+  fn size_limit() {
+    let mut data = vec![];
+    {
+      let mut writer =
+        WavWriter::new(Cursor::new(&mut data), 48_000, 2).unwrap();
+      writer.data_len = MAX_DATA_LEN - 4;
+      writer.write_samples(&[0.0, 0.0]).unwrap();
+      assert!(writer.write_samples(&[0.0, 0.0]).is_err());
+      assert_eq!(writer.data_len, MAX_DATA_LEN);
+    }
+    assert_eq!(data.len(), 44 + 4);
+    assert_eq!(data[4..8], u32::MAX.to_le_bytes());
+    assert_eq!(data[40..44], MAX_DATA_LEN.to_le_bytes());
+  }
+}
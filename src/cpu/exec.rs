@@ -19,6 +19,22 @@ impl CPU {
     }
   }
 
+  /// Create a CPU in the state the SGB boot ROM leaves it.
+  pub fn new_sgb() -> CPU {
+    CPU {
+      regs: Registers::new_sgb(),
+      ..CPU::new()
+    }
+  }
+
+  /// Create a CPU in the state the CGB boot ROM leaves it.
+  pub fn new_cgb() -> CPU {
+    CPU {
+      regs: Registers::new_cgb(),
+      ..CPU::new()
+    }
+  }
+
   fn push(&mut self, mem: &mut Memory, value: u16) {
     self.regs.sp -= 2;
     mem.ww(self.regs.sp, value);
@@ -422,7 +438,9 @@ impl CPU {
       }
 
       0x10 => {
-        self.stop = true;
+        if !mem.stop() {
+          self.stop = true;
+        }
         1
       }
       0x11 => ld_n_nn!(d, e),
@@ -1,6 +1,8 @@
 mod exec;
 mod reg;
 
+use crate::state::{self, Reader, Writer};
+
 pub struct CPU {
   regs: Registers,
 
@@ -40,3 +42,48 @@ struct Registers {
   m: u32,
   t: u32,
 }
+
+impl CPU {
+  pub fn save_state(&self, w: &mut Writer) {
+    let r = &self.regs;
+    for &reg in &[r.a, r.b, r.c, r.d, r.e, r.f, r.h, r.l] {
+      w.u8(reg);
+    }
+    w.u16(r.pc);
+    w.u16(r.sp);
+    w.u32(r.m);
+    w.u32(r.t);
+    w.u32(self.m);
+    w.u32(self.t);
+    w.bool(self.halt);
+    w.bool(self.stop);
+    w.bool(self.ime);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    let regs = &mut self.regs;
+    for reg in &mut [
+      &mut regs.a,
+      &mut regs.b,
+      &mut regs.c,
+      &mut regs.d,
+      &mut regs.e,
+      &mut regs.f,
+      &mut regs.h,
+      &mut regs.l,
+    ] {
+      **reg = r.u8()?;
+    }
+    regs.f &= 0xf0;
+    regs.pc = r.u16()?;
+    regs.sp = r.u16()?;
+    regs.m = r.u32()?;
+    regs.t = r.u32()?;
+    self.m = r.u32()?;
+    self.t = r.u32()?;
+    self.halt = r.bool()?;
+    self.stop = r.bool()?;
+    self.ime = r.bool()?;
+    Ok(())
+  }
+}
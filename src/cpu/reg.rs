@@ -25,6 +25,36 @@ impl Registers {
     }
   }
 
+  /// Registers as left by the SGB boot ROM.
+  pub fn new_sgb() -> Registers {
+    Registers {
+      a: 0x01,
+      f: 0x00,
+      b: 0x00,
+      c: 0x14,
+      d: 0x00,
+      e: 0x00,
+      h: 0xc0,
+      l: 0x60,
+      ..Registers::new()
+    }
+  }
+
+  /// Registers as left by the CGB boot ROM.
+  pub fn new_cgb() -> Registers {
+    Registers {
+      a: 0x11,
+      f: 0x80,
+      b: 0x00,
+      c: 0x00,
+      d: 0xff,
+      e: 0x56,
+      h: 0x00,
+      l: 0x0d,
+      ..Registers::new()
+    }
+  }
+
   pub fn af(&self) -> u16 {
     (u16::from(self.a) << 8) | u16::from(self.f)
   }
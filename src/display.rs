@@ -1,34 +1,43 @@
 use crate::gpu;
 
-const WIDTH: usize = gpu::WIDTH;
-const HEIGHT: usize = gpu::HEIGHT;
-const WINDOW_SCALE: u32 = 4;
-
 pub struct Display {
   pub display: minifb::Window,
+  width: usize,
+  height: usize,
 }
 
 impl Display {
-  pub fn new() -> anyhow::Result<Display> {
+  /// Create a window showing frames of `width` x `height` pixels.
+  pub fn new(width: usize, height: usize) -> anyhow::Result<Display> {
+    // Frames already scaled up by the video pipeline aren't stretched again.
+    let scale = if width < gpu::WIDTH * 2 {
+      minifb::Scale::X2
+    } else {
+      minifb::Scale::X1
+    };
     let window = minifb::Window::new(
       "GB Rust",
-      WIDTH,
-      HEIGHT,
+      width,
+      height,
       minifb::WindowOptions {
         resize: true,
-        scale: minifb::Scale::X2,
+        scale,
         scale_mode: minifb::ScaleMode::AspectRatioStretch,
         ..minifb::WindowOptions::default()
       },
     )?;
 
-    Ok(Display { display: window })
+    Ok(Display {
+      display: window,
+      width,
+      height,
+    })
   }
 
-  pub fn redraw(&mut self, frame: &gpu::Frame) {
+  pub fn redraw(&mut self, frame: &[u32]) {
     self
       .display
-      .update_with_buffer(frame, WIDTH, HEIGHT)
+      .update_with_buffer(frame, self.width, self.height)
       .unwrap();
   }
 }
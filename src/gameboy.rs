@@ -1,41 +1,183 @@
+use crate::audio::{self, AudioOutput, AudioSink, Recorder};
 use crate::cpu::CPU;
 use crate::display::Display;
-use crate::mem::Key;
+use crate::gpu;
+#[cfg(feature = "gamepad")]
+use crate::input::Gamepads;
+use crate::input::{self, Action, Bindings, Controller, Hotkey, Input};
+use crate::link::LinkCable;
+use crate::mem::sgb;
 use crate::mem::LoadError;
-use crate::mem::Memory;
+use crate::mem::{Memory, SAV_EXTENSION};
+use crate::movie::{Mode, Movie, MovieError, MoviePlayer};
+use crate::palette::{Palettes, Preset};
+use crate::rewind::Rewind;
+use crate::state::{self, LoadState, SaveState, StateError};
+use crate::video::{self, FrameDump, Image, Pipeline, VideoRecorder};
 
 use std::error::Error;
 use std::fmt;
+use std::fs;
+use std::io;
 use std::ops::Drop;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::mpsc;
 use std::thread;
 use std::time;
 
+/// How fast emulated time runs compared to real time.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Speed {
-  Normal,
-  Double,
+  /// A multiple of normal speed, e.g. 0.5 for half speed.
+  Times(f64),
+  /// As fast as the host allows.
+  Unlimited,
 }
 
+/// Speeds stepped through by the speed up and down hotkeys, below
+/// `Speed::Unlimited`.
+const SPEED_STEPS: [f64; 7] = [0.25, 0.5, 1.0, 1.5, 2.0, 4.0, 8.0];
+
 impl Speed {
-  pub fn factor(&self) -> f64 {
+  pub const NORMAL: Speed = Speed::Times(1.0);
+
+  /// Parse a multiplier such as `0.5` or `2`, or `unlimited`.
+  pub fn parse(s: &str) -> Option<Speed> {
+    if s == "unlimited" {
+      return Some(Speed::Unlimited);
+    }
+    match s.strip_suffix('x').unwrap_or(s).parse() {
+      Ok(factor) if factor > 0.0 && f64::is_finite(factor) => {
+        Some(Speed::Times(factor))
+      }
+      _ => None,
+    }
+  }
+
+  /// The multiple of normal speed, or None if unlimited.
+  pub fn factor(&self) -> Option<f64> {
     match *self {
-      Speed::Normal => 1.0,
-      Speed::Double => 2.0,
+      Speed::Times(factor) => Some(factor),
+      Speed::Unlimited => None,
+    }
+  }
+
+  /// The next step up from this speed.
+  pub fn faster(&self) -> Speed {
+    let factor = match *self {
+      Speed::Times(factor) => factor,
+      Speed::Unlimited => return Speed::Unlimited,
+    };
+    SPEED_STEPS
+      .iter()
+      .find(|&&step| step > factor)
+      .map_or(Speed::Unlimited, |&step| Speed::Times(step))
+  }
+
+  /// The next step down from this speed.
+  pub fn slower(&self) -> Speed {
+    let factor = self.factor().unwrap_or(f64::INFINITY);
+    let step = SPEED_STEPS
+      .iter()
+      .rev()
+      .find(|&&step| step < factor)
+      .unwrap_or(&SPEED_STEPS[0]);
+    Speed::Times(*step)
+  }
+}
+
+impl fmt::Display for Speed {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Speed::Times(factor) => write!(f, "{}x", factor),
+      Speed::Unlimited => write!(f, "unlimited"),
+    }
+  }
+}
+
+/// How emulation is paced when running at normal speed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyncMode {
+  /// Run a batch of cycles each time a timer fires.
+  Timer,
+  /// Run whenever the audio device's queue drops below `AUDIO_LATENCY_MS`.
+  Audio,
+}
+
+impl SyncMode {
+  pub fn from_name(name: &str) -> Option<SyncMode> {
+    match name {
+      "timer" => Some(SyncMode::Timer),
+      "audio" => Some(SyncMode::Audio),
+      _ => None,
     }
   }
 }
 
 const MS_PER_WAIT: u32 = 16;
 
+/// T-cycles in one frame at normal speed.
+const CYCLES_PER_FRAME: u32 = 70224;
+
+/// Frames between checks for changed cartridge RAM to save, about two
+/// seconds.
+const AUTOSAVE_FRAMES: u64 = 120;
+
+/// Audio to keep queued when syncing to audio.
+const AUDIO_LATENCY_MS: u32 = 50;
+
 pub struct GameBoy {
   cpu: CPU,
   mem: Memory,
 
-  pub speed: Speed,
+  speed: Speed,
+  /// Speed the fast-forward hotkey switches to.
+  fast_forward: Speed,
   pub title: String,
+  /// Path that save files are named after: the ROM's path, or its name in
+  /// the save directory.
+  save_base: PathBuf,
+  /// CRC-32 of the ROM, to match save states to it.
+  rom_crc: u32,
+  /// Save state slot used by the save and load state hotkeys.
+  slot: u8,
+
+  /// Frames run since power-on.
+  frames: u64,
+  /// Cycles run in the current frame.
+  frame_cycles: u32,
+
+  /// Preset most recently selected by cycling palettes.
+  preset: Preset,
+
+  video: Pipeline,
+  /// Scale of screenshots taken with the hotkey.
+  screenshot_scale: usize,
+  frame_dump: Option<FrameDump>,
+  video_recorder: Option<VideoRecorder>,
+
+  audio: Option<AudioOutput>,
+  sync: SyncMode,
+  recorder: Option<Recorder>,
+
+  bindings: Bindings,
+  controller: Controller,
+  /// Buttons currently held, as passed to memory.
+  buttons: u8,
+  #[cfg(feature = "gamepad")]
+  gamepads: Option<Gamepads>,
+  paused: bool,
+  /// Whether to run one more frame while paused.
+  advance: bool,
+
+  rewind: Option<Rewind>,
+  /// Whether the rewind hotkey is held.
+  rewinding: bool,
+
+  movie: Option<MoviePlayer>,
+  /// Where the movie is saved when recording stops.
+  movie_path: PathBuf,
 }
 
 #[derive(Debug)]
@@ -43,12 +185,6 @@ pub enum RunError {
   SyncError,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum KeyEvent {
-  Pressed,
-  Released,
-}
-
 impl fmt::Display for RunError {
   fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
     match *self {
@@ -65,84 +201,972 @@ impl Error for RunError {
 }
 
 impl GameBoy {
-  pub fn new(rom: Vec<u8>, filename: PathBuf) -> Result<GameBoy, LoadError> {
+  /// Load the ROM from `rom_path`. Save files go next to it, or in
+  /// `save_dir` if given.
+  pub fn new(
+    rom: Vec<u8>,
+    rom_path: &Path,
+    save_dir: Option<&Path>,
+  ) -> Result<GameBoy, LoadError> {
     let title =
       String::from_utf8(rom[0x134..0x144].to_vec()).unwrap_or_default();
+    let rom_crc = crc32fast::hash(&rom);
+    let save_base = match save_dir {
+      Some(dir) => dir.join(rom_path.file_name().unwrap_or_default()),
+      None => rom_path.to_path_buf(),
+    };
+    let mut mem = Memory::new(rom, save_base.with_extension(SAV_EXTENSION))?;
+    mem.set_sample_rate(audio::APU_RATE);
     Ok(GameBoy {
       title,
-      cpu: CPU::new(),
-      mem: Memory::new(rom, filename)?,
-      speed: Speed::Normal,
+      save_base,
+      rom_crc,
+      slot: 0,
+      frames: 0,
+      frame_cycles: 0,
+      cpu: initial_cpu(&mem),
+      mem,
+      speed: Speed::NORMAL,
+      fast_forward: Speed::Times(2.0),
+      preset: Preset::Grey,
+      video: Pipeline::new(),
+      screenshot_scale: 1,
+      frame_dump: None,
+      video_recorder: None,
+      audio: None,
+      sync: SyncMode::Timer,
+      recorder: None,
+      bindings: Bindings::default(),
+      controller: Controller::new(),
+      buttons: 0,
+      #[cfg(feature = "gamepad")]
+      gamepads: None,
+      paused: false,
+      advance: false,
+      rewind: None,
+      rewinding: false,
+      movie: None,
+      movie_path: PathBuf::new(),
     })
   }
 
-  pub fn run(mut self, mut display: Display, limit_speed: bool) {
+  /// Restart the game, keeping cartridge RAM, as with the power switch.
+  pub fn soft_reset(&mut self) {
+    self.reset(false);
+  }
+
+  /// Restart the game with cartridge RAM cleared, as if the battery had
+  /// been taken out too.
+  pub fn hard_reset(&mut self) {
+    self.reset(true);
+  }
+
+  fn reset(&mut self, clear_ram: bool) {
+    // Movies have no way to record a reset.
+    if self.movie.is_some() {
+      println!("Movie stopped by reset");
+      if let Err(e) = self.stop_movie() {
+        eprintln!("Unable to save movie: {}", e);
+      }
+    }
+    self.mem.reset(clear_ram);
+    self.cpu = initial_cpu(&self.mem);
+    self.frame_cycles = 0;
+    // Buttons held are pressed again at the start of the next frame.
+    self.buttons = 0;
+    // As after loading a state, rewinding starts from here.
+    let snapshot = self.rewind.as_ref().map(|_| self.save_state());
+    if let (Some(rewind), Some(snapshot)) = (self.rewind.as_mut(), snapshot) {
+      rewind.push(snapshot);
+    }
+  }
+
+  /// Run as a Super Game Boy, if the game supports one and isn't running
+  /// in CGB mode.
+  pub fn enable_sgb(&mut self) {
+    self.mem.enable_sgb();
+    self.cpu = initial_cpu(&self.mem);
+  }
+
+  /// Size of the frames produced by the emulated system.
+  pub fn screen_size(&self) -> (usize, usize) {
+    if self.mem.sgb() {
+      (sgb::SGB_WIDTH, sgb::SGB_HEIGHT)
+    } else {
+      (gpu::WIDTH, gpu::HEIGHT)
+    }
+  }
+
+  /// Set the filters applied to frames before they're displayed.
+  pub fn set_video_pipeline(&mut self, video: Pipeline) {
+    self.video = video;
+  }
+
+  /// Size of the images produced by `output_image`.
+  pub fn output_size(&self) -> (usize, usize) {
+    let (width, height) = self.screen_size();
+    self.video.output_size(width, height)
+  }
+
+  /// Run the current frame through the video pipeline.
+  pub fn output_image(&mut self) -> &Image {
+    let (width, height) = self.screen_size();
+    let frame: &[u32] = match self.mem.sgb_frame() {
+      Some(frame) => frame,
+      None => self.mem.frame(),
+    };
+    self.video.process(frame, width, height)
+  }
+
+  /// The current frame as emulated, before the video pipeline.
+  pub fn frame_image(&self) -> Image {
+    let (width, height) = self.screen_size();
+    match self.mem.sgb_frame() {
+      Some(frame) => Image::from_pixels(width, height, frame),
+      None => Image::from_pixels(width, height, self.mem.frame()),
+    }
+  }
+
+  /// Write the current frame to `path` as a PNG, scaled up `scale` times.
+  pub fn screenshot(&self, path: &Path, scale: usize) -> io::Result<()> {
+    video::write_png(path, &self.frame_image(), scale)
+  }
+
+  /// Set the scale of screenshots taken with the hotkey.
+  pub fn set_screenshot_scale(&mut self, scale: usize) {
+    self.screenshot_scale = scale;
+  }
+
+  /// Take a screenshot next to the save files, numbered after the last one.
+  fn hotkey_screenshot(&self) -> io::Result<PathBuf> {
+    let dir = self.save_base.parent().unwrap_or_else(|| Path::new(""));
+    let name = self.save_base.file_stem().unwrap_or_default();
+    let path = video::next_path(dir, &name.to_string_lossy());
+    self.screenshot(&path, self.screenshot_scale)?;
+    Ok(path)
+  }
+
+  /// Write every `every`th frame from now on to `dir` as PNGs, scaled up
+  /// `scale` times.
+  pub fn start_frame_dump(
+    &mut self,
+    dir: &Path,
+    every: u64,
+    scale: usize,
+  ) -> io::Result<()> {
+    self.frame_dump = Some(FrameDump::create(dir, every, scale)?);
+    Ok(())
+  }
+
+  pub fn stop_frame_dump(&mut self) {
+    self.frame_dump = None;
+  }
+
+  /// Start recording video and audio to the AVI file at `path`, with audio
+  /// at `sample_rate`.
+  pub fn start_video_recording(
+    &mut self,
+    path: &Path,
+    sample_rate: u32,
+  ) -> io::Result<()> {
+    self.stop_video_recording()?;
+    // Audio from before the recording started isn't part of it.
+    self.play_audio();
+    let (width, height) = self.screen_size();
+    let recorder = VideoRecorder::create(path, width, height, sample_rate)?;
+    self.video_recorder = Some(recorder);
+    Ok(())
+  }
+
+  pub fn stop_video_recording(&mut self) -> io::Result<()> {
+    match self.video_recorder.take() {
+      Some(recorder) => recorder.finish(),
+      None => Ok(()),
+    }
+  }
+
+  /// Set the rate of the audio samples produced by the APU.
+  pub fn set_sample_rate(&mut self, sample_rate: u32) {
+    self.mem.set_sample_rate(sample_rate);
+  }
+
+  /// Send audio to `sink`, resampling from the APU's rate.
+  pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+    self.audio = Some(AudioOutput::new(sink));
+  }
+
+  pub fn set_sync(&mut self, sync: SyncMode) {
+    self.sync = sync;
+  }
+
+  pub fn speed(&self) -> Speed {
+    self.speed
+  }
+
+  /// Set how fast `run` goes. Audio only sets the pace at normal speed.
+  pub fn set_speed(&mut self, speed: Speed) {
+    self.speed = speed;
+  }
+
+  /// Set the speed the fast-forward hotkey switches to.
+  pub fn set_fast_forward(&mut self, speed: Speed) {
+    self.fast_forward = speed;
+  }
+
+  pub fn paused(&self) -> bool {
+    self.paused
+  }
+
+  /// Stop or restart `run` emulating. The window stays responsive while
+  /// paused.
+  pub fn set_paused(&mut self, paused: bool) {
+    self.paused = paused;
+    self.advance = false;
+  }
+
+  /// Run one more frame while paused, then pause again. Pauses first if
+  /// running.
+  pub fn advance_frame(&mut self) {
+    if self.paused {
+      self.advance = true;
+    } else {
+      self.set_paused(true);
+    }
+  }
+
+  /// Start recording audio to the WAV file at `path`, and each channel to
+  /// its own file next to it if `channels` is set.
+  pub fn start_audio_recording(
+    &mut self,
+    path: &Path,
+    sample_rate: u32,
+    channels: bool,
+  ) -> io::Result<()> {
+    self.stop_audio_recording()?;
+    // Samples from before the recording started go to the sink only.
+    self.play_audio();
+    self.recorder = Some(Recorder::create(path, sample_rate, channels)?);
+    self.mem.set_channel_samples(channels);
+    Ok(())
+  }
+
+  pub fn stop_audio_recording(&mut self) -> io::Result<()> {
+    self.play_audio();
+    self.mem.set_channel_samples(false);
+    match self.recorder.take() {
+      Some(recorder) => recorder.finish(),
+      None => Ok(()),
+    }
+  }
+
+  /// Take the audio samples generated so far, as interleaved left/right
+  /// pairs.
+  pub fn take_samples(&mut self) -> Vec<f32> {
+    self.mem.take_samples()
+  }
+
+  /// Set the mapping from keyboard keys and gamepad inputs to actions.
+  pub fn set_bindings(&mut self, bindings: Bindings) {
+    self.bindings = bindings;
+  }
+
+  /// Read input from connected gamepads, as well as the keyboard.
+  #[cfg(feature = "gamepad")]
+  pub fn set_gamepads(&mut self, gamepads: Gamepads) {
+    self.gamepads = Some(gamepads);
+  }
+
+  /// Plug `cable` into the link port.
+  pub fn set_link_cable(&mut self, cable: Box<dyn LinkCable>) {
+    self.mem.set_link_cable(cable);
+  }
+
+  /// Set the palettes used to colour the DMG output.
+  pub fn set_palettes(&mut self, palettes: Palettes) {
+    self.mem.set_palettes(palettes);
+  }
+
+  /// Switch all layers to the next built-in palette preset.
+  pub fn cycle_palette(&mut self) {
+    self.preset = self.preset.next();
+    self.set_palettes(Palettes::uniform(self.preset.palette()));
+    println!("Palette set to: {}", self.preset.name());
+  }
+
+  /// Save the whole machine. Host settings such as palettes, audio output
+  /// and bindings aren't included.
+  pub fn save_state(&self) -> Vec<u8> {
+    let mut state = SaveState::new();
+    state.section(b"INFO", |w| {
+      w.u32(self.rom_crc);
+      w.str(&self.title);
+      w.bool(self.mem.cgb());
+      w.bool(self.mem.sgb());
+      w.str(env!("CARGO_PKG_VERSION"));
+    });
+    state.section(b"CPU ", |w| self.cpu.save_state(w));
+    state.section(b"FRAM", |w| {
+      w.u64(self.frames);
+      w.u32(self.frame_cycles);
+    });
+    self.mem.save_state(&mut state);
+    state.into_bytes()
+  }
+
+  /// Load a state made by `save_state` for the same ROM. On error the
+  /// machine is left as it was.
+  pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+    let state = LoadState::parse(data)?;
+    let mut info = state.section(b"INFO")?;
+    let crc = info.u32()?;
+    let title = info.str()?;
+    if crc != self.rom_crc {
+      return Err(StateError::WrongRom(format!(
+        "state is for {} (CRC {:08x}), but this is {} (CRC {:08x})",
+        title.trim_end_matches('\0'),
+        crc,
+        self.title.trim_end_matches('\0'),
+        self.rom_crc
+      )));
+    }
+    let (cgb, sgb) = (info.bool()?, info.bool()?);
+    if (cgb, sgb) != (self.mem.cgb(), self.mem.sgb()) {
+      return Err(StateError::WrongRom(format!(
+        "state is for {}, but this is running as {}",
+        system_name(cgb, sgb),
+        system_name(self.mem.cgb(), self.mem.sgb())
+      )));
+    }
+
+    let backup = self.save_state();
+    if let Err(e) = self.restore(&state) {
+      let backup = LoadState::parse(&backup).expect("invalid backup state");
+      self
+        .restore(&backup)
+        .expect("unable to restore backup state");
+      return Err(e);
+    }
+    // Rewinding replays inputs from the newest snapshot, so it has to be
+    // the loaded state.
+    let snapshot = self.rewind.as_ref().map(|_| self.save_state());
+    if let (Some(rewind), Some(snapshot)) = (self.rewind.as_mut(), snapshot) {
+      rewind.push(snapshot);
+    }
+    self.seek_movie();
+    Ok(())
+  }
+
+  fn restore(&mut self, state: &LoadState) -> state::Result<()> {
+    self.cpu.load_state(&mut state.section(b"CPU ")?)?;
+    let mut r = state.section(b"FRAM")?;
+    self.frames = r.u64()?;
+    self.frame_cycles = r.u32()?;
+    self.mem.load_state(state)?;
+    // The game sees the buttons from the state until the next frame, when
+    // the buttons held now are pressed again.
+    self.buttons = input::button_keys(0xff)
+      .filter(|&key| self.mem.key_pressed(key))
+      .fold(0, |buttons, key| buttons | input::mask(key));
+    Ok(())
+  }
+
+  /// Path of the file for save state slot `slot`, next to the ROM or in the
+  /// save directory.
+  pub fn slot_path(&self, slot: u8) -> PathBuf {
+    self.save_base.with_extension(format!("ss{}", slot))
+  }
+
+  pub fn save_state_slot(&self, slot: u8) -> io::Result<()> {
+    fs::write(self.slot_path(slot), self.save_state())
+  }
+
+  pub fn load_state_slot(&mut self, slot: u8) -> Result<(), StateError> {
+    let data = fs::read(self.slot_path(slot))?;
+    self.load_state(&data)
+  }
+
+  /// Keep a snapshot every `interval` frames for rewinding, using at most
+  /// `budget` bytes.
+  pub fn enable_rewind(&mut self, interval: u32, budget: usize) {
+    self.rewind = Some(Rewind::new(interval, budget));
+  }
+
+  pub fn disable_rewind(&mut self) {
+    self.rewind = None;
+  }
+
+  /// Go back a frame. Returns false if there's nothing left to rewind.
+  pub fn rewind_frame(&mut self) -> bool {
+    let rewind = match self.rewind {
+      Some(ref mut rewind) => rewind,
+      None => return false,
+    };
+    let frames = match rewind.frames_since_snapshot() {
+      Some(0) => {
+        if !rewind.pop() {
+          return false;
+        }
+        rewind.interval() - 1
+      }
+      Some(frames) => frames - 1,
+      None => return false,
+    };
+    let (state, inputs) = match rewind.seek(frames) {
+      Some((state, inputs)) => (state.to_vec(), inputs.to_vec()),
+      None => return false,
+    };
+
+    let state = LoadState::parse(&state).expect("invalid rewind snapshot");
+    self
+      .restore(&state)
+      .expect("unable to load rewind snapshot");
+    for buttons in inputs {
+      self.set_buttons(buttons);
+      self.emulate_frame();
+    }
+    // The audio was heard the first time round.
+    self.mem.take_samples();
+    self.mem.take_channel_samples();
+    self.seek_movie();
+    true
+  }
+
+  /// Take a rewind snapshot if one is due, and record the buttons for the
+  /// next frame. Called at the start of each frame.
+  fn record_frame(&mut self, buttons: u8) {
+    let due = match self.rewind {
+      Some(ref rewind) => rewind.snapshot_due(),
+      None => return,
+    };
+    let snapshot = if due { Some(self.save_state()) } else { None };
+    let rewind = self.rewind.as_mut().unwrap();
+    if let Some(snapshot) = snapshot {
+      rewind.push(snapshot);
+    }
+    rewind.push_input(buttons);
+  }
+
+  /// Start recording a movie to `path`, from power-on or from the current
+  /// state. It's saved when recording stops.
+  pub fn record_movie(
+    &mut self,
+    path: &Path,
+    from_power_on: bool,
+  ) -> Result<(), MovieError> {
+    self.stop_movie()?;
+    let start = if from_power_on {
+      if !self.at_power_on() {
+        return Err(MovieError::NotAtPowerOn);
+      }
+      None
+    } else {
+      Some(self.save_state())
+    };
+    let mut movie =
+      Movie::new(self.rom_crc, &self.title, self.mem.sgb(), start);
+    if from_power_on {
+      movie.battery = self.mem.battery();
+    }
+    self.start_movie(movie, Mode::Recording, path);
+    Ok(())
+  }
+
+  /// Play the movie at `path`. Unless `read_only` is set, pressing a button
+  /// during playback records over the rest of the movie.
+  pub fn play_movie(
+    &mut self,
+    path: &Path,
+    read_only: bool,
+  ) -> Result<(), MovieError> {
+    self.stop_movie()?;
+    let movie = Movie::load(path)?;
+    if movie.rom_crc != self.rom_crc {
+      return Err(MovieError::WrongGame(format!(
+        "movie is for {} (CRC {:08x}), but this is {} (CRC {:08x})",
+        movie.title.trim_end_matches('\0'),
+        movie.rom_crc,
+        self.title.trim_end_matches('\0'),
+        self.rom_crc
+      )));
+    }
+    if movie.sgb != self.mem.sgb() {
+      return Err(MovieError::WrongGame(format!(
+        "movie is for {}, but this is running as {}",
+        system_name(self.mem.cgb(), movie.sgb),
+        system_name(self.mem.cgb(), self.mem.sgb())
+      )));
+    }
+    match movie.start {
+      Some(ref start) => self.load_state(start)?,
+      None if !self.at_power_on() => return Err(MovieError::NotAtPowerOn),
+      None => {
+        if let Some(ref battery) = movie.battery {
+          self.mem.load_battery(battery);
+        }
+      }
+    }
+    self.start_movie(movie, Mode::Playing { read_only }, path);
+    Ok(())
+  }
+
+  fn start_movie(&mut self, movie: Movie, mode: Mode, path: &Path) {
+    let mut player = MoviePlayer::new(movie, mode);
+    player.start_frame = self.frames;
+    self.movie = Some(player);
+    self.movie_path = path.to_path_buf();
+  }
+
+  /// Stop the movie, saving it if it was recording.
+  pub fn stop_movie(&mut self) -> io::Result<()> {
+    match self.movie.take() {
+      Some(player) if player.mode == Mode::Recording => {
+        player.movie.save(&self.movie_path)?;
+        println!(
+          "Saved a movie of {} frames to {}",
+          player.movie.frames(),
+          self.movie_path.display()
+        );
+        Ok(())
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// First frame of the movie where playback didn't match the recording.
+  pub fn movie_desync(&self) -> Option<u64> {
+    self.movie.as_ref().and_then(|player| player.desync)
+  }
+
+  fn at_power_on(&self) -> bool {
+    self.frames == 0 && self.frame_cycles == 0
+  }
+
+  /// Get the buttons for the next frame from the movie, given the buttons
+  /// held by the player.
+  fn movie_buttons(&mut self, held: u8) -> u8 {
+    let due = match self.movie {
+      Some(ref player) => player.check_due(),
+      None => return held,
+    };
+    let check = if due {
+      Some(crc32fast::hash(&self.save_state()))
+    } else {
+      None
+    };
+    self.movie.as_mut().unwrap().next_frame(held, check)
+  }
+
+  /// Move the movie to the current frame after rewinding or loading a
+  /// state, stopping it if that frame isn't part of the movie.
+  fn seek_movie(&mut self) {
+    let frames = self.frames;
+    let player = match self.movie {
+      Some(ref mut player) => player,
+      None => return,
+    };
+    let in_movie = frames
+      .checked_sub(player.start_frame)
+      .is_some_and(|frame| player.seek(frame));
+    if !in_movie {
+      println!("Left the movie's frames, stopping it");
+      if let Err(e) = self.stop_movie() {
+        eprintln!("Unable to save movie: {}", e);
+      }
+    }
+  }
+
+  /// Write battery-backed cartridge RAM to the save file, if it changed.
+  /// Nothing is written while a movie is attached, so a replay doesn't
+  /// overwrite the player's save.
+  pub fn save_ram(&mut self) -> io::Result<()> {
+    if self.movie.is_some() {
+      return Ok(());
+    }
+    self.mem.save_ram()
+  }
+
+  /// Load battery-backed RAM from a save file in another format, such as
+  /// `.srm`, and write it to this game's save file.
+  pub fn import_save(&mut self, path: &Path) -> io::Result<()> {
+    self.mem.import_save(path)
+  }
+
+  /// Write battery-backed RAM to `path`, as an `.srm` file of RAM only or
+  /// otherwise with a clock trailer for cartridges with a clock.
+  pub fn export_save(&self, path: &Path) -> io::Result<()> {
+    self.mem.export_save(path)
+  }
+
+  /// Record, dump and save what's due after a frame is shown.
+  fn end_frame(&mut self) {
+    if self.video_recorder.is_some() {
+      // Keep the frame's audio with it.
+      self.play_audio();
+      let image = self.frame_image();
+      let recorder = self.video_recorder.as_mut().unwrap();
+      if let Err(e) = recorder.write_frame(&image) {
+        eprintln!("Video recording failed, stopping it: {}", e);
+        self.video_recorder = None;
+      }
+    }
+    if let Some(ref dump) = self.frame_dump {
+      // Number frames from 0, like movies.
+      if let Err(e) = dump.frame(self.frames - 1, &self.frame_image()) {
+        eprintln!("Unable to dump frame, stopping: {}", e);
+        self.frame_dump = None;
+      }
+    }
+    self.autosave();
+  }
+
+  /// Save cartridge RAM every `AUTOSAVE_FRAMES` frames. Failures are
+  /// reported, and retried next time.
+  fn autosave(&mut self) {
+    if self.frames.is_multiple_of(AUTOSAVE_FRAMES) {
+      if let Err(e) = self.save_ram() {
+        eprintln!("Unable to save game: {}", e);
+      }
+    }
+  }
+
+  /// Run in a window until it's closed, then save cartridge RAM.
+  pub fn run(
+    mut self,
+    mut display: Display,
+    limit_speed: bool,
+  ) -> io::Result<()> {
     let ticker = self.wait_timer(MS_PER_WAIT);
 
+    let realtime_audio =
+      matches!(self.audio, Some(ref a) if a.queued().is_some());
+    if self.sync == SyncMode::Audio && !realtime_audio {
+      eprintln!("Syncing to audio needs an audio device; using a timer");
+    }
+    let audio_sync = self.sync == SyncMode::Audio && realtime_audio;
+
+    // Buttons are updated before the first instruction of each frame.
+    let mut frame_start = true;
     while display.display.is_open() {
-      let clock_speed: f64 = 4.194304e+6 * self.speed.factor();
-      let ticks_per_wait: u32 =
-        (clock_speed / 1000.0 * MS_PER_WAIT as f64) as u32;
+      let speed = if limit_speed {
+        self.speed
+      } else {
+        Speed::Unlimited
+      };
+      // Audio only sets the pace at normal speed.
+      let wait_for_audio = audio_sync && speed == Speed::NORMAL;
+      let idle = self.rewinding || (self.paused && !self.advance);
 
-      // Wait a bit to catch up.
-      if limit_speed {
+      // Wait a bit to catch up. Unlimited speed still waits while idle, to
+      // keep from spinning.
+      if idle || (speed != Speed::Unlimited && !wait_for_audio) {
         ticker.recv().unwrap();
       }
 
-      let mut total = 0;
-      while total < ticks_per_wait {
-        let mut t = 0;
-        t += self.cpu.handle_interrupt(&mut self.mem);
-        t += self.cpu.step(&mut self.mem);
-        let ints = self.mem.step(t);
+      if self.rewinding {
+        if self.rewind_frame() {
+          display.redraw(&self.output_image().pixels);
+        } else {
+          display.display.update();
+        }
+        self.poll_input(&display);
+        frame_start = true;
+        continue;
+      }
 
-        self.mem.interrupt_flags |= ints;
+      if idle {
+        display.display.update();
+        self.poll_input(&display);
+        continue;
+      }
+
+      // Run a batch of cycles, or at unlimited speed as many frames as fit
+      // in a wait, showing only the last.
+      let ticks_per_wait = speed.factor().map(|factor| {
+        (4.194304e+6 * factor / 1000.0 * MS_PER_WAIT as f64) as u32
+      });
+      let batch_start = time::Instant::now();
+      let wait = time::Duration::from_millis(MS_PER_WAIT as u64);
+      let mut total = 0;
+      loop {
+        if frame_start {
+          self.update_buttons();
+          frame_start = false;
+        }
+        let (t, frame_done) = self.step_frame();
         total += t;
 
-        if ints & 0b00001 != 0 {
-          display.redraw(self.mem.frame());
-          if let Some(keys) =
-            display.display.get_keys_pressed(minifb::KeyRepeat::No)
-          {
-            for key in &keys {
-              self.handle_key(*key, KeyEvent::Pressed);
-            }
+        if frame_done {
+          let show = ticks_per_wait.is_some() || batch_start.elapsed() >= wait;
+          if show {
+            display.redraw(&self.output_image().pixels);
+            self.poll_input(&display);
           }
-          if let Some(keys) = display.display.get_keys_released() {
-            for key in &keys {
-              self.handle_key(*key, KeyEvent::Released);
+          self.end_frame();
+          frame_start = true;
+          if self.paused {
+            self.advance = false;
+            if !show {
+              display.redraw(&self.output_image().pixels);
             }
+            break;
           }
+          if self.rewinding || (show && ticks_per_wait.is_none()) {
+            break;
+          }
+        }
+        if ticks_per_wait.is_some_and(|ticks| total >= ticks) {
+          break;
         }
       }
+
+      self.play_audio();
+      if wait_for_audio {
+        self.wait_for_audio();
+      }
     }
+    self.save_ram()
   }
 
-  fn handle_key(&mut self, key: minifb::Key, event: KeyEvent) {
-    if let Some(key) = Key::from_code(key) {
-      match event {
-        KeyEvent::Pressed => {
-          self.mem.key_down(key);
-        }
-        KeyEvent::Released => {
-          self.mem.key_up(key);
+  /// Run without a display for `frames` frames, as fast as possible, then
+  /// save cartridge RAM.
+  pub fn run_headless(&mut self, frames: u64) -> io::Result<()> {
+    for _ in 0..frames {
+      self.run_frame();
+    }
+    self.save_ram()
+  }
+
+  /// Run until the next vblank, or for a frame's worth of cycles if the
+  /// LCD is off.
+  pub fn run_frame(&mut self) {
+    self.update_buttons();
+    self.emulate_frame();
+    self.play_audio();
+    self.end_frame();
+  }
+
+  fn emulate_frame(&mut self) {
+    while !self.step_frame().1 {}
+  }
+
+  /// Run one instruction, returning the cycles taken at normal speed and
+  /// whether it finished a frame. Frames end at vblank, or after a frame's
+  /// worth of cycles if the LCD is off.
+  fn step_frame(&mut self) -> (u32, bool) {
+    let (t, ints) = self.step();
+    self.frame_cycles += t;
+    let done = ints & 0b00001 != 0
+      || (!self.mem.lcd_on() && self.frame_cycles >= CYCLES_PER_FRAME);
+    if done {
+      self.frames += 1;
+      self.frame_cycles = 0;
+    }
+    (t, done)
+  }
+
+  /// Run one instruction, returning the cycles taken at normal speed.
+  pub fn run_instruction(&mut self) -> u32 {
+    self.step().0
+  }
+
+  /// Run one instruction, returning the cycles taken at normal speed and
+  /// the interrupts raised.
+  fn step(&mut self) -> (u32, u8) {
+    let mut t = 0;
+    t += self.cpu.handle_interrupt(&mut self.mem);
+    t += self.cpu.step(&mut self.mem);
+    let ints = self.mem.step(t);
+
+    self.mem.interrupt_flags |= ints;
+    // The CPU runs twice as many cycles in CGB double-speed mode.
+    let t = if self.mem.double_speed() { t / 2 } else { t };
+    (t, ints)
+  }
+
+  /// Send the samples generated since the last call to the audio sink and
+  /// recorder.
+  fn play_audio(&mut self) {
+    let samples = self.mem.take_samples();
+
+    if let Some(ref mut recorder) = self.recorder {
+      let channels = self.mem.take_channel_samples();
+      if let Err(e) = recorder.write(&samples, &channels) {
+        eprintln!("Audio recording failed, stopping it: {}", e);
+        self.recorder = None;
+        self.mem.set_channel_samples(false);
+      }
+    }
+
+    if let Some(ref mut recorder) = self.video_recorder {
+      if let Err(e) = recorder.write_audio(&samples) {
+        eprintln!("Video recording failed, stopping it: {}", e);
+        self.video_recorder = None;
+      }
+    }
+
+    if let Some(ref mut audio) = self.audio {
+      if let Err(e) = audio.push(&samples) {
+        eprintln!("Audio output failed, disabling it: {}", e);
+        self.audio = None;
+      }
+    }
+  }
+
+  /// Block until the audio queue drains to the target latency.
+  fn wait_for_audio(&self) {
+    if let Some(ref audio) = self.audio {
+      let target = (audio.sample_rate() * AUDIO_LATENCY_MS / 1000) as usize;
+      while audio.queued().is_some_and(|queued| queued > target) {
+        thread::sleep(time::Duration::from_millis(1));
+      }
+    }
+  }
+
+  /// Handle keys pressed and released in the window, and gamepad events.
+  fn poll_input(&mut self, display: &Display) {
+    if let Some(keys) = display.display.get_keys_pressed(minifb::KeyRepeat::No)
+    {
+      for key in keys {
+        self.handle_input(Input::Key(key), true);
+      }
+    }
+    if let Some(keys) = display.display.get_keys_released() {
+      for key in keys {
+        self.handle_input(Input::Key(key), false);
+      }
+    }
+    #[cfg(feature = "gamepad")]
+    {
+      let events = match self.gamepads {
+        Some(ref mut gamepads) => gamepads.poll(),
+        None => vec![],
+      };
+      for (input, pressed) in events {
+        self.handle_input(input, pressed);
+      }
+    }
+  }
+
+  fn handle_input(&mut self, input: Input, pressed: bool) {
+    for action in self.bindings.actions(input) {
+      match action {
+        Action::Button(key) if pressed => self.controller.press(key),
+        Action::Button(key) => self.controller.release(key),
+        Action::Turbo(key) => {
+          let rate = self.bindings.turbo_rate(key);
+          self.controller.turbo(key, rate, pressed);
         }
+        Action::Toggle(key) if pressed => self.controller.toggle(key),
+        Action::Hotkey(Hotkey::Rewind) => self.rewinding = pressed,
+        Action::Hotkey(hotkey) if pressed => self.hotkey(hotkey),
+        Action::Toggle(_) | Action::Hotkey(_) => (),
       }
     }
+  }
+
+  /// Pass the buttons for the next frame, from the player or a movie, on to
+  /// memory. This runs once per frame, so turbo, macros and movies stay in
+  /// step with emulation.
+  fn update_buttons(&mut self) {
+    let held = self.controller.next_frame();
+    let buttons = self.movie_buttons(held);
+    self.record_frame(buttons);
+    self.set_buttons(buttons);
+  }
 
-    if let KeyEvent::Pressed = event {
-      if let minifb::Key::S = key {
-        self.speed = match self.speed {
-          Speed::Normal => Speed::Double,
-          Speed::Double => Speed::Normal,
+  /// Press and release buttons to match `buttons`.
+  fn set_buttons(&mut self, buttons: u8) {
+    for key in input::button_keys(buttons & !self.buttons) {
+      self.mem.key_down(key);
+    }
+    for key in input::button_keys(self.buttons & !buttons) {
+      self.mem.key_up(key);
+    }
+    self.buttons = buttons;
+  }
+
+  fn hotkey(&mut self, hotkey: Hotkey) {
+    match hotkey {
+      Hotkey::Pause => {
+        self.set_paused(!self.paused);
+        println!("{}", if self.paused { "Paused" } else { "Resumed" });
+      }
+      Hotkey::FrameAdvance => {
+        if !self.paused {
+          println!("Paused");
+        }
+        self.advance_frame();
+      }
+      Hotkey::FastForward => {
+        self.toggle_speed(self.fast_forward);
+      }
+      Hotkey::SlowMotion => {
+        let speed = match self.speed {
+          Speed::Times(0.5) => Speed::Times(0.25),
+          Speed::Times(0.25) => Speed::NORMAL,
+          _ => Speed::Times(0.5),
         };
-        println!("Speed set to: {}", self.speed.factor());
+        self.change_speed(speed);
+      }
+      Hotkey::SpeedUp => self.change_speed(self.speed.faster()),
+      Hotkey::SpeedDown => self.change_speed(self.speed.slower()),
+      Hotkey::CyclePalette => self.cycle_palette(),
+      Hotkey::RecordMacro => {
+        if self.controller.recording() {
+          let frames = self.controller.stop_recording();
+          println!("Recorded a macro of {} frames", frames);
+        } else {
+          self.controller.start_recording();
+          println!("Recording a macro");
+        }
+      }
+      Hotkey::PlayMacro => self.controller.play_macro(),
+      Hotkey::SaveState => match self.save_state_slot(self.slot) {
+        Ok(()) => println!("Saved state to slot {}", self.slot),
+        Err(e) => eprintln!("Unable to save state: {}", e),
+      },
+      Hotkey::LoadState => match self.load_state_slot(self.slot) {
+        Ok(()) => println!("Loaded state from slot {}", self.slot),
+        Err(e) => eprintln!("Unable to load state: {}", e),
+      },
+      Hotkey::Slot(slot) => {
+        self.slot = slot;
+        println!("Save state slot set to: {}", slot);
+      }
+      Hotkey::Rewind => (),
+      Hotkey::Screenshot => match self.hotkey_screenshot() {
+        Ok(path) => println!("Saved screenshot to {}", path.display()),
+        Err(e) => eprintln!("Unable to save screenshot: {}", e),
+      },
+      Hotkey::Reset => {
+        self.soft_reset();
+        println!("Reset");
+      }
+      Hotkey::HardReset => {
+        self.hard_reset();
+        println!("Reset, with cartridge RAM cleared");
       }
     }
   }
 
+  /// Switch between `speed` and normal speed.
+  fn toggle_speed(&mut self, speed: Speed) {
+    if self.speed == speed {
+      self.change_speed(Speed::NORMAL);
+    } else {
+      self.change_speed(speed);
+    }
+  }
+
+  fn change_speed(&mut self, speed: Speed) {
+    self.set_speed(speed);
+    println!("Speed set to: {}", speed);
+  }
+
   fn wait_timer(&self, ms: u32) -> mpsc::Receiver<()> {
-    let (tx, rx) = mpsc::channel();
+    // Only one tick is buffered, so time spent not waiting on the timer
+    // isn't made up for later with a burst of ticks.
+    let (tx, rx) = mpsc::sync_channel(1);
 
     thread::spawn(move || loop {
       thread::sleep(time::Duration::from_millis(ms as u64));
@@ -155,8 +1179,288 @@ impl GameBoy {
   }
 }
 
+/// The CPU as the boot ROM leaves it on the system `mem` is set up as.
+fn initial_cpu(mem: &Memory) -> CPU {
+  if mem.cgb() {
+    CPU::new_cgb()
+  } else if mem.sgb() {
+    CPU::new_sgb()
+  } else {
+    CPU::new()
+  }
+}
+
+fn system_name(cgb: bool, sgb: bool) -> &'static str {
+  match (cgb, sgb) {
+    (true, _) => "a Game Boy Color",
+    (false, true) => "a Super Game Boy",
+    (false, false) => "a Game Boy",
+  }
+}
+
 impl Drop for GameBoy {
   fn drop(&mut self) {
-    self.mem.save_ram();
+    // Normally saved already, unless running was cut short by a panic.
+    // RAM from a movie isn't saved, so this comes before stopping it.
+    if let Err(e) = self.save_ram() {
+      eprintln!("Unable to save game: {}", e);
+    }
+    if let Err(e) = self.stop_movie() {
+      eprintln!("Unable to save movie: {}", e);
+    }
+    if let Err(e) = self.stop_audio_recording() {
+      eprintln!("Unable to finish audio recording: {}", e);
+    }
+    if let Err(e) = self.stop_video_recording() {
+      eprintln!("Unable to finish video recording: {}", e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A ROM that counts up in WRAM forever, with the LCD on.
+  fn counter_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x10a].copy_from_slice(&[
+      0x21, 0x00, 0xc0, // ld hl, 0xc000
+      0x34, // inc (hl)
+      0x23, // inc hl
+      0x18, 0xfc, // jr -4
+      0x00, 0x00, 0x00,
+    ]);
+    rom
+  }
+
+  fn gameboy(rom: Vec<u8>) -> GameBoy {
+    GameBoy::new(rom, Path::new("test.gb"), None).unwrap()
+  }
+
+  #[test]
+  fn speeds() {
+    assert_eq!(Speed::parse("0.25"), Some(Speed::Times(0.25)));
+    assert_eq!(Speed::parse("2x"), Some(Speed::Times(2.0)));
+    assert_eq!(Speed::parse("unlimited"), Some(Speed::Unlimited));
+    assert_eq!(Speed::parse("0"), None);
+    assert_eq!(Speed::parse("fast"), None);
+
+    assert_eq!(Speed::NORMAL.faster(), Speed::Times(1.5));
+    assert_eq!(Speed::Times(3.0).slower(), Speed::Times(2.0));
+    assert_eq!(Speed::Times(8.0).faster(), Speed::Unlimited);
+    assert_eq!(Speed::Unlimited.slower(), Speed::Times(8.0));
+    assert_eq!(Speed::Times(0.25).slower(), Speed::Times(0.25));
+    assert_eq!(Speed::Times(0.5).to_string(), "0.5x");
+  }
+
+  #[test]
+  fn state_round_trip() {
+    let mut gb = gameboy(counter_rom());
+    gb.run_frame();
+    let state = gb.save_state();
+    gb.run_frame();
+    gb.run_frame();
+    let expected = gb.save_state();
+
+    gb.load_state(&state).unwrap();
+    assert_eq!(gb.save_state(), state);
+    gb.run_frame();
+    gb.run_frame();
+    assert_eq!(gb.save_state(), expected);
+  }
+
+  #[test]
+  fn reset() {
+    let wram = |gb: &GameBoy| -> Vec<u8> {
+      (0xc000..0xe000).map(|addr| gb.mem.rb(addr)).collect()
+    };
+    let mut fresh = gameboy(counter_rom());
+    fresh.run_frame();
+
+    // After a reset the machine runs as it did from power-on.
+    let mut gb = gameboy(counter_rom());
+    gb.run_frame();
+    gb.run_frame();
+    gb.soft_reset();
+    assert!(wram(&gb).iter().all(|&b| b == 0));
+    gb.run_frame();
+    assert_eq!(wram(&gb), wram(&fresh));
+    assert_eq!(gb.frame_cycles, fresh.frame_cycles);
+  }
+
+  #[test]
+  fn rewind() {
+    let mut gb = gameboy(counter_rom());
+    gb.enable_rewind(3, 1 << 24);
+    let mut states = vec![];
+    for _ in 0..10 {
+      states.push(gb.save_state());
+      gb.run_frame();
+    }
+    let size = gb.rewind.as_ref().unwrap().size();
+    assert!(size < states[0].len() * 2, "{} bytes", size);
+
+    for state in states.iter().rev() {
+      assert!(gb.rewind_frame());
+      assert!(gb.save_state() == *state);
+    }
+    assert!(!gb.rewind_frame());
+
+    // Running on after rewinding records new history.
+    gb.run_frame();
+    assert!(gb.rewind_frame());
+    assert!(gb.save_state() == states[0]);
+  }
+
+  /// A ROM that adds up the joypad's button lines in WRAM every time it
+  /// reads them.
+  fn joypad_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x10b].copy_from_slice(&[
+      0x3e, 0x10, // ld a, 0x10
+      0xe0, 0x00, // ldh (0x00), a
+      0xf0, 0x00, // ldh a, (0x00)
+      0x86, // add (hl)
+      0x77, // ld (hl), a
+      0x18, 0xf5, // jr -11
+      0x00,
+    ]);
+    rom
+  }
+
+  #[test]
+  fn movie() {
+    let dir = std::env::temp_dir().join(format!("movie{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("test.gbm");
+
+    let mut gb = gameboy(joypad_rom());
+    gb.record_movie(&path, true).unwrap();
+    for frame in 0..150 {
+      match frame % 7 {
+        0 => gb.controller.press(crate::mem::Key::A),
+        3 => gb.controller.release(crate::mem::Key::A),
+        _ => (),
+      }
+      gb.run_frame();
+    }
+    let expected = gb.save_state();
+    drop(gb);
+
+    let mut gb = gameboy(joypad_rom());
+    gb.play_movie(&path, true).unwrap();
+    for _ in 0..150 {
+      gb.run_frame();
+    }
+    assert_eq!(gb.movie_desync(), None);
+    assert!(gb.save_state() == expected);
+
+    // Playback can't start once the game is running.
+    assert!(matches!(
+      gb.play_movie(&path, true),
+      Err(MovieError::NotAtPowerOn)
+    ));
+
+    // A different run is spotted.
+    let mut movie = Movie::load(&path).unwrap();
+    movie.inputs.iter_mut().for_each(|buttons| *buttons = 0);
+    movie.save(&path).unwrap();
+    let mut gb = gameboy(joypad_rom());
+    gb.play_movie(&path, true).unwrap();
+    for _ in 0..150 {
+      gb.run_frame();
+    }
+    assert_eq!(gb.movie_desync(), Some(60));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  /// An MBC3 ROM with a clock and battery-backed RAM that counts up in the
+  /// RAM forever.
+  fn rtc_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x147] = 0x10;
+    rom[0x149] = 0x02;
+    rom[0x100..0x10c].copy_from_slice(&[
+      0x3e, 0x0a, // ld a, 0x0a
+      0xea, 0x00, 0x00, // ld (0x0000), a
+      0x21, 0x00, 0xa0, // ld hl, 0xa000
+      0x34, // inc (hl)
+      0x18, 0xfd, // jr -3
+      0x00,
+    ]);
+    rom
+  }
+
+  /// A save file for `rtc_rom` of `ram`, with the clock at `days` days,
+  /// saved at `time`.
+  fn rtc_save(ram: u8, days: u8, time: u64) -> Vec<u8> {
+    let mut save = vec![ram; 0x2000];
+    for i in 0..10 {
+      let reg = if i % 5 == 3 { days } else { 0 };
+      save.extend_from_slice(&u32::from(reg).to_le_bytes());
+    }
+    save.extend_from_slice(&time.to_le_bytes());
+    save
+  }
+
+  #[test]
+  fn movie_with_battery() {
+    let dir =
+      std::env::temp_dir().join(format!("movie-rtc{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let rom_path = dir.join("rtc.gb");
+    let sav_path = dir.join("rtc.sav");
+    let path = dir.join("rtc.gbm");
+    let now = time::SystemTime::now()
+      .duration_since(time::UNIX_EPOCH)
+      .unwrap()
+      .as_secs();
+
+    let save = rtc_save(0x11, 1, now - 100_000);
+    fs::write(&sav_path, &save).unwrap();
+    let mut gb = GameBoy::new(rtc_rom(), &rom_path, None).unwrap();
+    gb.record_movie(&path, true).unwrap();
+    for _ in 0..AUTOSAVE_FRAMES * 2 {
+      gb.run_frame();
+    }
+    let expected = gb.save_state();
+    drop(gb);
+    // Nothing was saved while recording.
+    assert_eq!(fs::read(&sav_path).unwrap(), save);
+
+    // A different save, and time passing, don't change the replay.
+    let save = rtc_save(0x22, 5, now - 1_000_000);
+    fs::write(&sav_path, &save).unwrap();
+    let mut gb = GameBoy::new(rtc_rom(), &rom_path, None).unwrap();
+    gb.play_movie(&path, true).unwrap();
+    for _ in 0..AUTOSAVE_FRAMES * 2 {
+      gb.run_frame();
+    }
+    assert_eq!(gb.movie_desync(), None);
+    assert!(gb.save_state() == expected);
+    drop(gb);
+    assert_eq!(fs::read(&sav_path).unwrap(), save);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn state_for_other_rom() {
+    let mut gb = gameboy(counter_rom());
+    let mut rom = counter_rom();
+    rom[0x7fff] = 1;
+    let state = gameboy(rom).save_state();
+    gb.run_frame();
+    let before = gb.save_state();
+    assert!(matches!(
+      gb.load_state(&state),
+      Err(StateError::WrongRom(_))
+    ));
+
+    // A corrupt state leaves the machine as it was.
+    let mut corrupt = before.clone();
+    corrupt.truncate(corrupt.len() - 1);
+    assert!(gb.load_state(&corrupt).is_err());
+    assert_eq!(gb.save_state(), before);
   }
 }
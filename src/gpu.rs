@@ -1,3 +1,6 @@
+use crate::palette::{Palette, Palettes};
+use crate::state::{self, Reader, Writer};
+
 /// RGBA Color.
 pub type RGBAColor = (u8, u8, u8, u8);
 
@@ -10,12 +13,26 @@ const TILEMAP_WIDTH: usize = 32;
 pub const VRAM_SIZE: usize = 0x2000;
 pub const OAM_SIZE: usize = 0xa0;
 
+/// Size of the CGB background and object palette RAMs.
+const PRAM_SIZE: usize = 0x40;
+
+/// Tiles in each VRAM bank.
 const NUM_TILES: usize = 384;
 const NUM_OBJECTS: usize = 40;
 
 pub type Frame = [u32; WIDTH * HEIGHT];
 
-const COLORS: [u8; 4] = [255, 150, 50, 0];
+/// Identity mapping from colour numbers to shades.
+const SHADES: [u8; 4] = [0, 1, 2, 3];
+
+/// Layers stored in the upper bits of each `render` pixel,
+/// used to pick the output palette for its shade.
+const LAYER_BG: u8 = 0 << 2;
+const LAYER_OBJ0: u8 = 1 << 2;
+const LAYER_OBJ1: u8 = 2 << 2;
+
+/// Set in a scanrow entry when the BG tile has priority over objects.
+const BG_PRIORITY: u8 = 0x80;
 
 #[derive(Debug, Copy, Clone)]
 enum Mode {
@@ -36,6 +53,10 @@ struct Object {
   pub xflip: bool,
   pub yflip: bool,
   pub priority: bool,
+  /// CGB palette number.
+  pub cgb_palette: u8,
+  /// CGB VRAM bank holding the tile.
+  pub bank: bool,
   pub num: u32,
 }
 
@@ -49,6 +70,8 @@ impl Object {
       xflip: false,
       yflip: false,
       priority: false,
+      cgb_palette: 0,
+      bank: false,
       num: 0,
     }
   }
@@ -56,11 +79,22 @@ impl Object {
 
 pub struct GPU {
   pub frame: Box<Frame>,
+  /// Shade (bits 0-1) and layer (bits 2-3) of each pixel.
   pub render: Box<[u8; WIDTH * HEIGHT]>,
+  /// RGB555 colour of each pixel in CGB mode.
+  cgb_render: Box<[u16; WIDTH * HEIGHT]>,
+  pub palettes: Palettes,
+
+  /// Whether the GPU is running in CGB mode.
+  cgb: bool,
 
   pub vram: Vec<u8>,
+  vram_bank: usize,
   pub oam: Vec<u8>,
 
+  /// Set when the GPU enters HBlank, for HBlank DMA.
+  pub hblank: bool,
+
   mode: Mode,
   mode_clock: u32,
   line: usize,
@@ -69,7 +103,7 @@ pub struct GPU {
   bgmap: bool,
   bgtile: bool,
   switchbg: bool,
-  switchlcd: bool,
+  pub switchlcd: bool,
   scx: u8,
   scy: u8,
   bg_palette: [u8; 4],
@@ -89,12 +123,19 @@ pub struct GPU {
   mode1int: bool,
   mode2int: bool,
 
-  tileset: Box<[Tile; NUM_TILES]>,
+  bg_pram: [u8; PRAM_SIZE],
+  bg_pram_index: u8,
+  bg_pram_inc: bool,
+  obj_pram: [u8; PRAM_SIZE],
+  obj_pram_index: u8,
+  obj_pram_inc: bool,
+
+  tileset: Box<[Tile; NUM_TILES * 2]>,
   objects: Box<[Object; NUM_OBJECTS]>,
 }
 
 impl GPU {
-  pub fn new() -> GPU {
+  pub fn new(cgb: bool) -> GPU {
     let mut objects = [Object::new(); NUM_OBJECTS];
     for (i, obj) in objects.iter_mut().enumerate() {
       obj.num = i as u32;
@@ -103,10 +144,17 @@ impl GPU {
     GPU {
       frame: Box::new([0; WIDTH * HEIGHT]),
       render: Box::new([0; WIDTH * HEIGHT]),
+      cgb_render: Box::new([0; WIDTH * HEIGHT]),
+      palettes: Palettes::default(),
+
+      cgb,
 
-      vram: vec![0; VRAM_SIZE],
+      vram: vec![0; if cgb { VRAM_SIZE * 2 } else { VRAM_SIZE }],
+      vram_bank: 0,
       oam: vec![0; OAM_SIZE],
 
+      hblank: false,
+
       mode: Mode::HBlank,
       mode_clock: 0,
       line: 0,
@@ -118,12 +166,12 @@ impl GPU {
       switchlcd: false,
       scx: 0,
       scy: 0,
-      bg_palette: COLORS,
+      bg_palette: SHADES,
 
       switchobj: false,
       objsize: false,
-      obj0_palette: COLORS,
-      obj1_palette: COLORS,
+      obj0_palette: SHADES,
+      obj1_palette: SHADES,
 
       switchwin: false,
       winmap: false,
@@ -135,7 +183,15 @@ impl GPU {
       mode1int: false,
       mode2int: false,
 
-      tileset: Box::new([[[0; 8]; 8]; NUM_TILES]),
+      // The CGB boot ROM leaves the background palettes white.
+      bg_pram: [0xff; PRAM_SIZE],
+      bg_pram_index: 0,
+      bg_pram_inc: false,
+      obj_pram: [0; PRAM_SIZE],
+      obj_pram_index: 0,
+      obj_pram_inc: false,
+
+      tileset: Box::new([[[0; 8]; 8]; NUM_TILES * 2]),
       objects: Box::new(objects),
     }
   }
@@ -162,6 +218,7 @@ impl GPU {
         if self.mode_clock >= 172 {
           self.mode_clock = 0;
           self.mode = Mode::HBlank;
+          self.hblank = true;
           self.render_line();
           if self.mode0int {
             int |= 0x02;
@@ -211,16 +268,31 @@ impl GPU {
     int
   }
 
-  pub fn update_tile(&mut self, addr: u16) {
+  /// Read a byte from the currently selected VRAM bank.
+  pub fn read_vram(&self, addr: u16) -> u8 {
+    self.vram[self.vram_bank * VRAM_SIZE + (addr & 0x1fff) as usize]
+  }
+
+  /// Write a byte to the currently selected VRAM bank.
+  pub fn write_vram(&mut self, addr: u16, value: u8) {
+    let addr = self.vram_bank * VRAM_SIZE + (addr & 0x1fff) as usize;
+    self.vram[addr] = value;
+    self.update_tile(addr);
+  }
+
+  /// Update the cached tile row at `addr`, an offset into `vram`.
+  fn update_tile(&mut self, addr: usize) {
+    let bank = addr / VRAM_SIZE;
     // Base address for this tile row.
-    let addr = (addr & 0x1ffe) as usize;
+    let addr = addr & 0x3ffe;
 
-    let tile = (addr / 16) as usize;
+    let tile = (addr & 0x1fff) / 16;
     let row = ((addr / 2) % 8) as usize;
 
     if tile >= NUM_TILES {
       return;
     }
+    let tile = bank * NUM_TILES + tile;
 
     for col in 0..8 {
       let sx: u8 = 1 << (7 - col);
@@ -243,6 +315,8 @@ impl GPU {
           self.objects[i].xflip = val & 0x20 != 0;
           self.objects[i].yflip = val & 0x40 != 0;
           self.objects[i].priority = val & 0x80 == 0;
+          self.objects[i].cgb_palette = val & 0x07;
+          self.objects[i].bank = self.cgb && val & 0x08 != 0;
         }
         _ => panic!("addr % 4 > 3"),
       }
@@ -280,6 +354,15 @@ impl GPU {
       0xff45 => self.lyc,
       0xff4a => self.winy,
       0xff4b => self.winx,
+      0xff4f if self.cgb => 0xfe | self.vram_bank as u8,
+      0xff68 if self.cgb => {
+        self.bg_pram_index | if self.bg_pram_inc { 0x80 } else { 0 } | 0x40
+      }
+      0xff69 if self.cgb => self.bg_pram[self.bg_pram_index as usize],
+      0xff6a if self.cgb => {
+        self.obj_pram_index | if self.obj_pram_inc { 0x80 } else { 0 } | 0x40
+      }
+      0xff6b if self.cgb => self.obj_pram[self.obj_pram_index as usize],
       _ => 0,
     }
   }
@@ -314,25 +397,67 @@ impl GPU {
           _ => panic!(),
         };
         for (i, pal_item) in pal.iter_mut().enumerate() {
-          match (value >> (i * 2)) & 3 {
-            0 => *pal_item = COLORS[0],
-            1 => *pal_item = COLORS[1],
-            2 => *pal_item = COLORS[2],
-            3 => *pal_item = COLORS[3],
-            _ => unimplemented!(),
-          }
+          *pal_item = (value >> (i * 2)) & 3;
         }
       }
       0xff4a => self.winy = value,
       0xff4b => self.winx = value,
+      0xff4f if self.cgb => self.vram_bank = (value & 1) as usize,
+      0xff68 if self.cgb => {
+        self.bg_pram_index = value & 0x3f;
+        self.bg_pram_inc = value & 0x80 != 0;
+      }
+      0xff69 if self.cgb => {
+        self.bg_pram[self.bg_pram_index as usize] = value;
+        if self.bg_pram_inc {
+          self.bg_pram_index = (self.bg_pram_index + 1) & 0x3f;
+        }
+      }
+      0xff6a if self.cgb => {
+        self.obj_pram_index = value & 0x3f;
+        self.obj_pram_inc = value & 0x80 != 0;
+      }
+      0xff6b if self.cgb => {
+        self.obj_pram[self.obj_pram_index as usize] = value;
+        if self.obj_pram_inc {
+          self.obj_pram_index = (self.obj_pram_index + 1) & 0x3f;
+        }
+      }
       _ => (),
     }
   }
 
+  /// The first 256 tiles on screen as tile data, left to right and top to
+  /// bottom. This is how the SGB receives data in VRAM transfers: it reads
+  /// back the shades sent to the LCD, so the scroll, window, objects and
+  /// palette all apply.
+  pub fn screen_tile_data(&self) -> Vec<u8> {
+    let mut data = Vec::with_capacity(256 * 16);
+    for i in 0..256 {
+      let (x, y) = (i % (WIDTH / 8) * 8, i / (WIDTH / 8) * 8);
+      for row in y..y + 8 {
+        let (mut low, mut high) = (0, 0);
+        for (col, &pixel) in
+          self.render[row * WIDTH + x..][..8].iter().enumerate()
+        {
+          low |= (pixel & 1) << (7 - col);
+          high |= (pixel >> 1 & 1) << (7 - col);
+        }
+        data.push(low);
+        data.push(high);
+      }
+    }
+    data
+  }
+
   fn render_line(&mut self) {
+    // Colour number of each BG pixel, with BG_PRIORITY set if the tile
+    // attributes give the background priority over objects.
     let mut scanrow = [0u8; WIDTH];
 
-    if self.switchbg {
+    // In CGB mode LCDC bit 0 is the BG master priority instead, and the
+    // background is always drawn.
+    if self.switchbg || self.cgb {
       self.render_bg(&mut scanrow);
     }
 
@@ -345,6 +470,41 @@ impl GPU {
     }
   }
 
+  /// Get the tile number and CGB attributes of the map entry at `offset`.
+  fn map_tile(&self, offset: usize) -> (usize, u8) {
+    let mut tile = self.vram[offset] as u16;
+    if !self.bgtile {
+      tile = (tile as i8 as i16 + 256) as u16;
+    };
+    let attr = if self.cgb {
+      self.vram[VRAM_SIZE + offset]
+    } else {
+      0
+    };
+    (tile as usize, attr)
+  }
+
+  /// Get the colour number of a pixel in a BG tile, applying attributes.
+  fn tile_pixel(&self, tile: usize, attr: u8, row: usize, col: usize) -> u8 {
+    let tile = if attr & 0x08 != 0 {
+      tile + NUM_TILES
+    } else {
+      tile
+    };
+    let row = if attr & 0x40 != 0 { 7 - row } else { row };
+    let col = if attr & 0x20 != 0 { 7 - col } else { col };
+    self.tileset[tile][row][col]
+  }
+
+  fn set_bg_pixel(&mut self, col: usize, color: u8, attr: u8) {
+    let i = self.line * WIDTH + col;
+    if self.cgb {
+      self.cgb_render[i] = pram_color(&self.bg_pram, attr & 0x07, color);
+    } else {
+      self.render[i] = self.bg_palette[color as usize] | LAYER_BG;
+    }
+  }
+
   fn render_bg(&mut self, scanrow: &mut [u8; WIDTH]) {
     // Tile coordinate top left corner of the background.
     let row = (self.line + self.scy as usize) % 8;
@@ -362,17 +522,13 @@ impl GPU {
 
     // Add to that the horizontal offset (just offset / 8 pixels per tile).
     let mut map_col_offset = ((self.scx / 8) as usize % TILEMAP_WIDTH) as usize;
-    let mut tile = self.vram[map_row_offset + map_col_offset] as u16;
-    if !self.bgtile {
-      tile = (tile as i8 as i16 + 256) as u16;
-    };
+    let (mut tile, mut attr) = self.map_tile(map_row_offset + map_col_offset);
 
-    let line = self.line;
     for (i, row_cell) in scanrow.iter_mut().enumerate() {
-      let color = self.tileset[tile as usize][row][col];
+      let color = self.tile_pixel(tile, attr, row, col);
 
-      self.render[line * WIDTH + i] = self.bg_palette[color as usize];
-      *row_cell = color;
+      self.set_bg_pixel(i, color, attr);
+      *row_cell = color | (attr & BG_PRIORITY);
 
       col += 1;
       if col == 8 {
@@ -380,10 +536,9 @@ impl GPU {
         col = 0;
 
         map_col_offset = (map_col_offset + 1) % TILEMAP_WIDTH;
-        tile = self.vram[map_row_offset + map_col_offset] as u16;
-        if !self.bgtile {
-          tile = (tile as i8 as i16 + 256) as u16;
-        };
+        let next = self.map_tile(map_row_offset + map_col_offset);
+        tile = next.0;
+        attr = next.1;
       }
     }
   }
@@ -414,17 +569,13 @@ impl GPU {
 
     // Add to that the horizontal offset (just offset / 8 pixels per tile).
     let mut map_col_offset = ((winx / 8) as usize % TILEMAP_WIDTH) as usize;
-    let mut tile = self.vram[map_row_offset + map_col_offset] as u16;
-    if !self.bgtile {
-      tile = (tile as i8 as i16 + 256) as u16;
-    };
+    let (mut tile, mut attr) = self.map_tile(map_row_offset + map_col_offset);
 
-    let line = self.line;
     for (i, row_cell) in scanrow.iter_mut().enumerate() {
-      let color = self.tileset[tile as usize][row][col];
+      let color = self.tile_pixel(tile, attr, row, col);
 
-      self.render[line * WIDTH + i] = self.bg_palette[color as usize];
-      *row_cell = color;
+      self.set_bg_pixel(i, color, attr);
+      *row_cell = color | (attr & BG_PRIORITY);
 
       col += 1;
       if col == 8 {
@@ -432,26 +583,40 @@ impl GPU {
         col = 0;
 
         map_col_offset = (map_col_offset + 1) % TILEMAP_WIDTH;
-        tile = self.vram[map_row_offset + map_col_offset] as u16;
-        if !self.bgtile {
-          tile = (tile as i8 as i16 + 256) as u16;
-        };
+        let next = self.map_tile(map_row_offset + map_col_offset);
+        tile = next.0;
+        attr = next.1;
       }
     }
   }
 
+  /// Whether an object pixel is hidden behind the BG pixel in `bg`.
+  fn behind_bg(&self, object: &Object, bg: u8) -> bool {
+    if bg & 0x03 == 0 {
+      return false;
+    }
+    if self.cgb {
+      // LCDC bit 0 clear gives objects priority over everything.
+      self.switchbg && (bg & BG_PRIORITY != 0 || !object.priority)
+    } else {
+      !object.priority
+    }
+  }
+
   fn render_objects(&mut self, scanrow: &mut [u8; WIDTH]) {
-    for i in 0..NUM_OBJECTS {
+    for n in 0..NUM_OBJECTS {
+      // In CGB mode, objects earlier in OAM are drawn on top.
+      let i = if self.cgb { NUM_OBJECTS - 1 - n } else { n };
       let line = self.line as i32;
       let ysize = if self.objsize { 16 } else { 8 };
 
       let object = self.objects[i];
       if object.y <= line && line < (object.y + ysize) {
         debug!("Rendering object {} at line={} {:?}", i, self.line, object);
-        let pal = if object.palette {
-          self.obj1_palette
+        let (pal, layer) = if object.palette {
+          (self.obj1_palette, LAYER_OBJ1)
         } else {
-          self.obj0_palette
+          (self.obj0_palette, LAYER_OBJ0)
         };
 
         let (tile, objy) = if ysize == 16 {
@@ -472,6 +637,7 @@ impl GPU {
         } else {
           (object.tile, object.y)
         };
+        let tile = if object.bank { tile + NUM_TILES } else { tile };
 
         let tilerow = if object.yflip {
           self.tileset[tile][7 - (line - objy) as usize]
@@ -485,12 +651,17 @@ impl GPU {
             let tilerow_idx = if object.xflip { 7 - x } else { x } as usize;
             let pal_idx = tilerow[tilerow_idx] as usize;
             if pal_idx != 0
-              && (object.priority || scanrow[screen_idx as usize] == 0)
+              && !self.behind_bg(&object, scanrow[screen_idx as usize])
             {
-              let color = pal[pal_idx];
               let row = self.line;
               let col = screen_idx as usize;
-              self.set_color(row, col, color);
+              if self.cgb {
+                self.cgb_render[row * WIDTH + col] =
+                  pram_color(&self.obj_pram, object.cgb_palette, pal_idx as u8);
+              } else {
+                let color = pal[pal_idx] | layer;
+                self.set_color(row, col, color);
+              }
             }
           }
         }
@@ -499,14 +670,147 @@ impl GPU {
   }
 
   fn render_frame(&mut self) {
+    if self.cgb {
+      for i in 0..(WIDTH * HEIGHT) {
+        self.frame[i] = rgb555_to_rgb(self.cgb_render[i]);
+      }
+      return;
+    }
     for i in 0..(WIDTH * HEIGHT) {
-      self.frame[i] = (self.render[i] as u32) << 16
-        | (self.render[i] as u32) << 8
-        | self.render[i] as u32;
+      let pixel = self.render[i];
+      self.frame[i] = self.layer_palette(pixel).color(pixel & 3);
+    }
+  }
+
+  fn layer_palette(&self, pixel: u8) -> &Palette {
+    match pixel & 0xc {
+      LAYER_OBJ0 => &self.palettes.obj0,
+      LAYER_OBJ1 => &self.palettes.obj1,
+      _ => &self.palettes.bg,
     }
   }
 
   fn set_color(&mut self, row: usize, col: usize, value: u8) {
     self.render[(row * WIDTH) + col] = value;
   }
+
+  /// Save everything but the output palettes, which are host settings.
+  pub fn save_state(&self, w: &mut Writer) {
+    w.bytes(&self.vram);
+    w.u8(self.vram_bank as u8);
+    w.bytes(&self.oam);
+    w.bool(self.hblank);
+    w.u8(self.mode as u8);
+    w.u32(self.mode_clock);
+    w.u8(self.line as u8);
+    w.u8(self.lyc);
+    w.u8(self.rb(0xff40));
+    w.u8(self.rb(0xff41));
+    w.u8(self.scx);
+    w.u8(self.scy);
+    for palette in &[self.bg_palette, self.obj0_palette, self.obj1_palette] {
+      w.bytes(palette);
+    }
+    w.u8(self.winx);
+    w.u8(self.winy);
+    w.bytes(&self.bg_pram);
+    w.u8(self.bg_pram_index);
+    w.bool(self.bg_pram_inc);
+    w.bytes(&self.obj_pram);
+    w.u8(self.obj_pram_index);
+    w.bool(self.obj_pram_inc);
+
+    w.bytes(&self.render[..]);
+    let mut words = Vec::with_capacity(WIDTH * HEIGHT * 4);
+    for color in self.cgb_render.iter() {
+      words.extend_from_slice(&color.to_le_bytes());
+    }
+    w.bytes(&words);
+    words.clear();
+    for pixel in self.frame.iter() {
+      words.extend_from_slice(&pixel.to_le_bytes());
+    }
+    w.bytes(&words);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    r.bytes_into(&mut self.vram)?;
+    self.vram_bank = r.u8()? as usize & if self.cgb { 1 } else { 0 };
+    r.bytes_into(&mut self.oam)?;
+    self.hblank = r.bool()?;
+    self.mode = match r.u8()? {
+      0 => Mode::HBlank,
+      1 => Mode::VBlank,
+      2 => Mode::OAMRead,
+      3 => Mode::VRAMRead,
+      v => return Err(r.invalid(format!("bad mode {}", v))),
+    };
+    self.mode_clock = r.u32()?;
+    self.line = r.u8()? as usize;
+    if self.line > HEIGHT + 9 {
+      return Err(r.invalid(format!("bad line {}", self.line)));
+    }
+    self.lyc = r.u8()?;
+    let lcdc = r.u8()?;
+    self.wb(0xff40, lcdc);
+    let stat = r.u8()?;
+    self.wb(0xff41, stat);
+    self.scx = r.u8()?;
+    self.scy = r.u8()?;
+    r.bytes_into(&mut self.bg_palette)?;
+    r.bytes_into(&mut self.obj0_palette)?;
+    r.bytes_into(&mut self.obj1_palette)?;
+    for shade in self
+      .bg_palette
+      .iter_mut()
+      .chain(self.obj0_palette.iter_mut())
+      .chain(self.obj1_palette.iter_mut())
+    {
+      *shade &= 3;
+    }
+    self.winx = r.u8()?;
+    self.winy = r.u8()?;
+    r.bytes_into(&mut self.bg_pram)?;
+    self.bg_pram_index = r.u8()? & 0x3f;
+    self.bg_pram_inc = r.bool()?;
+    r.bytes_into(&mut self.obj_pram)?;
+    self.obj_pram_index = r.u8()? & 0x3f;
+    self.obj_pram_inc = r.bool()?;
+
+    r.bytes_into(&mut self.render[..])?;
+    let mut words = vec![0; WIDTH * HEIGHT * 2];
+    r.bytes_into(&mut words)?;
+    for (color, bytes) in self.cgb_render.iter_mut().zip(words.chunks(2)) {
+      *color = u16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+    let mut words = vec![0; WIDTH * HEIGHT * 4];
+    r.bytes_into(&mut words)?;
+    for (pixel, bytes) in self.frame.iter_mut().zip(words.chunks(4)) {
+      *pixel = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+
+    // Rebuild the caches decoded from VRAM and OAM.
+    for addr in (0..self.vram.len()).step_by(2) {
+      self.update_tile(addr);
+    }
+    for (i, value) in self.oam.clone().into_iter().enumerate() {
+      self.update_object(0xfe00 + i as u16, value);
+    }
+    Ok(())
+  }
+}
+
+/// Read colour `color` of palette `palette` from CGB palette RAM.
+fn pram_color(pram: &[u8; PRAM_SIZE], palette: u8, color: u8) -> u16 {
+  let i = (palette as usize * 4 + color as usize) * 2;
+  u16::from(pram[i]) | (u16::from(pram[i + 1]) << 8)
+}
+
+/// Expand a CGB RGB555 colour to 0x00rrggbb.
+pub fn rgb555_to_rgb(color: u16) -> u32 {
+  let expand = |c: u16| -> u32 {
+    let c = u32::from(c & 0x1f);
+    (c << 3) | (c >> 2)
+  };
+  (expand(color) << 16) | (expand(color >> 5) << 8) | expand(color >> 10)
 }
@@ -0,0 +1,182 @@
+use crate::input::BUTTONS;
+use crate::mem::Key;
+
+/// Bit for `key` in a set of buttons.
+pub fn mask(key: Key) -> u8 {
+  1 << key as u8
+}
+
+/// Buttons in the set `buttons`.
+pub fn button_keys(buttons: u8) -> impl Iterator<Item = Key> {
+  BUTTONS
+    .iter()
+    .map(|&(key, _)| key)
+    .filter(move |&key| buttons & mask(key) != 0)
+}
+
+/// Turns button presses, turbo, hold toggles and macros into the set of
+/// buttons held each frame.
+///
+/// Everything advances once per frame in `next_frame`, so the result only
+/// depends on the inputs received between frames.
+#[derive(Debug, Default)]
+pub struct Controller {
+  frame: u64,
+  /// Buttons held down directly.
+  held: u8,
+  /// Buttons latched on by hold toggles.
+  toggled: u8,
+  /// For each held turbo button, the frame it was pressed and the number
+  /// of frames it stays pressed and released for.
+  turbo: [Option<(u64, u32)>; 8],
+  recording: Option<Vec<u8>>,
+  /// The last recorded macro, as the buttons held on each frame.
+  recorded: Vec<u8>,
+  /// Position in `recorded` while a macro plays.
+  playing: Option<usize>,
+}
+
+impl Controller {
+  pub fn new() -> Controller {
+    Controller::default()
+  }
+
+  pub fn press(&mut self, key: Key) {
+    self.held |= mask(key);
+  }
+
+  pub fn release(&mut self, key: Key) {
+    self.held &= !mask(key);
+  }
+
+  /// Start or stop autofiring `key`, switching between pressed and
+  /// released every `rate` frames.
+  pub fn turbo(&mut self, key: Key, rate: u32, pressed: bool) {
+    let turbo = &mut self.turbo[key as usize];
+    if !pressed {
+      *turbo = None;
+    } else if turbo.is_none() {
+      *turbo = Some((self.frame, rate.max(1)));
+    }
+  }
+
+  /// Latch `key` on, or release it if it's already latched.
+  pub fn toggle(&mut self, key: Key) {
+    self.toggled ^= mask(key);
+  }
+
+  pub fn recording(&self) -> bool {
+    self.recording.is_some()
+  }
+
+  /// Start recording a macro, replacing the last one.
+  pub fn start_recording(&mut self) {
+    self.recording = Some(vec![]);
+  }
+
+  /// Stop recording, returning the number of frames recorded.
+  pub fn stop_recording(&mut self) -> usize {
+    if let Some(recording) = self.recording.take() {
+      self.recorded = recording;
+    }
+    self.recorded.len()
+  }
+
+  /// Play the last recorded macro from the start. Its buttons are held on
+  /// top of any others.
+  pub fn play_macro(&mut self) {
+    self.recording = None;
+    if !self.recorded.is_empty() {
+      self.playing = Some(0);
+    }
+  }
+
+  pub fn set_macro(&mut self, frames: Vec<u8>) {
+    self.recorded = frames;
+    self.playing = None;
+  }
+
+  pub fn recorded_macro(&self) -> &[u8] {
+    &self.recorded
+  }
+
+  /// Advance to the next frame, returning the buttons held during it.
+  pub fn next_frame(&mut self) -> u8 {
+    let mut buttons = self.held | self.toggled;
+    for &(key, _) in BUTTONS.iter() {
+      if let Some((start, rate)) = self.turbo[key as usize] {
+        let rate = rate as u64;
+        if (self.frame - start) % (rate * 2) < rate {
+          buttons |= mask(key);
+        }
+      }
+    }
+
+    if let Some(ref mut recording) = self.recording {
+      recording.push(buttons);
+    }
+    if let Some(i) = self.playing {
+      buttons |= self.recorded[i];
+      self.playing = Some(i + 1).filter(|&i| i < self.recorded.len());
+    }
+
+    self.frame += 1;
+    buttons
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn turbo() {
+    let mut controller = Controller::new();
+    controller.next_frame();
+    controller.turbo(Key::Left, 3, true);
+    controller.turbo(Key::A, 2, true);
+    let frames: Vec<u8> = (0..6).map(|_| controller.next_frame()).collect();
+    let (a, left) = (mask(Key::A), mask(Key::Left));
+    assert_eq!(frames, vec![a | left, a | left, left, 0, a, a]);
+    controller.turbo(Key::Left, 3, false);
+    controller.turbo(Key::A, 2, false);
+    assert_eq!(controller.next_frame(), 0);
+  }
+
+  #[test]
+  fn toggle() {
+    let mut controller = Controller::new();
+    controller.toggle(Key::B);
+    controller.press(Key::B);
+    controller.release(Key::B);
+    assert_eq!(controller.next_frame(), mask(Key::B));
+    controller.toggle(Key::B);
+    assert_eq!(controller.next_frame(), 0);
+  }
+
+  #[test]
+  fn record_macro() {
+    let mut controller = Controller::new();
+    controller.start_recording();
+    controller.press(Key::Right);
+    controller.next_frame();
+    controller.press(Key::A);
+    controller.next_frame();
+    controller.release(Key::Right);
+    controller.release(Key::A);
+    controller.next_frame();
+    assert_eq!(controller.stop_recording(), 3);
+
+    controller.press(Key::Start);
+    controller.play_macro();
+    let start = mask(Key::Start);
+    let right = mask(Key::Right);
+    let a = mask(Key::A);
+    let frames: Vec<u8> = (0..4).map(|_| controller.next_frame()).collect();
+    assert_eq!(frames, vec![start | right, start | right | a, start, start]);
+    assert_eq!(
+      button_keys(right | a).collect::<Vec<_>>(),
+      vec![Key::A, Key::Right]
+    );
+  }
+}
@@ -0,0 +1,100 @@
+use crate::input::{Input, PadAxis, PadButton, Sign};
+
+use gilrs::{Axis, Button, EventType, Gilrs};
+
+use std::collections::HashMap;
+use std::error::Error;
+
+/// How far a stick has to move before it counts as pressed.
+const AXIS_THRESHOLD: f32 = 0.5;
+
+/// Connected gamepads.
+pub struct Gamepads {
+  gilrs: Gilrs,
+  /// Direction each stick axis is currently pushed in.
+  axes: HashMap<PadAxis, Option<Sign>>,
+}
+
+impl Gamepads {
+  pub fn new() -> Result<Gamepads, Box<dyn Error>> {
+    let gilrs = Gilrs::new().map_err(|e| e.to_string())?;
+    Ok(Gamepads {
+      gilrs,
+      axes: HashMap::new(),
+    })
+  }
+
+  /// Inputs pressed (true) or released (false) since the last poll.
+  pub fn poll(&mut self) -> Vec<(Input, bool)> {
+    let mut events = vec![];
+    while let Some(event) = self.gilrs.next_event() {
+      match event.event {
+        EventType::ButtonPressed(button, _) => {
+          if let Some(button) = pad_button(button) {
+            events.push((Input::Pad(button), true));
+          }
+        }
+        EventType::ButtonReleased(button, _) => {
+          if let Some(button) = pad_button(button) {
+            events.push((Input::Pad(button), false));
+          }
+        }
+        EventType::AxisChanged(axis, value, _) => {
+          if let Some(axis) = pad_axis(axis) {
+            let sign = if value >= AXIS_THRESHOLD {
+              Some(Sign::Plus)
+            } else if value <= -AXIS_THRESHOLD {
+              Some(Sign::Minus)
+            } else {
+              None
+            };
+            let old = self.axes.insert(axis, sign).flatten();
+            if old != sign {
+              if let Some(old) = old {
+                events.push((Input::Axis(axis, old), false));
+              }
+              if let Some(sign) = sign {
+                events.push((Input::Axis(axis, sign), true));
+              }
+            }
+          }
+        }
+        _ => (),
+      }
+    }
+    events
+  }
+}
+
+fn pad_button(button: Button) -> Option<PadButton> {
+  Some(match button {
+    Button::South => PadButton::South,
+    Button::East => PadButton::East,
+    Button::North => PadButton::North,
+    Button::West => PadButton::West,
+    Button::LeftTrigger => PadButton::LeftTrigger,
+    Button::LeftTrigger2 => PadButton::LeftTrigger2,
+    Button::RightTrigger => PadButton::RightTrigger,
+    Button::RightTrigger2 => PadButton::RightTrigger2,
+    Button::Select => PadButton::Select,
+    Button::Start => PadButton::Start,
+    Button::Mode => PadButton::Mode,
+    Button::LeftThumb => PadButton::LeftThumb,
+    Button::RightThumb => PadButton::RightThumb,
+    Button::DPadUp => PadButton::DPadUp,
+    Button::DPadDown => PadButton::DPadDown,
+    Button::DPadLeft => PadButton::DPadLeft,
+    Button::DPadRight => PadButton::DPadRight,
+    _ => return None,
+  })
+}
+
+fn pad_axis(axis: Axis) -> Option<PadAxis> {
+  Some(match axis {
+    Axis::LeftStickX => PadAxis::LeftX,
+    Axis::LeftStickY => PadAxis::LeftY,
+    Axis::RightStickX => PadAxis::RightX,
+    Axis::RightStickY => PadAxis::RightY,
+    _ => return None,
+  })
+}
@@ -0,0 +1,490 @@
+mod controller;
+mod keys;
+
+#[cfg(feature = "gamepad")]
+mod gamepad;
+
+pub use self::controller::{button_keys, mask, Controller};
+#[cfg(feature = "gamepad")]
+pub use self::gamepad::Gamepads;
+
+use crate::mem::Key;
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Emulator functions that can be bound to inputs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+  Pause,
+  /// Run one frame while paused, or pause if running.
+  FrameAdvance,
+  /// Switch between normal speed and the fast-forward speed.
+  FastForward,
+  /// Cycle between half, quarter and normal speed.
+  SlowMotion,
+  SpeedUp,
+  SpeedDown,
+  SaveState,
+  LoadState,
+  Screenshot,
+  /// Restart the game, keeping cartridge RAM.
+  Reset,
+  /// Restart the game with cartridge RAM cleared.
+  HardReset,
+  CyclePalette,
+  /// Start or stop recording a macro.
+  RecordMacro,
+  PlayMacro,
+  /// Run backwards while held.
+  Rewind,
+  /// Select the save state slot used by `SaveState` and `LoadState`.
+  Slot(u8),
+}
+
+/// Number of save state slots.
+pub const SLOTS: u8 = 10;
+
+const SLOT_NAMES: [&str; SLOTS as usize] = [
+  "slot-0", "slot-1", "slot-2", "slot-3", "slot-4", "slot-5", "slot-6",
+  "slot-7", "slot-8", "slot-9",
+];
+
+const HOTKEYS: [Hotkey; 25] = [
+  Hotkey::Pause,
+  Hotkey::FrameAdvance,
+  Hotkey::FastForward,
+  Hotkey::SlowMotion,
+  Hotkey::SpeedUp,
+  Hotkey::SpeedDown,
+  Hotkey::SaveState,
+  Hotkey::LoadState,
+  Hotkey::Screenshot,
+  Hotkey::Reset,
+  Hotkey::HardReset,
+  Hotkey::CyclePalette,
+  Hotkey::RecordMacro,
+  Hotkey::PlayMacro,
+  Hotkey::Rewind,
+  Hotkey::Slot(0),
+  Hotkey::Slot(1),
+  Hotkey::Slot(2),
+  Hotkey::Slot(3),
+  Hotkey::Slot(4),
+  Hotkey::Slot(5),
+  Hotkey::Slot(6),
+  Hotkey::Slot(7),
+  Hotkey::Slot(8),
+  Hotkey::Slot(9),
+];
+
+impl Hotkey {
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Hotkey::Pause => "pause",
+      Hotkey::FrameAdvance => "frame-advance",
+      Hotkey::FastForward => "fast-forward",
+      Hotkey::SlowMotion => "slow-motion",
+      Hotkey::SpeedUp => "speed-up",
+      Hotkey::SpeedDown => "speed-down",
+      Hotkey::SaveState => "save-state",
+      Hotkey::LoadState => "load-state",
+      Hotkey::Screenshot => "screenshot",
+      Hotkey::Reset => "reset",
+      Hotkey::HardReset => "hard-reset",
+      Hotkey::CyclePalette => "cycle-palette",
+      Hotkey::RecordMacro => "record-macro",
+      Hotkey::PlayMacro => "play-macro",
+      Hotkey::Rewind => "rewind",
+      Hotkey::Slot(slot) => SLOT_NAMES[slot as usize],
+    }
+  }
+}
+
+/// What an input does: press a Game Boy button or trigger a hotkey.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Action {
+  Button(Key),
+  /// Autofire the button while held.
+  Turbo(Key),
+  /// Latch the button on or off with each press.
+  Toggle(Key),
+  Hotkey(Hotkey),
+}
+
+/// Frames turbo buttons stay pressed, and then released, for by default.
+const DEFAULT_TURBO_RATE: u32 = 2;
+
+const BUTTONS: [(Key, &str); 8] = [
+  (Key::A, "a"),
+  (Key::B, "b"),
+  (Key::Start, "start"),
+  (Key::Select, "select"),
+  (Key::Up, "up"),
+  (Key::Down, "down"),
+  (Key::Left, "left"),
+  (Key::Right, "right"),
+];
+
+impl Action {
+  /// Parse a button name such as `a`, a turbo or toggle button such as
+  /// `turbo-a` or `toggle-a`, or a hotkey name.
+  pub fn from_name(name: &str) -> Option<Action> {
+    if let Some(button) = name.strip_prefix("turbo-") {
+      return button_from_name(button).map(Action::Turbo);
+    }
+    if let Some(button) = name.strip_prefix("toggle-") {
+      return button_from_name(button).map(Action::Toggle);
+    }
+    button_from_name(name).map(Action::Button).or_else(|| {
+      HOTKEYS
+        .iter()
+        .find(|h| h.name() == name)
+        .map(|&h| Action::Hotkey(h))
+    })
+  }
+}
+
+fn button_from_name(name: &str) -> Option<Key> {
+  BUTTONS
+    .iter()
+    .find(|&&(_, n)| n == name)
+    .map(|&(key, _)| key)
+}
+
+/// Gamepad buttons, named by position.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PadButton {
+  South,
+  East,
+  North,
+  West,
+  LeftTrigger,
+  LeftTrigger2,
+  RightTrigger,
+  RightTrigger2,
+  Select,
+  Start,
+  Mode,
+  LeftThumb,
+  RightThumb,
+  DPadUp,
+  DPadDown,
+  DPadLeft,
+  DPadRight,
+}
+
+const PAD_BUTTONS: [(PadButton, &str); 17] = [
+  (PadButton::South, "south"),
+  (PadButton::East, "east"),
+  (PadButton::North, "north"),
+  (PadButton::West, "west"),
+  (PadButton::LeftTrigger, "l1"),
+  (PadButton::LeftTrigger2, "l2"),
+  (PadButton::RightTrigger, "r1"),
+  (PadButton::RightTrigger2, "r2"),
+  (PadButton::Select, "select"),
+  (PadButton::Start, "start"),
+  (PadButton::Mode, "mode"),
+  (PadButton::LeftThumb, "l3"),
+  (PadButton::RightThumb, "r3"),
+  (PadButton::DPadUp, "dpad-up"),
+  (PadButton::DPadDown, "dpad-down"),
+  (PadButton::DPadLeft, "dpad-left"),
+  (PadButton::DPadRight, "dpad-right"),
+];
+
+/// Gamepad stick axes. Positive Y is up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PadAxis {
+  LeftX,
+  LeftY,
+  RightX,
+  RightY,
+}
+
+const PAD_AXES: [(PadAxis, &str); 4] = [
+  (PadAxis::LeftX, "left-x"),
+  (PadAxis::LeftY, "left-y"),
+  (PadAxis::RightX, "right-x"),
+  (PadAxis::RightY, "right-y"),
+];
+
+/// A direction along a gamepad axis.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Sign {
+  Minus,
+  Plus,
+}
+
+/// A host input that can be bound to an action.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Input {
+  Key(minifb::Key),
+  Pad(PadButton),
+  /// A stick pushed past halfway in one direction.
+  Axis(PadAxis, Sign),
+}
+
+impl Input {
+  /// Parse a keyboard key name such as `Z` or `Enter`, a gamepad button
+  /// such as `pad:south`, or a stick direction such as `pad:left-x+`.
+  pub fn parse(s: &str) -> Option<Input> {
+    let pad = match s.strip_prefix("pad:") {
+      Some(pad) => pad,
+      None => return keys::from_name(s).map(Input::Key),
+    };
+    if let Some(&(button, _)) = PAD_BUTTONS.iter().find(|&&(_, n)| n == pad) {
+      return Some(Input::Pad(button));
+    }
+    let (axis, sign) = match pad.split_at(pad.len().saturating_sub(1)) {
+      (axis, "+") => (axis, Sign::Plus),
+      (axis, "-") => (axis, Sign::Minus),
+      _ => return None,
+    };
+    PAD_AXES
+      .iter()
+      .find(|&&(_, n)| n == axis)
+      .map(|&(axis, _)| Input::Axis(axis, sign))
+  }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+  Io(io::Error),
+  Line(usize, String),
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ConfigError::Io(ref e) => write!(f, "Unable to read input config: {}", e),
+      ConfigError::Line(line, ref message) => {
+        write!(f, "Input config line {}: {}", line, message)
+      }
+    }
+  }
+}
+
+impl Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+  fn from(e: io::Error) -> ConfigError {
+    ConfigError::Io(e)
+  }
+}
+
+/// Mapping from host inputs to actions.
+#[derive(Debug, Clone)]
+pub struct Bindings {
+  bindings: Vec<(Input, Action)>,
+  /// Turbo rate for each button, indexed by `Key`.
+  turbo_rates: [u32; 8],
+}
+
+impl Default for Bindings {
+  fn default() -> Bindings {
+    use minifb::Key as K;
+    let key = |k, a| (Input::Key(k), a);
+    let pad = |b, a| (Input::Pad(b), a);
+    let axis = |axis, sign, a| (Input::Axis(axis, sign), a);
+    let button = Action::Button;
+    let hotkey = Action::Hotkey;
+    Bindings {
+      bindings: vec![
+        key(K::Z, button(Key::A)),
+        key(K::X, button(Key::B)),
+        key(K::Enter, button(Key::Start)),
+        key(K::Space, button(Key::Select)),
+        key(K::Up, button(Key::Up)),
+        key(K::Down, button(Key::Down)),
+        key(K::Left, button(Key::Left)),
+        key(K::Right, button(Key::Right)),
+        key(K::Pause, hotkey(Hotkey::Pause)),
+        key(K::S, hotkey(Hotkey::FastForward)),
+        key(K::N, hotkey(Hotkey::FrameAdvance)),
+        key(K::M, hotkey(Hotkey::SlowMotion)),
+        key(K::Equal, hotkey(Hotkey::SpeedUp)),
+        key(K::Minus, hotkey(Hotkey::SpeedDown)),
+        key(K::F5, hotkey(Hotkey::SaveState)),
+        key(K::F7, hotkey(Hotkey::LoadState)),
+        key(K::F12, hotkey(Hotkey::Screenshot)),
+        key(K::F9, hotkey(Hotkey::Reset)),
+        key(K::F10, hotkey(Hotkey::HardReset)),
+        key(K::P, hotkey(Hotkey::CyclePalette)),
+        pad(PadButton::East, button(Key::A)),
+        pad(PadButton::South, button(Key::B)),
+        pad(PadButton::Start, button(Key::Start)),
+        pad(PadButton::Select, button(Key::Select)),
+        pad(PadButton::DPadUp, button(Key::Up)),
+        pad(PadButton::DPadDown, button(Key::Down)),
+        pad(PadButton::DPadLeft, button(Key::Left)),
+        pad(PadButton::DPadRight, button(Key::Right)),
+        axis(PadAxis::LeftY, Sign::Plus, button(Key::Up)),
+        axis(PadAxis::LeftY, Sign::Minus, button(Key::Down)),
+        axis(PadAxis::LeftX, Sign::Minus, button(Key::Left)),
+        axis(PadAxis::LeftX, Sign::Plus, button(Key::Right)),
+        pad(PadButton::Mode, hotkey(Hotkey::Pause)),
+        key(K::F2, hotkey(Hotkey::RecordMacro)),
+        key(K::F3, hotkey(Hotkey::PlayMacro)),
+        key(K::Key0, hotkey(Hotkey::Slot(0))),
+        key(K::Key1, hotkey(Hotkey::Slot(1))),
+        key(K::Key2, hotkey(Hotkey::Slot(2))),
+        key(K::Key3, hotkey(Hotkey::Slot(3))),
+        key(K::Key4, hotkey(Hotkey::Slot(4))),
+        key(K::Key5, hotkey(Hotkey::Slot(5))),
+        key(K::Key6, hotkey(Hotkey::Slot(6))),
+        key(K::Key7, hotkey(Hotkey::Slot(7))),
+        key(K::Key8, hotkey(Hotkey::Slot(8))),
+        key(K::Key9, hotkey(Hotkey::Slot(9))),
+        pad(PadButton::RightTrigger, hotkey(Hotkey::FastForward)),
+        key(K::Backspace, hotkey(Hotkey::Rewind)),
+        pad(PadButton::LeftTrigger, hotkey(Hotkey::Rewind)),
+      ],
+      turbo_rates: [DEFAULT_TURBO_RATE; 8],
+    }
+  }
+}
+
+impl Bindings {
+  /// Load bindings from a config file, on top of the defaults.
+  pub fn load(path: &Path) -> Result<Bindings, ConfigError> {
+    let mut bindings = Bindings::default();
+    bindings.apply(&fs::read_to_string(path)?)?;
+    Ok(bindings)
+  }
+
+  /// Apply a config made of lines like `a = Z, pad:east`. Each action listed
+  /// replaces all of that action's bindings; an empty list unbinds it.
+  /// `turbo-rate = N` sets how many frames turbo buttons stay pressed and
+  /// released for, and `turbo-rate-a = N` sets it for one button.
+  /// Lines starting with `#` are comments.
+  pub fn apply(&mut self, config: &str) -> Result<(), ConfigError> {
+    for (i, line) in config.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let error = |message: String| ConfigError::Line(i + 1, message);
+
+      let mut parts = line.splitn(2, '=');
+      let name = parts.next().unwrap().trim();
+      let inputs = match parts.next() {
+        Some(inputs) => inputs,
+        None => return Err(error("expected `action = inputs`".to_string())),
+      };
+      if let Some(button) = name.strip_prefix("turbo-rate") {
+        let rate = match inputs.trim().parse::<u32>() {
+          Ok(rate) if rate >= 1 => rate,
+          _ => return Err(error(format!("invalid turbo rate `{}`", inputs))),
+        };
+        match button.strip_prefix('-').map(button_from_name) {
+          None if button.is_empty() => self.turbo_rates = [rate; 8],
+          Some(Some(key)) => self.turbo_rates[key as usize] = rate,
+          _ => return Err(error(format!("unknown button in `{}`", name))),
+        }
+        continue;
+      }
+
+      let action = Action::from_name(name)
+        .ok_or_else(|| error(format!("unknown action `{}`", name)))?;
+
+      self.bindings.retain(|&(_, a)| a != action);
+      for input in inputs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let input = Input::parse(input)
+          .ok_or_else(|| error(format!("unknown input `{}`", input)))?;
+        self.bindings.push((input, action));
+      }
+    }
+    Ok(())
+  }
+
+  /// Frames the turbo for `key` stays pressed, and then released, for.
+  pub fn turbo_rate(&self, key: Key) -> u32 {
+    self.turbo_rates[key as usize]
+  }
+
+  /// Actions bound to `input`.
+  pub fn actions(&self, input: Input) -> Vec<Action> {
+    self
+      .bindings
+      .iter()
+      .filter(|&&(i, _)| i == input)
+      .map(|&(_, a)| a)
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_inputs() {
+    assert_eq!(Input::parse("enter"), Some(Input::Key(minifb::Key::Enter)));
+    assert_eq!(
+      Input::parse("NumPad0"),
+      Some(Input::Key(minifb::Key::NumPad0))
+    );
+    assert_eq!(
+      Input::parse("pad:south"),
+      Some(Input::Pad(PadButton::South))
+    );
+    assert_eq!(
+      Input::parse("pad:left-y-"),
+      Some(Input::Axis(PadAxis::LeftY, Sign::Minus))
+    );
+    assert_eq!(Input::parse("pad:left-y"), None);
+    assert_eq!(Input::parse("pad:"), None);
+    assert_eq!(Input::parse("Unknown"), None);
+  }
+
+  #[test]
+  fn config() {
+    let mut bindings = Bindings::default();
+    bindings
+      .apply(
+        "# AZERTY\n\
+         a = W, pad:south\n\
+         \n\
+         screenshot =\n",
+      )
+      .unwrap();
+    let w = Input::Key(minifb::Key::W);
+    assert_eq!(bindings.actions(w), vec![Action::Button(Key::A)]);
+    assert!(bindings.actions(Input::Key(minifb::Key::Z)).is_empty());
+    assert!(bindings.actions(Input::Key(minifb::Key::F12)).is_empty());
+    // Other bindings for the same input are kept.
+    assert_eq!(
+      bindings.actions(Input::Pad(PadButton::South)),
+      vec![Action::Button(Key::B), Action::Button(Key::A)]
+    );
+
+    bindings
+      .apply("turbo-b = pad:west\nturbo-rate = 3\nturbo-rate-b = 5")
+      .unwrap();
+    assert_eq!(
+      bindings.actions(Input::Pad(PadButton::West)),
+      vec![Action::Turbo(Key::B)]
+    );
+    assert_eq!(bindings.turbo_rate(Key::A), 3);
+    assert_eq!(bindings.turbo_rate(Key::B), 5);
+    assert!(bindings.apply("turbo-rate = 0").is_err());
+    assert!(bindings.apply("turbo-rate-c = 1").is_err());
+
+    match bindings.apply("a = Z\nturbo = T") {
+      Err(ConfigError::Line(2, _)) => (),
+      result => panic!("unexpected result: {:?}", result),
+    }
+    assert!(bindings.apply("a Z").is_err());
+
+    bindings.apply("slot-3 = F4").unwrap();
+    assert_eq!(
+      bindings.actions(Input::Key(minifb::Key::F4)),
+      vec![Action::Hotkey(Hotkey::Slot(3))]
+    );
+  }
+}
@@ -0,0 +1,208 @@
+mod socket;
+mod wire;
+
+pub use self::socket::SocketCable;
+pub use self::wire::{pair, LinkedGameBoy};
+
+use crate::gameboy::GameBoy;
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// What's plugged into the link port.
+pub trait LinkCable {
+  /// Shift out `byte` on this Game Boy's clock, returning the byte shifted
+  /// in from the other end.
+  fn transfer(&mut self, byte: u8) -> u8;
+
+  /// Check for a transfer clocked by the other end while this Game Boy
+  /// waits on an external clock with `byte` in SB. Returns the byte
+  /// received if one arrived.
+  fn receive(&mut self, _byte: u8) -> Option<u8> {
+    None
+  }
+
+  /// Called after every instruction with the cycles it took at normal
+  /// speed, for cables that need to keep up with emulated time.
+  fn step(&mut self, _t: u32) {}
+}
+
+/// Nothing plugged in. The input line floats high, so every transfer reads
+/// 0xff, and no clock ever arrives.
+pub struct Disconnected;
+
+impl LinkCable for Disconnected {
+  fn transfer(&mut self, _byte: u8) -> u8 {
+    0xff
+  }
+}
+
+/// Output wired back to input, so every byte sent is received.
+pub struct Loopback;
+
+impl LinkCable for Loopback {
+  fn transfer(&mut self, byte: u8) -> u8 {
+    byte
+  }
+}
+
+/// Writes every byte sent to a stream, as test ROMs that report results
+/// over serial expect.
+pub struct Logger<W: Write> {
+  out: W,
+}
+
+impl Logger<io::Stdout> {
+  pub fn stdout() -> Logger<io::Stdout> {
+    Logger::new(io::stdout())
+  }
+}
+
+impl Logger<File> {
+  pub fn create(path: &Path) -> io::Result<Logger<File>> {
+    Ok(Logger::new(File::create(path)?))
+  }
+}
+
+impl<W: Write> Logger<W> {
+  pub fn new(out: W) -> Logger<W> {
+    Logger { out }
+  }
+}
+
+impl<W: Write> LinkCable for Logger<W> {
+  fn transfer(&mut self, byte: u8) -> u8 {
+    let result = self.out.write_all(&[byte]).and_then(|_| self.out.flush());
+    if let Err(e) = result {
+      eprintln!("Unable to log serial output: {}", e);
+    }
+    0xff
+  }
+}
+
+/// Connect two Game Boys run by the caller, who steps them in turn.
+pub fn connect(a: &mut GameBoy, b: &mut GameBoy) {
+  let (port_a, port_b) = pair();
+  a.set_link_cable(Box::new(port_a));
+  b.set_link_cable(Box::new(port_b));
+}
+
+#[derive(Debug)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Invalid link cable: {}", self.0)
+  }
+}
+
+impl Error for ParseError {}
+
+/// A link cable selected on the command line.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Cable {
+  #[default]
+  Disconnected,
+  Loopback,
+  Stdout,
+  File(PathBuf),
+  /// Another Game Boy running the given ROM in this process.
+  GameBoy(PathBuf),
+  /// Another emulator, connecting to or listening on a TCP address.
+  Tcp(String),
+  TcpListen(String),
+  /// Another emulator, connecting to or listening on a Unix socket.
+  Unix(PathBuf),
+  UnixListen(PathBuf),
+}
+
+impl Cable {
+  /// Parse `none`, `loopback`, `stdout`, `file:PATH`, `gameboy:ROM`,
+  /// `tcp:ADDR`, `tcp-listen:ADDR`, `unix:PATH` or `unix-listen:PATH`.
+  pub fn parse(s: &str) -> Result<Cable, ParseError> {
+    let mut parts = s.splitn(2, ':');
+    match (parts.next().unwrap(), parts.next()) {
+      ("none", None) => Ok(Cable::Disconnected),
+      ("loopback", None) => Ok(Cable::Loopback),
+      ("stdout", None) => Ok(Cable::Stdout),
+      ("file", Some(path)) if !path.is_empty() => {
+        Ok(Cable::File(PathBuf::from(path)))
+      }
+      ("gameboy", Some(rom)) if !rom.is_empty() => {
+        Ok(Cable::GameBoy(PathBuf::from(rom)))
+      }
+      ("tcp", Some(addr)) if !addr.is_empty() => {
+        Ok(Cable::Tcp(addr.to_string()))
+      }
+      ("tcp-listen", Some(addr)) if !addr.is_empty() => {
+        Ok(Cable::TcpListen(addr.to_string()))
+      }
+      ("unix", Some(path)) if !path.is_empty() && cfg!(unix) => {
+        Ok(Cable::Unix(PathBuf::from(path)))
+      }
+      ("unix-listen", Some(path)) if !path.is_empty() && cfg!(unix) => {
+        Ok(Cable::UnixListen(PathBuf::from(path)))
+      }
+      _ => Err(ParseError(s.to_string())),
+    }
+  }
+
+  /// Connect the cable. A Game Boy on the other end keeps its save files in
+  /// `save_dir` if given.
+  pub fn open(
+    &self,
+    save_dir: Option<&Path>,
+  ) -> Result<Box<dyn LinkCable>, Box<dyn Error>> {
+    Ok(match *self {
+      Cable::Disconnected => Box::new(Disconnected),
+      Cable::Loopback => Box::new(Loopback),
+      Cable::Stdout => Box::new(Logger::stdout()),
+      Cable::File(ref path) => Box::new(Logger::create(path)?),
+      Cable::GameBoy(ref rom) => {
+        let peer = GameBoy::new(fs::read(rom)?, rom, save_dir)?;
+        Box::new(LinkedGameBoy::new(peer))
+      }
+      Cable::Tcp(ref addr) => Box::new(SocketCable::connect_tcp(addr)?),
+      Cable::TcpListen(ref addr) => Box::new(SocketCable::listen_tcp(addr)?),
+      #[cfg(unix)]
+      Cable::Unix(ref path) => Box::new(SocketCable::connect_unix(path)?),
+      #[cfg(unix)]
+      Cable::UnixListen(ref path) => Box::new(SocketCable::listen_unix(path)?),
+      #[cfg(not(unix))]
+      Cable::Unix(_) | Cable::UnixListen(_) => {
+        return Err("Unix sockets aren't supported on this platform".into())
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse() {
+    assert_eq!(Cable::parse("none").unwrap(), Cable::Disconnected);
+    assert_eq!(
+      Cable::parse("file:out.txt").unwrap(),
+      Cable::File(PathBuf::from("out.txt"))
+    );
+    assert_eq!(
+      Cable::parse("tcp:localhost:5000").unwrap(),
+      Cable::Tcp("localhost:5000".to_string())
+    );
+    assert!(Cable::parse("file:").is_err());
+    assert!(Cable::parse("loopback:x").is_err());
+  }
+
+  #[test]
+  fn logger() {
+    let mut logger = Logger::new(vec![]);
+    assert_eq!(logger.transfer(b'o'), 0xff);
+    assert_eq!(logger.transfer(b'k'), 0xff);
+    assert_eq!(logger.out, b"ok");
+  }
+}
@@ -0,0 +1,474 @@
+use crate::link::LinkCable;
+
+use std::io::{self, Read, Write};
+use std::net::{self, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+/// Sent by both ends when they connect.
+const MAGIC: &[u8; 4] = b"GBL1";
+
+/// How far, in cycles at normal speed, either end may run ahead of the
+/// other. Each end tells the other its time at least this often.
+const QUANTUM: u64 = 4096;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Message {
+  /// The sender has reached this time, and won't send anything earlier.
+  Sync(u64),
+  /// The sender started a transfer on its own clock at this time.
+  Transfer(u64, u8),
+  /// The byte shifted out in reply to a transfer.
+  Reply(u8),
+}
+
+impl Message {
+  fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(10);
+    match *self {
+      Message::Sync(time) => {
+        buf.push(0x01);
+        buf.extend_from_slice(&time.to_le_bytes());
+      }
+      Message::Transfer(time, byte) => {
+        buf.push(0x02);
+        buf.extend_from_slice(&time.to_le_bytes());
+        buf.push(byte);
+      }
+      Message::Reply(byte) => buf.extend_from_slice(&[0x03, byte]),
+    }
+    out.write_all(&buf)?;
+    out.flush()
+  }
+
+  fn read(input: &mut dyn Read) -> io::Result<Message> {
+    let mut byte = [0; 1];
+    let mut time = [0; 8];
+    input.read_exact(&mut byte)?;
+    match byte[0] {
+      0x01 => {
+        input.read_exact(&mut time)?;
+        Ok(Message::Sync(u64::from_le_bytes(time)))
+      }
+      0x02 => {
+        input.read_exact(&mut time)?;
+        input.read_exact(&mut byte)?;
+        Ok(Message::Transfer(u64::from_le_bytes(time), byte[0]))
+      }
+      0x03 => {
+        input.read_exact(&mut byte)?;
+        Ok(Message::Reply(byte[0]))
+      }
+      tag => Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unknown link message 0x{:02x}", tag),
+      )),
+    }
+  }
+}
+
+/// A connected socket.
+pub trait Stream: Read + Write + Send {
+  fn try_clone_box(&self) -> io::Result<Box<dyn Stream>>;
+  fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+  fn try_clone_box(&self) -> io::Result<Box<dyn Stream>> {
+    Ok(Box::new(self.try_clone()?))
+  }
+
+  fn shutdown(&self) -> io::Result<()> {
+    TcpStream::shutdown(self, net::Shutdown::Both)
+  }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+  fn try_clone_box(&self) -> io::Result<Box<dyn Stream>> {
+    Ok(Box::new(self.try_clone()?))
+  }
+
+  fn shutdown(&self) -> io::Result<()> {
+    UnixStream::shutdown(self, net::Shutdown::Both)
+  }
+}
+
+/// A link cable to another emulator process over a socket.
+///
+/// Both ends exchange their emulated time, in cycles at normal speed, and
+/// neither runs more than `QUANTUM` cycles ahead of the other. A transfer
+/// on this end's clock blocks until the other end reaches the time it
+/// started and replies with the byte it shifted out, or 0xff if it wasn't
+/// waiting on an external clock by then. An end waiting on an external
+/// clock doesn't run ahead of the other at all, so it can't miss one. If
+/// the connection drops, the cable acts as if it were unplugged.
+pub struct SocketCable {
+  stream: Box<dyn Stream>,
+  messages: mpsc::Receiver<io::Result<Message>>,
+  connected: bool,
+  /// Emulated time on this end.
+  now: u64,
+  /// Time last sent to the other end.
+  sent: u64,
+  /// Latest time received from the other end.
+  peer_time: u64,
+  /// When this end started waiting on an external clock, if it is.
+  waiting: Option<u64>,
+  /// Whether `receive` was called since the last step.
+  polled: bool,
+  /// A transfer by the other end not yet answered: when it started and the
+  /// byte shifted in.
+  pending: Option<(u64, u8)>,
+}
+
+impl SocketCable {
+  pub fn new(mut stream: Box<dyn Stream>) -> io::Result<SocketCable> {
+    stream.write_all(MAGIC)?;
+    let mut magic = [0; 4];
+    stream.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the other end isn't a link cable",
+      ));
+    }
+
+    let mut reader = stream.try_clone_box()?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || loop {
+      let message = Message::read(&mut reader);
+      let failed = message.is_err();
+      if tx.send(message).is_err() || failed {
+        break;
+      }
+    });
+
+    Ok(SocketCable {
+      stream,
+      messages: rx,
+      connected: true,
+      now: 0,
+      sent: 0,
+      peer_time: 0,
+      waiting: None,
+      polled: false,
+      pending: None,
+    })
+  }
+
+  pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<SocketCable> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    SocketCable::new(Box::new(stream))
+  }
+
+  /// Wait for the other end to connect to `addr`.
+  pub fn listen_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<SocketCable> {
+    SocketCable::accept_tcp(&TcpListener::bind(addr)?)
+  }
+
+  pub fn accept_tcp(listener: &TcpListener) -> io::Result<SocketCable> {
+    println!("Waiting for link connection on {}", listener.local_addr()?);
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    SocketCable::new(Box::new(stream))
+  }
+
+  #[cfg(unix)]
+  pub fn connect_unix(path: &Path) -> io::Result<SocketCable> {
+    SocketCable::new(Box::new(UnixStream::connect(path)?))
+  }
+
+  /// Wait for the other end to connect to a socket at `path`, replacing any
+  /// socket left there by an earlier run.
+  #[cfg(unix)]
+  pub fn listen_unix(path: &Path) -> io::Result<SocketCable> {
+    if let Ok(metadata) = path.symlink_metadata() {
+      if metadata.file_type().is_socket() {
+        std::fs::remove_file(path)?;
+      }
+    }
+    let listener = UnixListener::bind(path)?;
+    println!("Waiting for link connection on {}", path.display());
+    let (stream, _) = listener.accept()?;
+    SocketCable::new(Box::new(stream))
+  }
+
+  fn send(&mut self, message: Message) {
+    if !self.connected {
+      return;
+    }
+    if let Message::Sync(time) | Message::Transfer(time, _) = message {
+      self.sent = time;
+    }
+    if let Err(e) = message.write(&mut self.stream) {
+      self.disconnect(e);
+    }
+  }
+
+  /// Get the next message, waiting for one if `block` is set.
+  fn next(&mut self, block: bool) -> Option<Message> {
+    if !self.connected {
+      return None;
+    }
+    let message = if block {
+      self
+        .messages
+        .recv()
+        .map_err(|_| mpsc::TryRecvError::Disconnected)
+    } else {
+      self.messages.try_recv()
+    };
+    match message {
+      Ok(Ok(message)) => Some(message),
+      Ok(Err(e)) => {
+        self.disconnect(e);
+        None
+      }
+      Err(mpsc::TryRecvError::Empty) => None,
+      Err(mpsc::TryRecvError::Disconnected) => {
+        self.disconnect(io::ErrorKind::BrokenPipe.into());
+        None
+      }
+    }
+  }
+
+  fn disconnect(&mut self, e: io::Error) {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+      eprintln!("Link cable disconnected");
+    } else {
+      eprintln!("Link cable disconnected: {}", e);
+    }
+    self.connected = false;
+    let _ = self.stream.shutdown();
+  }
+
+  fn handle(&mut self, message: Message) {
+    match message {
+      Message::Sync(time) => self.peer_time = self.peer_time.max(time),
+      // Answered once this end reaches `time`. The other end is blocked
+      // until then, so there's only ever one.
+      Message::Transfer(time, byte) => {
+        self.peer_time = self.peer_time.max(time);
+        self.pending = Some((time, byte));
+      }
+      // Only expected while waiting in `transfer`.
+      Message::Reply(_) => (),
+    }
+  }
+
+  /// Reply 0xff to a pending transfer that started before `before`, if
+  /// this end hasn't been waiting on an external clock since then.
+  /// `receive` takes the others.
+  fn refuse_before(&mut self, before: u64) {
+    if let Some((time, _)) = self.pending {
+      let accepted = self.waiting.is_some_and(|since| since <= time);
+      if time < before && !accepted {
+        self.pending = None;
+        self.send(Message::Reply(0xff));
+      }
+    }
+  }
+
+  /// Whether to wait for the other end before running on.
+  fn ahead(&self) -> bool {
+    if self.now > self.peer_time + QUANTUM {
+      return true;
+    }
+    // Waiting on an external clock, the other end's next message may be a
+    // transfer that starts at any moment.
+    self.waiting.is_some()
+      && self.pending.is_none()
+      && self.now > self.peer_time
+  }
+}
+
+impl LinkCable for SocketCable {
+  fn transfer(&mut self, byte: u8) -> u8 {
+    self.waiting = None;
+    self.send(Message::Transfer(self.now, byte));
+    loop {
+      match self.next(true) {
+        Some(Message::Reply(reply)) => return reply,
+        Some(message) => {
+          // The other end may have started a transfer of its own.
+          self.handle(message);
+          self.refuse_before(self.now + 1);
+        }
+        None => return 0xff,
+      }
+    }
+  }
+
+  fn receive(&mut self, byte: u8) -> Option<u8> {
+    let since = *self.waiting.get_or_insert(self.now);
+    self.polled = true;
+    match self.pending {
+      Some((time, received)) if time <= self.now && since <= time => {
+        self.pending = None;
+        self.waiting = None;
+        self.send(Message::Reply(byte));
+        Some(received)
+      }
+      _ => None,
+    }
+  }
+
+  fn step(&mut self, t: u32) {
+    // `receive` is called after every step while still waiting.
+    if !self.polled {
+      self.waiting = None;
+    }
+    self.polled = false;
+    self.now += t as u64;
+    if self.now - self.sent >= QUANTUM {
+      self.send(Message::Sync(self.now));
+    }
+    while let Some(message) = self.next(false) {
+      self.handle(message);
+    }
+    // A transfer starting now can still be taken by `receive`.
+    self.refuse_before(self.now);
+    while self.connected && self.ahead() {
+      // Say where this end is first, or both ends could wait on each
+      // other.
+      if self.sent < self.now {
+        self.send(Message::Sync(self.now));
+      }
+      if let Some(message) = self.next(true) {
+        self.handle(message);
+        self.refuse_before(self.now);
+      }
+    }
+  }
+}
+
+impl Drop for SocketCable {
+  fn drop(&mut self) {
+    let _ = self.stream.shutdown();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn messages() {
+    let messages = [
+      Message::Sync(0x0123_4567_89ab),
+      Message::Transfer(70224, 0x42),
+      Message::Reply(0xff),
+    ];
+    let mut buf = vec![];
+    for message in messages.iter() {
+      message.write(&mut buf).unwrap();
+    }
+    let mut input = &buf[..];
+    for message in messages.iter() {
+      assert_eq!(Message::read(&mut input).unwrap(), *message);
+    }
+    assert!(Message::read(&mut &[0x04][..]).is_err());
+  }
+
+  /// Step `cable`, waiting on an external clock from time `from`, until it
+  /// receives a byte or disconnects. Returns the byte and the time it
+  /// arrived.
+  fn slave(mut cable: SocketCable, sb: u8, from: u64) -> Option<(u8, u64)> {
+    while cable.connected {
+      cable.step(4);
+      if cable.now < from {
+        continue;
+      }
+      if let Some(byte) = cable.receive(sb) {
+        return Some((byte, cable.now));
+      }
+    }
+    None
+  }
+
+  #[test]
+  fn tcp_transfer() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+      slave(SocketCable::connect_tcp(addr).unwrap(), 0x42, 0)
+    });
+
+    let mut master = SocketCable::accept_tcp(&listener).unwrap();
+    for _ in 0..10000 {
+      master.step(4);
+      // Never more than a quantum ahead.
+      assert!(master.now <= master.peer_time + QUANTUM);
+    }
+    assert_eq!(master.transfer(0x99), 0x42);
+    let (byte, time) = thread.join().unwrap().unwrap();
+    assert_eq!(byte, 0x99);
+    // Received no earlier than it was sent, and within a quantum of it.
+    assert!((40000..=40000 + QUANTUM).contains(&time));
+
+    // The other end has gone.
+    assert_eq!(master.transfer(0x99), 0xff);
+    master.step(QUANTUM as u32 * 2);
+  }
+
+  /// Run a master that transfers at time 40000 against a slave that starts
+  /// waiting at `from`, returning what each end received.
+  fn transfer_at_40000(from: u64) -> (u8, Option<(u8, u64)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+      slave(SocketCable::connect_tcp(addr).unwrap(), 0x42, from)
+    });
+
+    let mut master = SocketCable::accept_tcp(&listener).unwrap();
+    master.step(40000);
+    let byte = master.transfer(0x99);
+    drop(master);
+    (byte, thread.join().unwrap())
+  }
+
+  #[test]
+  fn lockstep() {
+    // However far ahead the slave gets before it starts waiting, it sees
+    // the transfer, within an instruction of the time it started...
+    for &from in &[39996, 40000] {
+      let (byte, received) = transfer_at_40000(from);
+      assert_eq!(byte, 0x42);
+      let (received, time) = received.unwrap();
+      assert_eq!(received, 0x99);
+      assert!((40000..=40004).contains(&time));
+    }
+    // ...and misses it if it starts waiting later.
+    assert_eq!(transfer_at_40000(40004), (0xff, None));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn unix_transfer() {
+    let path = std::env::temp_dir()
+      .join(format!("gb-rust-link-test-{}.sock", std::process::id()));
+    let listen_path = path.clone();
+    let thread = thread::spawn(move || {
+      slave(SocketCable::listen_unix(&listen_path).unwrap(), 0x24, 0)
+    });
+
+    let mut master = loop {
+      match SocketCable::connect_unix(&path) {
+        Ok(cable) => break cable,
+        Err(_) => thread::sleep(std::time::Duration::from_millis(10)),
+      }
+    };
+    master.step(100);
+    assert_eq!(master.transfer(0x18), 0x24);
+    assert_eq!(thread.join().unwrap().unwrap().0, 0x18);
+    let _ = std::fs::remove_file(&path);
+  }
+}
@@ -0,0 +1,113 @@
+use crate::gameboy::GameBoy;
+use crate::link::LinkCable;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// One end's view of the wire.
+#[derive(Debug, Default)]
+struct End {
+  /// SB, while this end waits on an external clock.
+  waiting: Option<u8>,
+  /// A byte shifted in by the other end's clock, not yet picked up.
+  received: Option<u8>,
+}
+
+/// One end of a cable between two Game Boys in the same process.
+///
+/// A transfer only reaches the other end if it's waiting on an external
+/// clock when the transfer starts. Otherwise the transfer reads 0xff, as if
+/// nothing were plugged in.
+pub struct WirePort {
+  ends: Rc<RefCell<[End; 2]>>,
+  side: usize,
+}
+
+/// Create the two ends of a cable.
+pub fn pair() -> (WirePort, WirePort) {
+  let ends = Rc::new(RefCell::new([End::default(), End::default()]));
+  (
+    WirePort {
+      ends: ends.clone(),
+      side: 0,
+    },
+    WirePort { ends, side: 1 },
+  )
+}
+
+impl LinkCable for WirePort {
+  fn transfer(&mut self, byte: u8) -> u8 {
+    let mut ends = self.ends.borrow_mut();
+    let other = &mut ends[1 - self.side];
+    match other.waiting.take() {
+      Some(theirs) => {
+        other.received = Some(byte);
+        theirs
+      }
+      None => 0xff,
+    }
+  }
+
+  fn receive(&mut self, byte: u8) -> Option<u8> {
+    let mut ends = self.ends.borrow_mut();
+    let end = &mut ends[self.side];
+    let received = end.received.take();
+    if received.is_none() {
+      end.waiting = Some(byte);
+    }
+    received
+  }
+}
+
+/// A cable to another Game Boy, which runs in lockstep with this one.
+pub struct LinkedGameBoy {
+  peer: GameBoy,
+  port: WirePort,
+  /// Cycles the peer has run ahead (positive) or behind.
+  lead: i64,
+}
+
+impl LinkedGameBoy {
+  pub fn new(mut peer: GameBoy) -> LinkedGameBoy {
+    let (port, peer_port) = pair();
+    peer.set_link_cable(Box::new(peer_port));
+    LinkedGameBoy {
+      peer,
+      port,
+      lead: 0,
+    }
+  }
+}
+
+impl LinkCable for LinkedGameBoy {
+  fn transfer(&mut self, byte: u8) -> u8 {
+    self.port.transfer(byte)
+  }
+
+  fn receive(&mut self, byte: u8) -> Option<u8> {
+    self.port.receive(byte)
+  }
+
+  fn step(&mut self, t: u32) {
+    self.lead -= t as i64;
+    while self.lead < 0 {
+      self.lead += self.peer.run_instruction() as i64;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn exchange() {
+    let (mut a, mut b) = pair();
+    // Nobody is listening yet.
+    assert_eq!(a.transfer(0x12), 0xff);
+    assert_eq!(b.receive(0x34), None);
+    assert_eq!(a.transfer(0x12), 0x34);
+    assert_eq!(b.receive(0x34), Some(0x12));
+    assert_eq!(a.transfer(0x56), 0xff);
+  }
+}
@@ -7,22 +7,80 @@ extern crate log;
 extern crate env_logger;
 
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process;
 
+mod apu;
+mod audio;
 mod cpu;
 mod display;
 mod gameboy;
 mod gpu;
+mod input;
+mod link;
 mod mem;
+mod movie;
+mod palette;
+mod rewind;
+mod rom;
+mod state;
+mod video;
 
 #[derive(Debug)]
 struct Args {
   rom: PathBuf,
   test: bool,
+  palette: Option<palette::Palette>,
+  obj0_palette: Option<palette::Palette>,
+  obj1_palette: Option<palette::Palette>,
+  colorize: Option<palette::Colorize>,
+  sgb: bool,
+  video: video::Pipeline,
+  audio: audio::Backend,
+  sample_rate: u32,
+  sync: gameboy::SyncMode,
+  speed: gameboy::Speed,
+  fast_forward: gameboy::Speed,
+  record_audio: Option<PathBuf>,
+  record_channels: bool,
+  record_video: Option<PathBuf>,
+  /// Frames to run for without a window.
+  headless: Option<u64>,
+  bindings: input::Bindings,
+  link: link::Cable,
+  /// Frames between rewind snapshots.
+  rewind_interval: u32,
+  /// Memory for rewinding in bytes, or 0 to disable it.
+  rewind_budget: usize,
+  movie: Option<MovieArgs>,
+  /// Where to keep save files, instead of next to the ROM.
+  save_dir: Option<PathBuf>,
+  import_save: Option<PathBuf>,
+  /// Patches to apply to the ROM, or empty to use any next to it.
+  patches: Vec<PathBuf>,
+  export_save: Option<PathBuf>,
+  /// Where to save a screenshot after running headless.
+  screenshot: Option<PathBuf>,
+  screenshot_scale: usize,
+  dump_frames: Option<PathBuf>,
+  /// Dump every this many frames.
+  dump_every: u64,
+}
+
+#[derive(Debug)]
+enum MovieArgs {
+  /// Record to a file, from power-on or from a save state file.
+  Record {
+    path: PathBuf,
+    start: Option<PathBuf>,
+  },
+  Play {
+    path: PathBuf,
+    read_only: bool,
+  },
 }
 
 fn main() {
@@ -40,23 +98,115 @@ fn main_result() -> Result<(), Box<dyn Error>> {
 
   let args = get_args()?;
 
-  let rom = read_file(&args.rom)?;
+  let patches = if args.patches.is_empty() {
+    rom::find_patches(&args.rom)
+  } else {
+    args.patches.clone()
+  };
+  let rom = rom::load(&args.rom, &patches)?;
+
+  let mut palettes = match args.colorize {
+    Some(colorize) => colorize.palettes(&rom),
+    None => palette::Palettes::default(),
+  };
+  if let Some(p) = args.palette {
+    palettes = palette::Palettes::uniform(p);
+  }
+  if let Some(p) = args.obj0_palette {
+    palettes.obj0 = p;
+  }
+  if let Some(p) = args.obj1_palette {
+    palettes.obj1 = p;
+  }
 
-  let gb = gameboy::GameBoy::new(rom, args.rom)?;
+  if let Some(ref dir) = args.save_dir {
+    fs::create_dir_all(dir)?;
+  }
+  let save_dir = args.save_dir.as_deref();
+  let mut gb = gameboy::GameBoy::new(rom, &args.rom, save_dir)?;
+  if let Some(ref path) = args.import_save {
+    gb.import_save(path)?;
+    println!("Imported save from {}", path.display());
+  }
+  if let Some(ref path) = args.export_save {
+    gb.export_save(path)?;
+    println!("Exported save to {}", path.display());
+    return Ok(());
+  }
+  gb.set_palettes(palettes);
+  if args.sgb {
+    gb.enable_sgb();
+  }
+  gb.set_video_pipeline(args.video);
+  gb.set_audio_sink(args.audio.open(args.sample_rate)?);
+  gb.set_sync(args.sync);
+  gb.set_speed(args.speed);
+  gb.set_fast_forward(args.fast_forward);
+  gb.set_bindings(args.bindings);
+  gb.set_link_cable(args.link.open(save_dir)?);
+  // Rewinding is only useful with a window to rewind in.
+  if args.rewind_budget > 0 && args.headless.is_none() {
+    gb.enable_rewind(args.rewind_interval, args.rewind_budget);
+  }
+  #[cfg(feature = "gamepad")]
+  match input::Gamepads::new() {
+    Ok(gamepads) => gb.set_gamepads(gamepads),
+    Err(e) => eprintln!("Gamepads unavailable: {}", e),
+  }
+  match args.movie {
+    Some(MovieArgs::Record {
+      ref path,
+      ref start,
+    }) => {
+      if let Some(start) = start {
+        gb.load_state(&read_file(start)?)?;
+      }
+      gb.record_movie(path, start.is_none())?;
+    }
+    Some(MovieArgs::Play {
+      ref path,
+      read_only,
+    }) => gb.play_movie(path, read_only)?,
+    None => (),
+  }
+  gb.set_screenshot_scale(args.screenshot_scale);
+  if let Some(ref dir) = args.dump_frames {
+    gb.start_frame_dump(dir, args.dump_every, args.screenshot_scale)?;
+  }
+  if let Some(ref path) = args.record_audio {
+    gb.start_audio_recording(path, args.sample_rate, args.record_channels)?;
+  }
+  if let Some(ref path) = args.record_video {
+    gb.start_video_recording(path, args.sample_rate)?;
+  }
   println!("Starting game: {}", gb.title);
-  gb.run(display::Display::new()?, !args.test);
+  match args.headless {
+    Some(frames) => {
+      gb.run_headless(frames)?;
+      if let Some(ref path) = args.screenshot {
+        gb.screenshot(path, args.screenshot_scale)?;
+      }
+    }
+    None => {
+      let (width, height) = gb.output_size();
+      gb.run(display::Display::new(width, height)?, !args.test)?;
+    }
+  }
   println!("Thanks for playing!");
   Ok(())
 }
 
-fn get_args() -> Result<Args, &'static str> {
+fn get_args() -> Result<Args, Box<dyn Error>> {
   let matches = App::new("GB Rust")
     .version(env!("CARGO_PKG_VERSION"))
     .about("Game Boy emulator")
     .arg(
       Arg::with_name("rom")
         .required(true)
-        .help("Path to the Game Boy ROM file to load")
+        .help(
+          "Path to the Game Boy ROM file to load, which can be in a .zip or \
+           .gz file",
+        )
         .value_name("FILE"),
     )
     .arg(
@@ -66,16 +216,389 @@ fn get_args() -> Result<Args, &'static str> {
         .short("t")
         .long("test"),
     )
+    .arg(
+      Arg::with_name("palette")
+        .required(false)
+        .help(
+          "DMG palette: grey, dmg, pocket, light, high-contrast, \
+           or four hex colours (e.g. e0f8d0,88c070,346856,081820)",
+        )
+        .long("palette")
+        .value_name("PALETTE"),
+    )
+    .arg(
+      Arg::with_name("obj0-palette")
+        .required(false)
+        .help("Palette for OBJ0 sprites, overriding --palette")
+        .long("obj0-palette")
+        .value_name("PALETTE"),
+    )
+    .arg(
+      Arg::with_name("obj1-palette")
+        .required(false)
+        .help("Palette for OBJ1 sprites, overriding --palette")
+        .long("obj1-palette")
+        .value_name("PALETTE"),
+    )
+    .arg(
+      Arg::with_name("colorize")
+        .required(false)
+        .help(
+          "Colourise DMG games like a CGB: auto (by title checksum), \
+           or a boot button combination such as up+a or left+b",
+        )
+        .long("colorize")
+        .value_name("MODE")
+        .conflicts_with("palette"),
+    )
+    .arg(
+      Arg::with_name("sgb")
+        .required(false)
+        .help("Run SGB games as a Super Game Boy, with borders and colours")
+        .long("sgb"),
+    )
+    .arg(
+      Arg::with_name("filter")
+        .required(false)
+        .help(
+          "Comma-separated video filters, applied in order: integer:N, \
+           scale2x, hq2x, xbr, lcd-grid[:N], ghosting[:PERCENT], \
+           color-correct",
+        )
+        .long("filter")
+        .value_name("FILTERS"),
+    )
+    .arg(
+      Arg::with_name("audio")
+        .required(false)
+        .help(
+          "Audio output: device, none, or a .wav file to write to \
+           [default: device if built with audio-device, otherwise none]",
+        )
+        .long("audio")
+        .value_name("OUTPUT"),
+    )
+    .arg(
+      Arg::with_name("sample-rate")
+        .required(false)
+        .help("Audio output sample rate in Hz")
+        .long("sample-rate")
+        .value_name("HZ")
+        .default_value("48000"),
+    )
+    .arg(
+      Arg::with_name("sync")
+        .required(false)
+        .help("Pace emulation by a timer or by the audio device")
+        .long("sync")
+        .value_name("MODE")
+        .possible_values(&["timer", "audio"])
+        .default_value("timer"),
+    )
+    .arg(
+      Arg::with_name("speed")
+        .required(false)
+        .help(
+          "Run at a multiple of normal speed, e.g. 0.5 or 2, or \
+           \"unlimited\"",
+        )
+        .long("speed")
+        .value_name("SPEED")
+        .default_value("1"),
+    )
+    .arg(
+      Arg::with_name("fast-forward")
+        .required(false)
+        .help("Speed for the fast-forward hotkey, as for --speed")
+        .long("fast-forward")
+        .value_name("SPEED")
+        .default_value("2"),
+    )
+    .arg(
+      Arg::with_name("record-audio")
+        .required(false)
+        .help("Record audio to a WAV file, following emulated time")
+        .long("record-audio")
+        .value_name("FILE"),
+    )
+    .arg(
+      Arg::with_name("record-channels")
+        .required(false)
+        .help(
+          "Also record each channel to its own file, e.g. FILE.ch1.wav \
+           for channel 1",
+        )
+        .long("record-channels")
+        .requires("record-audio"),
+    )
+    .arg(
+      Arg::with_name("record-video")
+        .required(false)
+        .help(
+          "Record video and audio to an uncompressed AVI file, at exactly \
+           the Game Boy's frame rate",
+        )
+        .long("record-video")
+        .value_name("FILE"),
+    )
+    .arg(
+      Arg::with_name("headless")
+        .required(false)
+        .help("Run without a window for the given number of frames")
+        .long("headless")
+        .value_name("FRAMES"),
+    )
+    .arg(
+      Arg::with_name("input-config")
+        .required(false)
+        .help(
+          "Key binding config, with lines like `a = Z, pad:east`, \
+           `turbo-a = A`, `toggle-b = C`, `turbo-rate = 2` or `pause = P`. \
+           Listed actions replace their default bindings",
+        )
+        .long("input-config")
+        .value_name("FILE"),
+    )
+    .arg(
+      Arg::with_name("link")
+        .required(false)
+        .help(
+          "What's plugged into the link port: none, loopback, stdout, \
+           file:PATH (log bytes sent), gameboy:ROM (a second Game Boy \
+           running ROM), or another emulator over tcp:ADDR, \
+           tcp-listen:ADDR, unix:PATH or unix-listen:PATH \
+           [default: stdout in test mode, otherwise none]",
+        )
+        .long("link")
+        .value_name("CABLE"),
+    )
+    .arg(
+      Arg::with_name("rewind-interval")
+        .required(false)
+        .help("Frames between rewind snapshots")
+        .long("rewind-interval")
+        .value_name("FRAMES")
+        .default_value("4"),
+    )
+    .arg(
+      Arg::with_name("rewind-memory")
+        .required(false)
+        .help("Memory to keep for rewinding, in MB, or 0 to disable it")
+        .long("rewind-memory")
+        .value_name("MB")
+        .default_value("32"),
+    )
+    .arg(
+      Arg::with_name("record-movie")
+        .required(false)
+        .help("Record the buttons pressed on every frame to a movie file")
+        .long("record-movie")
+        .value_name("FILE"),
+    )
+    .arg(
+      Arg::with_name("movie-start")
+        .required(false)
+        .help("Start the movie from a save state file, not power-on")
+        .long("movie-start")
+        .value_name("STATE")
+        .requires("record-movie"),
+    )
+    .arg(
+      Arg::with_name("play-movie")
+        .required(false)
+        .help("Play back a movie file, checking that the game follows it")
+        .long("play-movie")
+        .value_name("FILE")
+        .conflicts_with("record-movie"),
+    )
+    .arg(
+      Arg::with_name("movie-read-write")
+        .required(false)
+        .help(
+          "Let button presses during playback take over the movie, \
+           recording over the rest of it",
+        )
+        .long("movie-read-write")
+        .requires("play-movie"),
+    )
+    .arg(
+      Arg::with_name("save-dir")
+        .required(false)
+        .help(
+          "Directory for save files and save states, instead of next to \
+           the ROM",
+        )
+        .long("save-dir")
+        .value_name("DIR"),
+    )
+    .arg(
+      Arg::with_name("patch")
+        .required(false)
+        .help(
+          "Apply an IPS, UPS or BPS patch to the ROM; can be given more \
+           than once. By default, patches next to the ROM with the same \
+           name are applied",
+        )
+        .long("patch")
+        .value_name("FILE")
+        .multiple(true)
+        .number_of_values(1),
+    )
+    .arg(
+      Arg::with_name("import-save")
+        .required(false)
+        .help(
+          "Replace the game's save with a save file from another emulator \
+           (.sav with or without a clock, or .srm)",
+        )
+        .long("import-save")
+        .value_name("FILE"),
+    )
+    .arg(
+      Arg::with_name("export-save")
+        .required(false)
+        .help(
+          "Write the game's save to FILE and exit: RAM only for .srm, \
+           otherwise with the clock for cartridges with one",
+        )
+        .long("export-save")
+        .value_name("FILE"),
+    )
+    .arg(
+      Arg::with_name("screenshot")
+        .required(false)
+        .help("Save a PNG screenshot after running headless")
+        .long("screenshot")
+        .value_name("FILE")
+        .requires("headless"),
+    )
+    .arg(
+      Arg::with_name("screenshot-scale")
+        .required(false)
+        .help(
+          "Scale screenshots and dumped frames up from the native \
+           resolution",
+        )
+        .long("screenshot-scale")
+        .value_name("N")
+        .default_value("1"),
+    )
+    .arg(
+      Arg::with_name("dump-frames")
+        .required(false)
+        .help("Save frames to DIR as a numbered sequence of PNGs")
+        .long("dump-frames")
+        .value_name("DIR"),
+    )
+    .arg(
+      Arg::with_name("dump-every")
+        .required(false)
+        .help("Only dump every Nth frame")
+        .long("dump-every")
+        .value_name("N")
+        .default_value("1"),
+    )
     .get_matches();
 
   let rom = PathBuf::from(matches.value_of("rom").unwrap());
   if !rom.is_file() {
-    return Err("Provided ROM is a directory");
+    return Err("Provided ROM is a directory".into());
   }
 
+  let sample_rate: u32 = matches.value_of("sample-rate").unwrap().parse()?;
+  if !(8_000..=192_000).contains(&sample_rate) {
+    return Err("Sample rate must be between 8000 and 192000 Hz".into());
+  }
+
+  let rewind_interval: u32 =
+    matches.value_of("rewind-interval").unwrap().parse()?;
+  if rewind_interval == 0 {
+    return Err("Rewind interval must be at least 1 frame".into());
+  }
+  let rewind_memory: usize =
+    matches.value_of("rewind-memory").unwrap().parse()?;
+
+  let screenshot_scale: usize =
+    matches.value_of("screenshot-scale").unwrap().parse()?;
+  let dump_every: u64 = matches.value_of("dump-every").unwrap().parse()?;
+  if screenshot_scale == 0 || dump_every == 0 {
+    return Err("Screenshot scale and dump interval must be at least 1".into());
+  }
+
+  let parse_speed = |name| {
+    let value = matches.value_of(name).unwrap();
+    gameboy::Speed::parse(value)
+      .ok_or_else(|| format!("Invalid speed: {}", value))
+  };
+
+  let parse_palette = |name| matches.value_of(name).map(palette::parse);
+
   Ok(Args {
     rom,
     test: matches.is_present("test"),
+    palette: parse_palette("palette").transpose()?,
+    obj0_palette: parse_palette("obj0-palette").transpose()?,
+    obj1_palette: parse_palette("obj1-palette").transpose()?,
+    colorize: matches
+      .value_of("colorize")
+      .map(palette::Colorize::parse)
+      .transpose()?,
+    sgb: matches.is_present("sgb"),
+    video: match matches.value_of("filter") {
+      Some(filters) => video::Pipeline::parse(filters)?,
+      None => video::Pipeline::new(),
+    },
+    audio: matches
+      .value_of("audio")
+      .map(audio::Backend::parse)
+      .transpose()?
+      .unwrap_or_default(),
+    sample_rate,
+    sync: gameboy::SyncMode::from_name(matches.value_of("sync").unwrap())
+      .unwrap(),
+    speed: parse_speed("speed")?,
+    fast_forward: parse_speed("fast-forward")?,
+    record_audio: matches.value_of("record-audio").map(PathBuf::from),
+    record_channels: matches.is_present("record-channels"),
+    record_video: matches.value_of("record-video").map(PathBuf::from),
+    headless: matches.value_of("headless").map(str::parse).transpose()?,
+    bindings: match matches.value_of("input-config") {
+      Some(path) => input::Bindings::load(Path::new(path))?,
+      None => input::Bindings::default(),
+    },
+    link: match matches.value_of("link") {
+      Some(cable) => link::Cable::parse(cable)?,
+      // Test ROMs report their results over serial.
+      None if matches.is_present("test") => link::Cable::Stdout,
+      None => link::Cable::default(),
+    },
+    rewind_interval,
+    rewind_budget: rewind_memory * 1024 * 1024,
+    movie: match (
+      matches.value_of("record-movie"),
+      matches.value_of("play-movie"),
+    ) {
+      (Some(path), _) => Some(MovieArgs::Record {
+        path: PathBuf::from(path),
+        start: matches.value_of("movie-start").map(PathBuf::from),
+      }),
+      (None, Some(path)) => Some(MovieArgs::Play {
+        path: PathBuf::from(path),
+        read_only: !matches.is_present("movie-read-write"),
+      }),
+      (None, None) => None,
+    },
+    save_dir: matches.value_of("save-dir").map(PathBuf::from),
+    import_save: matches.value_of("import-save").map(PathBuf::from),
+    patches: matches
+      .values_of("patch")
+      .map(|paths| paths.map(PathBuf::from).collect())
+      .unwrap_or_default(),
+    export_save: matches.value_of("export-save").map(PathBuf::from),
+    screenshot: matches.value_of("screenshot").map(PathBuf::from),
+    screenshot_scale,
+    dump_frames: matches.value_of("dump-frames").map(PathBuf::from),
+    dump_every,
   })
 }
 
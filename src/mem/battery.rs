@@ -0,0 +1,189 @@
+//! Save files for battery-backed RAM, and writing them to disk without
+//! losing earlier saves.
+//!
+//! Save files hold the cartridge's RAM, followed by the clock for cartridges
+//! with one in the trailer format used by VBA-M, mGBA and BGB. RetroArch's
+//! `.srm` files hold the RAM alone.
+
+use super::mbc::{Rtc, SHORT_TRAILER_SIZE, TRAILER_SIZE};
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Saves from earlier sessions kept next to the save file.
+pub const BACKUPS: u32 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+  /// RAM, then the clock trailer if there's a clock.
+  Sav,
+  /// RAM only.
+  Srm,
+}
+
+impl Format {
+  /// The format for a file, by its extension.
+  pub fn from_path(path: &Path) -> Format {
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some(ext) if ext.eq_ignore_ascii_case("srm") => Format::Srm,
+      _ => Format::Sav,
+    }
+  }
+}
+
+/// The contents of a save file.
+#[derive(Debug)]
+pub struct Save {
+  pub ram: Vec<u8>,
+  /// The clock, if the file has a trailer for it.
+  pub rtc: Option<Rtc>,
+}
+
+/// Read a save file in any format, for a cartridge with `ram_size` bytes of
+/// RAM and a clock if `has_rtc` is set. A clock trailer is run on to `now`.
+/// Files of the wrong size are padded or truncated to fit, with a warning.
+pub fn decode(data: &[u8], ram_size: usize, has_rtc: bool, now: u64) -> Save {
+  if has_rtc && data.len() > ram_size {
+    if let Some(rtc) = Rtc::from_trailer(&data[ram_size..], now) {
+      return Save {
+        ram: data[..ram_size].to_vec(),
+        rtc: Some(rtc),
+      };
+    }
+  }
+  // A clock trailer is dropped quietly for cartridges without a clock.
+  let trailer = [TRAILER_SIZE, SHORT_TRAILER_SIZE]
+    .iter()
+    .any(|&size| data.len() == ram_size + size);
+  if data.len() != ram_size && !trailer {
+    eprintln!(
+      "Save file is {} bytes, but the cartridge has {} bytes of RAM; {}",
+      data.len(),
+      ram_size,
+      if data.len() < ram_size {
+        "padding it"
+      } else {
+        "ignoring the rest"
+      }
+    );
+  }
+  let mut ram = data[..data.len().min(ram_size)].to_vec();
+  ram.resize(ram_size, 0);
+  Save { ram, rtc: None }
+}
+
+/// Make a save file in `format` at time `now`.
+pub fn encode(
+  ram: &[u8],
+  rtc: Option<&Rtc>,
+  format: Format,
+  now: u64,
+) -> Vec<u8> {
+  let mut data = ram.to_vec();
+  if let (Format::Sav, Some(rtc)) = (format, rtc) {
+    data.extend(rtc.to_trailer(now));
+  }
+  data
+}
+
+/// Seconds since the Unix epoch, for clock trailers.
+pub fn unix_time() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |time| time.as_secs())
+}
+
+/// Path of backup `n` of `path`, with 1 the newest, e.g. `game.sav.bak1`.
+pub fn backup_path(path: &Path, n: u32) -> PathBuf {
+  with_suffix(path, &format!(".bak{}", n))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+  let mut name = OsString::from(path.as_os_str());
+  name.push(suffix);
+  PathBuf::from(name)
+}
+
+/// Replace the file at `path` with `data`. The data is written to a
+/// temporary file first and renamed over `path`, so a crash part way
+/// through leaves either the old file or the new one.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+  let temp = with_suffix(path, ".tmp");
+  let result = File::create(&temp).and_then(|mut f| {
+    f.write_all(data)?;
+    f.sync_all()
+  });
+  if let Err(e) = result.and_then(|_| fs::rename(&temp, path)) {
+    let _ = fs::remove_file(&temp);
+    return Err(e);
+  }
+  Ok(())
+}
+
+/// Copy the file at `path` to its first backup, moving older backups along
+/// and dropping the oldest. Does nothing if there's no file yet.
+pub fn back_up(path: &Path) -> io::Result<()> {
+  if !path.is_file() {
+    return Ok(());
+  }
+  for n in (1..BACKUPS).rev() {
+    let older = backup_path(path, n);
+    if older.is_file() {
+      fs::rename(&older, backup_path(path, n + 1))?;
+    }
+  }
+  fs::copy(path, backup_path(path, 1))?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn backups() {
+    let dir =
+      std::env::temp_dir().join(format!("battery{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("game.sav");
+
+    back_up(&path).unwrap();
+    assert!(!backup_path(&path, 1).exists());
+    for n in 0..5u8 {
+      back_up(&path).unwrap();
+      write_atomic(&path, &[n]).unwrap();
+    }
+    assert_eq!(fs::read(&path).unwrap(), [4]);
+    for n in 1..=BACKUPS {
+      let expected = [4 - n as u8];
+      assert_eq!(fs::read(backup_path(&path, n)).unwrap(), expected);
+    }
+    assert!(!backup_path(&path, BACKUPS + 1).exists());
+    assert!(!with_suffix(&path, ".tmp").exists());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn formats() {
+    let mut rtc = Rtc::new();
+    rtc.wb(0x0a, 5);
+    let sav = encode(&[1, 2], Some(&rtc), Format::Sav, 100);
+    assert_eq!(sav.len(), 2 + TRAILER_SIZE);
+    let save = decode(&sav, 2, true, 100);
+    assert_eq!(save.ram, [1, 2]);
+    assert_eq!(save.rtc.unwrap().rb(0x0a), 5);
+
+    let srm = encode(&[1, 2], Some(&rtc), Format::Srm, 100);
+    assert_eq!(srm, [1, 2]);
+    assert!(decode(&srm, 2, true, 100).rtc.is_none());
+    assert_eq!(Format::from_path(Path::new("game.SRM")), Format::Srm);
+
+    // Saves of the wrong size are fitted to the cartridge's RAM.
+    assert_eq!(decode(&[1], 2, false, 0).ram, [1, 0]);
+    assert_eq!(decode(&[1, 2, 3], 2, false, 0).ram, [1, 2]);
+    assert_eq!(decode(&sav, 2, false, 0).ram, [1, 2]);
+  }
+}
@@ -0,0 +1,120 @@
+use crate::gpu::OAM_SIZE;
+use crate::state::{self, Reader, Writer};
+
+/// OAM DMA, which copies 160 bytes to OAM at one byte per M-cycle.
+#[derive(Debug)]
+pub struct OamDma {
+  /// Last value written to DMA (0xff46).
+  reg: u8,
+  /// Source address and next OAM index of the running transfer.
+  running: Option<(u16, usize)>,
+  /// Source address of a transfer that takes over after the next M-cycle.
+  pending: Option<u16>,
+}
+
+impl OamDma {
+  pub fn new() -> OamDma {
+    OamDma {
+      reg: 0xff,
+      running: None,
+      pending: None,
+    }
+  }
+
+  pub fn rb(&self) -> u8 {
+    self.reg
+  }
+
+  /// Start a transfer from `value` * 0x100. A running transfer carries on
+  /// for one more M-cycle before the new one replaces it.
+  pub fn start(&mut self, value: u8) {
+    self.reg = value;
+    self.pending = Some(u16::from(value) << 8);
+  }
+
+  /// Whether the CPU is locked out of `addr`. Only the I/O registers and
+  /// HRAM stay accessible while a transfer is running.
+  pub fn blocks(&self, addr: u16) -> bool {
+    self.running.is_some() && addr < 0xff00
+  }
+
+  /// Advance one M-cycle.
+  /// Returns the source address and OAM index of a byte to copy.
+  pub fn tick(&mut self) -> Option<(u16, usize)> {
+    let copy = self.running.map(|(source, index)| {
+      self.running = if index + 1 < OAM_SIZE {
+        Some((source, index + 1))
+      } else {
+        None
+      };
+      // Sources from 0xe000 up read WRAM through its echo.
+      let addr = source + index as u16;
+      (if addr >= 0xe000 { addr - 0x2000 } else { addr }, index)
+    });
+    if let Some(source) = self.pending.take() {
+      self.running = Some((source, 0));
+    }
+    copy
+  }
+
+  pub fn save_state(&self, w: &mut Writer) {
+    w.u8(self.reg);
+    w.bool(self.running.is_some());
+    let (source, index) = self.running.unwrap_or((0, 0));
+    w.u16(source);
+    w.u8(index as u8);
+    w.bool(self.pending.is_some());
+    w.u16(self.pending.unwrap_or(0));
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    self.reg = r.u8()?;
+    let running = r.bool()?;
+    let source = r.u16()?;
+    let index = r.u8()? as usize;
+    if index >= OAM_SIZE {
+      return Err(r.invalid(format!("bad OAM DMA index {}", index)));
+    }
+    self.running = if running { Some((source, index)) } else { None };
+    let pending = r.bool()?;
+    let source = r.u16()?;
+    self.pending = if pending { Some(source) } else { None };
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn timing() {
+    let mut dma = OamDma::new();
+    dma.start(0xc1);
+    assert!(!dma.blocks(0xc000));
+    // One cycle of setup, then a byte per cycle.
+    assert_eq!(dma.tick(), None);
+    assert!(dma.blocks(0xc000));
+    assert!(!dma.blocks(0xff80));
+    assert_eq!(dma.tick(), Some((0xc100, 0)));
+    for i in 1..OAM_SIZE {
+      assert_eq!(dma.tick(), Some((0xc100 + i as u16, i)));
+    }
+    assert!(!dma.blocks(0xc000));
+    assert_eq!(dma.tick(), None);
+  }
+
+  #[test]
+  fn restart() {
+    let mut dma = OamDma::new();
+    dma.start(0xc0);
+    dma.tick();
+    dma.tick();
+    dma.start(0xfe);
+    // The old transfer runs for one more cycle.
+    assert_eq!(dma.tick(), Some((0xc001, 1)));
+    // 0xfe00 reads from 0xde00.
+    assert_eq!(dma.tick(), Some((0xde00, 0)));
+    assert_eq!(dma.rb(), 0xfe);
+  }
+}
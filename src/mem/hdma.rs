@@ -0,0 +1,150 @@
+use crate::state::{self, Reader, Writer};
+
+/// CGB VRAM DMA registers (HDMA1-HDMA5).
+#[derive(Debug)]
+pub struct Hdma {
+  src: u16,
+  dst: u16,
+  /// Remaining 16-byte blocks, minus one.
+  remaining: u8,
+  /// Whether an HBlank DMA is in progress.
+  pub active: bool,
+}
+
+/// A transfer requested by a write to HDMA5.
+pub enum Transfer {
+  /// Copy this many bytes immediately (general-purpose DMA).
+  General(u16),
+  /// Copy 16 bytes at the start of each HBlank.
+  HBlank,
+  /// Nothing to do, e.g. an HBlank DMA was cancelled.
+  None,
+}
+
+impl Hdma {
+  pub fn new() -> Hdma {
+    Hdma {
+      src: 0,
+      dst: 0,
+      remaining: 0x7f,
+      active: false,
+    }
+  }
+
+  pub fn rb(&self, addr: u16) -> u8 {
+    match addr {
+      0xff55 => {
+        if self.active {
+          self.remaining
+        } else {
+          0x80 | self.remaining
+        }
+      }
+      _ => 0xff,
+    }
+  }
+
+  pub fn wb(&mut self, addr: u16, value: u8) -> Transfer {
+    match addr {
+      0xff51 => self.src = (self.src & 0x00ff) | (u16::from(value) << 8),
+      0xff52 => self.src = (self.src & 0xff00) | u16::from(value & 0xf0),
+      0xff53 => self.dst = (self.dst & 0x00ff) | (u16::from(value & 0x1f) << 8),
+      0xff54 => self.dst = (self.dst & 0xff00) | u16::from(value & 0xf0),
+      0xff55 => {
+        if self.active && value & 0x80 == 0 {
+          // Writing bit 7 clear during an HBlank DMA stops it.
+          self.active = false;
+          return Transfer::None;
+        }
+        if value & 0x80 == 0 {
+          // General-purpose DMA completes at once, leaving HDMA5 at 0xff.
+          self.remaining = 0x7f;
+          return Transfer::General((u16::from(value & 0x7f) + 1) * 16);
+        }
+        self.remaining = value & 0x7f;
+        self.active = true;
+        return Transfer::HBlank;
+      }
+      _ => (),
+    }
+    Transfer::None
+  }
+
+  /// Advance by `len` bytes, returning the source and VRAM destination of
+  /// the bytes to copy.
+  pub fn advance(&mut self, len: u16) -> (u16, u16) {
+    let result = (self.src, 0x8000 | self.dst);
+    self.src = self.src.wrapping_add(len);
+    self.dst = (self.dst + len) & 0x1fff;
+    result
+  }
+
+  /// Finish copying one HBlank block.
+  pub fn finish_block(&mut self) {
+    if self.remaining == 0 {
+      self.active = false;
+      self.remaining = 0x7f;
+    } else {
+      self.remaining -= 1;
+    }
+  }
+
+  pub fn save_state(&self, w: &mut Writer) {
+    w.u16(self.src);
+    w.u16(self.dst);
+    w.u8(self.remaining);
+    w.bool(self.active);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    self.src = r.u16()?;
+    self.dst = r.u16()? & 0x1fff;
+    self.remaining = r.u8()? & 0x7f;
+    self.active = r.bool()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn general_dma() {
+    let mut hdma = Hdma::new();
+    hdma.wb(0xff51, 0x12);
+    hdma.wb(0xff52, 0x3f);
+    hdma.wb(0xff53, 0xff);
+    hdma.wb(0xff54, 0xff);
+    match hdma.wb(0xff55, 0x7f) {
+      Transfer::General(len) => assert_eq!(len, 0x800),
+      _ => panic!("expected a general-purpose DMA"),
+    }
+    assert_eq!(hdma.rb(0xff55), 0xff);
+    // The low four bits of the addresses are ignored, and the destination
+    // is always in VRAM.
+    assert_eq!(hdma.advance(0x10), (0x1230, 0x9ff0));
+    assert_eq!(hdma.advance(0x10), (0x1240, 0x8000));
+  }
+
+  #[test]
+  fn hblank_dma() {
+    let mut hdma = Hdma::new();
+    assert!(matches!(hdma.wb(0xff55, 0x82), Transfer::HBlank));
+    assert!(hdma.active);
+    // HDMA5 counts down the blocks left, minus one.
+    for remaining in (0..3).rev() {
+      assert_eq!(hdma.rb(0xff55), remaining);
+      hdma.finish_block();
+    }
+    assert!(!hdma.active);
+    assert_eq!(hdma.rb(0xff55), 0xff);
+
+    // Clearing bit 7 during a transfer stops it, keeping the length left.
+    hdma.wb(0xff55, 0x85);
+    hdma.finish_block();
+    assert!(matches!(hdma.wb(0xff55, 0x00), Transfer::None));
+    assert!(!hdma.active);
+    assert_eq!(hdma.rb(0xff55), 0x84);
+  }
+}
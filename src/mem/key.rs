@@ -1,4 +1,6 @@
-#[derive(Debug)]
+use crate::state::{self, Reader, Writer};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Key {
   A,
   B,
@@ -10,73 +12,146 @@ pub enum Key {
   Right,
 }
 
-impl Key {
-  pub fn from_code(code: minifb::Key) -> Option<Key> {
-    match code {
-      minifb::Key::Z => Some(Key::A),
-      minifb::Key::X => Some(Key::B),
-      minifb::Key::Enter => Some(Key::Start),
-      minifb::Key::Space => Some(Key::Select),
-      minifb::Key::Left => Some(Key::Left),
-      minifb::Key::Right => Some(Key::Right),
-      minifb::Key::Up => Some(Key::Up),
-      minifb::Key::Down => Some(Key::Down),
-      _ => None,
-    }
-  }
-}
-
+/// The joypad, read through P1 (0xff00).
 #[derive(Debug)]
 pub struct KeyData {
+  /// Button states for the action (A, B, Select, Start) and direction
+  /// (Right, Left, Up, Down) lines, with 0 meaning pressed.
   rows: (u8, u8),
-  column: u8,
+  /// Bits 4-5 of P1. P14 (bit 4) selects the directions and P15 (bit 5)
+  /// selects the action buttons, each when low.
+  select: u8,
 }
 
 impl KeyData {
   pub fn new() -> KeyData {
     KeyData {
       rows: (0x0f, 0x0f),
-      column: 0,
+      select: 0x30,
     }
   }
 
   pub fn rb(&self) -> u8 {
-    match self.column {
-      0x10 => self.rows.0,
-      0x20 => self.rows.1,
-      _ => 0,
-    }
+    0xc0 | self.select | self.lines()
   }
 
-  pub fn wb(&mut self, val: u8) {
-    self.column = val;
+  /// Write P1. Returns true if the joypad interrupt was raised.
+  pub fn wb(&mut self, val: u8) -> bool {
+    self.update(|key| key.select = val & 0x30)
   }
 
-  pub fn key_down(&mut self, key: Key) {
+  /// Returns true if the joypad interrupt was raised.
+  pub fn key_down(&mut self, key: Key) -> bool {
     debug!("Pressed {:?}. Key = {:?}", key, &self);
-    match key {
-      Key::Right => self.rows.1 &= 0xe,
-      Key::Left => self.rows.1 &= 0xd,
-      Key::Up => self.rows.1 &= 0xb,
-      Key::Down => self.rows.1 &= 0x7,
-      Key::A => self.rows.0 &= 0xe,
-      Key::B => self.rows.0 &= 0xd,
-      Key::Select => self.rows.0 &= 0xb,
-      Key::Start => self.rows.0 &= 0x7,
-    }
+    self.update(|k| match key {
+      Key::Right => k.rows.1 &= 0xe,
+      Key::Left => k.rows.1 &= 0xd,
+      Key::Up => k.rows.1 &= 0xb,
+      Key::Down => k.rows.1 &= 0x7,
+      Key::A => k.rows.0 &= 0xe,
+      Key::B => k.rows.0 &= 0xd,
+      Key::Select => k.rows.0 &= 0xb,
+      Key::Start => k.rows.0 &= 0x7,
+    })
   }
 
-  pub fn key_up(&mut self, key: Key) {
+  /// Returns true if the joypad interrupt was raised.
+  pub fn key_up(&mut self, key: Key) -> bool {
     debug!("Released {:?}. Key = {:?}", key, &self);
-    match key {
-      Key::Right => self.rows.1 |= 0x1,
-      Key::Left => self.rows.1 |= 0x2,
-      Key::Up => self.rows.1 |= 0x4,
-      Key::Down => self.rows.1 |= 0x8,
-      Key::A => self.rows.0 |= 0x1,
-      Key::B => self.rows.0 |= 0x2,
-      Key::Select => self.rows.0 |= 0x4,
-      Key::Start => self.rows.0 |= 0x8,
+    self.update(|k| match key {
+      Key::Right => k.rows.1 |= 0x1,
+      Key::Left => k.rows.1 |= 0x2,
+      Key::Up => k.rows.1 |= 0x4,
+      Key::Down => k.rows.1 |= 0x8,
+      Key::A => k.rows.0 |= 0x1,
+      Key::B => k.rows.0 |= 0x2,
+      Key::Select => k.rows.0 |= 0x4,
+      Key::Start => k.rows.0 |= 0x8,
+    })
+  }
+
+  /// P10-P13: the selected rows ANDed together, or all high if neither is
+  /// selected.
+  fn lines(&self) -> u8 {
+    let mut lines = 0x0f;
+    if self.select & 0x10 == 0 {
+      lines &= self.rows.1;
     }
+    if self.select & 0x20 == 0 {
+      lines &= self.rows.0;
+    }
+    lines
+  }
+
+  /// Apply `f`, returning true if any of P10-P13 went from high to low.
+  fn update<F: FnOnce(&mut KeyData)>(&mut self, f: F) -> bool {
+    let before = self.lines();
+    f(self);
+    before & !self.lines() != 0
+  }
+
+  /// Whether `key` is held down.
+  pub fn pressed(&self, key: Key) -> bool {
+    let (row, bit) = match key {
+      Key::Right => (self.rows.1, 0x1),
+      Key::Left => (self.rows.1, 0x2),
+      Key::Up => (self.rows.1, 0x4),
+      Key::Down => (self.rows.1, 0x8),
+      Key::A => (self.rows.0, 0x1),
+      Key::B => (self.rows.0, 0x2),
+      Key::Select => (self.rows.0, 0x4),
+      Key::Start => (self.rows.0, 0x8),
+    };
+    row & bit == 0
+  }
+
+  pub fn save_state(&self, w: &mut Writer) {
+    w.u8(self.rows.0);
+    w.u8(self.rows.1);
+    w.u8(self.select);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    self.rows = (r.u8()? & 0x0f, r.u8()? & 0x0f);
+    self.select = r.u8()? & 0x30;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn read() {
+    let mut key = KeyData::new();
+    assert_eq!(key.rb(), 0xff);
+    key.key_down(Key::A);
+    key.key_down(Key::Left);
+    key.wb(0x10);
+    assert_eq!(key.rb(), 0xde);
+    key.wb(0x20);
+    assert_eq!(key.rb(), 0xed);
+    // Both lines selected.
+    key.wb(0x00);
+    assert_eq!(key.rb(), 0xcc);
+    key.wb(0x30);
+    assert_eq!(key.rb(), 0xff);
+  }
+
+  #[test]
+  fn interrupt_edges() {
+    let mut key = KeyData::new();
+    // Nothing selected, so no line changes.
+    assert!(!key.key_down(Key::Start));
+    // Selecting the action buttons pulls P13 low.
+    assert!(key.wb(0x10));
+    assert!(!key.key_down(Key::Up));
+    assert!(key.key_down(Key::B));
+    assert!(!key.key_up(Key::B));
+    // Selecting the directions too pulls P12 low, as Up is held.
+    assert!(key.wb(0x00));
+    // P13 is already low from Start.
+    assert!(!key.key_down(Key::Down));
   }
 }
@@ -1,4 +1,5 @@
-use crate::mem::mbc::MBC;
+use crate::mem::mbc::{Rtc, MBC};
+use crate::state::{self, Reader, Writer};
 
 #[derive(Debug)]
 pub struct MBC0 {
@@ -36,4 +37,22 @@ impl MBC for MBC0 {
   fn to_save(&self) -> Vec<u8> {
     panic!("Cannot save MBC0");
   }
+
+  fn load_save(&mut self, _ram: Vec<u8>, _rtc: Option<Rtc>) {
+    panic!("Cannot load a save into MBC0");
+  }
+
+  fn reset(&mut self, clear_ram: bool) {
+    if clear_ram {
+      self.ram.iter_mut().for_each(|b| *b = 0);
+    }
+  }
+
+  fn save_state(&self, w: &mut Writer) {
+    w.bytes(&self.ram);
+  }
+
+  fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    r.bytes_into(&mut self.ram)
+  }
 }
@@ -1,4 +1,5 @@
-use crate::mem::mbc::MBC;
+use crate::mem::mbc::{Rtc, MBC};
+use crate::state::{self, Reader, Writer};
 
 #[derive(Debug)]
 pub struct MBC1 {
@@ -8,6 +9,8 @@ pub struct MBC1 {
   rom_bank: u8,
   ram_bank: u8,
   ram_on: bool,
+  /// RAM has been written since it was last saved.
+  dirty: bool,
   mode: Mode,
 }
 
@@ -26,18 +29,7 @@ impl MBC1 {
       rom_bank: 1,
       ram_bank: 0,
       ram_on: false,
-      mode: Mode::ROM,
-    }
-  }
-
-  pub fn from_save(rom: Vec<u8>, ram: Vec<u8>) -> Self {
-    Self {
-      rom,
-      ram,
-
-      rom_bank: 1,
-      ram_bank: 0,
-      ram_on: false,
+      dirty: false,
       mode: Mode::ROM,
     }
   }
@@ -85,8 +77,11 @@ impl MBC for MBC1 {
         };
       }
       0xa..=0xb => {
-        let offset = self.ram_offset();
-        self.ram[offset + (addr & 0x1fff) as usize] = value
+        let offset = self.ram_offset() + (addr & 0x1fff) as usize;
+        if self.ram[offset] != value {
+          self.ram[offset] = value;
+          self.dirty = true;
+        }
       }
       _ => panic!("Invalid address to MBC: {}", addr),
     }
@@ -95,11 +90,61 @@ impl MBC for MBC1 {
   fn to_save(&self) -> Vec<u8> {
     self.ram.clone()
   }
+
+  fn load_save(&mut self, ram: Vec<u8>, _rtc: Option<Rtc>) {
+    assert_eq!(ram.len(), self.ram.len(), "save is the wrong size");
+    self.ram = ram;
+    self.dirty = false;
+  }
+
+  fn reset(&mut self, clear_ram: bool) {
+    self.rom_bank = 1;
+    self.ram_bank = 0;
+    self.ram_on = false;
+    self.mode = Mode::ROM;
+    if clear_ram {
+      self.ram.iter_mut().for_each(|b| *b = 0);
+    }
+  }
+
+  fn dirty(&self) -> bool {
+    self.dirty
+  }
+
+  fn mark_saved(&mut self) {
+    self.dirty = false;
+  }
+
+  fn save_state(&self, w: &mut Writer) {
+    w.bytes(&self.ram);
+    w.u8(self.rom_bank);
+    w.u8(self.ram_bank);
+    w.bool(self.ram_on);
+    w.bool(matches!(self.mode, Mode::RAM));
+  }
+
+  fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    r.bytes_into(&mut self.ram)?;
+    self.rom_bank = r.u8()?;
+    self.ram_bank = r.u8()?;
+    if self.rom_offset() >= self.rom.len() {
+      return Err(r.invalid(format!("bad ROM bank {}", self.rom_bank)));
+    }
+    // Bank 0 is selected even when there's no RAM.
+    if self.ram_bank != 0 && self.ram_offset() >= self.ram.len() {
+      return Err(r.invalid(format!("bad RAM bank {}", self.ram_bank)));
+    }
+    self.ram_on = r.bool()?;
+    self.mode = if r.bool()? { Mode::RAM } else { Mode::ROM };
+    self.dirty = true;
+    Ok(())
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::state::{LoadState, SaveState};
 
   fn init() -> MBC1 {
     MBC1::new(vec![0; 0x20000], 0x20000)
@@ -131,4 +176,38 @@ mod tests {
     mbc.wb(0x4000, 2); // RAM Bank = 2
     assert_eq!(mbc.rb(0xb012), 43);
   }
+
+  #[test]
+  fn dirty_tracking() {
+    let mut mbc = init();
+    assert!(!mbc.dirty());
+    mbc.wb(0xa000, 0);
+    assert!(!mbc.dirty(), "writing the same value isn't a change");
+    mbc.wb(0xa000, 1);
+    assert!(mbc.dirty());
+    mbc.mark_saved();
+    assert!(!mbc.dirty());
+  }
+
+  #[test]
+  fn state_bank_range() {
+    let mut mbc = init();
+    mbc.wb(0x2000, 0x08);
+    let mut state = SaveState::new();
+    state.section(b"MBC ", |w| mbc.save_state(w));
+    let data = state.into_bytes();
+    let state = LoadState::parse(&data).unwrap();
+    let mut r = state.section(b"MBC ").unwrap();
+    assert!(init().load_state(&mut r).is_err());
+
+    let mut mbc = MBC1::new(vec![0; 0x20000], 0x2000);
+    mbc.wb(0x6000, 1);
+    mbc.wb(0x4000, 1);
+    let mut state = SaveState::new();
+    state.section(b"MBC ", |w| mbc.save_state(w));
+    let data = state.into_bytes();
+    let state = LoadState::parse(&data).unwrap();
+    let mut r = state.section(b"MBC ").unwrap();
+    assert!(mbc.load_state(&mut r).is_err());
+  }
 }
@@ -1,13 +1,18 @@
-use crate::mem::mbc::MBC;
+use crate::mem::mbc::{Rtc, MBC};
+use crate::state::{self, Reader, Writer};
 
 #[derive(Debug)]
 pub struct MBC3 {
   rom: Vec<u8>,
   ram: Vec<u8>,
+  rtc: Option<Rtc>,
 
   rom_bank: u8,
+  /// RAM bank 0-3, or RTC register 0x08-0x0c.
   ram_bank: u8,
   ram_on: bool,
+  /// RAM has been written since it was last saved.
+  dirty: bool,
 }
 
 impl MBC3 {
@@ -15,22 +20,19 @@ impl MBC3 {
     Self {
       rom,
       ram: vec![0; ram_size],
+      rtc: None,
 
       rom_bank: 1,
       ram_bank: 0,
       ram_on: false,
+      dirty: false,
     }
   }
 
-  pub fn from_save(rom: Vec<u8>, ram: Vec<u8>) -> Self {
-    Self {
-      rom,
-      ram,
-
-      rom_bank: 1,
-      ram_bank: 0,
-      ram_on: false,
-    }
+  /// Add a real time clock.
+  pub fn with_rtc(mut self) -> Self {
+    self.rtc = Some(Rtc::new());
+    self
   }
 
   fn rom_offset(&self) -> usize {
@@ -47,7 +49,11 @@ impl MBC for MBC3 {
     match addr >> 12 {
       0x0..=0x3 => self.rom[addr as usize],
       0x4..=0x7 => self.rom[self.rom_offset() + (addr & 0x3fff) as usize],
-      0xa..=0xb => self.ram[self.ram_offset() + (addr & 0x1fff) as usize],
+      0xa..=0xb => match (self.ram_bank, &self.rtc) {
+        (0x08..=0x0c, Some(rtc)) => rtc.rb(self.ram_bank),
+        (0x08..=0x0c, None) => 0xff,
+        _ => self.ram[self.ram_offset() + (addr & 0x1fff) as usize],
+      },
       _ => panic!("Invalid address to MBC: {}", addr),
     }
   }
@@ -61,16 +67,30 @@ impl MBC for MBC3 {
           v => v,
         }
       }
-      0x4..=0x5 => {
-        if let 0x0..=0x3 = value {
-          self.ram_bank = value & 0x03;
+      0x4..=0x5 => match value {
+        0x0..=0x3 => self.ram_bank = value,
+        0x08..=0x0c if self.rtc.is_some() => self.ram_bank = value,
+        _ => (),
+      },
+      0x6..=0x7 => {
+        if let Some(ref mut rtc) = self.rtc {
+          rtc.latch(value);
         }
       }
-      0x6..=0x7 => {} // RTC
-      0xa..=0xb => {
-        let offset = self.ram_offset();
-        self.ram[offset + (addr & 0x1fff) as usize] = value
-      }
+      0xa..=0xb => match (self.ram_bank, &mut self.rtc) {
+        (0x08..=0x0c, Some(rtc)) => {
+          rtc.wb(self.ram_bank, value);
+          self.dirty = true;
+        }
+        (0x08..=0x0c, None) => (),
+        _ => {
+          let offset = self.ram_offset() + (addr & 0x1fff) as usize;
+          if self.ram[offset] != value {
+            self.ram[offset] = value;
+            self.dirty = true;
+          }
+        }
+      },
       _ => panic!("Invalid address to MBC: {}", addr),
     }
   }
@@ -78,13 +98,130 @@ impl MBC for MBC3 {
   fn to_save(&self) -> Vec<u8> {
     self.ram.clone()
   }
+
+  fn load_save(&mut self, ram: Vec<u8>, rtc: Option<Rtc>) {
+    assert_eq!(ram.len(), self.ram.len(), "save is the wrong size");
+    self.ram = ram;
+    if let (Some(rtc), Some(_)) = (rtc, &self.rtc) {
+      self.rtc = Some(rtc);
+    }
+    self.dirty = false;
+  }
+
+  fn reset(&mut self, clear_ram: bool) {
+    self.rom_bank = 1;
+    self.ram_bank = 0;
+    self.ram_on = false;
+    if clear_ram {
+      self.ram.iter_mut().for_each(|b| *b = 0);
+      if self.rtc.is_some() {
+        self.rtc = Some(Rtc::new());
+      }
+    }
+  }
+
+  fn rtc(&self) -> Option<&Rtc> {
+    self.rtc.as_ref()
+  }
+
+  fn dirty(&self) -> bool {
+    self.dirty
+  }
+
+  fn mark_saved(&mut self) {
+    self.dirty = false;
+  }
+
+  fn step(&mut self, t: u32) {
+    if let Some(ref mut rtc) = self.rtc {
+      rtc.step(t);
+    }
+  }
+
+  fn save_state(&self, w: &mut Writer) {
+    w.bytes(&self.ram);
+    w.u8(self.rom_bank);
+    w.u8(self.ram_bank);
+    w.bool(self.ram_on);
+    w.bool(self.rtc.is_some());
+    if let Some(ref rtc) = self.rtc {
+      rtc.save_state(w);
+    }
+  }
+
+  fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    r.bytes_into(&mut self.ram)?;
+    self.rom_bank = r.u8()?;
+    self.ram_bank = r.u8()?;
+    if self.rom_offset() >= self.rom.len() {
+      return Err(r.invalid(format!("bad ROM bank {}", self.rom_bank)));
+    }
+    match self.ram_bank {
+      // Bank 0 is selected even when there's no RAM.
+      0 | 0x08..=0x0c => (),
+      _ if self.ram_offset() < self.ram.len() => (),
+      v => return Err(r.invalid(format!("bad RAM bank {}", v))),
+    }
+    self.ram_on = r.bool()?;
+    self.dirty = true;
+    match (r.bool()?, &mut self.rtc) {
+      (true, Some(rtc)) => rtc.load_state(r),
+      (false, None) => Ok(()),
+      _ => Err(r.invalid("RTC doesn't match the cartridge".to_string())),
+    }
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::state::{LoadState, SaveState};
 
   fn init() -> MBC3 {
-    MBC3::new(vec![0; 0x20000], 0x20000)
+    MBC3::new(vec![0; 0x20000], 0x8000).with_rtc()
+  }
+
+  #[test]
+  fn rtc_registers() {
+    let mut mbc = init();
+    mbc.wb(0xa000, 0x12);
+    // Select the hours register.
+    mbc.wb(0x4000, 0x0a);
+    mbc.wb(0xa000, 0x05);
+    mbc.wb(0x6000, 0);
+    mbc.wb(0x6000, 1);
+    assert_eq!(mbc.rb(0xa000), 0x05);
+    mbc.wb(0x4000, 0x00);
+    assert_eq!(mbc.rb(0xa000), 0x12);
+  }
+
+  #[test]
+  fn state_bank_range() {
+    let mut mbc = init();
+    mbc.wb(0x2000, 0x7f);
+    let mut state = SaveState::new();
+    state.section(b"MBC ", |w| mbc.save_state(w));
+    let data = state.into_bytes();
+    let state = LoadState::parse(&data).unwrap();
+    let mut r = state.section(b"MBC ").unwrap();
+    assert!(init().load_state(&mut r).is_err());
+
+    let mut mbc = MBC3::new(vec![0; 0x20000], 0x2000).with_rtc();
+    mbc.wb(0x4000, 0x02);
+    let mut state = SaveState::new();
+    state.section(b"MBC ", |w| mbc.save_state(w));
+    let data = state.into_bytes();
+    let state = LoadState::parse(&data).unwrap();
+    let mut r = state.section(b"MBC ").unwrap();
+    assert!(mbc.load_state(&mut r).is_err());
+
+    // RTC registers aren't RAM banks.
+    mbc.wb(0x4000, 0x0a);
+    let mut state = SaveState::new();
+    state.section(b"MBC ", |w| mbc.save_state(w));
+    let data = state.into_bytes();
+    let state = LoadState::parse(&data).unwrap();
+    let mut r = state.section(b"MBC ").unwrap();
+    assert!(mbc.load_state(&mut r).is_ok());
   }
 }
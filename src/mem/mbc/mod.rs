@@ -1,3 +1,5 @@
+use crate::state::{self, Reader, Writer};
+
 pub trait MBC {
   /// Read a byte from the MBC at `addr`.
   fn rb(&self, addr: u16) -> u8;
@@ -5,9 +7,39 @@ pub trait MBC {
   /// Write `value` to the MBC at `addr`, which can update internal state.
   fn wb(&mut self, addr: u16, value: u8);
 
-  /// Get the bytes to save to disk.
-  /// Can include more than just ERAM, if, for example, the MBC has an RTC.
+  /// Get the RAM to save to disk.
   fn to_save(&self) -> Vec<u8>;
+
+  /// Replace RAM with a save from disk of the same size, and the clock too
+  /// if `rtc` is given.
+  fn load_save(&mut self, ram: Vec<u8>, rtc: Option<Rtc>);
+
+  /// Return the bank registers to how they are at power-on. RAM and the
+  /// clock are kept, unless `clear_ram` is set to act as if the battery
+  /// had been taken out. Clearing doesn't count as a write, so the battery
+  /// save on disk is only replaced once the game writes to RAM again.
+  fn reset(&mut self, clear_ram: bool);
+
+  /// The real time clock, for MBCs with one.
+  fn rtc(&self) -> Option<&Rtc> {
+    None
+  }
+
+  /// Run for `t` cycles at normal speed, for MBCs with a clock.
+  fn step(&mut self, _t: u32) {}
+
+  /// Whether the bytes to save have changed since `mark_saved`.
+  fn dirty(&self) -> bool {
+    false
+  }
+
+  /// Note that the bytes to save have been written to disk.
+  fn mark_saved(&mut self) {}
+
+  /// Write RAM and bank registers to a save state.
+  fn save_state(&self, w: &mut Writer);
+
+  fn load_state(&mut self, r: &mut Reader) -> state::Result<()>;
 }
 
 mod mbc0;
@@ -18,3 +50,6 @@ pub use self::mbc1::MBC1;
 
 mod mbc3;
 pub use self::mbc3::MBC3;
+
+mod rtc;
+pub use self::rtc::{Rtc, SHORT_TRAILER_SIZE, TRAILER_SIZE};
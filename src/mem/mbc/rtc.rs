@@ -0,0 +1,245 @@
+use crate::state::{self, Reader, Writer};
+
+use std::convert::TryInto;
+
+/// CPU cycles per second at normal speed.
+const CYCLES_PER_SECOND: u32 = 4_194_304;
+
+/// Day counter high bit, halt flag and day counter carry in DH.
+const DAY_HIGH: u8 = 0x01;
+const HALT: u8 = 0x40;
+const CARRY: u8 = 0x80;
+
+/// Bits used in each register.
+const MASKS: [u8; 5] = [0x3f, 0x3f, 0x1f, 0xff, CARRY | HALT | DAY_HIGH];
+
+/// Size of the clock trailer at the end of save files from VBA-M, mGBA and
+/// BGB.
+pub const TRAILER_SIZE: usize = 48;
+/// Size of the older variant of the trailer, with a 32-bit timestamp.
+pub const SHORT_TRAILER_SIZE: usize = 44;
+
+/// The MBC3 real time clock.
+///
+/// The clock counts emulated time, so it stays in step with the game across
+/// save states and fast-forward. Only when it's loaded from a save file is it
+/// run on by the host time since the file was written, as a real cartridge's
+/// clock keeps going while the Game Boy is off.
+#[derive(Debug, Default)]
+pub struct Rtc {
+  /// Seconds, minutes, hours, day counter low and DH.
+  regs: [u8; 5],
+  /// The registers as of the last latch, which is what reads see.
+  latched: [u8; 5],
+  /// Last value written to the latch register.
+  latch_prev: u8,
+  /// Cycles into the current second.
+  cycles: u32,
+}
+
+impl Rtc {
+  pub fn new() -> Rtc {
+    Rtc::default()
+  }
+
+  /// Read the latched register selected by RAM bank `reg` (0x08-0x0c).
+  pub fn rb(&self, reg: u8) -> u8 {
+    match reg {
+      0x08..=0x0c => self.latched[(reg - 0x08) as usize],
+      _ => 0xff,
+    }
+  }
+
+  pub fn wb(&mut self, reg: u8, value: u8) {
+    let index = match reg {
+      0x08..=0x0c => (reg - 0x08) as usize,
+      _ => return,
+    };
+    self.regs[index] = value & MASKS[index];
+    self.latched[index] = value & MASKS[index];
+    if reg == 0x08 {
+      // Writing the seconds resets the sub-second counter.
+      self.cycles = 0;
+    }
+  }
+
+  /// Handle a write to 0x6000-0x7fff. Writing 0 then 1 latches the clock.
+  pub fn latch(&mut self, value: u8) {
+    if self.latch_prev == 0 && value == 1 {
+      self.latched = self.regs;
+    }
+    self.latch_prev = value;
+  }
+
+  /// Run for `t` cycles at normal speed.
+  pub fn step(&mut self, t: u32) {
+    if self.regs[4] & HALT != 0 {
+      return;
+    }
+    self.cycles += t;
+    while self.cycles >= CYCLES_PER_SECOND {
+      self.cycles -= CYCLES_PER_SECOND;
+      self.tick();
+    }
+  }
+
+  /// Advance the clock by a second.
+  fn tick(&mut self) {
+    let regs = &mut self.regs;
+    // Out of range values count up to the register's limit before
+    // wrapping, as on hardware.
+    regs[0] = (regs[0] + 1) & 0x3f;
+    if regs[0] != 60 {
+      return;
+    }
+    regs[0] = 0;
+    regs[1] = (regs[1] + 1) & 0x3f;
+    if regs[1] != 60 {
+      return;
+    }
+    regs[1] = 0;
+    regs[2] = (regs[2] + 1) & 0x1f;
+    if regs[2] != 24 {
+      return;
+    }
+    regs[2] = 0;
+    let (low, overflow) = regs[3].overflowing_add(1);
+    regs[3] = low;
+    if overflow {
+      if regs[4] & DAY_HIGH != 0 {
+        regs[4] = (regs[4] & !DAY_HIGH) | CARRY;
+      } else {
+        regs[4] |= DAY_HIGH;
+      }
+    }
+  }
+
+  /// Run the clock on by `seconds`, unless it's halted.
+  pub fn advance(&mut self, mut seconds: u64) {
+    if self.regs[4] & HALT != 0 {
+      return;
+    }
+    // Out of range values only wrap after counting up to their limit.
+    let out_of_range = |r: &[u8; 5]| r[0] >= 60 || r[1] >= 60 || r[2] >= 24;
+    while seconds > 0 && out_of_range(&self.regs) {
+      self.tick();
+      seconds -= 1;
+    }
+    let regs = &mut self.regs;
+    let days = regs[3] as u64 | ((regs[4] & DAY_HIGH) as u64) << 8;
+    let total = seconds
+      + regs[0] as u64
+      + regs[1] as u64 * 60
+      + regs[2] as u64 * 3600
+      + days * 86400;
+    let days = total / 86400;
+    regs[0] = (total % 60) as u8;
+    regs[1] = (total / 60 % 60) as u8;
+    regs[2] = (total / 3600 % 24) as u8;
+    regs[3] = days as u8;
+    regs[4] = (regs[4] & !DAY_HIGH) | (days >> 8) as u8 & DAY_HIGH;
+    if days >= 512 {
+      regs[4] |= CARRY;
+    }
+  }
+
+  /// The clock as a save file trailer: the registers, then the latched
+  /// registers, as u32s, then `time` as a 64-bit Unix timestamp.
+  pub fn to_trailer(&self, time: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(TRAILER_SIZE);
+    for &reg in self.regs.iter().chain(&self.latched) {
+      out.extend_from_slice(&(reg as u32).to_le_bytes());
+    }
+    out.extend_from_slice(&time.to_le_bytes());
+    out
+  }
+
+  /// Read a trailer made by `to_trailer`, or the short variant, and run the
+  /// clock on from its timestamp to `now`.
+  pub fn from_trailer(data: &[u8], now: u64) -> Option<Rtc> {
+    let time = match data.len() {
+      TRAILER_SIZE => u64::from_le_bytes(data[40..48].try_into().unwrap()),
+      SHORT_TRAILER_SIZE => {
+        u32::from_le_bytes(data[40..44].try_into().unwrap()) as u64
+      }
+      _ => return None,
+    };
+    let reg = |i: usize| data[i * 4] & MASKS[i % 5];
+    let mut rtc = Rtc::new();
+    for i in 0..5 {
+      rtc.regs[i] = reg(i);
+      rtc.latched[i] = reg(i + 5);
+    }
+    rtc.advance(now.saturating_sub(time));
+    Some(rtc)
+  }
+
+  pub fn save_state(&self, w: &mut Writer) {
+    w.bytes(&self.regs);
+    w.bytes(&self.latched);
+    w.u8(self.latch_prev);
+    w.u32(self.cycles);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    r.bytes_into(&mut self.regs)?;
+    r.bytes_into(&mut self.latched)?;
+    self.latch_prev = r.u8()?;
+    self.cycles = r.u32()? % CYCLES_PER_SECOND;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn count_and_latch() {
+    let mut rtc = Rtc::new();
+    rtc.wb(0x08, 59);
+    rtc.wb(0x09, 59);
+    rtc.wb(0x0a, 23);
+    rtc.wb(0x0b, 0xff);
+    rtc.wb(0x0c, DAY_HIGH);
+    rtc.step(CYCLES_PER_SECOND - 4);
+    rtc.step(4);
+    // Reads don't change until the clock is latched.
+    assert_eq!(rtc.rb(0x08), 59);
+    rtc.latch(0);
+    rtc.latch(1);
+    assert_eq!(
+      (0x08..=0x0c).map(|reg| rtc.rb(reg)).collect::<Vec<_>>(),
+      vec![0, 0, 0, 0, CARRY]
+    );
+
+    // Halted clocks don't count.
+    rtc.wb(0x0c, HALT);
+    rtc.step(CYCLES_PER_SECOND * 2);
+    rtc.latch(0);
+    rtc.latch(1);
+    assert_eq!(rtc.rb(0x08), 0);
+  }
+
+  #[test]
+  fn trailer() {
+    let mut rtc = Rtc::new();
+    rtc.wb(0x08, 30);
+    rtc.wb(0x09, 59);
+    rtc.wb(0x0a, 23);
+    rtc.wb(0x0b, 0xff);
+    rtc.wb(0x0c, DAY_HIGH);
+    let trailer = rtc.to_trailer(1000);
+    assert_eq!(trailer.len(), TRAILER_SIZE);
+
+    // 45 seconds later the day counter overflows.
+    let loaded = Rtc::from_trailer(&trailer, 1045).unwrap();
+    assert_eq!(loaded.regs, [15, 0, 0, 0, CARRY]);
+    assert_eq!(loaded.latched, rtc.latched);
+
+    let mut short = trailer[..40].to_vec();
+    short.extend_from_slice(&1000u32.to_le_bytes());
+    assert_eq!(Rtc::from_trailer(&short, 1000).unwrap().regs, rtc.regs);
+    assert!(Rtc::from_trailer(&trailer[..47], 1000).is_none());
+  }
+}
@@ -1,35 +1,55 @@
 #![cfg_attr(feature = "cargo-clippy", allow(clippy::match_same_arms))]
 
+mod battery;
+mod dma;
+mod hdma;
 mod key;
 mod mbc;
+mod serial;
+pub mod sgb;
 mod timer;
 
 pub use self::key::Key;
 
+use self::battery::Format;
+use self::dma::OamDma;
+use self::hdma::{Hdma, Transfer};
 use self::key::KeyData;
+use self::serial::Serial;
+use self::sgb::Sgb;
+use crate::apu::{self, Apu};
 use crate::gpu;
+use crate::link::LinkCable;
+use crate::palette::Palettes;
+use crate::state::{self, LoadState, SaveState};
 
 use self::mbc::{MBC, MBC0, MBC1, MBC3};
 
 use std::{
   error::Error,
   fmt,
-  fs::File,
-  io::{stdout, Read, Write},
+  fs::{self, File},
+  io::{self, Read},
   path::Path,
   path::PathBuf,
 };
 
 const WRAM_SIZE: usize = 0x2000;
+const CGB_WRAM_SIZE: usize = 0x8000;
 const ZRAM_SIZE: usize = 0xff;
 
 pub struct Memory {
+  /// Whether the cartridge runs in CGB mode.
+  cgb: bool,
+  /// Whether the cartridge supports the SGB.
+  sgb_game: bool,
+
   wram: Vec<u8>,
+  /// Switchable WRAM bank mapped at 0xd000 (CGB only).
+  wram_bank: usize,
   zram: Vec<u8>,
   key: KeyData,
-
-  sb: u8,
-  sc: u8,
+  serial: Serial,
 
   mbc: Box<dyn MBC>,
   cartridge_type: CartridgeType,
@@ -38,9 +58,20 @@ pub struct Memory {
   pub interrupt_flags: u8,
 
   gpu: gpu::GPU,
+  apu: Apu,
   timer: timer::Timer,
+  dma: OamDma,
+  hdma: Hdma,
+  sgb: Option<Sgb>,
+
+  /// CGB double-speed mode is active.
+  double_speed: bool,
+  /// KEY1 bit 0: switch speed on the next STOP.
+  speed_switch: bool,
 
   savepath: PathBuf,
+  /// Whether the save file from before this session has been backed up.
+  backed_up: bool,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -52,6 +83,8 @@ enum CartridgeType {
   MBC3,
   MBC3RAM,
   MBC3BatteryRAM,
+  MBC3TimerBattery,
+  MBC3TimerBatteryRAM,
 }
 
 impl fmt::Display for CartridgeType {
@@ -68,16 +101,30 @@ impl fmt::Display for CartridgeType {
       CartridgeType::MBC3BatteryRAM => {
         write!(f, "MBC3 with battery-backed RAM")?
       }
+      CartridgeType::MBC3TimerBattery => write!(f, "MBC3 with RTC")?,
+      CartridgeType::MBC3TimerBatteryRAM => {
+        write!(f, "MBC3 with RTC and battery-backed RAM")?
+      }
     }
     Ok(())
   }
 }
 
+fn no_battery() -> io::Error {
+  io::Error::new(
+    io::ErrorKind::InvalidInput,
+    "the cartridge has no battery-backed RAM",
+  )
+}
+
 impl CartridgeType {
   fn has_battery(&self) -> bool {
     matches!(
       *self,
-      CartridgeType::MBC1BatteryRAM | CartridgeType::MBC3BatteryRAM
+      CartridgeType::MBC1BatteryRAM
+        | CartridgeType::MBC3BatteryRAM
+        | CartridgeType::MBC3TimerBattery
+        | CartridgeType::MBC3TimerBatteryRAM
     )
   }
 }
@@ -141,22 +188,33 @@ fn ram_size(v: u8) -> Result<usize, LoadError> {
   }
 }
 
-const SAV_EXTENSION: &str = "sav";
+/// Whether the header's CGB flag (0x143) enables CGB features.
+fn is_cgb(rom: &[u8]) -> bool {
+  matches!(rom.get(0x0143), Some(&flag) if flag & 0x80 != 0)
+}
+
+/// Whether the header declares SGB support, which the SGB's boot ROM checks
+/// before running a game with its features.
+fn is_sgb(rom: &[u8]) -> bool {
+  rom.get(0x0146) == Some(&0x03) && rom.get(0x014b) == Some(&0x33)
+}
 
-fn read_save(filename: &Path) -> Option<Vec<u8>> {
-  let savepath = filename.with_extension(SAV_EXTENSION);
-  println!("Looking for save: {}", savepath.to_str().unwrap());
+/// Extension of save files for battery-backed RAM.
+pub const SAV_EXTENSION: &str = "sav";
+
+fn read_save(savepath: &Path) -> Option<Vec<u8>> {
+  println!("Looking for save: {}", savepath.display());
   if savepath.is_file() {
     // Load from save file.
-    if let Ok(mut f) = File::open(&savepath) {
-      println!("Reading save file: {}", savepath.to_str().unwrap());
+    if let Ok(mut f) = File::open(savepath) {
+      println!("Reading save file: {}", savepath.display());
       let mut buf = vec![];
       match f.read_to_end(&mut buf) {
         Ok(_) => {
           return Some(buf);
         }
         Err(_) => {
-          eprintln!("Unable to read save file: {}", savepath.to_str().unwrap());
+          eprintln!("Unable to read save file: {}", savepath.display());
         }
       };
     }
@@ -165,13 +223,17 @@ fn read_save(filename: &Path) -> Option<Vec<u8>> {
 }
 
 impl Memory {
-  pub fn new(rom: Vec<u8>, filename: PathBuf) -> Result<Memory, LoadError> {
+  /// Load a cartridge, with battery-backed RAM kept in the file at
+  /// `savepath`.
+  pub fn new(rom: Vec<u8>, savepath: PathBuf) -> Result<Memory, LoadError> {
     let cartridge_type = match rom.get(0x0147) {
       Some(&t) => match t {
         0x00 => CartridgeType::MBC0,
         0x01 => CartridgeType::MBC1,
         0x02 => CartridgeType::MBC1RAM,
         0x03 => CartridgeType::MBC1BatteryRAM,
+        0x0f => CartridgeType::MBC3TimerBattery,
+        0x10 => CartridgeType::MBC3TimerBatteryRAM,
         0x11 => CartridgeType::MBC3,
         0x12 => CartridgeType::MBC3RAM,
         0x13 => CartridgeType::MBC3BatteryRAM,
@@ -187,37 +249,32 @@ impl Memory {
     };
     info!("RAM size: 0x{:04x} bytes", ram_size);
 
+    let cgb = is_cgb(&rom);
+    info!("CGB mode: {}", cgb);
+    let sgb_game = is_sgb(&rom);
+
     let mbc: Box<dyn MBC> = match cartridge_type {
       CartridgeType::MBC0 => Box::new(MBC0::new(rom, ram_size)),
-      CartridgeType::MBC1 | CartridgeType::MBC1RAM => {
-        Box::new(MBC1::new(rom, ram_size))
-      }
-      CartridgeType::MBC1BatteryRAM => {
-        if let Some(save) = read_save(&filename) {
-          Box::new(MBC1::from_save(rom, save))
-        } else {
-          Box::new(MBC1::new(rom, ram_size))
-        }
-      }
-      CartridgeType::MBC3 | CartridgeType::MBC3RAM => {
-        Box::new(MBC3::new(rom, ram_size))
-      }
-      CartridgeType::MBC3BatteryRAM => {
-        if let Some(save) = read_save(&filename) {
-          Box::new(MBC3::from_save(rom, save))
-        } else {
-          Box::new(MBC3::new(rom, ram_size))
-        }
+      CartridgeType::MBC1
+      | CartridgeType::MBC1RAM
+      | CartridgeType::MBC1BatteryRAM => Box::new(MBC1::new(rom, ram_size)),
+      CartridgeType::MBC3
+      | CartridgeType::MBC3RAM
+      | CartridgeType::MBC3BatteryRAM => Box::new(MBC3::new(rom, ram_size)),
+      CartridgeType::MBC3TimerBattery | CartridgeType::MBC3TimerBatteryRAM => {
+        Box::new(MBC3::new(rom, ram_size).with_rtc())
       }
     };
 
     let mut result = Memory {
-      wram: vec![0; WRAM_SIZE],
+      cgb,
+      sgb_game,
+
+      wram: vec![0; if cgb { CGB_WRAM_SIZE } else { WRAM_SIZE }],
+      wram_bank: 1,
       zram: vec![0; ZRAM_SIZE],
       key: KeyData::new(),
-
-      sb: 0,
-      sc: 0,
+      serial: Serial::new(cgb),
 
       mbc,
       cartridge_type,
@@ -225,16 +282,58 @@ impl Memory {
       interrupt_enable: 0,
       interrupt_flags: 0,
 
-      gpu: gpu::GPU::new(),
+      gpu: gpu::GPU::new(cgb),
+      apu: Apu::new(apu::DEFAULT_SAMPLE_RATE),
       timer: timer::Timer::new(),
+      dma: OamDma::new(),
+      hdma: Hdma::new(),
+      sgb: None,
+
+      double_speed: false,
+      speed_switch: false,
 
-      savepath: filename.with_extension(SAV_EXTENSION),
+      savepath,
+      backed_up: false,
     };
     result.power_on();
+    if cartridge_type.has_battery() {
+      if let Some(save) = read_save(&result.savepath) {
+        result.load_save(&save, battery::unix_time());
+      }
+    }
 
     Ok(result)
   }
 
+  /// Return everything to how it is at power-on, as when the Game Boy is
+  /// switched off and on again. Cartridge RAM is kept unless `clear_ram` is
+  /// set. The link cable, palettes, audio settings and Super Game Boy mode
+  /// are kept too.
+  pub fn reset(&mut self, clear_ram: bool) {
+    let cgb = self.cgb;
+    self.wram = vec![0; if cgb { CGB_WRAM_SIZE } else { WRAM_SIZE }];
+    self.wram_bank = 1;
+    self.zram = vec![0; ZRAM_SIZE];
+    self.key = KeyData::new();
+    self.serial.reset();
+    self.mbc.reset(clear_ram);
+    self.interrupt_enable = 0;
+    self.interrupt_flags = 0;
+    let palettes = self.gpu.palettes;
+    self.gpu = gpu::GPU::new(cgb);
+    self.gpu.palettes = palettes;
+    self.apu.reset();
+    self.timer = timer::Timer::new();
+    self.dma = OamDma::new();
+    self.hdma = Hdma::new();
+    if self.sgb.is_some() {
+      self.sgb = Some(Sgb::new());
+    }
+    self.double_speed = false;
+    self.speed_switch = false;
+    self.power_on();
+  }
+
   fn power_on(&mut self) {
     // See http://nocash.emubase.de/pandocs.htm#powerupsequence
     self.wb(0xff05, 0x00); // TIMA
@@ -274,36 +373,178 @@ impl Memory {
   /// Returns the interrupts that have fired.
   pub fn step(&mut self, t: u32) -> u8 {
     let mut int = 0;
-    int |= self.gpu.step(t);
+    // The GPU and APU run at the same speed in double-speed mode,
+    // so they see half as many cycles.
+    let video_t = if self.double_speed { t / 2 } else { t };
+    int |= self.gpu.step(video_t);
+    self.mbc.step(video_t);
+
+    if int & 0x01 != 0 {
+      if let Some(ref mut sgb) = self.sgb {
+        if sgb.transfer_pending() {
+          sgb.transfer(&self.gpu.screen_tile_data());
+        }
+        sgb.render(&self.gpu.render);
+      }
+    }
+
+    if self.gpu.hblank {
+      self.gpu.hblank = false;
+      if self.hdma.active {
+        self.vram_dma(16);
+        self.hdma.finish_block();
+      }
+    }
 
     let m = if t == 0 { 1 } else { t / 4 };
-    if self.timer.inc(m) {
-      int |= 0b00100;
-    };
+    for _ in 0..m {
+      if self.timer.tick() {
+        int |= 0b00100;
+      }
+      if let Some((src, index)) = self.dma.tick() {
+        let value = self.read(src);
+        self.gpu.oam[index] = value;
+        self.gpu.update_object(0xfe00 + index as u16, value);
+      }
+    }
+    if self.serial.step(t, video_t) {
+      int |= 0b01000;
+    }
+
+    // The frame sequencer is clocked by DIV, which runs twice as fast in
+    // double-speed mode.
+    let div_bit = if self.double_speed { 0x20 } else { 0x10 };
+    self.apu.step(video_t, self.timer.div() & div_bit != 0);
     int
   }
 
+  /// Plug `cable` into the link port.
+  pub fn set_link_cable(&mut self, cable: Box<dyn LinkCable>) {
+    self.serial.set_cable(cable);
+  }
+
+  /// Whether the LCD is on, so there will be a vblank each frame.
+  pub fn lcd_on(&self) -> bool {
+    self.gpu.switchlcd
+  }
+
+  /// Whether the cartridge runs in CGB mode.
+  pub fn cgb(&self) -> bool {
+    self.cgb
+  }
+
+  /// Whether CGB double-speed mode is active.
+  pub fn double_speed(&self) -> bool {
+    self.double_speed
+  }
+
+  /// Called when the CPU executes STOP.
+  /// Returns true if the CPU switched speed rather than stopping.
+  pub fn stop(&mut self) -> bool {
+    if self.cgb && self.speed_switch {
+      self.speed_switch = false;
+      self.double_speed = !self.double_speed;
+      info!("Double speed: {}", self.double_speed);
+      true
+    } else {
+      false
+    }
+  }
+
+  /// Copy `len` bytes to VRAM from the HDMA source.
+  fn vram_dma(&mut self, len: u16) {
+    let (src, dst) = self.hdma.advance(len);
+    for i in 0..len {
+      let v = self.read(src.wrapping_add(i));
+      self.gpu.write_vram(dst.wrapping_add(i), v);
+    }
+  }
+
+  /// Get the index into `wram` for the WRAM (or echo) address `addr`.
+  fn wram_addr(&self, addr: u16) -> usize {
+    if addr & 0x1000 == 0 {
+      (addr & 0x0fff) as usize
+    } else {
+      self.wram_bank * 0x1000 + (addr & 0x0fff) as usize
+    }
+  }
+
+  /// Run as a Super Game Boy. Has no effect in CGB mode, or for games
+  /// without SGB support in their header.
+  pub fn enable_sgb(&mut self) {
+    if !self.cgb && self.sgb_game {
+      self.sgb = Some(Sgb::new());
+    }
+  }
+
+  /// Whether the system is a Super Game Boy.
+  pub fn sgb(&self) -> bool {
+    self.sgb.is_some()
+  }
+
   /// Return a reference to the current frame to draw.
   pub fn frame(&self) -> &gpu::Frame {
-    &self.gpu.frame
+    match self.sgb {
+      Some(ref sgb) => &sgb.frame,
+      None => &self.gpu.frame,
+    }
+  }
+
+  /// Return the current frame inside the SGB border, if running as an SGB.
+  pub fn sgb_frame(&self) -> Option<&sgb::SgbFrame> {
+    self.sgb.as_ref().map(|sgb| &*sgb.border_frame)
+  }
+
+  /// Set the rate of the samples produced by the APU.
+  pub fn set_sample_rate(&mut self, sample_rate: u32) {
+    self.apu.set_sample_rate(sample_rate);
+  }
+
+  /// Take the audio samples generated so far, as interleaved stereo pairs.
+  pub fn take_samples(&mut self) -> Vec<f32> {
+    self.apu.take_samples()
+  }
+
+  /// Also produce audio samples for each channel on its own.
+  pub fn set_channel_samples(&mut self, enabled: bool) {
+    self.apu.set_channel_samples(enabled);
   }
 
-  /// Read a byte at address `addr`.
+  /// Take the per-channel audio samples generated so far.
+  pub fn take_channel_samples(&mut self) -> Vec<f32> {
+    self.apu.take_channel_samples()
+  }
+
+  /// Set the palettes used to colour the DMG output.
+  pub fn set_palettes(&mut self, palettes: Palettes) {
+    self.gpu.palettes = palettes;
+  }
+
+  /// Read a byte at address `addr`, as seen by the CPU.
   pub fn rb(&self, addr: u16) -> u8 {
+    if self.dma.blocks(addr) {
+      0xff
+    } else {
+      self.read(addr)
+    }
+  }
+
+  /// Read a byte at address `addr`, ignoring OAM DMA bus conflicts.
+  fn read(&self, addr: u16) -> u8 {
     match addr >> 12 {
       0x0..=0x7 => self.mbc.rb(addr),
       // GPU VRAM
-      0x8..=0x9 => self.gpu.vram[(addr & 0x1fff) as usize],
+      0x8..=0x9 => self.gpu.read_vram(addr),
       // ERAM
       0xa..=0xb => self.mbc.rb(addr),
       // WRAM
-      0xc..=0xd => self.wram[(addr & 0x1fff) as usize],
+      0xc..=0xd => self.wram[self.wram_addr(addr)],
       // WRAM Shadow
-      0xe => self.wram[(addr & 0x1fff) as usize],
+      0xe => self.wram[self.wram_addr(addr)],
       0xf => {
         match (addr >> 8) & 0xf {
           // WRAM Shadow
-          0x0..=0xd => self.wram[(addr & 0x1fff) as usize],
+          0x0..=0xd => self.wram[self.wram_addr(addr)],
           // GPU OAM
           0xe => {
             let idx = (addr & 0xff) as usize;
@@ -321,20 +562,30 @@ impl Memory {
               self.zram[(addr & 0x7f) as usize]
             } else if addr >= 0xff40 {
               // I/O Control
-              match (addr >> 4) & 0xf {
-                0x4..=0x7 => self.gpu.rb(addr),
-                _ => 0,
+              match addr {
+                0xff4d if self.cgb => {
+                  (if self.double_speed { 0x80 } else { 0 })
+                    | 0x7e
+                    | self.speed_switch as u8
+                }
+                0xff46 => self.dma.rb(),
+                0xff51..=0xff55 if self.cgb => self.hdma.rb(addr),
+                0xff70 if self.cgb => 0xf8 | self.wram_bank as u8,
+                _ => match (addr >> 4) & 0xf {
+                  0x4..=0x7 => self.gpu.rb(addr),
+                  _ => 0,
+                },
               }
             } else {
               match addr & 0x3f {
-                0x00 => self.key.rb(),
-                0x01 => self.sb,
-                0x02 => self.sc,
-                0x04 => self.timer.reg.div as u8,
-                0x05 => self.timer.reg.tima as u8,
-                0x06 => self.timer.reg.tma as u8,
-                0x07 => self.timer.reg.tac as u8,
+                0x00 => match self.sgb.as_ref().and_then(Sgb::read_joypad) {
+                  Some(id) => 0xf0 | id,
+                  None => self.key.rb(),
+                },
+                0x01..=0x02 => self.serial.rb(addr),
+                0x04..=0x07 => self.timer.rb(addr),
                 0x0f => self.interrupt_flags,
+                0x10..=0x3f => self.apu.rb(addr),
                 _ => 0,
               }
             }
@@ -355,30 +606,37 @@ impl Memory {
 
   /// Write `value` at address `addr`.
   pub fn wb(&mut self, addr: u16, value: u8) {
-    if addr == 0xff02 && value == 0x81 {
-      print!("{}", self.rb(0xff01) as char);
-      stdout().flush().unwrap();
+    if self.dma.blocks(addr) {
+      return;
     }
     match addr >> 12 {
       0x0..=0x7 => self.mbc.wb(addr, value),
       // GPU VRAM
       0x8..=0x9 => {
         debug!("VRAM: 0x{:04x} <- 0x{:02x}", addr, value);
-        self.gpu.vram[(addr & 0x1fff) as usize] = value;
-        self.gpu.update_tile(addr);
+        self.gpu.write_vram(addr, value);
       }
       // ERAM
       0xa..=0xb => {
         self.mbc.wb(addr, value);
       }
       // WRAM
-      0xc..=0xd => self.wram[(addr & 0x1fff) as usize] = value,
+      0xc..=0xd => {
+        let addr = self.wram_addr(addr);
+        self.wram[addr] = value;
+      }
       // WRAM Shadow
-      0xe => self.wram[(addr & 0x1fff) as usize] = value,
+      0xe => {
+        let addr = self.wram_addr(addr);
+        self.wram[addr] = value;
+      }
       0xf => {
         match (addr >> 8) & 0xf {
           // WRAM Shadow
-          0x0..=0xd => self.wram[(addr & 0x1fff) as usize] = value,
+          0x0..=0xd => {
+            let addr = self.wram_addr(addr);
+            self.wram[addr] = value;
+          }
           // GPU OAM
           0xe => {
             let idx = (addr & 0xff) as usize;
@@ -395,28 +653,40 @@ impl Memory {
               // Zero page.
               self.zram[(addr & 0x7f) as usize] = value
             } else if addr >= 0xff40 {
-              // OAM DMA?
-              if addr == 0xff46 {
-                for i in 0..160 {
-                  let v = self.rb(((value as u16) << 8) + i);
-                  self.wb(0xfe00 + i, v);
+              match addr {
+                0xff46 => self.dma.start(value),
+                0xff4d if self.cgb => self.speed_switch = value & 1 != 0,
+                0xff51..=0xff55 if self.cgb => {
+                  if let Transfer::General(len) = self.hdma.wb(addr, value) {
+                    self.vram_dma(len);
+                  }
+                }
+                0xff70 if self.cgb => {
+                  self.wram_bank = match value & 0x07 {
+                    0 => 1,
+                    v => v as usize,
+                  }
+                }
+                _ => {
+                  if matches!((addr >> 4) & 0xf, 0x4..=0x7) {
+                    self.gpu.wb(addr, value);
+                  }
                 }
-                return;
-              }
-
-              if matches!((addr >> 4) & 0xf, 0x4..=0x7) {
-                self.gpu.wb(addr, value);
               }
             } else {
               match addr & 0x3f {
-                0x00 => self.key.wb(value),
-                0x01 => self.sb = value,
-                0x02 => self.sc = value,
-                0x04 => self.timer.reg.div = value as u32,
-                0x05 => self.timer.reg.tima = value as u32,
-                0x06 => self.timer.reg.tma = value as u32,
-                0x07 => self.timer.reg.tac = value as u32,
+                0x00 => {
+                  if self.key.wb(value) {
+                    self.interrupt_flags |= 0b10000;
+                  }
+                  if let Some(ref mut sgb) = self.sgb {
+                    sgb.write_joypad(value);
+                  }
+                }
+                0x01..=0x02 => self.serial.wb(addr, value),
+                0x04..=0x07 => self.timer.wb(addr, value),
                 0x0f => self.interrupt_flags = value,
+                0x10..=0x3f => self.apu.wb(addr, value),
                 _ => (),
               }
             }
@@ -443,42 +713,453 @@ impl Memory {
     }
   }
 
+  /// Press `key`, raising the joypad interrupt if a selected line falls.
   pub fn key_down(&mut self, key: Key) {
-    self.interrupt_flags |= 0b10000;
-    self.key.key_down(key);
+    if self.key.key_down(key) {
+      self.interrupt_flags |= 0b10000;
+    }
   }
 
   pub fn key_up(&mut self, key: Key) {
-    self.interrupt_flags |= 0b10000;
     self.key.key_up(key);
   }
 
-  pub fn save_ram(&self) {
-    let savepath = &self.savepath;
-    if self.cartridge_type.has_battery() && !savepath.is_dir() {
-      // Load ERAM from save file.
-      match File::create(&savepath) {
-        Ok(mut f) => {
-          println!("Writing save file: {}", savepath.to_str().unwrap());
-          match f.write(&self.mbc.to_save()) {
-            Ok(_) => (),
-            Err(e) => {
-              eprintln!(
-                "Unable to write save file {}: {}",
-                savepath.to_str().unwrap(),
-                e
-              );
-            }
-          }
-        }
-        Err(e) => {
-          eprintln!(
-            "Unable to open save file {}: {}",
-            savepath.to_str().unwrap(),
-            e
-          );
-        }
+  /// Add a section for each component to `state`.
+  pub fn save_state(&self, state: &mut SaveState) {
+    state.section(b"MEM ", |w| {
+      w.bytes(&self.wram);
+      w.u8(self.wram_bank as u8);
+      w.bytes(&self.zram);
+      w.u8(self.interrupt_enable);
+      w.u8(self.interrupt_flags);
+      w.bool(self.double_speed);
+      w.bool(self.speed_switch);
+    });
+    state.section(b"GPU ", |w| self.gpu.save_state(w));
+    state.section(b"APU ", |w| self.apu.save_state(w));
+    state.section(b"TIMR", |w| self.timer.save_state(w));
+    state.section(b"DMA ", |w| self.dma.save_state(w));
+    state.section(b"HDMA", |w| self.hdma.save_state(w));
+    state.section(b"JOYP", |w| self.key.save_state(w));
+    state.section(b"SERL", |w| self.serial.save_state(w));
+    state.section(b"MBC ", |w| self.mbc.save_state(w));
+    if let Some(ref sgb) = self.sgb {
+      state.section(b"SGB ", |w| sgb.save_state(w));
+    }
+  }
+
+  /// Load each component's section from `state`. Leaves the memory in an
+  /// inconsistent state on error.
+  pub fn load_state(&mut self, state: &LoadState) -> state::Result<()> {
+    let mut r = state.section(b"MEM ")?;
+    r.bytes_into(&mut self.wram)?;
+    self.wram_bank = r.u8()? as usize;
+    if self.wram_bank == 0 || self.wram_bank * 0x1000 >= self.wram.len() {
+      return Err(r.invalid(format!("bad WRAM bank {}", self.wram_bank)));
+    }
+    r.bytes_into(&mut self.zram)?;
+    self.interrupt_enable = r.u8()?;
+    self.interrupt_flags = r.u8()?;
+    self.double_speed = r.bool()? && self.cgb;
+    self.speed_switch = r.bool()? && self.cgb;
+
+    self.gpu.load_state(&mut state.section(b"GPU ")?)?;
+    self.apu.load_state(&mut state.section(b"APU ")?)?;
+    self.timer.load_state(&mut state.section(b"TIMR")?)?;
+    self.dma.load_state(&mut state.section(b"DMA ")?)?;
+    self.hdma.load_state(&mut state.section(b"HDMA")?)?;
+    self.key.load_state(&mut state.section(b"JOYP")?)?;
+    self.serial.load_state(&mut state.section(b"SERL")?)?;
+    self.mbc.load_state(&mut state.section(b"MBC ")?)?;
+    if let Some(ref mut sgb) = self.sgb {
+      sgb.load_state(&mut state.section(b"SGB ")?)?;
+    }
+    Ok(())
+  }
+
+  /// Whether `key` is held down, as far as the game can tell.
+  pub fn key_pressed(&self, key: Key) -> bool {
+    self.key.pressed(key)
+  }
+
+  /// Replace battery-backed RAM with a save file's contents, running a
+  /// clock on from the file's timestamp to `now`.
+  fn load_save(&mut self, data: &[u8], now: u64) {
+    let ram_size = self.mbc.to_save().len();
+    let has_rtc = self.mbc.rtc().is_some();
+    let save = battery::decode(data, ram_size, has_rtc, now);
+    self.mbc.load_save(save.ram, save.rtc);
+  }
+
+  /// Battery-backed RAM and clock as a save file timestamped 0, for
+  /// `load_battery` to restore exactly. None without a battery.
+  pub fn battery(&self) -> Option<Vec<u8>> {
+    if !self.cartridge_type.has_battery() {
+      return None;
+    }
+    let ram = self.mbc.to_save();
+    Some(battery::encode(&ram, self.mbc.rtc(), Format::Sav, 0))
+  }
+
+  /// Restore battery-backed RAM and clock saved by `battery`, without
+  /// running the clock on to the present.
+  pub fn load_battery(&mut self, data: &[u8]) {
+    self.load_save(data, 0);
+  }
+
+  /// Load battery-backed RAM from a save file in any supported format, such
+  /// as one from another emulator, and write it to this game's save file.
+  pub fn import_save(&mut self, path: &Path) -> io::Result<()> {
+    if !self.cartridge_type.has_battery() {
+      return Err(no_battery());
+    }
+    self.load_save(&fs::read(path)?, battery::unix_time());
+    self.write_save()
+  }
+
+  /// Write battery-backed RAM to `path`, in the format for its extension.
+  pub fn export_save(&self, path: &Path) -> io::Result<()> {
+    if !self.cartridge_type.has_battery() {
+      return Err(no_battery());
+    }
+    battery::write_atomic(path, &self.encode_save(Format::from_path(path)))
+  }
+
+  fn encode_save(&self, format: Format) -> Vec<u8> {
+    let ram = self.mbc.to_save();
+    battery::encode(&ram, self.mbc.rtc(), format, battery::unix_time())
+  }
+
+  /// Whether battery-backed RAM has changed since it was last saved.
+  pub fn ram_dirty(&self) -> bool {
+    self.cartridge_type.has_battery() && self.mbc.dirty()
+  }
+
+  /// Write battery-backed RAM to the save file if it has changed. The first
+  /// save of a session backs up the previous save file.
+  pub fn save_ram(&mut self) -> io::Result<()> {
+    if !self.ram_dirty() {
+      return Ok(());
+    }
+    self.write_save()
+  }
+
+  fn write_save(&mut self) -> io::Result<()> {
+    if !self.backed_up {
+      battery::back_up(&self.savepath)?;
+      self.backed_up = true;
+    }
+    let data = self.encode_save(Format::from_path(&self.savepath));
+    battery::write_atomic(&self.savepath, &data)?;
+    self.mbc.mark_saved();
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::Palette;
+
+  fn memory() -> Memory {
+    Memory::new(vec![0; 0x8000], PathBuf::from("test.sav")).unwrap()
+  }
+
+  fn cgb_memory() -> Memory {
+    let mut rom = vec![0; 0x8000];
+    rom[0x143] = 0x80;
+    Memory::new(rom, PathBuf::from("test.sav")).unwrap()
+  }
+
+  #[test]
+  fn cgb_vram_banks() {
+    let mut mem = cgb_memory();
+    mem.wb(0xff4f, 1);
+    assert_eq!(mem.rb(0xff4f), 0xff);
+    mem.wb(0x8000, 0x11);
+    mem.wb(0xff4f, 0);
+    assert_eq!(mem.rb(0xff4f), 0xfe);
+    assert_eq!(mem.rb(0x8000), 0);
+    mem.wb(0x8000, 0x22);
+    // Only bit 0 selects the bank.
+    mem.wb(0xff4f, 0xff);
+    assert_eq!(mem.rb(0x8000), 0x11);
+    mem.wb(0xff4f, 0);
+    assert_eq!(mem.rb(0x8000), 0x22);
+  }
+
+  #[test]
+  fn cgb_wram_banks() {
+    let mut mem = cgb_memory();
+    assert_eq!(mem.rb(0xff70), 0xf9);
+    mem.wb(0xc000, 0x00);
+    for bank in 1..8 {
+      mem.wb(0xff70, bank);
+      mem.wb(0xd000, bank * 0x11);
+    }
+    mem.wb(0xff70, 2);
+    assert_eq!(mem.rb(0xd000), 0x22);
+    // The echo of 0xd000 follows the bank too.
+    assert_eq!(mem.rb(0xf000), 0x22);
+    mem.wb(0xff70, 7);
+    assert_eq!((mem.rb(0xff70), mem.rb(0xd000)), (0xff, 0x77));
+    // Bank 0 selects bank 1.
+    mem.wb(0xff70, 0);
+    assert_eq!((mem.rb(0xff70), mem.rb(0xd000)), (0xf9, 0x11));
+    assert_eq!(mem.rb(0xc000), 0x00);
+
+    // The DMG has a single bank.
+    let mut mem = memory();
+    mem.wb(0xd000, 0x11);
+    mem.wb(0xff70, 2);
+    assert_eq!(mem.rb(0xd000), 0x11);
+  }
+
+  #[test]
+  fn cgb_palette_ram() {
+    let mut mem = cgb_memory();
+    // Auto-increment wraps from the last byte to the first.
+    mem.wb(0xff68, 0x80 | 0x3f);
+    mem.wb(0xff69, 0x12);
+    mem.wb(0xff69, 0x34);
+    assert_eq!(mem.rb(0xff68), 0xc1);
+    mem.wb(0xff68, 0x3f);
+    assert_eq!((mem.rb(0xff68), mem.rb(0xff69)), (0x7f, 0x12));
+    mem.wb(0xff68, 0x00);
+    assert_eq!(mem.rb(0xff69), 0x34);
+
+    // Without auto-increment, writes keep going to the same byte.
+    mem.wb(0xff6a, 0x05);
+    mem.wb(0xff6b, 0x56);
+    mem.wb(0xff6b, 0x78);
+    assert_eq!((mem.rb(0xff6a), mem.rb(0xff6b)), (0x45, 0x78));
+    mem.wb(0xff6a, 0x86);
+    assert_eq!(mem.rb(0xff6b), 0x00);
+    // Reading doesn't increment.
+    assert_eq!(mem.rb(0xff6a), 0xc6);
+  }
+
+  /// Fill WRAM from 0xc000 with 1, 2, 3... and point HDMA from there to
+  /// 0x8100.
+  fn start_vram_dma(mem: &mut Memory) {
+    for i in 0..0x80 {
+      mem.wb(0xc000 + i, i as u8 + 1);
+    }
+    mem.wb(0xff51, 0xc0);
+    mem.wb(0xff52, 0x00);
+    mem.wb(0xff53, 0x81);
+    mem.wb(0xff54, 0x00);
+  }
+
+  fn vram_copied(mem: &Memory) -> usize {
+    (0..0x80)
+      .take_while(|&i| mem.rb(0x8100 + i) == i as u8 + 1)
+      .count()
+  }
+
+  /// Run for whole 456-cycle lines, a few cycles at a time like the CPU.
+  fn run_lines(mem: &mut Memory, lines: u32) {
+    for _ in 0..lines * 456 / 4 {
+      mem.step(4);
+    }
+  }
+
+  #[test]
+  fn cgb_general_dma() {
+    let mut mem = cgb_memory();
+    start_vram_dma(&mut mem);
+    mem.wb(0xff55, 0x01);
+    assert_eq!(vram_copied(&mem), 0x20);
+    assert_eq!(mem.rb(0xff55), 0xff);
+
+    // The addresses carry on from where the last copy stopped.
+    mem.wb(0xff55, 0x00);
+    assert_eq!(vram_copied(&mem), 0x30);
+  }
+
+  #[test]
+  fn cgb_hblank_dma() {
+    let mut mem = cgb_memory();
+    start_vram_dma(&mut mem);
+    mem.wb(0xff55, 0x81);
+    assert_eq!(mem.rb(0xff55), 0x01);
+    assert_eq!(vram_copied(&mem), 0);
+
+    // One block is copied at the start of each line's HBlank.
+    run_lines(&mut mem, 1);
+    assert_eq!(vram_copied(&mem), 0x10);
+    assert_eq!(mem.rb(0xff55), 0x00);
+    run_lines(&mut mem, 1);
+    assert_eq!(vram_copied(&mem), 0x20);
+    assert_eq!(mem.rb(0xff55), 0xff);
+    run_lines(&mut mem, 1);
+    assert_eq!(vram_copied(&mem), 0x20);
+
+    // Writing with bit 7 clear stops a transfer part way.
+    mem.wb(0xff55, 0x83);
+    run_lines(&mut mem, 1);
+    assert_eq!(vram_copied(&mem), 0x30);
+    mem.wb(0xff55, 0x00);
+    assert_eq!(mem.rb(0xff55), 0x82);
+    run_lines(&mut mem, 4);
+    assert_eq!(vram_copied(&mem), 0x30);
+  }
+
+  #[test]
+  fn cgb_speed_switch() {
+    let mut mem = cgb_memory();
+    assert_eq!(mem.rb(0xff4d), 0x7e);
+    assert!(!mem.stop(), "STOP without a switch armed stops");
+    mem.wb(0xff4d, 0x01);
+    assert_eq!(mem.rb(0xff4d), 0x7f);
+    assert!(mem.stop());
+    assert!(mem.double_speed());
+    assert_eq!(mem.rb(0xff4d), 0xfe);
+    mem.wb(0xff4d, 0x01);
+    assert!(mem.stop());
+    assert!(!mem.double_speed());
+
+    let mut mem = memory();
+    mem.wb(0xff4d, 0x01);
+    assert!(!mem.stop());
+    assert!(!mem.double_speed());
+  }
+
+  #[test]
+  fn oam_dma() {
+    let mut mem = memory();
+    for i in 0..0xa0 {
+      mem.wb(0xc100 + i, i as u8 + 1);
+    }
+    mem.wb(0xff80, 0x12);
+    mem.wb(0xff46, 0xc1);
+    mem.step(4);
+    mem.step(4);
+    // Only the first byte has been copied, and the CPU only sees HRAM and
+    // I/O registers.
+    assert_eq!(mem.gpu.oam[0], 0x01);
+    assert_eq!(mem.gpu.oam[1], 0x00);
+    assert_eq!(mem.rb(0xc101), 0xff);
+    assert_eq!(mem.rb(0xff80), 0x12);
+    assert_eq!(mem.rb(0xff46), 0xc1);
+    mem.wb(0xc101, 0x55);
+
+    mem.step(4 * 0x9f);
+    assert_eq!(mem.rb(0xc101), 0x02);
+    assert_eq!(mem.rb(0xfe9f), 0xa0);
+  }
+
+  #[test]
+  fn reset() {
+    let mut rom = vec![0; 0x10000];
+    rom[0x146] = 0x03; // SGB support
+    rom[0x147] = 0x02; // MBC1 with RAM
+    rom[0x148] = 0x01; // 64 KB
+    rom[0x14b] = 0x33;
+    rom[0x4000] = 1;
+    rom[0x8000] = 2;
+    let mut mem = Memory::new(rom, PathBuf::from("test.sav")).unwrap();
+    let palettes = Palettes::uniform(Palette([0x123456; 4]));
+    mem.set_palettes(palettes);
+    mem.enable_sgb();
+    mem.wb(0x0000, 0x0a);
+    mem.wb(0x2000, 2);
+    mem.wb(0xa000, 0x42);
+    mem.wb(0xc000, 0x42);
+    mem.wb(0xff80, 0x42);
+    mem.wb(0xffff, 0x1f);
+    assert_eq!(mem.rb(0x4000), 2);
+
+    mem.reset(false);
+    assert_eq!(mem.rb(0x4000), 1);
+    assert_eq!((mem.rb(0xc000), mem.rb(0xff80)), (0, 0));
+    assert_eq!(mem.interrupt_enable, 0);
+    assert_eq!(mem.gpu.palettes, palettes);
+    assert!(mem.sgb());
+    mem.wb(0x0000, 0x0a);
+    assert_eq!(mem.rb(0xa000), 0x42);
+
+    mem.mbc.mark_saved();
+    mem.reset(true);
+    assert!(!mem.ram_dirty(), "clearing RAM would overwrite the save");
+    mem.wb(0x0000, 0x0a);
+    assert_eq!(mem.rb(0xa000), 0);
+  }
+
+  #[test]
+  fn sgb_header() {
+    let mut rom = vec![0; 0x8000];
+    let mut mem = Memory::new(rom.clone(), PathBuf::from("test.sav")).unwrap();
+    mem.enable_sgb();
+    assert!(!mem.sgb(), "only games that support the SGB use it");
+
+    rom[0x146] = 0x03;
+    rom[0x14b] = 0x33;
+    let mut mem = Memory::new(rom, PathBuf::from("test.sav")).unwrap();
+    mem.enable_sgb();
+    assert!(mem.sgb());
+  }
+
+  #[test]
+  fn sgb_transfer_scrolled() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x146] = 0x03;
+    rom[0x14b] = 0x33;
+    let mut mem = Memory::new(rom, PathBuf::from("test.sav")).unwrap();
+    mem.enable_sgb();
+    mem.wb(0xff40, 0);
+    // Tile 1 is solid colour 3, and fills the map except the top left.
+    for addr in 0x8010..0x8020 {
+      mem.wb(addr, 0xff);
+    }
+    for addr in 0x9801..0x9c00 {
+      mem.wb(addr, 1);
+    }
+    mem.wb(0xff47, 0xe4);
+    // Scroll the blank tile off screen.
+    mem.wb(0xff42, 4);
+    mem.wb(0xff43, 4);
+    mem.wb(0xff40, 0x91);
+    // The first frame after the LCD is switched on isn't complete.
+    for line in [144, 0, 144] {
+      while mem.gpu.rb(0xff44) != line {
+        mem.step(4);
       }
     }
+    let data = mem.gpu.screen_tile_data();
+    assert_eq!(data.len(), 0x1000);
+    assert_eq!(&data[..2], &[0x0f, 0x0f]);
+    assert_eq!(&data[8..10], &[0xff, 0xff]);
+    assert!(data[16..].iter().all(|&b| b == 0xff));
+  }
+
+  #[test]
+  fn battery_save() {
+    let dir = std::env::temp_dir().join(format!("save{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let savepath = dir.join("game.sav");
+    let mut rom = vec![0; 0x8000];
+    rom[0x147] = 0x03; // MBC1 with battery-backed RAM
+    rom[0x149] = 0x02; // 8 KB
+
+    let mut mem = Memory::new(rom.clone(), savepath.clone()).unwrap();
+    mem.save_ram().unwrap();
+    assert!(!mem.savepath.exists(), "nothing to save yet");
+    mem.wb(0xa000, 0x42);
+    assert!(mem.ram_dirty());
+    mem.save_ram().unwrap();
+    assert!(!mem.ram_dirty());
+    mem.wb(0xa001, 0x43);
+    mem.save_ram().unwrap();
+    assert!(!battery::backup_path(&mem.savepath, 1).exists());
+
+    // The next session backs up the last one's save.
+    let mut mem = Memory::new(rom, savepath).unwrap();
+    assert_eq!((mem.rb(0xa000), mem.rb(0xa001)), (0x42, 0x43));
+    mem.wb(0xa000, 0);
+    mem.save_ram().unwrap();
+    let backup = std::fs::read(battery::backup_path(&mem.savepath, 1));
+    assert_eq!(backup.unwrap()[..2], [0x42, 0x43]);
+    std::fs::remove_dir_all(&dir).unwrap();
   }
 }
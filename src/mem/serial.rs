@@ -0,0 +1,219 @@
+use crate::link::{Disconnected, LinkCable};
+use crate::state::{self, Reader, Writer};
+
+/// CPU cycles per bit with the internal clock at 8192 Hz.
+const SLOW_BIT_CYCLES: u32 = 512;
+/// CPU cycles per bit with the CGB's fast internal clock at 262144 Hz.
+const FAST_BIT_CYCLES: u32 = 16;
+
+/// A transfer on the internal clock.
+#[derive(Debug, Copy, Clone)]
+struct Transfer {
+  /// The byte coming in from the other end, shifted in a bit at a time.
+  incoming: u8,
+  bits_left: u8,
+  /// Cycles until the next bit is shifted.
+  counter: u32,
+}
+
+/// The serial port: SB (0xff01) and SC (0xff02).
+pub struct Serial {
+  sb: u8,
+  sc: u8,
+  cgb: bool,
+  transfer: Option<Transfer>,
+  cable: Box<dyn LinkCable>,
+}
+
+impl Serial {
+  pub fn new(cgb: bool) -> Serial {
+    Serial {
+      sb: 0,
+      sc: 0,
+      cgb,
+      transfer: None,
+      cable: Box::new(Disconnected),
+    }
+  }
+
+  pub fn set_cable(&mut self, cable: Box<dyn LinkCable>) {
+    self.cable = cable;
+  }
+
+  /// Return to the state at power-on, keeping the cable plugged in.
+  pub fn reset(&mut self) {
+    self.sb = 0;
+    self.sc = 0;
+    self.transfer = None;
+  }
+
+  pub fn rb(&self, addr: u16) -> u8 {
+    match addr {
+      0xff01 => self.sb,
+      // The clock speed bit only exists on the CGB.
+      0xff02 if self.cgb => 0x7c | self.sc,
+      0xff02 => 0x7e | self.sc,
+      _ => 0xff,
+    }
+  }
+
+  pub fn wb(&mut self, addr: u16, value: u8) {
+    match addr {
+      0xff01 => self.sb = value,
+      0xff02 => {
+        self.sc = value & if self.cgb { 0x83 } else { 0x81 };
+        self.transfer = None;
+        if self.sc & 0x81 == 0x81 {
+          let bit_cycles = self.bit_cycles();
+          self.transfer = Some(Transfer {
+            incoming: self.cable.transfer(self.sb),
+            bits_left: 8,
+            counter: bit_cycles,
+          });
+        }
+      }
+      _ => (),
+    }
+  }
+
+  /// Run for `t` CPU cycles, which took `real_t` cycles at normal speed.
+  /// Returns true if the serial interrupt was raised.
+  pub fn step(&mut self, t: u32, real_t: u32) -> bool {
+    self.cable.step(real_t);
+
+    if self.sc & 0x80 == 0 {
+      return false;
+    }
+    if self.sc & 0x01 == 0 {
+      // Waiting for the other end to clock a transfer.
+      return match self.cable.receive(self.sb) {
+        Some(byte) => {
+          self.sb = byte;
+          self.finish();
+          true
+        }
+        None => false,
+      };
+    }
+
+    let bit_cycles = self.bit_cycles();
+    let transfer = match self.transfer {
+      Some(ref mut transfer) => transfer,
+      None => return false,
+    };
+    let mut t = t;
+    while t >= transfer.counter {
+      t -= transfer.counter;
+      transfer.counter = bit_cycles;
+      transfer.bits_left -= 1;
+      let bit = (transfer.incoming >> transfer.bits_left) & 1;
+      self.sb = (self.sb << 1) | bit;
+      if transfer.bits_left == 0 {
+        self.finish();
+        return true;
+      }
+    }
+    transfer.counter -= t;
+    false
+  }
+
+  fn bit_cycles(&self) -> u32 {
+    if self.sc & 0x02 != 0 {
+      FAST_BIT_CYCLES
+    } else {
+      SLOW_BIT_CYCLES
+    }
+  }
+
+  fn finish(&mut self) {
+    self.sc &= 0x7f;
+    self.transfer = None;
+  }
+
+  /// Save the port's registers. Whatever is plugged in stays plugged in.
+  pub fn save_state(&self, w: &mut Writer) {
+    w.u8(self.sb);
+    w.u8(self.sc);
+    w.bool(self.transfer.is_some());
+    if let Some(transfer) = self.transfer {
+      w.u8(transfer.incoming);
+      w.u8(transfer.bits_left);
+      w.u32(transfer.counter);
+    }
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    self.sb = r.u8()?;
+    self.sc = r.u8()? & if self.cgb { 0x83 } else { 0x81 };
+    self.transfer = if r.bool()? {
+      let transfer = Transfer {
+        incoming: r.u8()?,
+        bits_left: r.u8()?,
+        counter: r.u32()?,
+      };
+      if transfer.bits_left == 0 || transfer.bits_left > 8 {
+        return Err(r.invalid("bad serial transfer".to_string()));
+      }
+      Some(transfer)
+    } else {
+      None
+    };
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::link::{pair, Loopback};
+
+  fn run(serial: &mut Serial, cycles: u32) -> bool {
+    (0..cycles / 4).any(|_| serial.step(4, 4))
+  }
+
+  #[test]
+  fn internal_clock() {
+    let mut serial = Serial::new(false);
+    serial.set_cable(Box::new(Loopback));
+    serial.wb(0xff01, 0xa5);
+    serial.wb(0xff02, 0x81);
+    assert_eq!(serial.rb(0xff02), 0xff);
+    assert!(!run(&mut serial, 512 * 8 - 4));
+    assert!(serial.step(4, 4));
+    assert_eq!(serial.rb(0xff01), 0xa5);
+    assert_eq!(serial.rb(0xff02), 0x7f);
+  }
+
+  #[test]
+  fn disconnected() {
+    let mut serial = Serial::new(true);
+    serial.wb(0xff01, 0x00);
+    // Fast clock.
+    serial.wb(0xff02, 0x83);
+    assert!(run(&mut serial, 16 * 8));
+    assert_eq!(serial.rb(0xff01), 0xff);
+    assert_eq!(serial.rb(0xff02), 0x7f);
+  }
+
+  #[test]
+  fn external_clock() {
+    let (a, b) = pair();
+    let mut master = Serial::new(false);
+    let mut slave = Serial::new(false);
+    master.set_cable(Box::new(a));
+    slave.set_cable(Box::new(b));
+
+    slave.wb(0xff01, 0x42);
+    slave.wb(0xff02, 0x80);
+    // Without a clock from the other end, nothing happens.
+    assert!(!run(&mut slave, 10000));
+
+    master.wb(0xff01, 0x99);
+    master.wb(0xff02, 0x81);
+    assert!(slave.step(4, 4));
+    assert_eq!(slave.rb(0xff01), 0x99);
+    assert_eq!(slave.rb(0xff02), 0x7e);
+    assert!(run(&mut master, 512 * 8));
+    assert_eq!(master.rb(0xff01), 0x42);
+  }
+}
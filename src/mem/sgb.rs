@@ -0,0 +1,688 @@
+use crate::gpu;
+use crate::state::{self, Reader, Writer};
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+/// Output frame including the border.
+pub type SgbFrame = [u32; SGB_WIDTH * SGB_HEIGHT];
+
+/// The game screen's position inside the border.
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+/// Size of the attribute map, in tiles.
+const ATTR_WIDTH: usize = gpu::WIDTH / 8;
+const ATTR_HEIGHT: usize = gpu::HEIGHT / 8;
+
+const PACKET_SIZE: usize = 16;
+
+const NUM_SYSTEM_PALETTES: usize = 512;
+const NUM_ATTR_FILES: usize = 45;
+/// Each attribute file holds 2 bits per tile.
+const ATTR_FILE_SIZE: usize = ATTR_WIDTH * ATTR_HEIGHT / 4;
+
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_SIZE: usize = 0x800;
+const BORDER_MAP_WIDTH: usize = 32;
+
+// Command codes, from the top 5 bits of a packet's first byte.
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0a;
+const PAL_TRN: u8 = 0x0b;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mask {
+  Cancel,
+  /// Keep showing the last frame.
+  Freeze,
+  Black,
+  /// Fill the screen with colour 0.
+  Color0,
+}
+
+/// A pending VRAM transfer, which copies 4 KB of the next frame's tile data.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Transfer {
+  Palettes,
+  Attributes,
+  /// Border tiles, to the upper half of the tile set if true.
+  Tiles(bool),
+  Border,
+}
+
+/// Super Game Boy command decoding, palettes and border.
+pub struct Sgb {
+  /// Last value written to P1.
+  p1: u8,
+  /// Whether a packet is being received.
+  receiving: bool,
+  bit: usize,
+  packet: [u8; PACKET_SIZE],
+  /// All packets of the current command.
+  command: Vec<u8>,
+
+  players: u8,
+  player: u8,
+
+  /// Palettes 0-3 in RGB555. Colour 0 is shared by all of them.
+  palettes: [[u16; 4]; 4],
+  system_palettes: Vec<u16>,
+  attr_files: Vec<u8>,
+  /// Palette number for each tile of the screen.
+  attrs: [u8; ATTR_WIDTH * ATTR_HEIGHT],
+  mask: Mask,
+  transfer: Option<Transfer>,
+
+  border_tiles: Vec<u8>,
+  border_map: Vec<u8>,
+  /// Border palettes 4-7 in RGB555.
+  border_palettes: [u16; 64],
+
+  /// The colourised game screen.
+  pub frame: Box<gpu::Frame>,
+  /// The game screen inside the border.
+  pub border_frame: Box<SgbFrame>,
+}
+
+impl Sgb {
+  pub fn new() -> Sgb {
+    let grey = [0x7fff, 0x5294, 0x294a, 0x0000];
+    Sgb {
+      p1: 0x30,
+      receiving: false,
+      bit: 0,
+      packet: [0; PACKET_SIZE],
+      command: vec![],
+
+      players: 1,
+      player: 0,
+
+      palettes: [grey; 4],
+      system_palettes: vec![0; NUM_SYSTEM_PALETTES * 4],
+      attr_files: vec![0; NUM_ATTR_FILES * ATTR_FILE_SIZE],
+      attrs: [0; ATTR_WIDTH * ATTR_HEIGHT],
+      mask: Mask::Cancel,
+      transfer: None,
+
+      border_tiles: vec![0; 256 * BORDER_TILE_SIZE],
+      border_map: vec![0; BORDER_MAP_SIZE],
+      border_palettes: [0; 64],
+
+      frame: Box::new([0; gpu::WIDTH * gpu::HEIGHT]),
+      border_frame: Box::new([0; SGB_WIDTH * SGB_HEIGHT]),
+    }
+  }
+
+  /// Decode pulses written to P14 (bit 4) and P15 (bit 5) of P1.
+  pub fn write_joypad(&mut self, value: u8) {
+    let value = value & 0x30;
+    let prev = self.p1;
+    self.p1 = value;
+
+    if value == 0x00 {
+      // Reset pulse: start a new packet.
+      self.receiving = true;
+      self.bit = 0;
+      self.packet = [0; PACKET_SIZE];
+      return;
+    }
+
+    if !self.receiving {
+      if self.players > 1 && prev & 0x20 == 0 && value & 0x20 != 0 {
+        self.player = (self.player + 1) % self.players;
+      }
+      return;
+    }
+
+    // Bits are sent as a low pulse on one line, followed by both high.
+    if prev != 0x30 || value == 0x30 {
+      return;
+    }
+    if self.bit == PACKET_SIZE * 8 {
+      // Stop bit.
+      self.receiving = false;
+      self.receive_packet();
+      return;
+    }
+    if value == 0x10 {
+      self.packet[self.bit / 8] |= 1 << (self.bit % 8);
+    }
+    self.bit += 1;
+  }
+
+  /// The value read from P1 while multiplayer is enabled and both lines are
+  /// deselected: the current joypad ID.
+  pub fn read_joypad(&self) -> Option<u8> {
+    if self.players > 1 && self.p1 == 0x30 {
+      Some(0x0f - self.player)
+    } else {
+      None
+    }
+  }
+
+  fn receive_packet(&mut self) {
+    if self.command.is_empty() && self.packet[0] & 0x07 == 0 {
+      // A command must be at least one packet long.
+      return;
+    }
+    self.command.extend_from_slice(&self.packet);
+    let len = (self.command[0] & 0x07) as usize;
+    if self.command.len() >= len * PACKET_SIZE {
+      let command = std::mem::take(&mut self.command);
+      self.run_command(&command);
+    }
+  }
+
+  fn run_command(&mut self, data: &[u8]) {
+    let command = data[0] >> 3;
+    debug!("SGB command 0x{:02x}", command);
+    match command {
+      PAL01 => self.set_palettes(data, 0, 1),
+      PAL23 => self.set_palettes(data, 2, 3),
+      PAL03 => self.set_palettes(data, 0, 3),
+      PAL12 => self.set_palettes(data, 1, 2),
+      ATTR_BLK => self.attr_blk(data),
+      ATTR_LIN => self.attr_lin(data),
+      ATTR_DIV => self.attr_div(data),
+      ATTR_CHR => self.attr_chr(data),
+      PAL_SET => self.pal_set(data),
+      PAL_TRN => self.transfer = Some(Transfer::Palettes),
+      MLT_REQ => {
+        self.players = match data[1] & 0x03 {
+          1 => 2,
+          3 => 4,
+          _ => 1,
+        };
+        self.player = 0;
+      }
+      CHR_TRN => self.transfer = Some(Transfer::Tiles(data[1] & 1 != 0)),
+      PCT_TRN => self.transfer = Some(Transfer::Border),
+      ATTR_TRN => self.transfer = Some(Transfer::Attributes),
+      ATTR_SET => self.attr_set(data[1]),
+      MASK_EN => {
+        self.mask = match data[1] & 0x03 {
+          0 => Mask::Cancel,
+          1 => Mask::Freeze,
+          2 => Mask::Black,
+          _ => Mask::Color0,
+        }
+      }
+      _ => debug!("Unsupported SGB command 0x{:02x}", command),
+    }
+  }
+
+  fn set_palettes(&mut self, data: &[u8], a: usize, b: usize) {
+    let color = |i: usize| u16::from(data[i]) | (u16::from(data[i + 1]) << 8);
+    for pal in self.palettes.iter_mut() {
+      pal[0] = color(1);
+    }
+    for i in 0..3 {
+      self.palettes[a][i + 1] = color(3 + i * 2);
+      self.palettes[b][i + 1] = color(9 + i * 2);
+    }
+  }
+
+  fn set_attr(&mut self, x: usize, y: usize, palette: u8) {
+    if x < ATTR_WIDTH && y < ATTR_HEIGHT {
+      self.attrs[y * ATTR_WIDTH + x] = palette & 0x03;
+    }
+  }
+
+  fn attr_blk(&mut self, data: &[u8]) {
+    let count = (data[1] & 0x1f) as usize;
+    for set in data[2..].chunks(6).take(count) {
+      if set.len() < 6 {
+        break;
+      }
+      let control = set[0] & 0x07;
+      let inside = set[1] & 0x03;
+      let outside = (set[1] >> 4) & 0x03;
+      // With only one of inside and outside set, the border takes
+      // the same palette.
+      let border = match control {
+        0x01 => Some(inside),
+        0x04 => Some(outside),
+        c if c & 0x02 != 0 => Some((set[1] >> 2) & 0x03),
+        _ => None,
+      };
+      let (x1, y1) = (set[2] as usize, set[3] as usize);
+      let (x2, y2) = (set[4] as usize, set[5] as usize);
+      for y in 0..ATTR_HEIGHT {
+        for x in 0..ATTR_WIDTH {
+          let in_rect = x1 <= x && x <= x2 && y1 <= y && y <= y2;
+          let strictly_inside = x1 < x && x < x2 && y1 < y && y < y2;
+          if strictly_inside {
+            if control & 0x01 != 0 {
+              self.set_attr(x, y, inside);
+            }
+          } else if in_rect {
+            if let Some(border) = border {
+              self.set_attr(x, y, border);
+            }
+          } else if control & 0x04 != 0 {
+            self.set_attr(x, y, outside);
+          }
+        }
+      }
+    }
+  }
+
+  fn attr_lin(&mut self, data: &[u8]) {
+    let count = data[1] as usize;
+    for &line in data[2..].iter().take(count) {
+      let n = (line & 0x1f) as usize;
+      let palette = (line >> 5) & 0x03;
+      if line & 0x80 != 0 {
+        for x in 0..ATTR_WIDTH {
+          self.set_attr(x, n, palette);
+        }
+      } else {
+        for y in 0..ATTR_HEIGHT {
+          self.set_attr(n, y, palette);
+        }
+      }
+    }
+  }
+
+  fn attr_div(&mut self, data: &[u8]) {
+    let after = data[1] & 0x03;
+    let before = (data[1] >> 2) & 0x03;
+    let on = (data[1] >> 4) & 0x03;
+    let horizontal = data[1] & 0x40 != 0;
+    let n = (data[2] & 0x1f) as usize;
+    for y in 0..ATTR_HEIGHT {
+      for x in 0..ATTR_WIDTH {
+        let pos = if horizontal { y } else { x };
+        let palette = match pos {
+          p if p < n => before,
+          p if p == n => on,
+          _ => after,
+        };
+        self.set_attr(x, y, palette);
+      }
+    }
+  }
+
+  fn attr_chr(&mut self, data: &[u8]) {
+    let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+    let count = (data[3] as usize) | ((data[4] as usize) << 8);
+    let vertical = data[5] & 1 != 0;
+    for i in 0..count.min(ATTR_WIDTH * ATTR_HEIGHT) {
+      let byte = match data.get(6 + i / 4) {
+        Some(&b) => b,
+        None => break,
+      };
+      let palette = (byte >> (6 - (i % 4) * 2)) & 0x03;
+      self.set_attr(x, y, palette);
+      if vertical {
+        y += 1;
+        if y == ATTR_HEIGHT {
+          y = 0;
+          x += 1;
+        }
+      } else {
+        x += 1;
+        if x == ATTR_WIDTH {
+          x = 0;
+          y += 1;
+        }
+      }
+    }
+  }
+
+  fn pal_set(&mut self, data: &[u8]) {
+    for i in 0..4 {
+      let n = (data[1 + i * 2] as usize) | ((data[2 + i * 2] as usize) << 8);
+      let n = n % NUM_SYSTEM_PALETTES;
+      self.palettes[i].copy_from_slice(&self.system_palettes[n * 4..n * 4 + 4]);
+    }
+    // Colour 0 of palette 0 is shared.
+    let color0 = self.palettes[0][0];
+    for pal in self.palettes.iter_mut() {
+      pal[0] = color0;
+    }
+    if data[9] & 0x80 != 0 {
+      self.attr_set(data[9]);
+    } else if data[9] & 0x40 != 0 {
+      self.mask = Mask::Cancel;
+    }
+  }
+
+  fn attr_set(&mut self, value: u8) {
+    let file = (value & 0x3f) as usize;
+    if file < NUM_ATTR_FILES {
+      let base = file * ATTR_FILE_SIZE;
+      for i in 0..ATTR_WIDTH * ATTR_HEIGHT {
+        let byte = self.attr_files[base + i / 4];
+        self.attrs[i] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+      }
+    }
+    if value & 0x40 != 0 {
+      self.mask = Mask::Cancel;
+    }
+  }
+
+  /// Whether a VRAM transfer is waiting for the next frame's data.
+  pub fn transfer_pending(&self) -> bool {
+    self.transfer.is_some()
+  }
+
+  /// Complete a pending VRAM transfer with 4 KB of tile data.
+  pub fn transfer(&mut self, data: &[u8]) {
+    let word = |i: usize| u16::from(data[i]) | (u16::from(data[i + 1]) << 8);
+    match self.transfer.take() {
+      Some(Transfer::Palettes) => {
+        for (i, color) in self.system_palettes.iter_mut().enumerate() {
+          *color = word(i * 2);
+        }
+      }
+      Some(Transfer::Attributes) => {
+        let len = self.attr_files.len();
+        self.attr_files.copy_from_slice(&data[..len]);
+      }
+      Some(Transfer::Tiles(high)) => {
+        let offset = if high { 0x1000 } else { 0 };
+        self.border_tiles[offset..offset + 0x1000]
+          .copy_from_slice(&data[..0x1000]);
+      }
+      Some(Transfer::Border) => {
+        self.border_map.copy_from_slice(&data[..BORDER_MAP_SIZE]);
+        for (i, color) in self.border_palettes.iter_mut().enumerate() {
+          *color = word(BORDER_MAP_SIZE + i * 2);
+        }
+      }
+      None => (),
+    }
+  }
+
+  /// Colourise a completed frame of DMG shades and redraw the border.
+  pub fn render(&mut self, shades: &[u8; gpu::WIDTH * gpu::HEIGHT]) {
+    match self.mask {
+      Mask::Freeze => (),
+      Mask::Black => {
+        for p in self.frame.iter_mut() {
+          *p = 0;
+        }
+      }
+      Mask::Color0 => {
+        let color = gpu::rgb555_to_rgb(self.palettes[0][0]);
+        for p in self.frame.iter_mut() {
+          *p = color;
+        }
+      }
+      Mask::Cancel => {
+        for (i, p) in self.frame.iter_mut().enumerate() {
+          let (x, y) = (i % gpu::WIDTH, i / gpu::WIDTH);
+          let palette = self.attrs[(y / 8) * ATTR_WIDTH + x / 8] as usize;
+          let color = self.palettes[palette][(shades[i] & 3) as usize];
+          *p = gpu::rgb555_to_rgb(color);
+        }
+      }
+    }
+    self.render_border();
+  }
+
+  fn render_border(&mut self) {
+    let backdrop = gpu::rgb555_to_rgb(self.palettes[0][0]);
+    for y in 0..SGB_HEIGHT {
+      for x in 0..SGB_WIDTH {
+        let in_screen = (SCREEN_X..SCREEN_X + gpu::WIDTH).contains(&x)
+          && (SCREEN_Y..SCREEN_Y + gpu::HEIGHT).contains(&y);
+        let color = if in_screen {
+          self.frame[(y - SCREEN_Y) * gpu::WIDTH + (x - SCREEN_X)]
+        } else {
+          match self.border_pixel(x, y) {
+            0 => backdrop,
+            c => gpu::rgb555_to_rgb(c),
+          }
+        };
+        self.border_frame[y * SGB_WIDTH + x] = color;
+      }
+    }
+  }
+
+  /// Get the RGB555 colour of the border at (x, y), or 0 if transparent.
+  fn border_pixel(&self, x: usize, y: usize) -> u16 {
+    let i = ((y / 8) * BORDER_MAP_WIDTH + x / 8) * 2;
+    let entry =
+      u16::from(self.border_map[i]) | (u16::from(self.border_map[i + 1]) << 8);
+    let tile = (entry & 0xff) as usize;
+    let palette = ((entry >> 10) & 0x07) as usize;
+    let col = if entry & 0x4000 != 0 {
+      x % 8
+    } else {
+      7 - x % 8
+    };
+    let row = if entry & 0x8000 != 0 {
+      7 - y % 8
+    } else {
+      y % 8
+    };
+
+    // SNES 4bpp planar tiles: planes 0-1 interleaved in the first
+    // 16 bytes, planes 2-3 in the second.
+    let base = tile * BORDER_TILE_SIZE;
+    let planes = [
+      self.border_tiles[base + row * 2],
+      self.border_tiles[base + row * 2 + 1],
+      self.border_tiles[base + 16 + row * 2],
+      self.border_tiles[base + 16 + row * 2 + 1],
+    ];
+    let color = planes
+      .iter()
+      .enumerate()
+      .fold(0, |c, (i, p)| c | (((p >> col) & 1) << i));
+    if color == 0 || palette < 4 {
+      return 0;
+    }
+    self.border_palettes[(palette - 4) * 16 + color as usize]
+  }
+
+  pub fn save_state(&self, w: &mut Writer) {
+    w.u8(self.p1);
+    w.bool(self.receiving);
+    w.u8(self.bit as u8);
+    w.bytes(&self.packet);
+    w.bytes(&self.command);
+    w.u8(self.players);
+    w.u8(self.player);
+
+    for &color in self.palettes.iter().flatten() {
+      w.u16(color);
+    }
+    w.bytes(&u16_bytes(&self.system_palettes));
+    w.bytes(&self.attr_files);
+    w.bytes(&self.attrs);
+    w.u8(self.mask as u8);
+    w.u8(match self.transfer {
+      None => 0,
+      Some(Transfer::Palettes) => 1,
+      Some(Transfer::Attributes) => 2,
+      Some(Transfer::Tiles(false)) => 3,
+      Some(Transfer::Tiles(true)) => 4,
+      Some(Transfer::Border) => 5,
+    });
+
+    w.bytes(&self.border_tiles);
+    w.bytes(&self.border_map);
+    w.bytes(&u16_bytes(&self.border_palettes));
+    let mut frame = Vec::with_capacity(self.frame.len() * 4);
+    for pixel in self.frame.iter() {
+      frame.extend_from_slice(&pixel.to_le_bytes());
+    }
+    w.bytes(&frame);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    self.p1 = r.u8()?;
+    self.receiving = r.bool()?;
+    self.bit = r.u8()? as usize;
+    // All the bits can be in, waiting for the stop bit.
+    if self.bit > PACKET_SIZE * 8 {
+      return Err(r.invalid(format!("bad packet bit {}", self.bit)));
+    }
+    r.bytes_into(&mut self.packet)?;
+    self.command = r.bytes()?.to_vec();
+    self.players = r.u8()?;
+    self.player = r.u8()?;
+    if !matches!(self.players, 1 | 2 | 4) || self.player >= self.players {
+      return Err(r.invalid("bad player count".to_string()));
+    }
+
+    for color in self.palettes.iter_mut().flatten() {
+      *color = r.u16()?;
+    }
+    read_u16s(r, &mut self.system_palettes)?;
+    r.bytes_into(&mut self.attr_files)?;
+    r.bytes_into(&mut self.attrs)?;
+    for attr in self.attrs.iter_mut() {
+      *attr &= 3;
+    }
+    self.mask = match r.u8()? {
+      0 => Mask::Cancel,
+      1 => Mask::Freeze,
+      2 => Mask::Black,
+      3 => Mask::Color0,
+      v => return Err(r.invalid(format!("bad mask {}", v))),
+    };
+    self.transfer = match r.u8()? {
+      0 => None,
+      1 => Some(Transfer::Palettes),
+      2 => Some(Transfer::Attributes),
+      3 => Some(Transfer::Tiles(false)),
+      4 => Some(Transfer::Tiles(true)),
+      5 => Some(Transfer::Border),
+      v => return Err(r.invalid(format!("bad transfer {}", v))),
+    };
+
+    r.bytes_into(&mut self.border_tiles)?;
+    r.bytes_into(&mut self.border_map)?;
+    read_u16s(r, &mut self.border_palettes)?;
+    let mut frame = vec![0; self.frame.len() * 4];
+    r.bytes_into(&mut frame)?;
+    for (pixel, bytes) in self.frame.iter_mut().zip(frame.chunks(4)) {
+      *pixel = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    self.render_border();
+    Ok(())
+  }
+}
+
+fn u16_bytes(values: &[u16]) -> Vec<u8> {
+  values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// Read a byte string written by `u16_bytes` into `out`.
+fn read_u16s(r: &mut Reader, out: &mut [u16]) -> state::Result<()> {
+  let mut bytes = vec![0; out.len() * 2];
+  r.bytes_into(&mut bytes)?;
+  for (value, pair) in out.iter_mut().zip(bytes.chunks(2)) {
+    *value = u16::from_le_bytes([pair[0], pair[1]]);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::state::{LoadState, SaveState};
+
+  /// Send `bytes` as one packet, with reset and stop bits.
+  fn send(sgb: &mut Sgb, bytes: &[u8]) {
+    let mut packet = [0; PACKET_SIZE];
+    packet[..bytes.len()].copy_from_slice(bytes);
+    sgb.write_joypad(0x00);
+    sgb.write_joypad(0x30);
+    for i in 0..PACKET_SIZE * 8 {
+      let bit = (packet[i / 8] >> (i % 8)) & 1;
+      sgb.write_joypad(if bit == 1 { 0x10 } else { 0x20 });
+      sgb.write_joypad(0x30);
+    }
+    sgb.write_joypad(0x20);
+    sgb.write_joypad(0x30);
+  }
+
+  #[test]
+  fn pal01() {
+    let mut sgb = Sgb::new();
+    send(
+      &mut sgb,
+      &[
+        (PAL01 << 3) | 1,
+        0x1f,
+        0x00,
+        0xe0,
+        0x03,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0x7c,
+      ],
+    );
+    assert_eq!(sgb.palettes[0][0], 0x001f);
+    assert_eq!(sgb.palettes[3][0], 0x001f);
+    assert_eq!(sgb.palettes[0][1], 0x03e0);
+    assert_eq!(sgb.palettes[1][1], 0x7c00);
+  }
+
+  #[test]
+  fn state_before_stop_bit() {
+    let mut sgb = Sgb::new();
+    sgb.write_joypad(0x00);
+    sgb.write_joypad(0x30);
+    for i in 0..PACKET_SIZE * 8 {
+      // PAL01 setting colour 0 to 0x001f.
+      let bit = i == 0 || (8..13).contains(&i);
+      sgb.write_joypad(if bit { 0x10 } else { 0x20 });
+      sgb.write_joypad(0x30);
+    }
+    assert_eq!(sgb.bit, PACKET_SIZE * 8);
+
+    let mut state = SaveState::new();
+    state.section(b"SGB ", |w| sgb.save_state(w));
+    let data = state.into_bytes();
+    let mut loaded = Sgb::new();
+    let state = LoadState::parse(&data).unwrap();
+    loaded
+      .load_state(&mut state.section(b"SGB ").unwrap())
+      .unwrap();
+    assert_eq!(loaded.bit, PACKET_SIZE * 8);
+
+    // The stop bit finishes the packet.
+    loaded.write_joypad(0x20);
+    loaded.write_joypad(0x30);
+    assert_eq!(loaded.palettes[0][0], 0x001f);
+  }
+
+  #[test]
+  fn attr_div_and_mlt_req() {
+    let mut sgb = Sgb::new();
+    // Left of column 5 gets palette 1, the column palette 2, the rest 3.
+    send(&mut sgb, &[(ATTR_DIV << 3) | 1, 0x27, 5]);
+    assert_eq!(sgb.attrs[4], 1);
+    assert_eq!(sgb.attrs[5], 2);
+    assert_eq!(sgb.attrs[ATTR_WIDTH + 6], 3);
+
+    send(&mut sgb, &[(MLT_REQ << 3) | 1, 1]);
+    assert_eq!(sgb.read_joypad(), Some(0x0f));
+    sgb.write_joypad(0x10);
+    sgb.write_joypad(0x30);
+    assert_eq!(sgb.read_joypad(), Some(0x0e));
+  }
+}
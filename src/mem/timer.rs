@@ -1,87 +1,235 @@
-#[derive(Debug)]
-struct Clock {
-  main: u32,
-  sub: u32,
-  div: u32,
-}
+use crate::state::{self, Reader, Writer};
 
-#[derive(Debug)]
-pub struct Registers {
-  pub div: u32,
-  pub tima: u32,
-  pub tma: u32,
-  pub tac: u32,
+/// State of a TIMA overflow that hasn't finished reloading.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Reload {
+  None,
+  /// TIMA overflowed during the last M-cycle and reads as 0. Writing TIMA
+  /// now cancels the reload and the interrupt.
+  Pending,
+  /// TMA was loaded into TIMA during the last M-cycle. TIMA writes are
+  /// ignored, and TMA writes are copied to TIMA.
+  Reloading,
 }
 
+/// The timer, driven by the 16-bit system counter that also provides DIV.
 #[derive(Debug)]
 pub struct Timer {
-  pub reg: Registers,
-  clock: Clock,
+  /// System counter, incremented every T-cycle. DIV is its upper byte.
+  counter: u16,
+  tima: u8,
+  tma: u8,
+  tac: u8,
+  reload: Reload,
 }
 
 impl Timer {
   pub fn new() -> Timer {
     Timer {
-      clock: Clock {
-        main: 0,
-        sub: 0,
-        div: 0,
-      },
-      reg: Registers {
-        div: 0,
-        tima: 0,
-        tma: 0,
-        tac: 0,
-      },
+      counter: 0,
+      tima: 0,
+      tma: 0,
+      tac: 0,
+      reload: Reload::None,
     }
   }
 
-  /// Updates the local registers using the m-time.
-  /// Returns true if an interrupt was triggered.
-  pub fn inc(&mut self, m: u32) -> bool {
-    self.clock.sub += m;
-    if self.clock.sub >= 4 {
-      self.clock.main += 1;
-      self.clock.sub -= 4;
+  /// DIV, the upper byte of the system counter.
+  pub fn div(&self) -> u8 {
+    (self.counter >> 8) as u8
+  }
 
-      self.clock.div += 1;
-      if self.clock.div == 16 {
-        self.reg.div = (self.reg.div + 1) & 0xff;
-        self.clock.div = 0;
+  pub fn rb(&self, addr: u16) -> u8 {
+    match addr {
+      0xff04 => self.div(),
+      0xff05 => self.tima,
+      0xff06 => self.tma,
+      0xff07 => 0xf8 | self.tac,
+      _ => 0xff,
+    }
+  }
+
+  pub fn wb(&mut self, addr: u16, value: u8) {
+    match addr {
+      0xff04 => {
+        // Any write resets the whole counter, which can cause a falling
+        // edge on the selected bit.
+        let before = self.signal();
+        self.counter = 0;
+        self.check_edge(before);
       }
+      0xff05 => match self.reload {
+        Reload::Pending => {
+          self.reload = Reload::None;
+          self.tima = value;
+        }
+        Reload::Reloading => (),
+        Reload::None => self.tima = value,
+      },
+      0xff06 => {
+        self.tma = value;
+        if self.reload == Reload::Reloading {
+          self.tima = value;
+        }
+      }
+      0xff07 => {
+        let before = self.signal();
+        self.tac = value & 0x07;
+        self.check_edge(before);
+      }
+      _ => (),
     }
+  }
 
-    self.check_step()
-  }
-
-  /// Return true if an interrupt was triggered.
-  fn check_step(&mut self) -> bool {
-    if self.reg.tac & 0x4 != 0 {
-      let threshold = match self.reg.tac & 3 {
-        0 => 64,
-        1 => 1,
-        2 => 4,
-        3 => 16,
-        _ => panic!("Invalid & 3 result"),
-      };
-      if self.clock.main >= threshold {
-        return self.step();
+  /// Run for one M-cycle.
+  /// Returns true if the timer interrupt was raised.
+  pub fn tick(&mut self) -> bool {
+    let mut interrupt = false;
+    match self.reload {
+      Reload::Pending => {
+        self.tima = self.tma;
+        self.reload = Reload::Reloading;
+        interrupt = true;
       }
+      Reload::Reloading => self.reload = Reload::None,
+      Reload::None => (),
     }
 
-    false
+    let before = self.signal();
+    self.counter = self.counter.wrapping_add(4);
+    self.check_edge(before);
+    interrupt
   }
 
-  /// Step the clocks and return true if an interrupt was triggered.
-  fn step(&mut self) -> bool {
-    self.clock.main = 0;
-    self.reg.tima += 1;
+  /// The input to TIMA's edge detector: the counter bit selected by TAC,
+  /// ANDed with the timer enable bit.
+  fn signal(&self) -> bool {
+    let bit = match self.tac & 0x03 {
+      0 => 9,
+      1 => 3,
+      2 => 5,
+      _ => 7,
+    };
+    self.tac & 0x04 != 0 && self.counter & (1 << bit) != 0
+  }
 
-    if self.reg.tima > 0xff {
-      self.reg.tima = self.reg.tma;
-      true
-    } else {
-      false
+  /// Increment TIMA if the signal fell from `before`.
+  fn check_edge(&mut self, before: bool) {
+    if before && !self.signal() {
+      let (tima, overflow) = self.tima.overflowing_add(1);
+      self.tima = tima;
+      if overflow {
+        self.reload = Reload::Pending;
+      }
     }
   }
+
+  pub fn save_state(&self, w: &mut Writer) {
+    w.u16(self.counter);
+    w.u8(self.tima);
+    w.u8(self.tma);
+    w.u8(self.tac);
+    w.u8(self.reload as u8);
+  }
+
+  pub fn load_state(&mut self, r: &mut Reader) -> state::Result<()> {
+    self.counter = r.u16()?;
+    self.tima = r.u8()?;
+    self.tma = r.u8()?;
+    self.tac = r.u8()? & 0x07;
+    self.reload = match r.u8()? {
+      0 => Reload::None,
+      1 => Reload::Pending,
+      2 => Reload::Reloading,
+      v => return Err(r.invalid(format!("bad reload state {}", v))),
+    };
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ticks(timer: &mut Timer, n: u32) -> u32 {
+    (0..n).filter(|_| timer.tick()).count() as u32
+  }
+
+  #[test]
+  fn div() {
+    let mut timer = Timer::new();
+    ticks(&mut timer, 64);
+    assert_eq!(timer.rb(0xff04), 1);
+    timer.wb(0xff04, 0x42);
+    assert_eq!(timer.rb(0xff04), 0);
+  }
+
+  #[test]
+  fn increments() {
+    let mut timer = Timer::new();
+    // Every 16 T-cycles.
+    timer.wb(0xff07, 0x05);
+    ticks(&mut timer, 4 * 10);
+    assert_eq!(timer.rb(0xff05), 10);
+  }
+
+  #[test]
+  fn div_write_falling_edge() {
+    let mut timer = Timer::new();
+    timer.wb(0xff07, 0x05);
+    ticks(&mut timer, 2);
+    assert_eq!(timer.rb(0xff05), 0);
+    // Bit 3 is set, so resetting the counter increments TIMA.
+    timer.wb(0xff04, 0);
+    assert_eq!(timer.rb(0xff05), 1);
+  }
+
+  #[test]
+  fn tac_write_falling_edge() {
+    let mut timer = Timer::new();
+    timer.wb(0xff07, 0x05);
+    ticks(&mut timer, 2);
+    // Disabling the timer while the selected bit is set increments TIMA.
+    timer.wb(0xff07, 0x01);
+    assert_eq!(timer.rb(0xff05), 1);
+  }
+
+  #[test]
+  fn delayed_reload() {
+    let mut timer = Timer::new();
+    timer.wb(0xff06, 0x80);
+    timer.wb(0xff05, 0xff);
+    timer.wb(0xff07, 0x05);
+    assert_eq!(ticks(&mut timer, 4), 0);
+    // TIMA reads 0 for a cycle before TMA is loaded.
+    assert_eq!(timer.rb(0xff05), 0);
+    assert!(timer.tick());
+    assert_eq!(timer.rb(0xff05), 0x80);
+  }
+
+  #[test]
+  fn write_cancels_reload() {
+    let mut timer = Timer::new();
+    timer.wb(0xff06, 0x80);
+    timer.wb(0xff05, 0xff);
+    timer.wb(0xff07, 0x05);
+    ticks(&mut timer, 4);
+    timer.wb(0xff05, 0x10);
+    assert!(!timer.tick());
+    assert_eq!(timer.rb(0xff05), 0x10);
+  }
+
+  #[test]
+  fn writes_while_reloading() {
+    let mut timer = Timer::new();
+    timer.wb(0xff06, 0x80);
+    timer.wb(0xff05, 0xff);
+    timer.wb(0xff07, 0x05);
+    ticks(&mut timer, 5);
+    // TIMA writes are ignored, and TMA writes go through to TIMA.
+    timer.wb(0xff05, 0x10);
+    assert_eq!(timer.rb(0xff05), 0x80);
+    timer.wb(0xff06, 0x20);
+    assert_eq!(timer.rb(0xff05), 0x20);
+  }
 }
@@ -0,0 +1,355 @@
+//! Input movies: the buttons held on every frame from power-on or from a
+//! save state, for replaying a run exactly.
+
+use crate::state::{LoadState, SaveState, StateError};
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"GBRMOVIE";
+const VERSION: u16 = 1;
+
+/// Frames between checks of the machine state, for spotting desyncs.
+pub const CHECK_INTERVAL: u64 = 60;
+
+#[derive(Debug)]
+pub enum MovieError {
+  Io(io::Error),
+  NotAMovie,
+  Version(u16),
+  /// The movie's start state, or another part of it, is invalid.
+  Invalid(StateError),
+  /// A movie for a different ROM or system.
+  WrongGame(String),
+  /// A movie from power-on, but the machine has already started.
+  NotAtPowerOn,
+}
+
+impl fmt::Display for MovieError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      MovieError::Io(ref e) => write!(f, "{}", e),
+      MovieError::NotAMovie => write!(f, "Not a movie"),
+      MovieError::Version(v) => write!(
+        f,
+        "Movie is version {}, but only version {} is supported",
+        v, VERSION
+      ),
+      MovieError::Invalid(ref e) => write!(f, "Invalid movie: {}", e),
+      MovieError::WrongGame(ref why) => {
+        write!(f, "Movie is for a different game: {}", why)
+      }
+      MovieError::NotAtPowerOn => write!(
+        f,
+        "Movie starts at power-on, but the Game Boy is already running"
+      ),
+    }
+  }
+}
+
+impl Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+  fn from(e: io::Error) -> MovieError {
+    MovieError::Io(e)
+  }
+}
+
+impl From<StateError> for MovieError {
+  fn from(e: StateError) -> MovieError {
+    match e {
+      StateError::Io(e) => MovieError::Io(e),
+      e => MovieError::Invalid(e),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+  pub rom_crc: u32,
+  pub title: String,
+  /// Whether the game ran as a Super Game Boy.
+  pub sgb: bool,
+  /// The save state the movie starts from, or None for power-on.
+  pub start: Option<Vec<u8>>,
+  /// For movies from power-on, the cartridge's battery-backed RAM and clock
+  /// as a save file timestamped 0, so the run doesn't depend on the save
+  /// file or the time it's played at.
+  pub battery: Option<Vec<u8>>,
+  /// Buttons held on each frame.
+  pub inputs: Vec<u8>,
+  /// CRC-32 of the save state at the start of every `CHECK_INTERVAL`th
+  /// frame.
+  pub checks: Vec<u32>,
+}
+
+impl Movie {
+  pub fn new(
+    rom_crc: u32,
+    title: &str,
+    sgb: bool,
+    start: Option<Vec<u8>>,
+  ) -> Movie {
+    Movie {
+      rom_crc,
+      title: title.to_string(),
+      sgb,
+      start,
+      battery: None,
+      inputs: vec![],
+      checks: vec![],
+    }
+  }
+
+  pub fn load(path: &Path) -> Result<Movie, MovieError> {
+    Movie::from_bytes(&fs::read(path)?)
+  }
+
+  pub fn save(&self, path: &Path) -> io::Result<()> {
+    fs::write(path, self.to_bytes())
+  }
+
+  pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+    let file = match LoadState::parse_with_magic(data, MAGIC, VERSION) {
+      Ok(file) => file,
+      Err(StateError::NotAState) => return Err(MovieError::NotAMovie),
+      Err(StateError::Version(v)) => return Err(MovieError::Version(v)),
+      Err(e) => return Err(e.into()),
+    };
+
+    let mut info = file.section(b"INFO")?;
+    let rom_crc = info.u32()?;
+    let title = info.str()?;
+    let sgb = info.bool()?;
+    let start = if file.has_section(b"STRT") {
+      Some(file.section(b"STRT")?.bytes()?.to_vec())
+    } else {
+      None
+    };
+    let battery = if file.has_section(b"BATT") {
+      Some(file.section(b"BATT")?.bytes()?.to_vec())
+    } else {
+      None
+    };
+    let inputs = file.section(b"INPT")?.bytes()?.to_vec();
+    let mut r = file.section(b"SYNC")?;
+    let checks = (0..r.u32()?).map(|_| r.u32()).collect::<Result<_, _>>()?;
+    Ok(Movie {
+      rom_crc,
+      title,
+      sgb,
+      start,
+      battery,
+      inputs,
+      checks,
+    })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut file = SaveState::with_magic(MAGIC, VERSION);
+    file.section(b"INFO", |w| {
+      w.u32(self.rom_crc);
+      w.str(&self.title);
+      w.bool(self.sgb);
+    });
+    if let Some(ref start) = self.start {
+      file.section(b"STRT", |w| w.bytes(start));
+    }
+    if let Some(ref battery) = self.battery {
+      file.section(b"BATT", |w| w.bytes(battery));
+    }
+    file.section(b"INPT", |w| w.bytes(&self.inputs));
+    file.section(b"SYNC", |w| {
+      w.u32(self.checks.len() as u32);
+      for &check in &self.checks {
+        w.u32(check);
+      }
+    });
+    file.into_bytes()
+  }
+
+  pub fn frames(&self) -> u64 {
+    self.inputs.len() as u64
+  }
+
+  /// Forget everything from `frame` on, to record from there.
+  pub fn truncate(&mut self, frame: u64) {
+    self.inputs.truncate(frame as usize);
+    let checks = frame.div_ceil(CHECK_INTERVAL);
+    self.checks.truncate(checks as usize);
+  }
+}
+
+/// What a movie attached to a Game Boy is doing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+  /// Adding the buttons held on each frame.
+  Recording,
+  /// Replaying the buttons. Unless read-only, pressing a button takes over
+  /// and records from that frame on.
+  Playing { read_only: bool },
+}
+
+/// A movie being recorded or played.
+#[derive(Debug)]
+pub struct MoviePlayer {
+  pub movie: Movie,
+  pub mode: Mode,
+  /// Frames played or recorded.
+  pub frame: u64,
+  /// The Game Boy's frame count when the movie started.
+  pub start_frame: u64,
+  /// First frame whose state didn't match the recording.
+  pub desync: Option<u64>,
+}
+
+impl MoviePlayer {
+  pub fn new(movie: Movie, mode: Mode) -> MoviePlayer {
+    MoviePlayer {
+      movie,
+      mode,
+      frame: 0,
+      start_frame: 0,
+      desync: None,
+    }
+  }
+
+  /// Go to `frame` of the movie, after rewinding or loading a state. When
+  /// recording, everything after it is forgotten. Returns false if the
+  /// frame isn't part of the movie.
+  pub fn seek(&mut self, frame: u64) -> bool {
+    if frame > self.movie.frames() {
+      return false;
+    }
+    if self.mode == Mode::Recording {
+      self.movie.truncate(frame);
+    }
+    self.frame = frame;
+    true
+  }
+
+  /// Whether the state is checked at the start of the current frame.
+  pub fn check_due(&self) -> bool {
+    self.frame.is_multiple_of(CHECK_INTERVAL)
+  }
+
+  /// Get the buttons for the current frame and move on to the next.
+  /// `held` is what the player is holding, and `check` the CRC of the
+  /// state if `check_due` returned true.
+  pub fn next_frame(&mut self, held: u8, check: Option<u32>) -> u8 {
+    let index = (self.frame / CHECK_INTERVAL) as usize;
+    if let Mode::Playing { read_only } = self.mode {
+      if !read_only && held != 0 {
+        println!("Movie recording from frame {}", self.frame);
+        self.movie.truncate(self.frame);
+        self.mode = Mode::Recording;
+      } else if self.frame == self.movie.frames() {
+        println!("Movie finished after {} frames", self.frame);
+        self.mode = if read_only {
+          Mode::Playing { read_only }
+        } else {
+          Mode::Recording
+        };
+      }
+    }
+
+    let buttons = match self.mode {
+      Mode::Recording => {
+        if let Some(check) = check {
+          self.movie.checks.truncate(index);
+          self.movie.checks.push(check);
+        }
+        self.movie.inputs.push(held);
+        held
+      }
+      Mode::Playing { .. } => {
+        let expected = self.movie.checks.get(index);
+        if let (Some(&expected), Some(check)) = (expected, check) {
+          if expected != check && self.desync.is_none() {
+            eprintln!("Movie desynced at frame {}", self.frame);
+            self.desync = Some(self.frame);
+          }
+        }
+        // Past the end of a read-only movie, the player takes over.
+        match self.movie.inputs.get(self.frame as usize) {
+          Some(&buttons) => buttons,
+          None => held,
+        }
+      }
+    };
+    self.frame += 1;
+    buttons
+  }
+
+  /// Whether a read-only movie has played to the end.
+  pub fn finished(&self) -> bool {
+    matches!(self.mode, Mode::Playing { .. })
+      && self.frame >= self.movie.frames()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trip() {
+    let mut movie = Movie::new(0x1234_5678, "TITLE", true, Some(vec![1, 2]));
+    movie.inputs = vec![0, 1, 0x80];
+    movie.checks = vec![0xdead_beef];
+    assert_eq!(Movie::from_bytes(&movie.to_bytes()).unwrap(), movie);
+
+    movie.start = None;
+    movie.battery = Some(vec![3; 0x2000]);
+    assert_eq!(Movie::from_bytes(&movie.to_bytes()).unwrap(), movie);
+    assert!(matches!(
+      Movie::from_bytes(b"GBRSTATE\x01\x00"),
+      Err(MovieError::NotAMovie)
+    ));
+  }
+
+  #[test]
+  fn record_and_play() {
+    let mut player =
+      MoviePlayer::new(Movie::new(0, "", false, None), Mode::Recording);
+    for frame in 0..100 {
+      let check = if player.check_due() {
+        Some(frame)
+      } else {
+        None
+      };
+      assert_eq!(player.next_frame(frame as u8, check), frame as u8);
+    }
+    let movie = player.movie;
+    assert_eq!(movie.frames(), 100);
+    assert_eq!(movie.checks, vec![0, 60]);
+
+    let mut player =
+      MoviePlayer::new(movie.clone(), Mode::Playing { read_only: true });
+    for frame in 0..100 {
+      let check = match frame {
+        60 => Some(1),
+        _ if player.check_due() => Some(frame),
+        _ => None,
+      };
+      assert_eq!(player.next_frame(0xff, check), frame as u8);
+    }
+    assert_eq!(player.desync, Some(60));
+    assert!(player.finished());
+    assert_eq!(player.next_frame(0x02, None), 0x02);
+
+    // Pressing a button during read-write playback takes over.
+    let mut player =
+      MoviePlayer::new(movie, Mode::Playing { read_only: false });
+    for _ in 0..10 {
+      player.next_frame(0, None);
+    }
+    assert_eq!(player.next_frame(0x04, None), 0x04);
+    assert_eq!(player.mode, Mode::Recording);
+    assert_eq!(player.movie.inputs.len(), 11);
+    assert_eq!(player.movie.checks, vec![0]);
+  }
+}
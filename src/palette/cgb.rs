@@ -0,0 +1,460 @@
+use crate::palette::{Palette, Palettes};
+
+/// The boot ROM's palettes, in RGB555.
+const PALETTES: [[u16; 4]; 30] = [
+  [0x7fff, 0x32bf, 0x00d0, 0x0000],
+  [0x639f, 0x4279, 0x15b0, 0x04cb],
+  [0x7fff, 0x6e31, 0x454a, 0x0000],
+  [0x7fff, 0x1bef, 0x0200, 0x0000],
+  [0x7fff, 0x421f, 0x1cf2, 0x0000],
+  [0x7fff, 0x5294, 0x294a, 0x0000],
+  [0x7fff, 0x03ff, 0x012f, 0x0000],
+  [0x7fff, 0x03ef, 0x01d6, 0x0000],
+  [0x7fff, 0x42b5, 0x3dc8, 0x0000],
+  [0x7e74, 0x03ff, 0x0180, 0x0000],
+  [0x67ff, 0x77ac, 0x1a13, 0x2d6b],
+  [0x7ed6, 0x4bff, 0x2175, 0x0000],
+  [0x53ff, 0x4a5f, 0x7e52, 0x0000],
+  [0x4fff, 0x7ed2, 0x3a4c, 0x1ce0],
+  [0x03ed, 0x7fff, 0x255f, 0x0000],
+  [0x036a, 0x021f, 0x03ff, 0x7fff],
+  [0x7fff, 0x01df, 0x0112, 0x0000],
+  [0x231f, 0x035f, 0x00f2, 0x0009],
+  [0x7fff, 0x03ea, 0x011f, 0x0000],
+  [0x299f, 0x001a, 0x000c, 0x0000],
+  [0x7fff, 0x027f, 0x001f, 0x0000],
+  [0x7fff, 0x03e0, 0x0206, 0x0120],
+  [0x7fff, 0x7eeb, 0x001f, 0x7c00],
+  [0x7fff, 0x3fff, 0x7e00, 0x001f],
+  [0x7fff, 0x03ff, 0x001f, 0x0000],
+  [0x03ff, 0x001f, 0x000c, 0x0000],
+  [0x7fff, 0x033f, 0x0193, 0x0000],
+  [0x0000, 0x4200, 0x037f, 0x7fff],
+  [0x7fff, 0x7e8c, 0x7c00, 0x0000],
+  [0x7fff, 0x1bef, 0x6180, 0x0000],
+];
+
+/// Palette combinations as colour offsets into `PALETTES` of the OBJ0,
+/// OBJ1 and background palettes. A few don't start on a palette boundary,
+/// taking the last colour of the palette before.
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+  (16, 16, 116),
+  (72, 72, 72),
+  (80, 80, 80),
+  (96, 96, 96),
+  (36, 36, 36),
+  (0, 0, 0),
+  (108, 108, 108),
+  (20, 20, 20),
+  (48, 48, 48),
+  (104, 104, 104),
+  (64, 32, 32),
+  (16, 112, 112),
+  (16, 8, 8),
+  (12, 16, 16),
+  (16, 116, 116),
+  (112, 16, 112),
+  (8, 68, 8),
+  (64, 64, 32),
+  (16, 16, 28),
+  (16, 16, 72),
+  (16, 16, 80),
+  (76, 76, 36),
+  (15, 15, 44),
+  (68, 68, 8),
+  (16, 16, 8),
+  (16, 16, 12),
+  (112, 112, 0),
+  (12, 12, 0),
+  (0, 0, 4),
+  (72, 88, 72),
+  (80, 88, 80),
+  (96, 88, 96),
+  (64, 88, 32),
+  (68, 16, 52),
+  (111, 0, 56),
+  (111, 16, 60),
+  (76, 88, 36),
+  (64, 112, 40),
+  (16, 92, 112),
+  (68, 88, 8),
+  (16, 0, 8),
+  (16, 112, 12),
+  (112, 12, 0),
+  (12, 112, 16),
+  (84, 112, 16),
+  (12, 112, 0),
+  (100, 12, 112),
+  (0, 112, 32),
+  (16, 12, 112),
+  (112, 12, 24),
+  (16, 112, 116),
+];
+
+/// Title checksums of Nintendo games, with the fourth title letter used
+/// to tell apart titles sharing a checksum, and their combinations.
+const TITLES: [(u8, Option<u8>, u8); 94] = [
+  (0x00, None, 0),
+  // ALLEY WAY
+  (0x88, None, 4),
+  // YAKUMAN
+  (0x16, None, 5),
+  // BASEBALL
+  (0x36, None, 35),
+  // TENNIS
+  (0xd1, None, 34),
+  // TETRIS
+  (0xdb, None, 3),
+  // QIX
+  (0xf2, None, 31),
+  // DR.MARIO
+  (0x3c, None, 15),
+  // RADARMISSION
+  (0x8c, None, 10),
+  // F1RACE
+  (0x92, None, 5),
+  // YOSSY NO TAMAGO
+  (0x3d, None, 19),
+  (0x5c, None, 36),
+  // X
+  (0x58, None, 7),
+  // MARIOLAND2
+  (0xc9, None, 37),
+  // YOSSY NO COOKIE
+  (0x3e, None, 30),
+  // ZELDA
+  (0x70, None, 44),
+  (0x1d, None, 21),
+  (0x59, None, 32),
+  // TETRIS FLASH
+  (0x69, None, 31),
+  // DONKEY KONG
+  (0x19, None, 20),
+  // MARIO'S PICROSS
+  (0x35, None, 5),
+  (0xa8, None, 33),
+  // POKEMON RED
+  (0x14, None, 13),
+  // POKEMON GREEN
+  (0xaa, None, 14),
+  // PICROSS 2
+  (0x75, None, 5),
+  // YOSSY NO PANEPON
+  (0x95, None, 29),
+  // KIRAKIRA KIDS
+  (0x99, None, 5),
+  // GAMEBOY GALLERY
+  (0x34, None, 18),
+  // POCKETCAMERA
+  (0x6f, None, 9),
+  (0x15, None, 3),
+  // BALLOON KID
+  (0xff, None, 2),
+  // KINGOFTHEZOO
+  (0x97, None, 26),
+  // DMG FOOTBALL
+  (0x4b, None, 25),
+  // WORLD CUP
+  (0x90, None, 25),
+  // OTHELLO
+  (0x17, None, 41),
+  // SUPER RC PRO-AM
+  (0x10, None, 42),
+  // DYNABLASTER
+  (0x39, None, 26),
+  // BOY AND BLOB GB2
+  (0xf7, None, 45),
+  // MEGAMAN
+  (0xf6, None, 42),
+  // STAR WARS-NOA
+  (0xa2, None, 45),
+  (0x49, None, 36),
+  // WAVERACE
+  (0x4e, None, 38),
+  (0x43, None, 26),
+  // LOLO2
+  (0x68, None, 42),
+  // YOSHI'S COOKIE
+  (0xe0, None, 30),
+  // MYSTIC QUEST
+  (0x8b, None, 41),
+  (0xf0, None, 34),
+  // TOPRANKINGTENNIS
+  (0xce, None, 34),
+  // MANSELL
+  (0x0c, None, 5),
+  // MEGAMAN3
+  (0x29, None, 42),
+  // SPACE INVADERS
+  (0xe8, None, 6),
+  // GAME&WATCH
+  (0xb7, None, 5),
+  // DONKEYKONGLAND95
+  (0x86, None, 33),
+  // ASTEROIDS/MISCMD
+  (0x9a, None, 25),
+  // STREET FIGHTER 2
+  (0x52, None, 42),
+  // DEFENDER/JOUST
+  (0x01, None, 42),
+  // KILLERINSTINCT95
+  (0x9d, None, 40),
+  // TETRIS BLAST
+  (0x71, None, 2),
+  // PINOCCHIO
+  (0x9c, None, 16),
+  (0xbd, None, 25),
+  // BA.TOSHINDEN
+  (0x5d, None, 42),
+  // NETTOU KOF 95
+  (0x6d, None, 42),
+  (0x67, None, 5),
+  // TETRIS PLUS
+  (0x3f, None, 0),
+  // DONKEYKONGLAND 3
+  (0x6b, None, 39),
+  // KIRBY2
+  (0xb3, Some(b'B'), 36),
+  // SUPER MARIOLAND
+  (0x46, Some(b'E'), 22),
+  // GOLF
+  (0x28, Some(b'F'), 25),
+  // SOLARSTRIKER
+  (0xa5, Some(b'A'), 6),
+  (0xc6, Some(b'A'), 32),
+  (0xd3, Some(b'R'), 12),
+  (0x27, Some(b'B'), 36),
+  // POKEMON BLUE
+  (0x61, Some(b'E'), 11),
+  (0x18, Some(b'K'), 39),
+  // GAMEBOY GALLERY2
+  (0x66, Some(b'E'), 18),
+  // DONKEYKONGLAND 2
+  (0x6a, Some(b'K'), 39),
+  // KID ICARUS
+  (0xbf, Some(b' '), 24),
+  (0x0d, Some(b'R'), 31),
+  (0xf4, Some(b'-'), 50),
+  (0xb3, Some(b'U'), 17),
+  // METROID2
+  (0x46, Some(b'R'), 46),
+  (0x28, Some(b'A'), 6),
+  (0xa5, Some(b'R'), 27),
+  (0xc6, Some(b' '), 0),
+  (0xd3, Some(b'I'), 47),
+  (0x27, Some(b'N'), 41),
+  (0x61, Some(b'A'), 41),
+  (0x18, Some(b'I'), 0),
+  (0x66, Some(b'L'), 0),
+  (0x6a, Some(b'I'), 19),
+  (0xbf, Some(b'C'), 34),
+  (0x0d, Some(b'E'), 23),
+  (0xf4, Some(b' '), 18),
+  (0xb3, Some(b'R'), 29),
+];
+
+/// The button combinations that can be held during the CGB boot animation
+/// to pick a palette manually.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Combo {
+  Up,
+  UpA,
+  UpB,
+  Left,
+  LeftA,
+  LeftB,
+  Down,
+  DownA,
+  DownB,
+  Right,
+  RightA,
+  RightB,
+}
+
+pub const COMBOS: [Combo; 12] = [
+  Combo::Up,
+  Combo::UpA,
+  Combo::UpB,
+  Combo::Left,
+  Combo::LeftA,
+  Combo::LeftB,
+  Combo::Down,
+  Combo::DownA,
+  Combo::DownB,
+  Combo::Right,
+  Combo::RightA,
+  Combo::RightB,
+];
+
+impl Combo {
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Combo::Up => "up",
+      Combo::UpA => "up+a",
+      Combo::UpB => "up+b",
+      Combo::Left => "left",
+      Combo::LeftA => "left+a",
+      Combo::LeftB => "left+b",
+      Combo::Down => "down",
+      Combo::DownA => "down+a",
+      Combo::DownB => "down+b",
+      Combo::Right => "right",
+      Combo::RightA => "right+a",
+      Combo::RightB => "right+b",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Combo> {
+    COMBOS.iter().copied().find(|c| c.name() == name)
+  }
+
+  /// The boot ROM's combination for this button combination.
+  fn combination(&self) -> u8 {
+    match *self {
+      Combo::Up => 5,
+      Combo::UpA => 43,
+      Combo::UpB => 28,
+      Combo::Left => 48,
+      Combo::LeftA => 40,
+      Combo::LeftB => 7,
+      Combo::Down => 8,
+      Combo::DownA => 3,
+      Combo::DownB => 49,
+      Combo::Right => 1,
+      Combo::RightA => 0,
+      Combo::RightB => 6,
+    }
+  }
+
+  pub fn palettes(&self) -> Palettes {
+    combination(self.combination())
+  }
+}
+
+/// The combination for games that aren't in the title table, the same as
+/// right+A.
+const DEFAULT: u8 = 0;
+
+/// Convert an RGB555 colour the way the boot ROM colours are usually
+/// quoted, rounding to the nearest 8-bit value.
+fn rgb(color: u16) -> u32 {
+  let scale = |c: u16| -> u32 { (u32::from(c & 0x1f) * 255 + 15) / 31 };
+  (scale(color) << 16) | (scale(color >> 5) << 8) | scale(color >> 10)
+}
+
+fn palette(offset: usize) -> Palette {
+  let mut colors = [0; 4];
+  for (i, color) in colors.iter_mut().enumerate() {
+    let offset = offset + i;
+    *color = rgb(PALETTES[offset / 4][offset % 4]);
+  }
+  Palette(colors)
+}
+
+fn combination(index: u8) -> Palettes {
+  let (obj0, obj1, bg) = COMBINATIONS[index as usize];
+  Palettes {
+    bg: palette(bg),
+    obj0: palette(obj0),
+    obj1: palette(obj1),
+  }
+}
+
+/// Sum of the title bytes, as computed by the boot ROM.
+fn title_checksum(rom: &[u8]) -> u8 {
+  rom[0x134..0x144]
+    .iter()
+    .fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Whether the header's licensee code is Nintendo's. The boot ROM only
+/// looks up titles for Nintendo games.
+fn is_nintendo(rom: &[u8]) -> bool {
+  match rom[0x14b] {
+    0x01 => true,
+    0x33 => &rom[0x144..0x146] == b"01",
+    _ => false,
+  }
+}
+
+/// Pick palettes for `rom` the way the CGB boot ROM does.
+pub fn for_rom(rom: &[u8]) -> Palettes {
+  if rom.len() < 0x150 || !is_nintendo(rom) {
+    return combination(DEFAULT);
+  }
+  let checksum = title_checksum(rom);
+  let index = TITLES
+    .iter()
+    .find(|&&(sum, letter, _)| {
+      sum == checksum && (letter.is_none() || letter == Some(rom[0x137]))
+    })
+    .map_or(DEFAULT, |&(_, _, index)| index);
+  combination(index)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rom(title: &[u8], licensee: u8) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x134..0x134 + title.len()].copy_from_slice(title);
+    rom[0x14b] = licensee;
+    rom
+  }
+
+  const WHITE: u32 = 0xffffff;
+  const RED: Palette = Palette([WHITE, 0xff8484, 0x943a3a, 0]);
+  const GREEN: Palette = Palette([WHITE, 0x7bff31, 0x008400, 0]);
+  const BLUE: Palette = Palette([WHITE, 0x63a5ff, 0x0000ff, 0]);
+  const ORANGE: Palette = Palette([WHITE, 0xffff00, 0xff0000, 0]);
+
+  #[test]
+  fn combos() {
+    let up_a = Combo::UpA.palettes();
+    assert_eq!((up_a.bg, up_a.obj0, up_a.obj1), (RED, GREEN, BLUE));
+    let down_b = Combo::DownB.palettes();
+    assert_eq!(down_b.bg.0, [WHITE, 0xffff00, 0x7b4a00, 0]);
+    assert_eq!(Combo::DownA.palettes().obj1, ORANGE);
+    assert_eq!(
+      Combo::RightB.palettes().bg.0,
+      [0, 0x008484, 0xffde00, WHITE]
+    );
+  }
+
+  #[test]
+  fn lookup() {
+    assert_eq!(title_checksum(&rom(b"POKEMON RED", 0x01)), 0x14);
+    let red = for_rom(&rom(b"POKEMON RED", 0x01));
+    assert_eq!((red.bg, red.obj0, red.obj1), (RED, GREEN, RED));
+    let blue = for_rom(&rom(b"POKEMON BLUE", 0x01));
+    assert_eq!((blue.bg, blue.obj0, blue.obj1), (BLUE, RED, BLUE));
+    let tetris = for_rom(&rom(b"TETRIS", 0x01));
+    assert_eq!(
+      (tetris.bg, tetris.obj0, tetris.obj1),
+      (ORANGE, ORANGE, ORANGE)
+    );
+    let mut dr_mario = rom(b"DR.MARIO", 0x33);
+    dr_mario[0x144..0x146].copy_from_slice(b"01");
+    assert_eq!(for_rom(&dr_mario), combination(15));
+    // Only Nintendo titles are looked up.
+    assert_eq!(for_rom(&rom(b"POKEMON RED", 0x00)), combination(DEFAULT));
+  }
+
+  #[test]
+  fn fourth_letter() {
+    // SUPER MARIOLAND and METROID2 share a checksum.
+    let mario = rom(b"SUPER MARIOLAND", 0x01);
+    let metroid = rom(b"METROID2", 0x01);
+    assert_eq!(title_checksum(&mario), title_checksum(&metroid));
+    assert_eq!(for_rom(&mario), combination(22));
+    assert_eq!(for_rom(&metroid), combination(46));
+    // A checksum in the shared part of the table with an unknown letter
+    // gets the default palettes.
+    let mut other = rom(b"SUPER MARIOLAND", 0x01);
+    other[0x137] = b'F';
+    other[0x138] = b'Q';
+    assert_eq!(title_checksum(&other), title_checksum(&mario));
+    assert_eq!(for_rom(&other), combination(DEFAULT));
+  }
+}
@@ -0,0 +1,194 @@
+use std::error::Error;
+use std::fmt;
+
+pub mod cgb;
+
+/// Four RGB colours (0x00rrggbb), from the lightest shade to the darkest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Palette(pub [u32; 4]);
+
+impl Palette {
+  /// Get the RGB colour for DMG shade `shade` (0-3).
+  pub fn color(&self, shade: u8) -> u32 {
+    self.0[(shade & 3) as usize]
+  }
+
+  /// Parse a user-defined palette of four comma-separated hex colours,
+  /// e.g. `e0f8d0,88c070,346856,081820`.
+  pub fn parse(s: &str) -> Result<Palette, ParseError> {
+    let mut colors = [0; 4];
+    let mut parts = s.split(',');
+    for color in colors.iter_mut() {
+      let part = parts.next().ok_or(ParseError::Length)?.trim();
+      let part = part.trim_start_matches('#');
+      if part.len() != 6 {
+        return Err(ParseError::Color(part.to_string()));
+      }
+      *color = u32::from_str_radix(part, 16)
+        .map_err(|_| ParseError::Color(part.to_string()))?;
+    }
+    if parts.next().is_some() {
+      return Err(ParseError::Length);
+    }
+    Ok(Palette(colors))
+  }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+  Length,
+  Color(String),
+  Preset(String),
+  Combo(String),
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ParseError::Length => write!(f, "Palette must have exactly 4 colours"),
+      ParseError::Color(ref c) => write!(f, "Invalid palette colour: {}", c),
+      ParseError::Preset(ref p) => write!(f, "Unknown palette: {}", p),
+      ParseError::Combo(ref c) => write!(f, "Unknown colourisation: {}", c),
+    }
+  }
+}
+
+impl Error for ParseError {}
+
+/// Built-in palettes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Preset {
+  Grey,
+  /// Original DMG pea-green LCD.
+  Dmg,
+  Pocket,
+  /// Game Boy Light with the backlight on.
+  Light,
+  HighContrast,
+}
+
+pub const PRESETS: [Preset; 5] = [
+  Preset::Grey,
+  Preset::Dmg,
+  Preset::Pocket,
+  Preset::Light,
+  Preset::HighContrast,
+];
+
+impl Preset {
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Preset::Grey => "grey",
+      Preset::Dmg => "dmg",
+      Preset::Pocket => "pocket",
+      Preset::Light => "light",
+      Preset::HighContrast => "high-contrast",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Preset> {
+    PRESETS.iter().copied().find(|p| p.name() == name)
+  }
+
+  pub fn palette(&self) -> Palette {
+    match *self {
+      Preset::Grey => Palette([0xffffff, 0x969696, 0x323232, 0x000000]),
+      Preset::Dmg => Palette([0x9bbc0f, 0x8bac0f, 0x306230, 0x0f380f]),
+      Preset::Pocket => Palette([0xc4cfa1, 0x8b956d, 0x4d533c, 0x1f1f1f]),
+      Preset::Light => Palette([0x00b581, 0x009a71, 0x00694a, 0x004f3b]),
+      Preset::HighContrast => Palette([0xffffff, 0xaaaaaa, 0x555555, 0x000000]),
+    }
+  }
+
+  /// The preset after this one, wrapping around.
+  pub fn next(&self) -> Preset {
+    let i = PRESETS.iter().position(|p| p == self).unwrap();
+    PRESETS[(i + 1) % PRESETS.len()]
+  }
+}
+
+/// Parse either a preset name or a user-defined palette.
+pub fn parse(s: &str) -> Result<Palette, ParseError> {
+  if let Some(preset) = Preset::from_name(s) {
+    return Ok(preset.palette());
+  }
+  if s.contains(',') {
+    Palette::parse(s)
+  } else {
+    Err(ParseError::Preset(s.to_string()))
+  }
+}
+
+/// How to colourise DMG games like the CGB boot ROM.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Colorize {
+  /// Look up the palettes from the title checksum.
+  Auto,
+  /// Use the palettes for a button combination.
+  Combo(cgb::Combo),
+}
+
+impl Colorize {
+  pub fn parse(s: &str) -> Result<Colorize, ParseError> {
+    if s == "auto" {
+      return Ok(Colorize::Auto);
+    }
+    cgb::Combo::from_name(s)
+      .map(Colorize::Combo)
+      .ok_or_else(|| ParseError::Combo(s.to_string()))
+  }
+
+  /// Get the palettes to use for `rom`.
+  pub fn palettes(&self, rom: &[u8]) -> Palettes {
+    match *self {
+      Colorize::Auto => cgb::for_rom(rom),
+      Colorize::Combo(combo) => combo.palettes(),
+    }
+  }
+}
+
+/// The palettes used for each layer of the DMG output.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Palettes {
+  pub bg: Palette,
+  pub obj0: Palette,
+  pub obj1: Palette,
+}
+
+impl Palettes {
+  /// Use `palette` for all layers.
+  pub fn uniform(palette: Palette) -> Palettes {
+    Palettes {
+      bg: palette,
+      obj0: palette,
+      obj1: palette,
+    }
+  }
+}
+
+impl Default for Palettes {
+  fn default() -> Palettes {
+    Palettes::uniform(Preset::Grey.palette())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_custom() {
+    let pal = parse("e0f8d0,88c070,#346856,081820").unwrap();
+    assert_eq!(pal.0, [0xe0f8d0, 0x88c070, 0x346856, 0x081820]);
+    assert!(parse("e0f8d0,88c070,346856").is_err());
+    assert!(parse("e0f8d0,88c070,346856,081820,000000").is_err());
+    assert!(parse("e0f8d0,88c070,34685g,081820").is_err());
+  }
+
+  #[test]
+  fn presets() {
+    assert_eq!(parse("dmg").unwrap(), Preset::Dmg.palette());
+    assert!(parse("nope").is_err());
+    assert_eq!(Preset::HighContrast.next(), Preset::Grey);
+  }
+}
@@ -0,0 +1,280 @@
+//! Rewind buffer of save states.
+//!
+//! Only the newest snapshot is kept whole. Each older snapshot is stored as
+//! the difference from the one after it, so memory is mostly spent on what
+//! changed between snapshots rather than on all of WRAM and VRAM each time.
+
+use std::collections::VecDeque;
+
+/// A snapshot, and the buttons held on each frame run after it.
+struct Snapshot {
+  /// The whole state for the newest snapshot, otherwise a delta against
+  /// the next newer one.
+  data: Vec<u8>,
+  inputs: Vec<u8>,
+}
+
+impl Snapshot {
+  fn size(&self) -> usize {
+    self.data.len() + self.inputs.len()
+  }
+}
+
+/// Snapshots taken every `interval` frames, within a memory budget.
+pub struct Rewind {
+  interval: u32,
+  budget: usize,
+  newest: Option<Snapshot>,
+  /// Older snapshots, oldest first.
+  older: VecDeque<Snapshot>,
+  /// Total size of `older`.
+  older_size: usize,
+}
+
+impl Rewind {
+  /// Keep a snapshot every `interval` frames, in `budget` bytes at most.
+  pub fn new(interval: u32, budget: usize) -> Rewind {
+    assert!(interval > 0);
+    Rewind {
+      interval,
+      budget,
+      newest: None,
+      older: VecDeque::new(),
+      older_size: 0,
+    }
+  }
+
+  pub fn interval(&self) -> u32 {
+    self.interval
+  }
+
+  /// Bytes used by snapshots.
+  pub fn size(&self) -> usize {
+    self.older_size + self.newest.as_ref().map_or(0, Snapshot::size)
+  }
+
+  /// Number of snapshots held.
+  pub fn len(&self) -> usize {
+    self.older.len() + self.newest.is_some() as usize
+  }
+
+  pub fn clear(&mut self) {
+    self.newest = None;
+    self.older.clear();
+    self.older_size = 0;
+  }
+
+  /// Frames run since the newest snapshot, or None if there isn't one.
+  pub fn frames_since_snapshot(&self) -> Option<u32> {
+    self.newest.as_ref().map(|s| s.inputs.len() as u32)
+  }
+
+  /// Whether a snapshot is due before running another frame.
+  pub fn snapshot_due(&self) -> bool {
+    match self.frames_since_snapshot() {
+      Some(frames) => frames >= self.interval,
+      None => true,
+    }
+  }
+
+  /// Add a snapshot of the current state.
+  pub fn push(&mut self, state: Vec<u8>) {
+    if let Some(mut previous) = self.newest.take() {
+      previous.data = delta(&state, &previous.data);
+      self.older_size += previous.size();
+      self.older.push_back(previous);
+    }
+    self.newest = Some(Snapshot {
+      data: state,
+      inputs: vec![],
+    });
+    while self.size() > self.budget {
+      match self.older.pop_front() {
+        Some(oldest) => self.older_size -= oldest.size(),
+        None => break,
+      }
+    }
+  }
+
+  /// Record the buttons held for the next frame, after the newest snapshot.
+  pub fn push_input(&mut self, buttons: u8) {
+    if let Some(ref mut newest) = self.newest {
+      newest.inputs.push(buttons);
+    }
+  }
+
+  /// Get the state `frames` frames after the newest snapshot, as the
+  /// snapshot and the buttons for each frame to run from it. Inputs after
+  /// that are forgotten.
+  pub fn seek(&mut self, frames: u32) -> Option<(&[u8], &[u8])> {
+    let newest = self.newest.as_mut()?;
+    newest.inputs.truncate(frames as usize);
+    Some((&newest.data, &newest.inputs))
+  }
+
+  /// Drop the newest snapshot, making the one before it the newest.
+  /// Returns false if there's no earlier snapshot.
+  pub fn pop(&mut self) -> bool {
+    let previous = match self.older.pop_back() {
+      Some(previous) => previous,
+      None => return false,
+    };
+    self.older_size -= previous.size();
+    let newest = self.newest.take().expect("older snapshot without newest");
+    self.newest = Some(Snapshot {
+      data: apply(&newest.data, &previous.data),
+      inputs: previous.inputs,
+    });
+    true
+  }
+}
+
+/// Marks a delta that holds the target as it is, when it isn't the same
+/// length as the base.
+const RAW: u8 = 0;
+/// Marks a delta of runs of unchanged and XORed bytes.
+const XOR: u8 = 1;
+
+/// Encode `target` as a difference from `base`.
+fn delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+  if base.len() != target.len() {
+    let mut out = vec![RAW];
+    out.extend_from_slice(target);
+    return out;
+  }
+  let mut out = vec![XOR];
+  let mut i = 0;
+  while i < target.len() {
+    // A run of unchanged bytes, then a run of changed ones. Short
+    // unchanged runs are folded into the changed run.
+    let start = i;
+    while i < target.len() && base[i] == target[i] {
+      i += 1;
+    }
+    let unchanged = i - start;
+    let changed_start = i;
+    while i < target.len()
+      && (base[i] != target[i] || !unchanged_run(base, target, i))
+    {
+      i += 1;
+    }
+    write_varint(&mut out, unchanged);
+    write_varint(&mut out, i - changed_start);
+    out.extend(
+      base[changed_start..i]
+        .iter()
+        .zip(&target[changed_start..i])
+        .map(|(b, t)| b ^ t),
+    );
+  }
+  out
+}
+
+/// Whether at least 4 unchanged bytes start at `i`, worth a new run.
+fn unchanged_run(base: &[u8], target: &[u8], i: usize) -> bool {
+  let end = (i + 4).min(target.len());
+  base[i..end] == target[i..end]
+}
+
+/// Decode a delta made by `delta` from `base`.
+fn apply(base: &[u8], delta: &[u8]) -> Vec<u8> {
+  let (&kind, mut rest) = delta.split_first().expect("empty delta");
+  if kind == RAW {
+    return rest.to_vec();
+  }
+  let mut out = base.to_vec();
+  let mut i = 0;
+  while !rest.is_empty() {
+    i += read_varint(&mut rest);
+    let changed = read_varint(&mut rest);
+    for (byte, x) in out[i..i + changed].iter_mut().zip(&rest[..changed]) {
+      *byte ^= x;
+    }
+    rest = &rest[changed..];
+    i += changed;
+  }
+  out
+}
+
+/// Write an unsigned LEB128 number.
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+  while value >= 0x80 {
+    out.push(value as u8 | 0x80);
+    value >>= 7;
+  }
+  out.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> usize {
+  let mut value = 0;
+  let mut shift = 0;
+  loop {
+    let byte = data[0];
+    *data = &data[1..];
+    value |= ((byte & 0x7f) as usize) << shift;
+    if byte & 0x80 == 0 {
+      return value;
+    }
+    shift += 7;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn deltas() {
+    let base: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+    let mut target = base.clone();
+    target[0] ^= 1;
+    target[500] = 0;
+    target[502] = 0;
+    target[999] = 0x55;
+    let d = delta(&base, &target);
+    assert!(d.len() < 20);
+    assert_eq!(apply(&base, &d), target);
+    assert_eq!(apply(&base, &delta(&base, &base)), base);
+    assert_eq!(apply(&base, &delta(&base, &[1, 2, 3])), vec![1, 2, 3]);
+  }
+
+  #[test]
+  fn snapshots() {
+    let state = |n: u8| {
+      let mut state = vec![0; 4096];
+      state[n as usize * 10] = n;
+      state
+    };
+    let mut rewind = Rewind::new(2, 1 << 20);
+    assert!(rewind.snapshot_due());
+    rewind.push(state(1));
+    rewind.push_input(0x01);
+    assert!(!rewind.snapshot_due());
+    rewind.push_input(0x02);
+    assert!(rewind.snapshot_due());
+    rewind.push(state(2));
+    rewind.push_input(0x03);
+    // Older snapshots are much smaller than whole ones.
+    assert!(rewind.size() < 4096 + 100);
+
+    assert_eq!(rewind.seek(0).unwrap(), (&state(2)[..], &[][..]));
+    assert!(rewind.pop());
+    assert_eq!(rewind.seek(1).unwrap(), (&state(1)[..], &[0x01][..]));
+    assert!(!rewind.pop());
+    assert_eq!(rewind.len(), 1);
+  }
+
+  #[test]
+  fn budget() {
+    let mut rewind = Rewind::new(1, 10_000);
+    for n in 0..100 {
+      let state: Vec<u8> = (0..4096).map(|i| (i * n) as u8).collect();
+      rewind.push(state);
+      assert!(rewind.size() <= 10_000);
+    }
+    assert!(rewind.len() > 1);
+    while rewind.pop() {}
+    let (state, _) = rewind.seek(0).unwrap();
+    assert_eq!(state.len(), 4096);
+  }
+}
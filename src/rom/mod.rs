@@ -0,0 +1,152 @@
+//! Reading ROMs from disk, from inside zip and gzip archives, and with
+//! soft-patches applied.
+
+mod patch;
+
+pub use self::patch::PatchError;
+
+use flate2::read::GzDecoder;
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+/// Extensions of ROMs picked out of zip files.
+const ROM_EXTENSIONS: [&str; 2] = ["gb", "gbc"];
+
+/// Extensions of patches found next to a ROM, in the order they're applied.
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+#[derive(Debug)]
+pub enum RomError {
+  Io(io::Error),
+  Zip(zip::result::ZipError),
+  /// A zip file with no `.gb` or `.gbc` file in it.
+  NoRomInZip,
+  Patch(PathBuf, PatchError),
+}
+
+impl fmt::Display for RomError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      RomError::Io(ref e) => write!(f, "{}", e),
+      RomError::Zip(ref e) => write!(f, "Unable to read zip file: {}", e),
+      RomError::NoRomInZip => {
+        write!(f, "Zip file doesn't contain a .gb or .gbc file")
+      }
+      RomError::Patch(ref path, ref e) => {
+        write!(f, "Unable to apply {}: {}", path.display(), e)
+      }
+    }
+  }
+}
+
+impl Error for RomError {}
+
+impl From<io::Error> for RomError {
+  fn from(e: io::Error) -> RomError {
+    RomError::Io(e)
+  }
+}
+
+impl From<zip::result::ZipError> for RomError {
+  fn from(e: zip::result::ZipError) -> RomError {
+    match e {
+      zip::result::ZipError::Io(e) => RomError::Io(e),
+      e => RomError::Zip(e),
+    }
+  }
+}
+
+/// Read the ROM at `path`, unpacking it if it's a zip or gzip file, and
+/// apply `patches` in turn.
+pub fn load(path: &Path, patches: &[PathBuf]) -> Result<Vec<u8>, RomError> {
+  let mut rom = unpack(fs::read(path)?)?;
+  for patch in patches {
+    println!("Applying patch: {}", patch.display());
+    let data = fs::read(patch)?;
+    rom = patch::apply(&rom, &data)
+      .map_err(|e| RomError::Patch(patch.clone(), e))?;
+  }
+  Ok(rom)
+}
+
+/// Patches next to the ROM at `path` with the same name, e.g. `game.ips`
+/// for `game.gb` or `game.zip`.
+pub fn find_patches(path: &Path) -> Vec<PathBuf> {
+  PATCH_EXTENSIONS
+    .iter()
+    .map(|ext| path.with_extension(ext))
+    .filter(|patch| patch.is_file())
+    .collect()
+}
+
+/// Get the ROM out of `data`, which is the ROM itself or an archive of it,
+/// going by the first bytes.
+fn unpack(data: Vec<u8>) -> Result<Vec<u8>, RomError> {
+  if data.starts_with(b"PK\x03\x04") {
+    unzip(&data)
+  } else if data.starts_with(&[0x1f, 0x8b]) {
+    let mut rom = vec![];
+    GzDecoder::new(&data[..]).read_to_end(&mut rom)?;
+    Ok(rom)
+  } else {
+    Ok(data)
+  }
+}
+
+/// Read the first `.gb` or `.gbc` file in a zip file.
+fn unzip(data: &[u8]) -> Result<Vec<u8>, RomError> {
+  let mut zip = zip::ZipArchive::new(Cursor::new(data))?;
+  for i in 0..zip.len() {
+    let mut file = zip.by_index(i)?;
+    let is_rom = Path::new(file.name())
+      .extension()
+      .and_then(|ext| ext.to_str())
+      .is_some_and(|ext| {
+        ROM_EXTENSIONS
+          .iter()
+          .any(|rom| ext.eq_ignore_ascii_case(rom))
+      });
+    if file.is_file() && is_rom {
+      let mut rom = vec![];
+      file.read_to_end(&mut rom)?;
+      return Ok(rom);
+    }
+  }
+  Err(RomError::NoRomInZip)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use flate2::write::GzEncoder;
+  use std::io::Write;
+
+  #[test]
+  fn archives() {
+    let rom = (0..=255).cycle().take(0x8000).collect::<Vec<u8>>();
+    assert_eq!(unpack(rom.clone()).unwrap(), rom);
+
+    let mut gz = GzEncoder::new(vec![], flate2::Compression::default());
+    gz.write_all(&rom).unwrap();
+    assert_eq!(unpack(gz.finish().unwrap()).unwrap(), rom);
+
+    let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+    let options = zip::write::FileOptions::default();
+    zip.start_file("readme.txt", options).unwrap();
+    zip.write_all(b"Not a ROM").unwrap();
+    zip.start_file("Game.GBC", options).unwrap();
+    zip.write_all(&rom).unwrap();
+    let zip = zip.finish().unwrap().into_inner();
+    assert_eq!(unpack(zip).unwrap(), rom);
+
+    let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+    zip.start_file("readme.txt", options).unwrap();
+    let zip = zip.finish().unwrap().into_inner();
+    assert!(matches!(unpack(zip), Err(RomError::NoRomInZip)));
+  }
+}
@@ -0,0 +1,386 @@
+//! Soft-patches in the IPS, UPS and BPS formats, applied to ROMs as they're
+//! loaded.
+//!
+//! UPS and BPS patches carry CRC-32s of the ROM they're for, the patched
+//! ROM and the patch itself, which are all checked. IPS patches have no
+//! checksums.
+
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PatchError {
+  /// Not an IPS, UPS or BPS patch.
+  UnknownFormat,
+  /// The patch is cut short, or writes outside the ROM.
+  Corrupt,
+  /// The patch doesn't match its own checksum, so it's damaged.
+  PatchChecksum,
+  /// The patch is for a different ROM, or a different version of it.
+  WrongRom { expected: u32, actual: u32 },
+  /// The patched ROM doesn't match the checksum in the patch.
+  OutputChecksum,
+}
+
+impl fmt::Display for PatchError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      PatchError::UnknownFormat => write!(f, "Not an IPS, UPS or BPS patch"),
+      PatchError::Corrupt => write!(f, "Patch is corrupt"),
+      PatchError::PatchChecksum => {
+        write!(f, "Patch doesn't match its checksum")
+      }
+      PatchError::WrongRom { expected, actual } => write!(
+        f,
+        "Patch is for a ROM with CRC {:08x}, but this ROM's CRC is {:08x}",
+        expected, actual
+      ),
+      PatchError::OutputChecksum => {
+        write!(f, "Patched ROM doesn't match the patch's checksum")
+      }
+    }
+  }
+}
+
+impl Error for PatchError {}
+
+pub type Result<T> = std::result::Result<T, PatchError>;
+
+/// Largest patched ROM accepted, well beyond any real cartridge, so a
+/// corrupt size can't exhaust memory.
+const MAX_SIZE: usize = 64 << 20;
+
+/// Apply `patch`, in any supported format, to `rom`.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+  if patch.starts_with(b"PATCH") {
+    apply_ips(rom, patch)
+  } else if patch.starts_with(b"UPS1") {
+    apply_ups(rom, patch)
+  } else if patch.starts_with(b"BPS1") {
+    apply_bps(rom, patch)
+  } else {
+    Err(PatchError::UnknownFormat)
+  }
+}
+
+/// Reads a patch from the front.
+struct Reader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn new(data: &'a [u8], pos: usize) -> Reader<'a> {
+    Reader { data, pos }
+  }
+
+  fn done(&self) -> bool {
+    self.pos >= self.data.len()
+  }
+
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+    let end = self.pos.checked_add(len).ok_or(PatchError::Corrupt)?;
+    let bytes = self.data.get(self.pos..end).ok_or(PatchError::Corrupt)?;
+    self.pos = end;
+    Ok(bytes)
+  }
+
+  fn u8(&mut self) -> Result<u8> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  /// A big-endian number of `len` bytes, as used by IPS.
+  fn be(&mut self, len: usize) -> Result<usize> {
+    let bytes = self.bytes(len)?;
+    Ok(bytes.iter().fold(0, |n, &b| n << 8 | b as usize))
+  }
+
+  /// A variable-length number, as used by UPS and BPS. Each byte holds
+  /// seven bits, lowest first, with the top bit set on the last byte.
+  fn number(&mut self) -> Result<usize> {
+    let mut n: usize = 0;
+    let mut shift: usize = 1;
+    loop {
+      let b = self.u8()?;
+      n = (b as usize & 0x7f)
+        .checked_mul(shift)
+        .and_then(|v| n.checked_add(v))
+        .ok_or(PatchError::Corrupt)?;
+      if b & 0x80 != 0 {
+        return Ok(n);
+      }
+      shift = shift.checked_mul(0x80).ok_or(PatchError::Corrupt)?;
+      n = n.checked_add(shift).ok_or(PatchError::Corrupt)?;
+    }
+  }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+  let mut out = rom.to_vec();
+  let mut r = Reader::new(patch, 5);
+  loop {
+    let offset = r.be(3)?;
+    if offset == 0x454f46 {
+      // "EOF", optionally followed by the size to truncate to.
+      if let Ok(size) = r.be(3) {
+        out.truncate(size);
+      }
+      return Ok(out);
+    }
+    let (len, data) = match r.be(2)? {
+      0 => {
+        let len = r.be(2)?;
+        (len, None)
+      }
+      len => (len, Some(r.bytes(len)?)),
+    };
+    if out.len() < offset + len {
+      out.resize(offset + len, 0);
+    }
+    let dest = &mut out[offset..offset + len];
+    match data {
+      Some(data) => dest.copy_from_slice(data),
+      None => {
+        let value = r.u8()?;
+        dest.iter_mut().for_each(|b| *b = value);
+      }
+    }
+  }
+}
+
+/// Check the footer of a UPS or BPS patch, returning the CRCs of the
+/// source and target, and the offset of the footer.
+fn footer(patch: &[u8]) -> Result<(u32, u32, usize)> {
+  if patch.len() < 16 {
+    return Err(PatchError::Corrupt);
+  }
+  let end = patch.len() - 12;
+  let crc = |pos: usize| {
+    u32::from_le_bytes([
+      patch[pos],
+      patch[pos + 1],
+      patch[pos + 2],
+      patch[pos + 3],
+    ])
+  };
+  if crc32fast::hash(&patch[..end + 8]) != crc(end + 8) {
+    return Err(PatchError::PatchChecksum);
+  }
+  Ok((crc(end), crc(end + 4), end))
+}
+
+fn check_source(rom: &[u8], expected: u32) -> Result<()> {
+  let actual = crc32fast::hash(rom);
+  if actual != expected {
+    return Err(PatchError::WrongRom { expected, actual });
+  }
+  Ok(())
+}
+
+fn check_target(out: &[u8], expected: u32) -> Result<()> {
+  if crc32fast::hash(out) != expected {
+    return Err(PatchError::OutputChecksum);
+  }
+  Ok(())
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+  let (source_crc, target_crc, end) = footer(patch)?;
+  check_source(rom, source_crc)?;
+  let mut r = Reader::new(&patch[..end], 4);
+  let source_size = r.number()?;
+  let target_size = r.number()?;
+  if source_size != rom.len() || target_size > MAX_SIZE {
+    return Err(PatchError::Corrupt);
+  }
+
+  let mut out = rom.to_vec();
+  out.resize(target_size, 0);
+  let mut pos = 0;
+  while !r.done() {
+    pos += r.number()?;
+    // XOR bytes in until a zero, which ends the run.
+    loop {
+      let b = r.u8()?;
+      if b == 0 {
+        break;
+      }
+      *out.get_mut(pos).ok_or(PatchError::Corrupt)? ^= b;
+      pos += 1;
+    }
+    pos += 1;
+  }
+  check_target(&out, target_crc)?;
+  Ok(out)
+}
+
+/// Move `offset` by a signed BPS offset: the sign in the low bit and the
+/// size in the rest.
+fn seek(offset: usize, n: usize) -> Result<usize> {
+  let delta = n >> 1;
+  let offset = if n & 1 != 0 {
+    offset.checked_sub(delta)
+  } else {
+    offset.checked_add(delta)
+  };
+  offset.ok_or(PatchError::Corrupt)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+  let (source_crc, target_crc, end) = footer(patch)?;
+  check_source(rom, source_crc)?;
+  let mut r = Reader::new(&patch[..end], 4);
+  let source_size = r.number()?;
+  let target_size = r.number()?;
+  let metadata_size = r.number()?;
+  r.bytes(metadata_size)?;
+  if source_size != rom.len() || target_size > MAX_SIZE {
+    return Err(PatchError::Corrupt);
+  }
+
+  let mut out = Vec::with_capacity(target_size);
+  let mut source_offset = 0;
+  let mut target_offset = 0;
+  while !r.done() {
+    let n = r.number()?;
+    let len = (n >> 2) + 1;
+    if len > target_size - out.len() {
+      return Err(PatchError::Corrupt);
+    }
+    match n & 3 {
+      // Copy from the same place in the source.
+      0 => {
+        let pos = out.len();
+        let from = rom.get(pos..pos + len).ok_or(PatchError::Corrupt)?;
+        out.extend_from_slice(from);
+      }
+      // Copy from the patch.
+      1 => out.extend_from_slice(r.bytes(len)?),
+      // Copy from elsewhere in the source.
+      2 => {
+        source_offset = seek(source_offset, r.number()?)?;
+        if source_offset > rom.len() {
+          return Err(PatchError::Corrupt);
+        }
+        let from = rom
+          .get(source_offset..source_offset + len)
+          .ok_or(PatchError::Corrupt)?;
+        out.extend_from_slice(from);
+        source_offset += len;
+      }
+      // Copy from earlier in the output, a byte at a time as the copy can
+      // overlap itself.
+      _ => {
+        target_offset = seek(target_offset, r.number()?)?;
+        if target_offset >= out.len() {
+          return Err(PatchError::Corrupt);
+        }
+        for _ in 0..len {
+          out.push(out[target_offset]);
+          target_offset += 1;
+        }
+      }
+    }
+  }
+  if out.len() != target_size {
+    return Err(PatchError::Corrupt);
+  }
+  check_target(&out, target_crc)?;
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn number(mut n: usize) -> Vec<u8> {
+    let mut out = vec![];
+    loop {
+      let b = (n & 0x7f) as u8;
+      n >>= 7;
+      if n == 0 {
+        out.push(b | 0x80);
+        return out;
+      }
+      out.push(b);
+      n -= 1;
+    }
+  }
+
+  /// Add the checksums that end UPS and BPS patches.
+  fn finish(patch: &[u8], source: u32, target: u32) -> Vec<u8> {
+    let mut patch = patch.to_vec();
+    patch.extend(&source.to_le_bytes());
+    patch.extend(&target.to_le_bytes());
+    patch.extend(&crc32fast::hash(&patch).to_le_bytes());
+    patch
+  }
+
+  #[test]
+  fn ips() {
+    let mut patch = b"PATCH".to_vec();
+    patch.extend(&[0, 0, 1, 0, 2, 0xaa, 0xbb]);
+    // A run of four 0xcc bytes, past the end of the ROM.
+    patch.extend(&[0, 0, 6, 0, 0, 0, 4, 0xcc]);
+    patch.extend(b"EOF");
+    let rom = [0; 4];
+    assert_eq!(
+      apply(&rom, &patch).unwrap(),
+      [0, 0xaa, 0xbb, 0, 0, 0, 0xcc, 0xcc, 0xcc, 0xcc]
+    );
+    patch.extend(&[0, 0, 3]);
+    assert_eq!(apply(&rom, &patch).unwrap(), [0, 0xaa, 0xbb]);
+    assert_eq!(apply(&rom, b"PATCH\0\0"), Err(PatchError::Corrupt));
+  }
+
+  #[test]
+  fn ups() {
+    let source = b"Hello, world".to_vec();
+    let target = b"Hello, World!".to_vec();
+    let mut patch = b"UPS1".to_vec();
+    patch.extend(number(source.len()));
+    patch.extend(number(target.len()));
+    patch.extend(number(7));
+    patch.extend(&[b'w' ^ b'W', 0]);
+    patch.extend(number(3));
+    patch.extend(&[b'!', 0]);
+    let patch =
+      finish(&patch, crc32fast::hash(&source), crc32fast::hash(&target));
+    assert_eq!(apply(&source, &patch).unwrap(), target);
+
+    assert!(matches!(
+      apply(b"Goodbye", &patch),
+      Err(PatchError::WrongRom { .. })
+    ));
+    let mut damaged = patch.clone();
+    damaged[8] ^= 1;
+    assert_eq!(apply(&source, &damaged), Err(PatchError::PatchChecksum));
+  }
+
+  #[test]
+  fn bps() {
+    let source = b"abcdef".to_vec();
+    let target = b"abcXYZXYZcdef".to_vec();
+    let mut patch = b"BPS1".to_vec();
+    patch.extend(number(source.len()));
+    patch.extend(number(target.len()));
+    patch.extend(number(2));
+    patch.extend(b"{}");
+    // Source read "abc", target read "XYZ", then a target copy of "XYZ"
+    // from three bytes back and a source copy of "cdef".
+    patch.extend(number((3 - 1) << 2));
+    patch.extend(number((3 - 1) << 2 | 1));
+    patch.extend(b"XYZ");
+    patch.extend(number((3 - 1) << 2 | 3));
+    patch.extend(number(3 << 1));
+    patch.extend(number((4 - 1) << 2 | 2));
+    patch.extend(number(2 << 1));
+    let source_crc = crc32fast::hash(&source);
+    let good = finish(&patch, source_crc, crc32fast::hash(&target));
+    assert_eq!(apply(&source, &good).unwrap(), target);
+
+    let wrong = finish(&patch, source_crc, 0);
+    assert_eq!(apply(&source, &wrong), Err(PatchError::OutputChecksum));
+    assert_eq!(apply(&source, b"NOPE"), Err(PatchError::UnknownFormat));
+  }
+}
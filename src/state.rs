@@ -0,0 +1,356 @@
+//! Save state format.
+//!
+//! A state starts with `MAGIC` and a little-endian u16 version, followed by
+//! sections. Each section has a four-character name, a u32 length and that
+//! many bytes of fields, all little-endian. Sections can be listed and
+//! skipped without knowing their contents.
+//!
+//! Other files, such as movies, use the same container with their own magic
+//! and version.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+const MAGIC: &[u8; 8] = b"GBRSTATE";
+
+/// Version of the state format. Bump this when any section's fields change.
+pub const VERSION: u16 = 1;
+
+pub type Result<T> = std::result::Result<T, StateError>;
+
+#[derive(Debug)]
+pub enum StateError {
+  Io(io::Error),
+  /// The data isn't a save state.
+  NotAState,
+  /// A state from a different version of the format.
+  Version(u16),
+  /// A state for a different ROM, or the same ROM in a different mode.
+  WrongRom(String),
+  MissingSection(String),
+  /// A section is shorter than its fields.
+  Truncated(String),
+  Invalid(String),
+}
+
+impl fmt::Display for StateError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      StateError::Io(ref e) => write!(f, "{}", e),
+      StateError::NotAState => write!(f, "Not a save state"),
+      StateError::Version(v) => write!(
+        f,
+        "Save state is version {}, but only version {} is supported",
+        v, VERSION
+      ),
+      StateError::WrongRom(ref why) => {
+        write!(f, "Save state is for a different game: {}", why)
+      }
+      StateError::MissingSection(ref name) => {
+        write!(f, "Save state has no {} section", name)
+      }
+      StateError::Truncated(ref name) => {
+        write!(f, "Save state's {} section is truncated", name)
+      }
+      StateError::Invalid(ref why) => write!(f, "Invalid save state: {}", why),
+    }
+  }
+}
+
+impl Error for StateError {}
+
+impl From<io::Error> for StateError {
+  fn from(e: io::Error) -> StateError {
+    StateError::Io(e)
+  }
+}
+
+/// Writes the fields of a section.
+#[derive(Debug, Default)]
+pub struct Writer {
+  buf: Vec<u8>,
+}
+
+impl Writer {
+  pub fn u8(&mut self, value: u8) {
+    self.buf.push(value);
+  }
+
+  pub fn bool(&mut self, value: bool) {
+    self.u8(value as u8);
+  }
+
+  pub fn u16(&mut self, value: u16) {
+    self.buf.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn u32(&mut self, value: u32) {
+    self.buf.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn u64(&mut self, value: u64) {
+    self.buf.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn f32(&mut self, value: f32) {
+    self.u32(value.to_bits());
+  }
+
+  /// Write a length-prefixed byte string.
+  pub fn bytes(&mut self, value: &[u8]) {
+    self.u32(value.len() as u32);
+    self.buf.extend_from_slice(value);
+  }
+
+  pub fn str(&mut self, value: &str) {
+    self.bytes(value.as_bytes());
+  }
+}
+
+/// Reads the fields of a section, in the order they were written.
+pub struct Reader<'a> {
+  name: String,
+  data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+    if self.data.len() < n {
+      return Err(StateError::Truncated(self.name.clone()));
+    }
+    let (taken, rest) = self.data.split_at(n);
+    self.data = rest;
+    Ok(taken)
+  }
+
+  fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+    let mut array = [0; N];
+    array.copy_from_slice(self.take(N)?);
+    Ok(array)
+  }
+
+  pub fn u8(&mut self) -> Result<u8> {
+    Ok(self.take(1)?[0])
+  }
+
+  pub fn bool(&mut self) -> Result<bool> {
+    Ok(self.u8()? != 0)
+  }
+
+  pub fn u16(&mut self) -> Result<u16> {
+    Ok(u16::from_le_bytes(self.array()?))
+  }
+
+  pub fn u32(&mut self) -> Result<u32> {
+    Ok(u32::from_le_bytes(self.array()?))
+  }
+
+  pub fn u64(&mut self) -> Result<u64> {
+    Ok(u64::from_le_bytes(self.array()?))
+  }
+
+  pub fn f32(&mut self) -> Result<f32> {
+    Ok(f32::from_bits(self.u32()?))
+  }
+
+  pub fn bytes(&mut self) -> Result<&'a [u8]> {
+    let len = self.u32()? as usize;
+    self.take(len)
+  }
+
+  /// Read a byte string into `out`, which must be the same length.
+  pub fn bytes_into(&mut self, out: &mut [u8]) -> Result<()> {
+    let bytes = self.bytes()?;
+    if bytes.len() != out.len() {
+      return Err(self.invalid(format!(
+        "expected {} bytes, found {}",
+        out.len(),
+        bytes.len()
+      )));
+    }
+    out.copy_from_slice(bytes);
+    Ok(())
+  }
+
+  pub fn str(&mut self) -> Result<String> {
+    let name = self.name.clone();
+    String::from_utf8(self.bytes()?.to_vec())
+      .map_err(|_| StateError::Invalid(format!("bad string in {}", name)))
+  }
+
+  /// An error for an invalid value in this section.
+  pub fn invalid(&self, why: String) -> StateError {
+    StateError::Invalid(format!("{}: {}", self.name, why))
+  }
+}
+
+/// A save state being written.
+pub struct SaveState {
+  data: Vec<u8>,
+}
+
+impl SaveState {
+  pub fn new() -> SaveState {
+    SaveState::with_magic(MAGIC, VERSION)
+  }
+
+  /// Start a file of another kind with the same layout.
+  pub fn with_magic(magic: &[u8; 8], version: u16) -> SaveState {
+    let mut data = magic.to_vec();
+    data.extend_from_slice(&version.to_le_bytes());
+    SaveState { data }
+  }
+
+  /// Add a section with the fields written by `f`.
+  pub fn section<F: FnOnce(&mut Writer)>(&mut self, name: &[u8; 4], f: F) {
+    let mut writer = Writer::default();
+    f(&mut writer);
+    self.data.extend_from_slice(name);
+    self
+      .data
+      .extend_from_slice(&(writer.buf.len() as u32).to_le_bytes());
+    self.data.extend_from_slice(&writer.buf);
+  }
+
+  pub fn into_bytes(self) -> Vec<u8> {
+    self.data
+  }
+}
+
+/// A save state being read.
+pub struct LoadState<'a> {
+  sections: Vec<([u8; 4], &'a [u8])>,
+}
+
+impl<'a> LoadState<'a> {
+  pub fn parse(data: &'a [u8]) -> Result<LoadState<'a>> {
+    LoadState::parse_with_magic(data, MAGIC, VERSION)
+  }
+
+  /// Parse a file made by `SaveState::with_magic`.
+  pub fn parse_with_magic(
+    data: &'a [u8],
+    magic: &[u8; 8],
+    version: u16,
+  ) -> Result<LoadState<'a>> {
+    if data.len() < magic.len() + 2 || &data[..magic.len()] != magic {
+      return Err(StateError::NotAState);
+    }
+    let found = u16::from_le_bytes([data[8], data[9]]);
+    if found != version {
+      return Err(StateError::Version(found));
+    }
+
+    let mut sections = vec![];
+    let mut rest = &data[magic.len() + 2..];
+    while !rest.is_empty() {
+      if rest.len() < 8 {
+        return Err(StateError::Invalid("truncated section header".into()));
+      }
+      let mut name = [0; 4];
+      name.copy_from_slice(&rest[..4]);
+      let len =
+        u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+      rest = &rest[8..];
+      if rest.len() < len {
+        return Err(StateError::Truncated(section_name(&name)));
+      }
+      sections.push((name, &rest[..len]));
+      rest = &rest[len..];
+    }
+    Ok(LoadState { sections })
+  }
+
+  pub fn has_section(&self, name: &[u8; 4]) -> bool {
+    self.sections.iter().any(|(n, _)| n == name)
+  }
+
+  pub fn section(&self, name: &[u8; 4]) -> Result<Reader<'a>> {
+    match self.sections.iter().find(|(n, _)| n == name) {
+      Some(&(_, data)) => Ok(Reader {
+        name: section_name(name),
+        data,
+      }),
+      None => Err(StateError::MissingSection(section_name(name))),
+    }
+  }
+
+  /// Names and sizes of all sections, in order.
+  pub fn sections(&self) -> Vec<(String, usize)> {
+    self
+      .sections
+      .iter()
+      .map(|(name, data)| (section_name(name), data.len()))
+      .collect()
+  }
+}
+
+fn section_name(name: &[u8; 4]) -> String {
+  String::from_utf8_lossy(name).trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trip() {
+    let mut state = SaveState::new();
+    state.section(b"TEST", |w| {
+      w.u8(1);
+      w.bool(true);
+      w.u16(0x1234);
+      w.u32(0xdead_beef);
+      w.u64(1 << 40);
+      w.f32(-0.5);
+      w.bytes(&[1, 2, 3]);
+      w.str("hi");
+    });
+    state.section(b"NEXT", |w| w.u8(9));
+    let data = state.into_bytes();
+
+    let state = LoadState::parse(&data).unwrap();
+    assert_eq!(
+      state.sections(),
+      vec![("TEST".to_string(), 33), ("NEXT".to_string(), 1)]
+    );
+    let mut r = state.section(b"TEST").unwrap();
+    assert_eq!(r.u8().unwrap(), 1);
+    assert!(r.bool().unwrap());
+    assert_eq!(r.u16().unwrap(), 0x1234);
+    assert_eq!(r.u32().unwrap(), 0xdead_beef);
+    assert_eq!(r.u64().unwrap(), 1 << 40);
+    assert_eq!(r.f32().unwrap(), -0.5);
+    let mut three = [0; 3];
+    r.bytes_into(&mut three).unwrap();
+    assert_eq!(three, [1, 2, 3]);
+    assert_eq!(r.str().unwrap(), "hi");
+    assert!(matches!(r.u8(), Err(StateError::Truncated(_))));
+    assert!(matches!(
+      state.section(b"NONE"),
+      Err(StateError::MissingSection(_))
+    ));
+  }
+
+  #[test]
+  fn bad_header() {
+    assert!(matches!(
+      LoadState::parse(b"not a state"),
+      Err(StateError::NotAState)
+    ));
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&(VERSION + 1).to_le_bytes());
+    assert!(matches!(
+      LoadState::parse(&data),
+      Err(StateError::Version(_))
+    ));
+    data.truncate(MAGIC.len());
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(b"CPU \x10\0\0\0");
+    assert!(matches!(
+      LoadState::parse(&data),
+      Err(StateError::Truncated(_))
+    ));
+  }
+}
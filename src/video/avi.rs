@@ -0,0 +1,574 @@
+//! Uncompressed AVI files, with 24-bit RGB video and 16-bit stereo PCM
+//! audio.
+//!
+//! Files are split into RIFF chunks of about 1 GB using the OpenDML
+//! extensions, so recordings can be as long as needed. The first chunk also
+//! has an old-style index for players without OpenDML support.
+
+use super::Image;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Frames per second as a fraction: the CPU clock over cycles per frame,
+/// about 59.73.
+const FRAME_RATE: u32 = 4_194_304;
+const FRAME_SCALE: u32 = 70_224;
+
+/// Size to keep each RIFF chunk under.
+const RIFF_LIMIT: u64 = 1 << 30;
+
+/// Entries reserved in each stream's index of RIFF chunks.
+const SUPER_INDEX_ENTRIES: usize = 256;
+const SUPER_INDEX_SIZE: usize = 24 + 16 * SUPER_INDEX_ENTRIES;
+
+/// Index flag for chunks that don't depend on earlier ones.
+const KEYFRAME: u32 = 0x10;
+
+/// A stream's chunks and indexes.
+struct Stream {
+  id: [u8; 4],
+  /// Offset and size of each chunk in the current RIFF, relative to the
+  /// start of its movi list.
+  chunks: Vec<(u32, u32)>,
+  /// Offset, size and duration of each RIFF chunk's index.
+  indexes: Vec<(u64, u32, u32)>,
+  /// Frames or audio samples in the current RIFF.
+  duration: u32,
+  total: u64,
+  /// Where the header's stream length and index are.
+  length_pos: u64,
+  index_pos: u64,
+}
+
+impl Stream {
+  fn new(id: &[u8; 4]) -> Stream {
+    Stream {
+      id: *id,
+      chunks: vec![],
+      indexes: vec![],
+      duration: 0,
+      total: 0,
+      length_pos: 0,
+      index_pos: 0,
+    }
+  }
+}
+
+/// Writes an AVI file as frames and audio come in. The headers and indexes
+/// are completed when the writer is finished or dropped.
+pub struct AviWriter<W: Write + Seek> {
+  writer: W,
+  width: usize,
+  height: usize,
+  video: Stream,
+  audio: Stream,
+  /// Position written up to.
+  pos: u64,
+  riff_start: u64,
+  /// Position of the current movi list's "movi".
+  movi_start: u64,
+  riff_limit: u64,
+  /// Chunks in the first RIFF, for the old-style index.
+  first_riff: Vec<([u8; 4], u32, u32)>,
+  first_riff_frames: u32,
+  total_frames_pos: u64,
+  odml_frames_pos: u64,
+  /// The index has no room for another RIFF, so the last one has been ended
+  /// and nothing more can be written.
+  full: bool,
+  finished: bool,
+}
+
+impl AviWriter<BufWriter<File>> {
+  pub fn create(
+    path: &Path,
+    width: usize,
+    height: usize,
+    sample_rate: u32,
+  ) -> io::Result<AviWriter<BufWriter<File>>> {
+    let file = BufWriter::new(File::create(path)?);
+    AviWriter::new(file, width, height, sample_rate)
+  }
+}
+
+/// Bytes in a row of video, padded to 4 bytes.
+fn row_size(width: usize) -> usize {
+  (width * 3 + 3) & !3
+}
+
+/// Builds headers in memory.
+#[derive(Default)]
+struct Header {
+  data: Vec<u8>,
+}
+
+impl Header {
+  fn bytes(&mut self, bytes: &[u8]) {
+    self.data.extend_from_slice(bytes);
+  }
+
+  fn u16(&mut self, value: u16) {
+    self.bytes(&value.to_le_bytes());
+  }
+
+  fn u32(&mut self, value: u32) {
+    self.bytes(&value.to_le_bytes());
+  }
+
+  /// Start a chunk or list, returning where to patch its size.
+  fn start(&mut self, id: &[u8; 4]) -> usize {
+    self.bytes(id);
+    self.u32(0);
+    self.data.len()
+  }
+
+  fn end(&mut self, start: usize) {
+    let size = (self.data.len() - start) as u32;
+    self.data[start - 4..start].copy_from_slice(&size.to_le_bytes());
+  }
+
+  fn list(&mut self, kind: &[u8; 4]) -> usize {
+    let start = self.start(b"LIST");
+    self.bytes(kind);
+    start
+  }
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+  pub fn new(
+    writer: W,
+    width: usize,
+    height: usize,
+    sample_rate: u32,
+  ) -> io::Result<AviWriter<W>> {
+    let frame_size = (row_size(width) * height) as u32;
+    let mut avi = AviWriter {
+      writer,
+      width,
+      height,
+      video: Stream::new(b"00dc"),
+      audio: Stream::new(b"01wb"),
+      pos: 0,
+      riff_start: 0,
+      movi_start: 0,
+      riff_limit: RIFF_LIMIT,
+      first_riff: vec![],
+      first_riff_frames: 0,
+      total_frames_pos: 0,
+      odml_frames_pos: 0,
+      full: false,
+      finished: false,
+    };
+
+    let mut h = Header::default();
+    h.bytes(b"RIFF");
+    h.u32(0);
+    h.bytes(b"AVI ");
+    let hdrl = h.list(b"hdrl");
+
+    let avih = h.start(b"avih");
+    h.u32((1_000_000 * u64::from(FRAME_SCALE) / u64::from(FRAME_RATE)) as u32);
+    h.u32(frame_size * 60 + sample_rate * 4);
+    h.u32(0); // Padding granularity
+    h.u32(0x10 | 0x100); // Has an index, and is interleaved
+    avi.total_frames_pos = h.data.len() as u64;
+    h.u32(0);
+    h.u32(0); // Initial frames
+    h.u32(2); // Streams
+    h.u32(frame_size);
+    h.u32(width as u32);
+    h.u32(height as u32);
+    h.bytes(&[0; 16]);
+    h.end(avih);
+
+    // Video stream
+    let strl = h.list(b"strl");
+    let strh = h.start(b"strh");
+    h.bytes(b"vids");
+    h.bytes(&[0; 4]); // Handler
+    h.u32(0); // Flags
+    h.u32(0); // Priority and language
+    h.u32(0); // Initial frames
+    h.u32(FRAME_SCALE);
+    h.u32(FRAME_RATE);
+    h.u32(0); // Start
+    avi.video.length_pos = h.data.len() as u64;
+    h.u32(0);
+    h.u32(frame_size);
+    h.u32(u32::MAX); // Default quality
+    h.u32(0); // Sample size, which varies
+    h.u16(0);
+    h.u16(0);
+    h.u16(width as u16);
+    h.u16(height as u16);
+    h.end(strh);
+    let strf = h.start(b"strf");
+    h.u32(40);
+    h.u32(width as u32);
+    h.u32(height as u32); // Positive for rows from the bottom up
+    h.u16(1); // Planes
+    h.u16(24);
+    h.u32(0); // Uncompressed RGB
+    h.u32(frame_size);
+    h.bytes(&[0; 16]);
+    h.end(strf);
+    avi.video.index_pos = h.data.len() as u64;
+    h.bytes(&[0; 8 + SUPER_INDEX_SIZE]);
+    h.end(strl);
+
+    // Audio stream
+    let strl = h.list(b"strl");
+    let strh = h.start(b"strh");
+    h.bytes(b"auds");
+    h.bytes(&[0; 4]);
+    h.u32(0);
+    h.u32(0);
+    h.u32(0);
+    h.u32(4); // Scale and rate in bytes of stereo samples
+    h.u32(sample_rate * 4);
+    h.u32(0);
+    avi.audio.length_pos = h.data.len() as u64;
+    h.u32(0);
+    h.u32(sample_rate * 4 / 10);
+    h.u32(u32::MAX);
+    h.u32(4);
+    h.bytes(&[0; 8]);
+    h.end(strh);
+    let strf = h.start(b"strf");
+    h.u16(1); // PCM
+    h.u16(2);
+    h.u32(sample_rate);
+    h.u32(sample_rate * 4);
+    h.u16(4);
+    h.u16(16);
+    h.end(strf);
+    avi.audio.index_pos = h.data.len() as u64;
+    h.bytes(&[0; 8 + SUPER_INDEX_SIZE]);
+    h.end(strl);
+
+    let odml = h.list(b"odml");
+    let dmlh = h.start(b"dmlh");
+    avi.odml_frames_pos = h.data.len() as u64;
+    h.bytes(&[0; 248]);
+    h.end(dmlh);
+    h.end(odml);
+    h.end(hdrl);
+
+    // Fill in the index headers, as they'll be if no chunks are written.
+    for stream in [&avi.video, &avi.audio] {
+      let index = super_index(stream);
+      let pos = stream.index_pos as usize;
+      h.data[pos..pos + index.len()].copy_from_slice(&index);
+    }
+
+    avi.put(&h.data)?;
+    avi.start_movi()?;
+    Ok(avi)
+  }
+
+  fn put(&mut self, data: &[u8]) -> io::Result<()> {
+    self.writer.write_all(data)?;
+    self.pos += data.len() as u64;
+    Ok(())
+  }
+
+  /// Overwrite earlier data at `pos`.
+  fn patch(&mut self, pos: u64, data: &[u8]) -> io::Result<()> {
+    self.writer.seek(SeekFrom::Start(pos))?;
+    self.writer.write_all(data)?;
+    self.writer.seek(SeekFrom::Start(self.pos))?;
+    Ok(())
+  }
+
+  fn start_movi(&mut self) -> io::Result<()> {
+    self.put(b"LIST\0\0\0\0")?;
+    self.movi_start = self.pos;
+    self.put(b"movi")
+  }
+
+  /// Write a frame, which must be the size given when creating the writer.
+  pub fn write_frame(&mut self, image: &Image) -> io::Result<()> {
+    assert_eq!((image.width, image.height), (self.width, self.height));
+    let row = row_size(self.width);
+    let mut data = vec![0; row * self.height];
+    for (y, pixels) in image.pixels.chunks_exact(self.width).enumerate() {
+      let out = &mut data[(self.height - 1 - y) * row..];
+      for (x, &p) in pixels.iter().enumerate() {
+        out[x * 3..x * 3 + 3].copy_from_slice(&[
+          p as u8,
+          (p >> 8) as u8,
+          (p >> 16) as u8,
+        ]);
+      }
+    }
+    self.write_chunk(true, &data, 1)
+  }
+
+  /// Write interleaved stereo samples in the range -1.0 to 1.0.
+  pub fn write_audio(&mut self, samples: &[f32]) -> io::Result<()> {
+    if samples.len() < 2 {
+      return Ok(());
+    }
+    let data: Vec<u8> = samples[..samples.len() & !1]
+      .iter()
+      .flat_map(|&sample| {
+        let value = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16;
+        value.to_le_bytes()
+      })
+      .collect();
+    self.write_chunk(false, &data, samples.len() as u32 / 2)
+  }
+
+  fn write_chunk(
+    &mut self,
+    video: bool,
+    data: &[u8],
+    duration: u32,
+  ) -> io::Result<()> {
+    if self.full {
+      return Err(too_long());
+    }
+    // Leave room for the indexes at the end of the RIFF.
+    let entries = self.video.chunks.len() + self.audio.chunks.len() + 1;
+    let size =
+      self.pos - self.riff_start + data.len() as u64 + entries as u64 * 24;
+    if size > self.riff_limit && entries > 1 {
+      self.end_riff()?;
+      // Stop at a whole RIFF, so what's been recorded can still be played.
+      let indexes = self.video.indexes.len().max(self.audio.indexes.len());
+      if indexes == SUPER_INDEX_ENTRIES {
+        self.full = true;
+        return Err(too_long());
+      }
+      self.riff_start = self.pos;
+      self.put(b"RIFF\0\0\0\0AVIX")?;
+      self.start_movi()?;
+    }
+
+    let offset = (self.pos - self.movi_start) as u32;
+    let stream = if video {
+      &mut self.video
+    } else {
+      &mut self.audio
+    };
+    let id = stream.id;
+    stream.chunks.push((offset + 8, data.len() as u32));
+    stream.duration += duration;
+    stream.total += u64::from(duration);
+    if self.riff_start == 0 {
+      self.first_riff.push((id, offset, data.len() as u32));
+      if video {
+        self.first_riff_frames += 1;
+      }
+    }
+    self.put(&id)?;
+    self.put(&(data.len() as u32).to_le_bytes())?;
+    self.put(data)?;
+    if data.len() % 2 == 1 {
+      self.put(&[0])?;
+    }
+    Ok(())
+  }
+
+  /// Write the current RIFF's indexes and fill in its sizes.
+  fn end_riff(&mut self) -> io::Result<()> {
+    for video in [true, false] {
+      let stream = if video { &self.video } else { &self.audio };
+      if stream.chunks.is_empty() {
+        continue;
+      }
+      let mut h = Header::default();
+      let ix = h.start(if video { b"ix00" } else { b"ix01" });
+      h.u16(2); // Longs per entry
+      h.u16(0x0100); // An index of chunks
+      h.u32(stream.chunks.len() as u32);
+      h.bytes(&stream.id);
+      h.bytes(&self.movi_start.to_le_bytes());
+      h.u32(0);
+      for &(offset, size) in &stream.chunks {
+        h.u32(offset);
+        // Audio is all keyframes too, so the high bit is never set.
+        h.u32(size);
+      }
+      h.end(ix);
+
+      let entry = (self.pos, h.data.len() as u32, stream.duration);
+      let stream = if video {
+        &mut self.video
+      } else {
+        &mut self.audio
+      };
+      stream.indexes.push(entry);
+      stream.chunks.clear();
+      stream.duration = 0;
+      self.put(&h.data)?;
+    }
+    let movi_size = (self.pos - self.movi_start) as u32;
+    self.patch(self.movi_start - 4, &movi_size.to_le_bytes())?;
+
+    if self.riff_start == 0 {
+      let mut h = Header::default();
+      let idx1 = h.start(b"idx1");
+      for &(id, offset, size) in &self.first_riff {
+        h.bytes(&id);
+        h.u32(KEYFRAME);
+        h.u32(offset);
+        h.u32(size);
+      }
+      h.end(idx1);
+      self.put(&h.data)?;
+      self.first_riff = vec![];
+    }
+
+    let riff_size = (self.pos - self.riff_start - 8) as u32;
+    self.patch(self.riff_start + 4, &riff_size.to_le_bytes())
+  }
+
+  /// Write the indexes and fill in the headers.
+  pub fn finish(&mut self) -> io::Result<()> {
+    self.finished = true;
+    if !self.full {
+      self.end_riff()?;
+    }
+    self.patch(self.total_frames_pos, &self.first_riff_frames.to_le_bytes())?;
+    let frames = self.video.total as u32;
+    self.patch(self.odml_frames_pos, &frames.to_le_bytes())?;
+    self.patch(self.video.length_pos, &frames.to_le_bytes())?;
+    let samples = self.audio.total as u32;
+    self.patch(self.audio.length_pos, &samples.to_le_bytes())?;
+    for video in [true, false] {
+      let stream = if video { &self.video } else { &self.audio };
+      let (pos, index) = (stream.index_pos, super_index(stream));
+      self.patch(pos, &index)?;
+    }
+    self.writer.flush()
+  }
+}
+
+fn too_long() -> io::Error {
+  io::Error::other("recording is too long for the AVI index")
+}
+
+/// A stream's index of RIFF chunk indexes, with room for more entries.
+fn super_index(stream: &Stream) -> Vec<u8> {
+  let mut h = Header::default();
+  let indx = h.start(b"indx");
+  h.u16(4); // Longs per entry
+  h.u16(0); // An index of indexes
+  h.u32(stream.indexes.len() as u32);
+  h.bytes(&stream.id);
+  h.bytes(&[0; 12]);
+  for &(offset, size, duration) in &stream.indexes {
+    h.bytes(&offset.to_le_bytes());
+    h.u32(size);
+    h.u32(duration);
+  }
+  h.data.resize(8 + SUPER_INDEX_SIZE, 0);
+  h.end(indx);
+  h.data
+}
+
+impl<W: Write + Seek> Drop for AviWriter<W> {
+  fn drop(&mut self) {
+    if !self.finished {
+      if let Err(e) = self.finish() {
+        eprintln!("Unable to finish AVI file: {}", e);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+  }
+
+  /// Top-level chunks as (id, form type, position, size).
+  fn riffs(data: &[u8]) -> Vec<(&[u8], &[u8], usize, usize)> {
+    let mut riffs = vec![];
+    let mut pos = 0;
+    while pos < data.len() {
+      let size = u32_at(data, pos + 4) as usize;
+      riffs.push((&data[pos..pos + 4], &data[pos + 8..pos + 12], pos, size));
+      pos += 8 + size;
+    }
+    riffs
+  }
+
+  #[test]
+  fn riff_chunks_and_indexes() {
+    let mut file = Cursor::new(vec![]);
+    {
+      let mut avi = AviWriter::new(&mut file, 2, 2, 48000).unwrap();
+      avi.riff_limit = 10_000;
+      for n in 0..100 {
+        let image =
+          Image::from_pixels(2, 2, &[n, 0x0000ff, 0x00ff00, 0xff0000]);
+        avi.write_frame(&image).unwrap();
+        avi.write_audio(&[0.5; 1600]).unwrap();
+      }
+      avi.finish().unwrap();
+    }
+    let data = file.into_inner();
+
+    let riffs = riffs(&data);
+    assert!(riffs.len() > 2);
+    assert_eq!((riffs[0].0, riffs[0].1), (&b"RIFF"[..], &b"AVI "[..]));
+    for riff in &riffs[1..] {
+      assert_eq!((riff.0, riff.1), (&b"RIFF"[..], &b"AVIX"[..]));
+      assert!(riff.3 < 12_000);
+    }
+    let first_riff_frames = u32_at(&data, 48);
+    assert!(first_riff_frames > 0 && first_riff_frames < 100);
+
+    // Follow the video stream's indexes to the last frame.
+    let indx = data.windows(4).position(|w| w == b"indx").unwrap();
+    // The last RIFF may only hold audio.
+    let indexes = u32_at(&data, indx + 12) as usize;
+    assert!(indexes == riffs.len() || indexes == riffs.len() - 1);
+    let last = indx + 32 + 16 * (indexes - 1);
+    let ix = u32_at(&data, last) as usize;
+    assert_eq!(&data[ix..ix + 4], b"ix00");
+    let entries = u32_at(&data, ix + 12) as usize;
+    let base = u32_at(&data, ix + 20) as usize;
+    let frame = ix + 32 + 8 * (entries - 1);
+    let offset = base + u32_at(&data, frame) as usize;
+    assert_eq!(&data[offset - 8..offset], b"00dc\x10\0\0\0");
+    // Bottom row first, in BGR order, with rows padded to 4 bytes.
+    assert_eq!(&data[offset..offset + 6], &[0, 0xff, 0, 0, 0, 0xff]);
+    assert_eq!(&data[offset + 8..offset + 11], &[99, 0, 0]);
+  }
+
+  #[test]
+  fn index_full() {
+    let mut file = Cursor::new(vec![]);
+    let mut frames = 0;
+    {
+      let mut avi = AviWriter::new(&mut file, 2, 2, 48000).unwrap();
+      avi.riff_limit = 1_000;
+      let image = Image::from_pixels(2, 2, &[0; 4]);
+      while avi.write_frame(&image).is_ok() {
+        frames += 1;
+      }
+      assert!(avi.write_frame(&image).is_err());
+      avi.finish().unwrap();
+    }
+    let data = file.into_inner();
+
+    // Every RIFF is complete and the headers are filled in.
+    let riffs = riffs(&data);
+    assert_eq!(riffs.len(), SUPER_INDEX_ENTRIES);
+    let last = riffs[riffs.len() - 1];
+    assert_eq!(last.2 + 8 + last.3, data.len());
+    let indx = data.windows(4).position(|w| w == b"indx").unwrap();
+    assert_eq!(u32_at(&data, indx + 12) as usize, SUPER_INDEX_ENTRIES);
+    let dmlh = data.windows(4).position(|w| w == b"dmlh").unwrap();
+    assert_eq!(u32_at(&data, dmlh + 8), frames);
+  }
+}
@@ -0,0 +1,99 @@
+//! Saving frames as PNG images, one at a time or as a numbered sequence.
+
+use super::{Filter, Image, IntegerScale};
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// Write `image` to `path` as a PNG, scaled up `scale` times.
+pub fn write_png(path: &Path, image: &Image, scale: usize) -> io::Result<()> {
+  let scaled;
+  let image = if scale > 1 {
+    scaled = IntegerScale(scale).apply(image);
+    &scaled
+  } else {
+    image
+  };
+  let file = BufWriter::new(File::create(path)?);
+  let mut encoder =
+    png::Encoder::new(file, image.width as u32, image.height as u32);
+  encoder.set_color(png::ColorType::Rgb);
+  encoder.set_depth(png::BitDepth::Eight);
+  let mut writer = encoder.write_header()?;
+  let data: Vec<u8> = image
+    .pixels
+    .iter()
+    .flat_map(|&p| [(p >> 16) as u8, (p >> 8) as u8, p as u8])
+    .collect();
+  writer.write_image_data(&data)?;
+  writer.finish()?;
+  Ok(())
+}
+
+/// First path of the form `dir/name-N.png` that doesn't exist yet, counting
+/// from 1.
+pub fn next_path(dir: &Path, name: &str) -> PathBuf {
+  (1..)
+    .map(|n| dir.join(format!("{}-{}.png", name, n)))
+    .find(|path| !path.exists())
+    .unwrap()
+}
+
+/// Writes every `every`th frame to a directory, as `frame-000000.png` and
+/// so on, numbered by frame.
+#[derive(Debug)]
+pub struct FrameDump {
+  dir: PathBuf,
+  every: u64,
+  scale: usize,
+}
+
+impl FrameDump {
+  /// Start dumping to `dir`, creating it if needed.
+  pub fn create(dir: &Path, every: u64, scale: usize) -> io::Result<FrameDump> {
+    assert!(every > 0);
+    fs::create_dir_all(dir)?;
+    Ok(FrameDump {
+      dir: dir.to_path_buf(),
+      every,
+      scale,
+    })
+  }
+
+  /// Write frame number `frame`, if it's one to dump.
+  pub fn frame(&self, frame: u64, image: &Image) -> io::Result<()> {
+    if !frame.is_multiple_of(self.every) {
+      return Ok(());
+    }
+    let path = self.dir.join(format!("frame-{:06}.png", frame));
+    write_png(&path, image, self.scale)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn png_round_trip() {
+    let dir =
+      std::env::temp_dir().join(format!("capture{}", std::process::id()));
+    let dump = FrameDump::create(&dir, 2, 2).unwrap();
+    let image = Image::from_pixels(2, 1, &[0x123456, 0xffffff]);
+    for frame in 0..3 {
+      dump.frame(frame, &image).unwrap();
+    }
+    assert!(!dir.join("frame-000001.png").exists());
+    assert_eq!(next_path(&dir, "frame"), dir.join("frame-1.png"));
+
+    let file = File::open(dir.join("frame-000002.png")).unwrap();
+    let mut reader = png::Decoder::new(file).read_info().unwrap();
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).unwrap();
+    assert_eq!((info.width, info.height), (4, 2));
+    assert_eq!(&data[..6], &[0x12, 0x34, 0x56, 0x12, 0x34, 0x56]);
+    assert_eq!(&data[6..9], &[0xff, 0xff, 0xff]);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
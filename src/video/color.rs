@@ -0,0 +1,27 @@
+use crate::video::{Filter, Image};
+
+/// Approximates the colours of the CGB's LCD, which are less saturated
+/// and bleed between channels compared to a modern display.
+pub struct ColorCorrection;
+
+impl ColorCorrection {
+  fn correct(color: u32) -> u32 {
+    let r = (color >> 16) & 0xff;
+    let g = (color >> 8) & 0xff;
+    let b = color & 0xff;
+    let r2 = (r * 26 + g * 4 + b * 2) / 32;
+    let g2 = (g * 24 + b * 8) / 32;
+    let b2 = (r * 6 + g * 4 + b * 22) / 32;
+    (r2 << 16) | (g2 << 8) | b2
+  }
+}
+
+impl Filter for ColorCorrection {
+  fn apply(&mut self, input: &Image) -> Image {
+    let mut out = input.clone();
+    for p in out.pixels.iter_mut() {
+      *p = ColorCorrection::correct(*p);
+    }
+    out
+  }
+}
@@ -0,0 +1,71 @@
+use crate::video::{mix, Filter, Image};
+
+/// Scales up and darkens the gaps between pixels, like the LCD's grid.
+pub struct LcdGrid {
+  scale: usize,
+}
+
+impl LcdGrid {
+  /// Darkening of the grid lines, from 0.0 (none) to 1.0 (black).
+  const STRENGTH: f32 = 0.35;
+
+  pub fn new(scale: usize) -> LcdGrid {
+    LcdGrid { scale }
+  }
+}
+
+impl Filter for LcdGrid {
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width * self.scale, height * self.scale)
+  }
+
+  fn apply(&mut self, input: &Image) -> Image {
+    let n = self.scale;
+    let mut out = Image::new(input.width * n, input.height * n);
+    for y in 0..out.height {
+      for x in 0..out.width {
+        let color = input.pixels[(y / n) * input.width + x / n];
+        let on_grid = x % n == n - 1 || y % n == n - 1;
+        let color = if on_grid {
+          mix(color, 0, LcdGrid::STRENGTH)
+        } else {
+          color
+        };
+        out.set(x, y, color);
+      }
+    }
+    out
+  }
+}
+
+/// Blends each frame with the previous output, simulating the slow
+/// response of the LCD. Games that flicker objects rely on this.
+pub struct Ghosting {
+  /// Weight of the previous frame, from 0.0 to 1.0.
+  strength: f32,
+  last: Option<Image>,
+}
+
+impl Ghosting {
+  pub fn new(strength: f32) -> Ghosting {
+    Ghosting {
+      strength,
+      last: None,
+    }
+  }
+}
+
+impl Filter for Ghosting {
+  fn apply(&mut self, input: &Image) -> Image {
+    let mut out = input.clone();
+    if let Some(ref last) = self.last {
+      if last.width == input.width && last.height == input.height {
+        for (p, &prev) in out.pixels.iter_mut().zip(last.pixels.iter()) {
+          *p = mix(*p, prev, self.strength);
+        }
+      }
+    }
+    self.last = Some(out.clone());
+    out
+  }
+}
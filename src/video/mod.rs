@@ -0,0 +1,189 @@
+use std::error::Error;
+use std::fmt;
+
+mod avi;
+mod capture;
+mod color;
+mod lcd;
+mod record;
+mod scale;
+
+pub use self::avi::AviWriter;
+pub use self::capture::{next_path, write_png, FrameDump};
+pub use self::color::ColorCorrection;
+pub use self::lcd::{Ghosting, LcdGrid};
+pub use self::record::VideoRecorder;
+pub use self::scale::{Hq2x, IntegerScale, Scale2x, Xbr2x};
+
+/// An RGB (0x00rrggbb) image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+  pub width: usize,
+  pub height: usize,
+  pub pixels: Vec<u32>,
+}
+
+impl Image {
+  pub fn new(width: usize, height: usize) -> Image {
+    Image {
+      width,
+      height,
+      pixels: vec![0; width * height],
+    }
+  }
+
+  pub fn from_pixels(width: usize, height: usize, pixels: &[u32]) -> Image {
+    assert_eq!(pixels.len(), width * height);
+    Image {
+      width,
+      height,
+      pixels: pixels.to_vec(),
+    }
+  }
+
+  /// Get the pixel at (x, y), clamping coordinates to the edges.
+  pub fn get(&self, x: isize, y: isize) -> u32 {
+    let x = x.max(0).min(self.width as isize - 1) as usize;
+    let y = y.max(0).min(self.height as isize - 1) as usize;
+    self.pixels[y * self.width + x]
+  }
+
+  pub fn set(&mut self, x: usize, y: usize, color: u32) {
+    self.pixels[y * self.width + x] = color;
+  }
+}
+
+/// A post-processing step applied to each frame.
+pub trait Filter {
+  /// Size of the output for an input of `width` x `height`.
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width, height)
+  }
+
+  fn apply(&mut self, input: &Image) -> Image;
+}
+
+/// Linearly interpolate each channel from `a` to `b` by `t` (0.0-1.0).
+pub fn mix(a: u32, b: u32, t: f32) -> u32 {
+  let channel = |shift: u32| -> u32 {
+    let ca = ((a >> shift) & 0xff) as f32;
+    let cb = ((b >> shift) & 0xff) as f32;
+    ((ca + (cb - ca) * t).round() as u32) << shift
+  };
+  channel(16) | channel(8) | channel(0)
+}
+
+#[derive(Debug)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Invalid video filter: {}", self.0)
+  }
+}
+
+impl Error for ParseError {}
+
+/// A chain of filters turning emulator frames into output images.
+#[derive(Default)]
+pub struct Pipeline {
+  filters: Vec<Box<dyn Filter>>,
+  output: Option<Image>,
+}
+
+impl Pipeline {
+  pub fn new() -> Pipeline {
+    Pipeline::default()
+  }
+
+  /// Parse a comma-separated list of filters, each optionally followed by a
+  /// parameter, e.g. `color-correct,scale2x,lcd-grid:3`.
+  pub fn parse(s: &str) -> Result<Pipeline, ParseError> {
+    let mut pipeline = Pipeline::new();
+    for spec in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+      let mut parts = spec.splitn(2, ':');
+      let name = parts.next().unwrap();
+      let param = match parts.next() {
+        Some(p) => {
+          Some(p.parse::<u32>().map_err(|_| ParseError(spec.to_string()))?)
+        }
+        None => None,
+      };
+      let filter: Box<dyn Filter> = match (name, param) {
+        ("integer", Some(n)) if n >= 1 => Box::new(IntegerScale(n as usize)),
+        ("scale2x", None) => Box::new(Scale2x),
+        ("hq2x", None) => Box::new(Hq2x),
+        ("xbr", None) => Box::new(Xbr2x),
+        ("lcd-grid", p) if p.is_none() || p >= Some(2) => {
+          Box::new(LcdGrid::new(p.unwrap_or(3) as usize))
+        }
+        ("ghosting", p) if p.is_none() || p < Some(100) => {
+          Box::new(Ghosting::new(p.unwrap_or(50) as f32 / 100.0))
+        }
+        ("color-correct", None) => Box::new(ColorCorrection),
+        _ => return Err(ParseError(spec.to_string())),
+      };
+      pipeline.push(filter);
+    }
+    Ok(pipeline)
+  }
+
+  pub fn push(&mut self, filter: Box<dyn Filter>) {
+    self.filters.push(filter);
+  }
+
+  /// Size of the output for frames of `width` x `height`.
+  pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    self
+      .filters
+      .iter()
+      .fold((width, height), |(w, h), f| f.output_size(w, h))
+  }
+
+  /// Run a frame of `width` x `height` pixels through all the filters.
+  pub fn process(
+    &mut self,
+    frame: &[u32],
+    width: usize,
+    height: usize,
+  ) -> &Image {
+    let mut image = Image::from_pixels(width, height, frame);
+    for filter in self.filters.iter_mut() {
+      image = filter.apply(&image);
+    }
+    self.output.insert(image)
+  }
+}
+
+impl fmt::Debug for Pipeline {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Pipeline")
+      .field("filters", &self.filters.len())
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse() {
+    let pipeline = Pipeline::parse("color-correct,scale2x,lcd-grid:3").unwrap();
+    assert_eq!(pipeline.output_size(160, 144), (960, 864));
+    assert_eq!(
+      Pipeline::parse("").unwrap().output_size(160, 144),
+      (160, 144)
+    );
+    assert!(Pipeline::parse("integer").is_err());
+    assert!(Pipeline::parse("integer:0").is_err());
+    assert!(Pipeline::parse("blur").is_err());
+    assert!(Pipeline::parse("hq2x").is_ok());
+  }
+
+  #[test]
+  fn mix_channels() {
+    assert_eq!(mix(0x000000, 0xffffff, 0.5), 0x808080);
+    assert_eq!(mix(0x102030, 0x102030, 0.3), 0x102030);
+  }
+}
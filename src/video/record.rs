@@ -0,0 +1,47 @@
+use super::{AviWriter, Image};
+use crate::audio::{Resampler, APU_RATE};
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+/// Records frames and audio to an AVI file.
+/// Frames are written as they're emulated rather than as they're shown, so
+/// the video runs at exactly the Game Boy's frame rate and a recording only
+/// depends on emulated time.
+pub struct VideoRecorder {
+  avi: AviWriter<BufWriter<File>>,
+  resampler: Resampler,
+  buffer: Vec<f32>,
+}
+
+impl VideoRecorder {
+  pub fn create(
+    path: &Path,
+    width: usize,
+    height: usize,
+    sample_rate: u32,
+  ) -> io::Result<VideoRecorder> {
+    Ok(VideoRecorder {
+      avi: AviWriter::create(path, width, height, sample_rate)?,
+      resampler: Resampler::new(APU_RATE, sample_rate),
+      buffer: vec![],
+    })
+  }
+
+  pub fn write_frame(&mut self, image: &Image) -> io::Result<()> {
+    self.avi.write_frame(image)
+  }
+
+  /// Write APU samples, as interleaved left/right pairs.
+  pub fn write_audio(&mut self, samples: &[f32]) -> io::Result<()> {
+    self.buffer.clear();
+    self.resampler.process(samples, &mut self.buffer);
+    self.avi.write_audio(&self.buffer)
+  }
+
+  /// Complete the file, reporting any error.
+  pub fn finish(mut self) -> io::Result<()> {
+    self.avi.finish()
+  }
+}
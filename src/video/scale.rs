@@ -0,0 +1,412 @@
+use crate::video::{mix, Filter, Image};
+
+/// Nearest-neighbour scaling by a whole factor.
+pub struct IntegerScale(pub usize);
+
+impl Filter for IntegerScale {
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width * self.0, height * self.0)
+  }
+
+  fn apply(&mut self, input: &Image) -> Image {
+    let n = self.0;
+    let mut out = Image::new(input.width * n, input.height * n);
+    for y in 0..out.height {
+      for x in 0..out.width {
+        let color = input.pixels[(y / n) * input.width + x / n];
+        out.set(x, y, color);
+      }
+    }
+    out
+  }
+}
+
+/// Scale2x (AdvMAME2x), which rounds off diagonal edges.
+pub struct Scale2x;
+
+impl Filter for Scale2x {
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width * 2, height * 2)
+  }
+
+  fn apply(&mut self, input: &Image) -> Image {
+    let mut out = Image::new(input.width * 2, input.height * 2);
+    for y in 0..input.height {
+      for x in 0..input.width {
+        let (xi, yi) = (x as isize, y as isize);
+        let b = input.get(xi, yi - 1);
+        let d = input.get(xi - 1, yi);
+        let e = input.get(xi, yi);
+        let f = input.get(xi + 1, yi);
+        let h = input.get(xi, yi + 1);
+
+        let mut quad = [e; 4];
+        if b != h && d != f {
+          if d == b {
+            quad[0] = d;
+          }
+          if b == f {
+            quad[1] = f;
+          }
+          if d == h {
+            quad[2] = d;
+          }
+          if h == f {
+            quad[3] = f;
+          }
+        }
+        write_quad(&mut out, x, y, &quad);
+      }
+    }
+    out
+  }
+}
+
+/// Write the 2x2 block for input pixel (x, y): top left, top right,
+/// bottom left, bottom right.
+fn write_quad(out: &mut Image, x: usize, y: usize, quad: &[u32; 4]) {
+  out.set(x * 2, y * 2, quad[0]);
+  out.set(x * 2 + 1, y * 2, quad[1]);
+  out.set(x * 2, y * 2 + 1, quad[2]);
+  out.set(x * 2 + 1, y * 2 + 1, quad[3]);
+}
+
+fn yuv(color: u32) -> (i32, i32, i32) {
+  let r = ((color >> 16) & 0xff) as i32;
+  let g = ((color >> 8) & 0xff) as i32;
+  let b = (color & 0xff) as i32;
+  let y = (r * 299 + g * 587 + b * 114) / 1000;
+  let u = (-r * 169 - g * 331 + b * 500) / 1000;
+  let v = (r * 500 - g * 419 - b * 81) / 1000;
+  (y, u, v)
+}
+
+/// Whether two colours are far apart, using hq2x's YUV thresholds.
+fn yuv_differ(a: u32, b: u32) -> bool {
+  if a == b {
+    return false;
+  }
+  let (ya, ua, va) = yuv(a);
+  let (yb, ub, vb) = yuv(b);
+  (ya - yb).abs() > 48 || (ua - ub).abs() > 7 || (va - vb).abs() > 6
+}
+
+/// Weighted YUV distance between two colours, as used by xBR.
+fn yuv_distance(a: u32, b: u32) -> i32 {
+  let (ya, ua, va) = yuv(a);
+  let (yb, ub, vb) = yuv(b);
+  (ya - yb).abs() * 48 + (ua - ub).abs() * 7 + (va - vb).abs() * 6
+}
+
+type Rotation = fn(isize, isize) -> (isize, isize);
+
+/// Rotations that map neighbour offsets written for a pixel's bottom-right
+/// corner onto each of its four corners.
+const ROTATIONS: [Rotation; 4] = [
+  |dx, dy| (dx, dy),
+  |dx, dy| (-dy, dx),
+  |dx, dy| (-dx, -dy),
+  |dx, dy| (dy, -dx),
+];
+
+/// Index in a 2x2 block of the subpixel in direction (dx, dy).
+fn quad_index(dx: isize, dy: isize) -> usize {
+  (if dy > 0 { 2 } else { 0 }) + (if dx > 0 { 1 } else { 0 })
+}
+
+/// hq2x, which compares each pixel with its eight neighbours in YUV and
+/// blends each corner according to the pattern of neighbours that differ.
+/// The 256-case table is expressed as rules for the top-left corner, which
+/// the other corners use mirrored.
+pub struct Hq2x;
+
+/// Indices into a 3x3 neighbourhood, in reading order, that put each
+/// corner of the centre pixel at the top left.
+const MIRRORS: [[usize; 9]; 4] = [
+  [0, 1, 2, 3, 4, 5, 6, 7, 8],
+  [2, 1, 0, 5, 4, 3, 8, 7, 6],
+  [6, 7, 8, 3, 4, 5, 0, 1, 2],
+  [8, 7, 6, 5, 4, 3, 2, 1, 0],
+];
+
+impl Filter for Hq2x {
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width * 2, height * 2)
+  }
+
+  fn apply(&mut self, input: &Image) -> Image {
+    let mut out = Image::new(input.width * 2, input.height * 2);
+    for y in 0..input.height {
+      for x in 0..input.width {
+        let (xi, yi) = (x as isize, y as isize);
+        let mut w = [0; 9];
+        for (i, color) in w.iter_mut().enumerate() {
+          *color = input.get(xi + i as isize % 3 - 1, yi + i as isize / 3 - 1);
+        }
+        let mut quad = [0; 4];
+        for (corner, mirror) in quad.iter_mut().zip(MIRRORS.iter()) {
+          let mut n = [0; 9];
+          for (color, &i) in n.iter_mut().zip(mirror.iter()) {
+            *color = w[i];
+          }
+          *corner = hq2x_corner(&n);
+        }
+        write_quad(&mut out, x, y, &quad);
+      }
+    }
+    out
+  }
+}
+
+/// The top-left quarter of pixel `w[4]` given its neighbourhood `w`.
+fn hq2x_corner(w: &[u32; 9]) -> u32 {
+  // Bits 0-7 are set for neighbours 0-3 and 5-8 that differ from the
+  // centre.
+  let pattern = [0, 1, 2, 3, 5, 6, 7, 8]
+    .iter()
+    .enumerate()
+    .filter(|&(_, &i)| yuv_differ(w[4], w[i]))
+    .fold(0u8, |pattern, (bit, _)| pattern | 1 << bit);
+  let p = |mask: u8, value: u8| pattern & mask == value;
+  let any = |cases: &[(u8, u8)]| cases.iter().any(|&(m, v)| p(m, v));
+  let (w0, w1, w3, w4, w5, w7) = (w[0], w[1], w[3], w[4], w[5], w[7]);
+
+  if any(&[(0xbf, 0x37), (0xdb, 0x13)]) && yuv_differ(w1, w5) {
+    return interpolate(&[(w4, 3), (w3, 1)], 2);
+  }
+  if any(&[(0xdb, 0x49), (0xef, 0x6d)]) && yuv_differ(w7, w3) {
+    return interpolate(&[(w4, 3), (w1, 1)], 2);
+  }
+  if any(&[(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)]) && yuv_differ(w3, w1) {
+    return w4;
+  }
+  if any(&[
+    (0x6f, 0x2a),
+    (0x5b, 0x0a),
+    (0xbf, 0x3a),
+    (0xdf, 0x5a),
+    (0x9f, 0x8a),
+    (0xcf, 0x8a),
+    (0xef, 0x4e),
+    (0x3f, 0x0e),
+    (0xfb, 0x5a),
+    (0xbb, 0x8a),
+    (0x7f, 0x5a),
+    (0xaf, 0x8a),
+    (0xeb, 0x8a),
+  ]) && yuv_differ(w3, w1)
+  {
+    return interpolate(&[(w4, 3), (w0, 1)], 2);
+  }
+  if p(0x0b, 0x08) {
+    return interpolate(&[(w4, 2), (w0, 1), (w1, 1)], 2);
+  }
+  if p(0x0b, 0x02) {
+    return interpolate(&[(w4, 2), (w0, 1), (w3, 1)], 2);
+  }
+  if p(0x2f, 0x2f) {
+    return interpolate(&[(w4, 14), (w3, 1), (w1, 1)], 4);
+  }
+  if any(&[(0xbf, 0x37), (0xdb, 0x13)]) {
+    return interpolate(&[(w4, 5), (w1, 2), (w3, 1)], 3);
+  }
+  if any(&[(0xdb, 0x49), (0xef, 0x6d)]) {
+    return interpolate(&[(w4, 5), (w3, 2), (w1, 1)], 3);
+  }
+  if any(&[(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)]) {
+    return interpolate(&[(w4, 3), (w3, 1)], 2);
+  }
+  if any(&[(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)]) {
+    return interpolate(&[(w4, 3), (w1, 1)], 2);
+  }
+  if any(&[(0x7e, 0x2a), (0xef, 0xab), (0xbf, 0x8f), (0x7e, 0x0e)]) {
+    return interpolate(&[(w4, 2), (w3, 3), (w1, 3)], 3);
+  }
+  if any(&[
+    (0xfb, 0x6a),
+    (0x6f, 0x6e),
+    (0x3f, 0x3e),
+    (0xfb, 0xfa),
+    (0xdf, 0xde),
+    (0xdf, 0x1e),
+  ]) {
+    return interpolate(&[(w4, 3), (w0, 1)], 2);
+  }
+  if any(&[
+    (0x0a, 0x00),
+    (0x4f, 0x4b),
+    (0x9f, 0x1b),
+    (0x2f, 0x0b),
+    (0xbe, 0x0a),
+    (0xee, 0x0a),
+    (0x7e, 0x0a),
+    (0xeb, 0x4b),
+    (0x3b, 0x1b),
+  ]) {
+    return interpolate(&[(w4, 2), (w3, 1), (w1, 1)], 2);
+  }
+  interpolate(&[(w4, 6), (w3, 1), (w1, 1)], 3)
+}
+
+/// Weighted sum of colours, whose weights add up to `1 << shift`.
+fn interpolate(colors: &[(u32, u32)], shift: u32) -> u32 {
+  let channel = |mask: u32| -> u32 {
+    let sum: u32 = colors.iter().map(|&(c, w)| (c & mask) * w).sum();
+    (sum >> shift) & mask
+  };
+  channel(0xff0000) | channel(0x00ff00) | channel(0x0000ff)
+}
+
+/// xBR level 2 at 2x, which detects edges from weighted colour distances
+/// over a 5x5 neighbourhood.
+pub struct Xbr2x;
+
+impl Filter for Xbr2x {
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width * 2, height * 2)
+  }
+
+  fn apply(&mut self, input: &Image) -> Image {
+    let d = yuv_distance;
+    let mut out = Image::new(input.width * 2, input.height * 2);
+    for y in 0..input.height {
+      for x in 0..input.width {
+        let (xi, yi) = (x as isize, y as isize);
+        let e = input.get(xi, yi);
+        let mut quad = [e; 4];
+        for rotate in ROTATIONS.iter() {
+          let at = |dx, dy| {
+            let (dx, dy) = rotate(dx, dy);
+            input.get(xi + dx, yi + dy)
+          };
+          //     A1 B1 C1
+          //  A0 A  B  C  C4
+          //  D0 D  E  F  F4
+          //  G0 G  H  I  I4
+          //     G5 H5 I5
+          let (b, c, d0, f, g, h, i) = (
+            at(0, -1),
+            at(1, -1),
+            at(-1, 0),
+            at(1, 0),
+            at(-1, 1),
+            at(0, 1),
+            at(1, 1),
+          );
+          let (f4, h5, i4, i5) = (at(2, 0), at(0, 2), at(2, 1), at(1, 2));
+          if e == f || e == h {
+            continue;
+          }
+          let edge_e = d(e, c) + d(e, g) + d(i, h5) + d(i, f4) + 4 * d(h, f);
+          let edge_i = d(h, d0) + d(h, i5) + d(f, i4) + d(f, b) + 4 * d(e, i);
+          if edge_e >= edge_i {
+            continue;
+          }
+
+          let px = if d(e, f) <= d(e, h) { f } else { h };
+          let ke = d(f, g);
+          let ki = d(h, c);
+          let ex2 = e != c && b != c;
+          let ex3 = e != g && d0 != g;
+
+          let corner = rotate(1, 1);
+          let left = rotate(-1, 1);
+          let up = rotate(1, -1);
+          let corner = quad_index(corner.0, corner.1);
+          if ke * 2 <= ki && ex3 {
+            // Shallow edge.
+            let left = quad_index(left.0, left.1);
+            quad[left] = mix(quad[left], px, 0.25);
+            quad[corner] = mix(quad[corner], px, 0.75);
+          } else if ke >= ki * 2 && ex2 {
+            // Steep edge.
+            let up = quad_index(up.0, up.1);
+            quad[up] = mix(quad[up], px, 0.25);
+            quad[corner] = mix(quad[corner], px, 0.75);
+          } else {
+            quad[corner] = mix(quad[corner], px, 0.5);
+          }
+        }
+        write_quad(&mut out, x, y, &quad);
+      }
+    }
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn integer_scale() {
+    let input = Image::from_pixels(2, 1, &[1, 2]);
+    let out = IntegerScale(2).apply(&input);
+    assert_eq!(out.pixels, vec![1, 1, 2, 2, 1, 1, 2, 2]);
+  }
+
+  #[test]
+  fn scale2x_diagonal() {
+    // A diagonal line from the top right to the bottom left.
+    let (w, k) = (0xffffff, 0x000000);
+    let input = Image::from_pixels(2, 2, &[w, k, k, w]);
+    let out = Scale2x.apply(&input);
+    // The white top-left pixel gets its bottom-right corner filled in.
+    assert_eq!(out.get(1, 1), k);
+    assert_eq!(out.get(0, 0), w);
+  }
+
+  #[test]
+  fn hq2x_single_pixel() {
+    let (w, k) = (0xffffff, 0x000000);
+    let mut pixels = [w; 9];
+    pixels[4] = k;
+    let out = Hq2x.apply(&Image::from_pixels(3, 3, &pixels));
+    // Every neighbour differs, so each corner of the dot takes a sixteenth
+    // of each of the two nearest neighbours.
+    for &(x, y) in &[(2, 2), (3, 2), (2, 3), (3, 3)] {
+      assert_eq!(out.get(x, y), 0x1f1f1f);
+    }
+    // The pixels around it stay white.
+    assert_eq!(out.get(1, 2), w);
+    assert_eq!(out.get(2, 1), w);
+  }
+
+  #[test]
+  fn hq2x_mirrors() {
+    let pixels = (0..25)
+      .map(|i: u32| {
+        if (i * 7) % 5 < 2 {
+          0x000000
+        } else {
+          0xffffff - i
+        }
+      })
+      .collect::<Vec<_>>();
+    let input = Image::from_pixels(5, 5, &pixels);
+    let mirror = |image: &Image| {
+      let mut out = Image::new(image.width, image.height);
+      for y in 0..image.height {
+        for x in 0..image.width {
+          let color = image.get((image.width - 1 - x) as isize, y as isize);
+          out.set(x, y, color);
+        }
+      }
+      out
+    };
+    let out = Hq2x.apply(&input);
+    assert_eq!(Hq2x.apply(&mirror(&input)).pixels, mirror(&out).pixels);
+  }
+
+  #[test]
+  fn flat_images_unchanged() {
+    let input = Image::from_pixels(3, 3, &[0x123456; 9]);
+    for filter in &mut [
+      Box::new(Hq2x) as Box<dyn Filter>,
+      Box::new(Xbr2x),
+      Box::new(Scale2x),
+    ] {
+      assert!(filter.apply(&input).pixels.iter().all(|&p| p == 0x123456));
+    }
+  }
+}